@@ -307,10 +307,10 @@ async fn my_nat_info(pipe_writer: &PipeWriter<u32>) -> Arc<Mutex<NatInfo>> {
         "stun.chat.bilibili.com:3478".to_string(),
         "stun.hitv.com:3478".to_string(),
     ];
-    let (nat_type, public_ips, port_range) = rust_p2p_core::stun::stun_test_nat(stun_server, None)
+    let nat_test = rust_p2p_core::stun::stun_test_nat_behavior(stun_server, None)
         .await
         .unwrap();
-    log::info!("nat_type:{nat_type:?},public_ips:{public_ips:?},port_range={port_range}");
+    log::info!("nat_test:{nat_test:?}");
     let local_ipv4 = rust_p2p_core::extend::addr::local_ipv4().await.unwrap();
     let local_udp_ports = pipe_writer
         .udp_pipe_writer()
@@ -321,18 +321,23 @@ async fn my_nat_info(pipe_writer: &PipeWriter<u32>) -> Arc<Mutex<NatInfo>> {
     let mut public_ports = local_udp_ports.clone();
     public_ports.fill(0);
     let nat_info = NatInfo {
-        nat_type,
-        public_ips,
+        nat_type: nat_test.nat_type,
+        public_ips: nat_test.public_ips,
         public_ports,
         mapping_tcp_addr: vec![],
         mapping_udp_addr: vec![],
-        public_port_range: port_range,
+        public_port_range: nat_test.port_range,
         local_ipv4,
         ipv6: None,
         local_udp_ports,
         local_tcp_port,
         public_tcp_port: 0,
         seq: 0,
+        mapping_behavior: nat_test.mapping_behavior,
+        filtering_behavior: nat_test.filtering_behavior,
+        hairpinning: nat_test.hairpinning,
+        mapping_lifetime: None,
+        predicted_ports: nat_test.predicted_ports,
     };
     Arc::new(Mutex::new(nat_info))
 }
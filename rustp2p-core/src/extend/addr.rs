@@ -1,7 +1,9 @@
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use tokio::net::UdpSocket;
 
+use crate::socket::{bind_udp, LocalInterface};
+
 pub async fn local_ipv4() -> io::Result<Ipv4Addr> {
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
     socket.connect("8.8.8.8:80").await?;
@@ -23,6 +25,25 @@ pub async fn local_ipv6() -> io::Result<Ipv6Addr> {
     }
 }
 
+/// The local address the OS sends to `dest` from, the route is looked up without sending
+pub fn local_ip_to(
+    dest: SocketAddr,
+    default_interface: Option<&LocalInterface>,
+) -> io::Result<IpAddr> {
+    let unspecified: IpAddr = if dest.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket =
+        bind_udp(SocketAddr::new(unspecified, 0), default_interface).map_err(io::Error::other)?;
+    socket.connect(&dest.into())?;
+    socket
+        .local_addr()?
+        .as_socket()
+        .map(|addr| addr.ip())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))
+}
 pub const fn is_ipv4_global(ipv4: &Ipv4Addr) -> bool {
     !(ipv4.octets()[0] == 0 // "This network"
         || ipv4.is_private()
@@ -68,3 +89,10 @@ pub const fn is_ipv6_global(ipv6addr: &Ipv6Addr) -> bool {
         || (ipv6addr.segments()[0] & 0xfe00) == 0xfc00//ipv6addr.is_unique_local()
         || (ipv6addr.segments()[0] & 0xffc0) == 0xfe80) //ipv6addr.is_unicast_link_local())
 }
+
+pub const fn is_ip_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_ipv4_global(ip),
+        IpAddr::V6(ip) => is_ipv6_global(ip),
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+
+/// Without netlink the interfaces are listed this often
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// One change comes as a burst of events, e.g. link up and then the addresses
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Notices when the addresses of the local interfaces change, such as on a move from Wi-Fi to Ethernet.
+/// Linux and Android are told by netlink, the other platforms list the interfaces periodically
+pub struct InterfaceWatcher {
+    addrs: BTreeSet<(String, IpAddr)>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    netlink: Option<netlink::Netlink>,
+}
+
+impl InterfaceWatcher {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            addrs: local_addrs()?,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            netlink: match netlink::Netlink::new() {
+                Ok(netlink) => Some(netlink),
+                Err(e) => {
+                    log::warn!("netlink {e:?}, polling the interfaces instead");
+                    None
+                }
+            },
+        })
+    }
+    /// The addresses seen last, by interface name
+    pub fn addrs(&self) -> &BTreeSet<(String, IpAddr)> {
+        &self.addrs
+    }
+    /// Waits until the addresses differ from the ones seen last and returns the new ones
+    pub async fn changed(&mut self) -> io::Result<&BTreeSet<(String, IpAddr)>> {
+        loop {
+            self.event().await?;
+            tokio::time::sleep(SETTLE_TIME).await;
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if let Some(netlink) = &self.netlink {
+                netlink.drain();
+            }
+            let addrs = local_addrs()?;
+            if addrs != self.addrs {
+                self.addrs = addrs;
+                return Ok(&self.addrs);
+            }
+        }
+    }
+    async fn event(&self) -> io::Result<()> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(netlink) = &self.netlink {
+            return netlink.readable().await;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        Ok(())
+    }
+}
+
+/// The addresses of the local interfaces except loopback
+pub fn local_addrs() -> io::Result<BTreeSet<(String, IpAddr)>> {
+    let interfaces = NetworkInterface::show().map_err(io::Error::other)?;
+    Ok(interfaces
+        .into_iter()
+        .flat_map(|interface| {
+            let name = interface.name;
+            interface
+                .addr
+                .into_iter()
+                .map(move |addr| (name.clone(), addr.ip()))
+        })
+        .filter(|(_, ip)| !ip.is_loopback())
+        .collect())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod netlink {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use tokio::io::unix::AsyncFd;
+
+    /// A `NETLINK_ROUTE` socket in the link and address groups,
+    /// the messages only wake the watcher and are not parsed
+    pub(super) struct Netlink {
+        fd: AsyncFd<OwnedFd>,
+    }
+
+    impl Netlink {
+        pub(super) fn new() -> io::Result<Self> {
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_ROUTE,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups =
+                (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+            let rs = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if rs < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the fd is owned and stays open as long as the `AsyncFd`
+            let fd = unsafe { AsyncFd::register(fd) }?;
+            Ok(Self { fd })
+        }
+        pub(super) async fn readable(&self) -> io::Result<()> {
+            loop {
+                let mut guard = self.fd.readable().await?;
+                match guard.try_io(|fd| recv(fd.get_ref())) {
+                    Ok(Ok(_)) => return Ok(()),
+                    // The kernel dropped messages, a change happened anyway
+                    Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(()),
+                    Ok(Err(e)) => return Err(e),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+        /// Discards the queued messages
+        pub(super) fn drain(&self) {
+            loop {
+                match recv(self.fd.get_ref()) {
+                    Ok(_) => continue,
+                    Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    fn recv(fd: &OwnedFd) -> io::Result<usize> {
+        let mut buf = [0u8; 8192];
+        let len = unsafe {
+            libc::recv(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(len as usize)
+        }
+    }
+}
@@ -1 +1,2 @@
 pub mod addr;
+pub mod if_watch;
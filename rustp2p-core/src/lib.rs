@@ -1,9 +1,17 @@
 pub mod error;
 pub mod extend;
 pub mod idle;
+pub mod mapping;
 pub mod nat;
 pub mod pipe;
+pub mod proxy;
 pub mod punch;
 pub mod route;
+#[cfg(feature = "sim")]
+pub mod sim;
 pub mod socket;
 pub mod stun;
+#[cfg(feature = "turn")]
+pub mod turn;
+#[cfg(feature = "websocket")]
+pub mod websocket;
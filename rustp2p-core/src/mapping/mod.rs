@@ -0,0 +1,544 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rand::RngCore;
+use tokio::net::UdpSocket;
+
+use crate::route::ConnectProtocol;
+use crate::socket::{bind_udp, LocalInterface};
+
+mod natpmp;
+mod pcp;
+mod upnp;
+
+/// The port PCP and NAT-PMP servers listen on
+pub const GATEWAY_PORT: u16 = 5351;
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The protocol used to ask the gateway for a port mapping
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MappingMethod {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+/// A port forwarded by the gateway
+#[derive(Clone, Debug)]
+pub struct PortMapping {
+    pub method: MappingMethod,
+    pub protocol: ConnectProtocol,
+    pub internal_port: u16,
+    pub external_addr: SocketAddr,
+    /// The mapping has to be renewed before the lifetime expires, zero for a permanent lease
+    pub lifetime: Duration,
+}
+
+impl PortMapping {
+    /// A permanent lease lasts until it is deleted and is never renewed
+    pub fn is_permanent(&self) -> bool {
+        self.lifetime.is_zero()
+    }
+}
+
+/// Asks the local gateway to forward ports via PCP, NAT-PMP or UPnP IGD,
+/// the methods are tried in order until one succeeds
+#[derive(Clone, Debug)]
+pub struct PortMappingClient {
+    gateway: Option<Ipv4Addr>,
+    gateway_port: u16,
+    upnp_location: Option<String>,
+    methods: Vec<MappingMethod>,
+    lifetime: Duration,
+    timeout: Duration,
+    nonce: [u8; pcp::NONCE_LEN],
+    default_interface: Option<LocalInterface>,
+}
+
+impl PortMappingClient {
+    /// PCP and NAT-PMP are skipped without the gateway address, see [`default_gateway`]
+    pub fn new(gateway: Option<Ipv4Addr>) -> Self {
+        let mut nonce = [0; pcp::NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        Self {
+            gateway,
+            gateway_port: GATEWAY_PORT,
+            upnp_location: None,
+            methods: vec![
+                MappingMethod::Pcp,
+                MappingMethod::NatPmp,
+                MappingMethod::Upnp,
+            ],
+            lifetime: DEFAULT_LIFETIME,
+            timeout: DEFAULT_TIMEOUT,
+            nonce,
+            default_interface: None,
+        }
+    }
+    pub fn set_gateway_port(mut self, gateway_port: u16) -> Self {
+        self.gateway_port = gateway_port;
+        self
+    }
+    /// Use the device description at `location` instead of searching the gateway via SSDP
+    pub fn set_upnp_location(mut self, location: String) -> Self {
+        self.upnp_location = Some(location);
+        self
+    }
+    pub fn set_methods(mut self, methods: Vec<MappingMethod>) -> Self {
+        self.methods = methods;
+        self
+    }
+    /// The requested lifetime of the mappings
+    pub fn set_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Talk to the gateway through this interface
+    pub fn set_default_interface(mut self, default_interface: Option<LocalInterface>) -> Self {
+        self.default_interface = default_interface;
+        self
+    }
+    /// Map the local `internal_port`, the same external port is suggested to the gateway
+    pub async fn map(
+        &self,
+        protocol: ConnectProtocol,
+        internal_port: u16,
+    ) -> anyhow::Result<PortMapping> {
+        for method in &self.methods {
+            match self
+                .map_with(*method, protocol, internal_port, internal_port)
+                .await
+            {
+                Ok(mapping) => return Ok(mapping),
+                Err(e) => {
+                    log::debug!("port mapping {method:?} {protocol:?} {internal_port} {e:?}");
+                }
+            }
+        }
+        Err(anyhow!("the gateway does not support port mapping"))
+    }
+    /// Extend the lifetime of the mapping, the external port is kept if possible
+    pub async fn renew(&self, mapping: &PortMapping) -> anyhow::Result<PortMapping> {
+        self.map_with(
+            mapping.method,
+            mapping.protocol,
+            mapping.internal_port,
+            mapping.external_addr.port(),
+        )
+        .await
+    }
+    /// Ask the gateway to delete the mapping before its lifetime expires
+    pub async fn unmap(&self, mapping: &PortMapping) -> anyhow::Result<()> {
+        match mapping.method {
+            MappingMethod::Pcp => {
+                let (udp, client_ip) = self.gateway_socket().await?;
+                // A lifetime of 0 deletes the mapping of the same nonce, protocol and internal port
+                let request = pcp::map_request(
+                    client_ip,
+                    &self.nonce,
+                    pcp_protocol(mapping.protocol)?,
+                    mapping.internal_port,
+                    0,
+                    0,
+                );
+                let response = self.request(&udp, &request).await?;
+                pcp::parse_map_response(&response, &self.nonce)?;
+            }
+            MappingMethod::NatPmp => {
+                let (udp, _) = self.gateway_socket().await?;
+                let opcode = natpmp_opcode(mapping.protocol)?;
+                let request = natpmp::map_request(opcode, mapping.internal_port, 0, 0);
+                let response = self.request(&udp, &request).await?;
+                natpmp::parse_map_response(&response, opcode)?;
+            }
+            MappingMethod::Upnp => {
+                let control = self.upnp_control().await?;
+                upnp::delete_port_mapping(
+                    &control,
+                    upnp_protocol(mapping.protocol)?,
+                    mapping.external_addr.port(),
+                    self.timeout,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+    async fn map_with(
+        &self,
+        method: MappingMethod,
+        protocol: ConnectProtocol,
+        internal_port: u16,
+        external_port: u16,
+    ) -> anyhow::Result<PortMapping> {
+        let lifetime = self.lifetime.as_secs().min(u32::MAX as u64) as u32;
+        let (external_addr, lifetime) = match method {
+            MappingMethod::Pcp => {
+                let (udp, client_ip) = self.gateway_socket().await?;
+                let request = pcp::map_request(
+                    client_ip,
+                    &self.nonce,
+                    pcp_protocol(protocol)?,
+                    internal_port,
+                    external_port,
+                    lifetime,
+                );
+                let response = self.request(&udp, &request).await?;
+                let rs = pcp::parse_map_response(&response, &self.nonce)?;
+                (
+                    SocketAddr::new(rs.external_ip, rs.external_port),
+                    rs.lifetime,
+                )
+            }
+            MappingMethod::NatPmp => {
+                let opcode = natpmp_opcode(protocol)?;
+                let (udp, _) = self.gateway_socket().await?;
+                let response = self
+                    .request(&udp, &natpmp::external_address_request())
+                    .await?;
+                let external_ip = natpmp::parse_external_address_response(&response)?;
+                let request = natpmp::map_request(opcode, internal_port, external_port, lifetime);
+                let response = self.request(&udp, &request).await?;
+                let rs = natpmp::parse_map_response(&response, opcode)?;
+                (
+                    SocketAddr::new(IpAddr::V4(external_ip), rs.external_port),
+                    rs.lifetime,
+                )
+            }
+            MappingMethod::Upnp => {
+                let protocol = upnp_protocol(protocol)?;
+                let control = self.upnp_control().await?;
+                let external_ip = upnp::external_ip(&control, self.timeout).await?;
+                let mut lifetime = lifetime;
+                if let Err(e) = upnp::add_port_mapping(
+                    &control,
+                    protocol,
+                    external_port,
+                    internal_port,
+                    lifetime,
+                    self.timeout,
+                )
+                .await
+                {
+                    // 725 OnlyPermanentLeasesSupported
+                    if !e.to_string().ends_with("725") {
+                        Err(e)?
+                    }
+                    upnp::add_port_mapping(
+                        &control,
+                        protocol,
+                        external_port,
+                        internal_port,
+                        0,
+                        self.timeout,
+                    )
+                    .await?;
+                    lifetime = 0;
+                }
+                (SocketAddr::new(external_ip, external_port), lifetime)
+            }
+        };
+        Ok(PortMapping {
+            method,
+            protocol,
+            internal_port,
+            external_addr,
+            lifetime: Duration::from_secs(lifetime as u64),
+        })
+    }
+    /// Returns a socket connected to the PCP server of the gateway and the local IP facing it
+    async fn gateway_socket(&self) -> anyhow::Result<(UdpSocket, Ipv4Addr)> {
+        let gateway = self.gateway.ok_or_else(|| anyhow!("unknown gateway"))?;
+        let udp = bind_udp(
+            "0.0.0.0:0".parse().unwrap(),
+            self.default_interface.as_ref(),
+        )?;
+        let udp = UdpSocket::from_std(udp.into())?;
+        udp.connect((gateway, self.gateway_port)).await?;
+        let client_ip = match udp.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        Ok((udp, client_ip))
+    }
+    async fn upnp_control(&self) -> anyhow::Result<upnp::Control> {
+        let default_interface = self.default_interface.as_ref();
+        let location = match self.upnp_location.as_ref() {
+            Some(location) => location.clone(),
+            None => upnp::discover(self.timeout, default_interface).await?,
+        };
+        Ok(upnp::control(&location, self.timeout, default_interface).await?)
+    }
+    async fn request(&self, udp: &UdpSocket, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut buf = [0; 1100];
+        for _ in 0..2 {
+            udp.send(request).await?;
+            match tokio::time::timeout(self.timeout, udp.recv(&mut buf)).await {
+                Ok(len) => {
+                    let len = len?;
+                    let response = &buf[..len];
+                    if request[0] == pcp::VERSION
+                        && response.first() == Some(&natpmp::VERSION)
+                        && natpmp::result_code(response) == Some(natpmp::UNSUPPORTED_VERSION)
+                    {
+                        Err(anyhow!("the gateway only supports nat-pmp"))?
+                    }
+                    return Ok(response.to_vec());
+                }
+                Err(_) => continue,
+            }
+        }
+        Err(anyhow!("gateway request timeout"))
+    }
+}
+
+fn pcp_protocol(protocol: ConnectProtocol) -> anyhow::Result<u8> {
+    match protocol {
+        ConnectProtocol::UDP => Ok(17),
+        ConnectProtocol::TCP => Ok(6),
+        ConnectProtocol::Extend | ConnectProtocol::QUIC => Err(anyhow!("unsupported protocol")),
+    }
+}
+
+fn natpmp_opcode(protocol: ConnectProtocol) -> anyhow::Result<u8> {
+    match protocol {
+        ConnectProtocol::UDP => Ok(1),
+        ConnectProtocol::TCP => Ok(2),
+        ConnectProtocol::Extend | ConnectProtocol::QUIC => Err(anyhow!("unsupported protocol")),
+    }
+}
+
+fn upnp_protocol(protocol: ConnectProtocol) -> anyhow::Result<&'static str> {
+    match protocol {
+        ConnectProtocol::UDP => Ok("UDP"),
+        ConnectProtocol::TCP => Ok("TCP"),
+        ConnectProtocol::Extend | ConnectProtocol::QUIC => Err(anyhow!("unsupported protocol")),
+    }
+}
+
+/// The IPv4 default gateway read from the routing table, only linux is supported
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let route = std::fs::read_to_string("/proc/net/route")?;
+        for line in route.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields[1] != "00000000" {
+                continue;
+            }
+            if let Ok(gateway) = u32::from_str_radix(fields[2], 16) {
+                if gateway != 0 {
+                    return Ok(Ipv4Addr::from(gateway.to_le_bytes()));
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no default gateway",
+        ))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "default gateway lookup is not supported",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mapping::{MappingMethod, PortMappingClient};
+    use crate::route::ConnectProtocol;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A NAT-PMP only gateway, PCP requests are answered with UNSUPP_VERSION
+    #[tokio::test]
+    async fn test_nat_pmp_gateway() {
+        let gateway = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway_port = gateway.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0; 1100];
+            loop {
+                let (len, addr) = gateway.recv_from(&mut buf).await.unwrap();
+                let response: Vec<u8> = match (buf[0], buf[1]) {
+                    (2, _) => vec![0, 0x81, 0, 1, 0, 0, 0, 0],
+                    (0, 0) => vec![0, 0x80, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7],
+                    (0, opcode) => {
+                        assert_eq!(len, 12);
+                        let mut response = vec![0, 0x80 | opcode, 0, 0, 0, 0, 0, 9];
+                        response.extend_from_slice(&buf[4..6]);
+                        response.extend_from_slice(&40000u16.to_be_bytes());
+                        response.extend_from_slice(&buf[8..12]);
+                        response
+                    }
+                    _ => continue,
+                };
+                gateway.send_to(&response, addr).await.unwrap();
+            }
+        });
+        let client = PortMappingClient::new(Some(Ipv4Addr::LOCALHOST))
+            .set_gateway_port(gateway_port)
+            .set_methods(vec![MappingMethod::Pcp, MappingMethod::NatPmp])
+            .set_timeout(Duration::from_millis(500))
+            .set_lifetime(Duration::from_secs(60));
+        let mapping = client.map(ConnectProtocol::UDP, 23333).await.unwrap();
+        assert_eq!(mapping.method, MappingMethod::NatPmp);
+        assert_eq!(
+            mapping.external_addr,
+            "203.0.113.7:40000".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(mapping.lifetime, Duration::from_secs(60));
+        let mapping = client.renew(&mapping).await.unwrap();
+        assert_eq!(mapping.internal_port, 23333);
+    }
+
+    /// A PCP gateway, a request of lifetime 0 deletes the mapping
+    #[tokio::test]
+    async fn test_pcp_gateway() {
+        let gateway = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway_port = gateway.local_addr().unwrap().port();
+        let (deleted_sender, mut deleted_receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0; 1100];
+            loop {
+                let (len, addr) = gateway.recv_from(&mut buf).await.unwrap();
+                assert_eq!(len, 60);
+                assert_eq!(&buf[..2], &[2, 1]);
+                let lifetime = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+                let mut response = buf[..len].to_vec();
+                response[1] = 0x81;
+                response[3] = 0;
+                response[8..24].fill(0);
+                if lifetime == 0 {
+                    deleted_sender
+                        .send(u16::from_be_bytes(buf[40..42].try_into().unwrap()))
+                        .unwrap();
+                } else {
+                    response[4..8].copy_from_slice(&lifetime.min(600).to_be_bytes());
+                    response[42..44].copy_from_slice(&40001u16.to_be_bytes());
+                    response[44..60]
+                        .copy_from_slice(&Ipv4Addr::new(203, 0, 113, 9).to_ipv6_mapped().octets());
+                }
+                gateway.send_to(&response, addr).await.unwrap();
+            }
+        });
+        let client = PortMappingClient::new(Some(Ipv4Addr::LOCALHOST))
+            .set_gateway_port(gateway_port)
+            .set_timeout(Duration::from_millis(500));
+        let mapping = client.map(ConnectProtocol::TCP, 23335).await.unwrap();
+        assert_eq!(mapping.method, MappingMethod::Pcp);
+        assert_eq!(
+            mapping.external_addr,
+            "203.0.113.9:40001".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(mapping.lifetime, Duration::from_secs(600));
+        let mapping = client.renew(&mapping).await.unwrap();
+        assert_eq!(mapping.external_addr.port(), 40001);
+        client.unmap(&mapping).await.unwrap();
+        assert_eq!(deleted_receiver.recv().await, Some(23335));
+    }
+
+    #[tokio::test]
+    async fn test_upnp_gateway() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                let body = if request.starts_with("GET /rootDesc.xml") {
+                    "<root><device><serviceList><service>\
+                     <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                     <controlURL>/ctl/IPConn</controlURL>\
+                     </service></serviceList></device></root>"
+                } else if request.contains("#GetExternalIPAddress") {
+                    "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                     <NewExternalIPAddress>203.0.113.8</NewExternalIPAddress>\
+                     </u:GetExternalIPAddressResponse></s:Body></s:Envelope>"
+                } else {
+                    assert!(request.starts_with("POST /ctl/IPConn"));
+                    assert!(request.contains("<NewInternalPort>23334</NewInternalPort>"));
+                    "<s:Envelope><s:Body><u:AddPortMappingResponse/></s:Body></s:Envelope>"
+                };
+                let chunk = format!("{:x}\r\n{body}\r\n0\r\n\r\n", body.len());
+                let response =
+                    format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{chunk}");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let client = PortMappingClient::new(None)
+            .set_upnp_location(location)
+            .set_timeout(Duration::from_secs(1));
+        let mapping = client.map(ConnectProtocol::TCP, 23334).await.unwrap();
+        assert_eq!(mapping.method, MappingMethod::Upnp);
+        assert_eq!(
+            mapping.external_addr,
+            "203.0.113.8:23334".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    /// A UPnP gateway that only grants permanent leases answers error 725 to a finite lease
+    #[tokio::test]
+    async fn test_upnp_permanent_lease() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                let (status, body) = if request.starts_with("GET /rootDesc.xml") {
+                    (
+                        "200 OK",
+                        "<root><device><serviceList><service>\
+                         <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                         <controlURL>/ctl/IPConn</controlURL>\
+                         </service></serviceList></device></root>",
+                    )
+                } else if request.contains("#GetExternalIPAddress") {
+                    (
+                        "200 OK",
+                        "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                         <NewExternalIPAddress>203.0.113.8</NewExternalIPAddress>\
+                         </u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+                    )
+                } else if request.contains("<NewLeaseDuration>0</NewLeaseDuration>") {
+                    (
+                        "200 OK",
+                        "<s:Envelope><s:Body><u:AddPortMappingResponse/></s:Body></s:Envelope>",
+                    )
+                } else {
+                    (
+                        "500 Internal Server Error",
+                        "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                         <errorCode>725</errorCode>\
+                         </UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+                    )
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let client = PortMappingClient::new(None)
+            .set_upnp_location(location)
+            .set_timeout(Duration::from_secs(1));
+        let mapping = client.map(ConnectProtocol::UDP, 23336).await.unwrap();
+        assert_eq!(mapping.method, MappingMethod::Upnp);
+        assert_eq!(mapping.lifetime, Duration::ZERO);
+        assert!(mapping.is_permanent());
+    }
+}
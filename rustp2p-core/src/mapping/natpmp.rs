@@ -0,0 +1,76 @@
+/*
+  NAT-PMP (RFC 6886), it shares the server port with PCP
+
+  External address request: version(8) = 0 | opcode(8) = 0
+  External address response: version(8) | opcode(8) = 128 | result(16) | epoch(32) | address(32)
+
+  Mapping request: version(8) | opcode(8) = 1 UDP, 2 TCP | reserved(16)
+                   | internal port(16) | suggested external port(16) | lifetime(32)
+  Mapping response: version(8) | opcode(8) = 128 + request opcode | result(16) | epoch(32)
+                   | internal port(16) | mapped external port(16) | lifetime(32)
+*/
+
+use std::io;
+use std::net::Ipv4Addr;
+
+pub(crate) const VERSION: u8 = 0;
+const OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+const RESPONSE_BIT: u8 = 0x80;
+/// The result code of a NAT-PMP server receiving a request of another version
+pub(crate) const UNSUPPORTED_VERSION: u16 = 1;
+
+pub(crate) struct MapResponse {
+    pub(crate) external_port: u16,
+    pub(crate) lifetime: u32,
+}
+
+pub(crate) fn external_address_request() -> [u8; 2] {
+    [VERSION, OPCODE_EXTERNAL_ADDRESS]
+}
+
+pub(crate) fn map_request(
+    opcode: u8,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> [u8; 12] {
+    let mut buf = [0u8; 12];
+    buf[0] = VERSION;
+    buf[1] = opcode;
+    buf[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    buf[6..8].copy_from_slice(&external_port.to_be_bytes());
+    buf[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    buf
+}
+
+/// Returns the result code of a response of any version
+pub(crate) fn result_code(buf: &[u8]) -> Option<u16> {
+    buf.get(2..4)
+        .map(|v| u16::from_be_bytes(v.try_into().unwrap()))
+}
+
+fn check_response(buf: &[u8], opcode: u8, len: usize) -> io::Result<()> {
+    if buf.len() < len || buf[0] != VERSION || buf[1] != RESPONSE_BIT | opcode {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid nat-pmp response",
+        ));
+    }
+    match result_code(buf) {
+        Some(0) => Ok(()),
+        code => Err(io::Error::other(format!("nat-pmp result code {code:?}"))),
+    }
+}
+
+pub(crate) fn parse_external_address_response(buf: &[u8]) -> io::Result<Ipv4Addr> {
+    check_response(buf, OPCODE_EXTERNAL_ADDRESS, 12)?;
+    Ok(Ipv4Addr::new(buf[8], buf[9], buf[10], buf[11]))
+}
+
+pub(crate) fn parse_map_response(buf: &[u8], opcode: u8) -> io::Result<MapResponse> {
+    check_response(buf, opcode, 16)?;
+    Ok(MapResponse {
+        external_port: u16::from_be_bytes(buf[10..12].try_into().unwrap()),
+        lifetime: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
+    })
+}
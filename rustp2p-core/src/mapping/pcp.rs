@@ -0,0 +1,90 @@
+/*
+  PCP MAP request (RFC 6887)
+
+   0                   1                   2                   3
+   0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |  Version = 2  |R|   Opcode    |         Reserved              |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                 Requested Lifetime (32 bits)                  |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |            PCP Client's IP Address (128 bits)                 |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                 Mapping Nonce (96 bits)                       |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |   Protocol    |          Reserved (24 bits)                   |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |        Internal Port          |    Suggested External Port    |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |           Suggested External IP Address (128 bits)            |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+
+  The response replaces the client's IP address with the epoch time and 96 reserved bits,
+  the result code follows the opcode
+*/
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub(crate) const VERSION: u8 = 2;
+const OPCODE_MAP: u8 = 1;
+const RESPONSE_BIT: u8 = 0x80;
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+pub(crate) const REQUEST_LEN: usize = HEADER_LEN + MAP_LEN;
+pub(crate) const NONCE_LEN: usize = 12;
+
+pub(crate) struct MapResponse {
+    pub(crate) lifetime: u32,
+    pub(crate) external_port: u16,
+    pub(crate) external_ip: IpAddr,
+}
+
+pub(crate) fn map_request(
+    client_ip: Ipv4Addr,
+    nonce: &[u8; NONCE_LEN],
+    protocol: u8,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> [u8; REQUEST_LEN] {
+    let mut buf = [0u8; REQUEST_LEN];
+    buf[0] = VERSION;
+    buf[1] = OPCODE_MAP;
+    buf[4..8].copy_from_slice(&lifetime.to_be_bytes());
+    buf[8..24].copy_from_slice(&client_ip.to_ipv6_mapped().octets());
+    buf[24..36].copy_from_slice(nonce);
+    buf[36] = protocol;
+    buf[40..42].copy_from_slice(&internal_port.to_be_bytes());
+    buf[42..44].copy_from_slice(&external_port.to_be_bytes());
+    buf[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    buf
+}
+
+pub(crate) fn parse_map_response(buf: &[u8], nonce: &[u8; NONCE_LEN]) -> io::Result<MapResponse> {
+    if buf.len() < REQUEST_LEN || buf[0] != VERSION || buf[1] != RESPONSE_BIT | OPCODE_MAP {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid pcp response",
+        ));
+    }
+    if buf[3] != 0 {
+        return Err(io::Error::other(format!("pcp result code {}", buf[3])));
+    }
+    if &buf[24..36] != nonce {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "pcp nonce mismatch",
+        ));
+    }
+    let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&buf[44..60]).unwrap());
+    let external_ip = match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    };
+    Ok(MapResponse {
+        lifetime: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+        external_port: u16::from_be_bytes(buf[42..44].try_into().unwrap()),
+        external_ip,
+    })
+}
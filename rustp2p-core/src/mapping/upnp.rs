@@ -0,0 +1,281 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::socket::{bind_udp, connect_tcp, LocalInterface};
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services of the gateway that are able to map ports
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// The control endpoint of the gateway
+#[derive(Clone, Debug)]
+pub(crate) struct Control {
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    service_type: String,
+    default_interface: Option<LocalInterface>,
+}
+
+/// Search the gateway via SSDP and return the location of its device description
+pub(crate) async fn discover(
+    timeout: Duration,
+    default_interface: Option<&LocalInterface>,
+) -> io::Result<String> {
+    let udp = bind_udp_socket(default_interface)?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nST: {SEARCH_TARGET}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n"
+    );
+    udp.send_to(request.as_bytes(), SSDP_ADDR).await?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 2048];
+    while let Ok(rs) = tokio::time::timeout_at(deadline, udp.recv_from(&mut buf)).await {
+        let (len, _addr) = rs?;
+        let response = String::from_utf8_lossy(&buf[..len]);
+        if let Some(location) = header(&response, "location") {
+            return Ok(location.to_string());
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no upnp gateway found",
+    ))
+}
+
+/// Fetch the device description and find the control url of the connection service
+pub(crate) async fn control(
+    location: &str,
+    timeout: Duration,
+    default_interface: Option<&LocalInterface>,
+) -> io::Result<Control> {
+    let (addr, host, path) = parse_url(location).await?;
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+    let (body, _) = http(addr, request.as_bytes(), timeout, default_interface).await?;
+    for service in body.split("<service>").skip(1) {
+        let Some(service_type) = tag_value(service, "serviceType") else {
+            continue;
+        };
+        if !SERVICE_TYPES.contains(&service_type) {
+            continue;
+        }
+        let Some(control_url) = tag_value(service, "controlURL") else {
+            continue;
+        };
+        let path = if control_url.starts_with("http://") {
+            parse_url(control_url).await?.2
+        } else if control_url.starts_with('/') {
+            control_url.to_string()
+        } else {
+            format!("/{control_url}")
+        };
+        return Ok(Control {
+            addr,
+            host,
+            path,
+            service_type: service_type.to_string(),
+            default_interface: default_interface.cloned(),
+        });
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no connection service in the upnp device",
+    ))
+}
+
+pub(crate) async fn add_port_mapping(
+    control: &Control,
+    protocol: &str,
+    external_port: u16,
+    internal_port: u16,
+    lifetime: u32,
+    timeout: Duration,
+) -> io::Result<()> {
+    // The address the gateway sees us from
+    let internal_client = match local_ip(control.addr, control.default_interface.as_ref()).await? {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{external_port}</NewExternalPort>\
+         <NewProtocol>{protocol}</NewProtocol>\
+         <NewInternalPort>{internal_port}</NewInternalPort>\
+         <NewInternalClient>{internal_client}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>rustp2p</NewPortMappingDescription>\
+         <NewLeaseDuration>{lifetime}</NewLeaseDuration>"
+    );
+    soap(control, "AddPortMapping", &args, timeout).await?;
+    Ok(())
+}
+
+pub(crate) async fn delete_port_mapping(
+    control: &Control,
+    protocol: &str,
+    external_port: u16,
+    timeout: Duration,
+) -> io::Result<()> {
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{external_port}</NewExternalPort>\
+         <NewProtocol>{protocol}</NewProtocol>"
+    );
+    soap(control, "DeletePortMapping", &args, timeout).await?;
+    Ok(())
+}
+
+pub(crate) async fn external_ip(control: &Control, timeout: Duration) -> io::Result<IpAddr> {
+    let body = soap(control, "GetExternalIPAddress", "", timeout).await?;
+    tag_value(&body, "NewExternalIPAddress")
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid external ip"))
+}
+
+async fn local_ip(
+    addr: SocketAddr,
+    default_interface: Option<&LocalInterface>,
+) -> io::Result<IpAddr> {
+    let udp = bind_udp_socket(default_interface)?;
+    udp.connect(addr).await?;
+    Ok(udp.local_addr()?.ip())
+}
+
+async fn soap(
+    control: &Control,
+    action: &str,
+    args: &str,
+    timeout: Duration,
+) -> io::Result<String> {
+    let service_type = &control.service_type;
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service_type}\">{args}</u:{action}></s:Body></s:Envelope>"
+    );
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+         SOAPAction: \"{service_type}#{action}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        control.path,
+        control.host,
+        body.len()
+    );
+    let (body, status) = http(
+        control.addr,
+        request.as_bytes(),
+        timeout,
+        control.default_interface.as_ref(),
+    )
+    .await?;
+    if status != 200 {
+        let error = tag_value(&body, "errorCode").unwrap_or_default();
+        return Err(io::Error::other(format!(
+            "upnp {action} status {status} error {error}"
+        )));
+    }
+    Ok(body)
+}
+
+/// Send a request with `Connection: close` and return the body and the status code
+async fn http(
+    addr: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+    default_interface: Option<&LocalInterface>,
+) -> io::Result<(String, u16)> {
+    let response = tokio::time::timeout(timeout, async {
+        let mut stream = connect_tcp(addr, 0, default_interface, None)
+            .await
+            .map_err(io::Error::other)?;
+        stream.write_all(request).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        io::Result::Ok(response)
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "upnp http timeout"))??;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid http response"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid http status"))?;
+    let body =
+        if header(head, "transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+            dechunk(body)?
+        } else {
+            body.to_string()
+        };
+    Ok((body, status))
+}
+
+fn bind_udp_socket(default_interface: Option<&LocalInterface>) -> io::Result<UdpSocket> {
+    let udp =
+        bind_udp("0.0.0.0:0".parse().unwrap(), default_interface).map_err(io::Error::other)?;
+    UdpSocket::from_std(udp.into())
+}
+
+fn dechunk(mut body: &str) -> io::Result<String> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid http chunk");
+    let mut rs = String::with_capacity(body.len());
+    loop {
+        let (size, rest) = body.split_once("\r\n").ok_or_else(invalid)?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid())?;
+        if size == 0 {
+            return Ok(rs);
+        }
+        rs.push_str(rest.get(..size).ok_or_else(invalid)?);
+        body = rest
+            .get(size..)
+            .and_then(|v| v.strip_prefix("\r\n"))
+            .ok_or_else(invalid)?;
+    }
+}
+
+/// Returns the address, the host header and the path of a http url
+async fn parse_url(url: &str) -> io::Result<(SocketAddr, String, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "only http is supported"))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        tokio::net::lookup_host(host).await?.next()
+    } else {
+        tokio::net::lookup_host((host, 80)).await?.next()
+    }
+    .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "invalid upnp host"))?;
+    Ok((addr, host.to_string(), path.to_string()))
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+/// The text of the first element named `tag`, namespace prefixes are not supported
+fn tag_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{tag}>"))? + start;
+    Some(xml[start..end].trim())
+}
@@ -1,6 +1,7 @@
 use crate::extend::addr::is_ipv6_global;
 use serde::{Deserialize, Serialize};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Default)]
 pub enum NatType {
@@ -20,6 +21,31 @@ impl NatType {
     }
 }
 
+/// Mapping or filtering behaviour of a nat as defined by RFC 5780,
+/// the variants are ordered from the least to the most restrictive
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize, Default,
+)]
+pub enum NatBehavior {
+    /// The behaviour could not be determined, e.g. the stun servers do not support RFC 5780
+    #[default]
+    Unknown,
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+impl NatBehavior {
+    #[inline]
+    pub fn is_unknown(&self) -> bool {
+        self == &NatBehavior::Unknown
+    }
+    /// Returns the more restrictive behaviour, `Unknown` is ignored
+    pub fn merge(self, other: NatBehavior) -> NatBehavior {
+        self.max(other)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NatInfo {
     /// nat type of the network
@@ -32,7 +58,7 @@ pub struct NatInfo {
     pub mapping_tcp_addr: Vec<SocketAddr>,
     /// the set of mapped addresses where `UDP` serves on
     pub mapping_udp_addr: Vec<SocketAddr>,
-    /// The predicted range of public ports, it is used when the nat_type is symmertric
+    /// The predicted range of public ports, it is used when the nat_type is symmetric
     pub public_port_range: u16,
     /// local IP address
     pub local_ipv4: Ipv4Addr,
@@ -46,6 +72,22 @@ pub struct NatInfo {
     pub public_tcp_port: u16,
     /// Both parties' seq in the same round of hole punching need to be the same
     pub seq: u32,
+    /// How the nat allocates the public address for different destinations
+    #[serde(default)]
+    pub mapping_behavior: NatBehavior,
+    /// Which inbound packets the nat forwards to an existing mapping
+    #[serde(default)]
+    pub filtering_behavior: NatBehavior,
+    /// Whether the nat loops back packets sent to its own public address
+    #[serde(default)]
+    pub hairpinning: Option<bool>,
+    /// How long an idle `UDP` mapping stays alive
+    #[serde(default)]
+    pub mapping_lifetime: Option<Duration>,
+    /// The next public ports predicted from the allocation pattern, they are tried first when the nat_type is symmetric.
+    /// They are sampled again right before the punch consult
+    #[serde(default)]
+    pub predicted_ports: Vec<u16>,
 }
 impl NatInfo {
     pub fn ipv6_addr(&self) -> Vec<SocketAddr> {
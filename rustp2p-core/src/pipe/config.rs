@@ -1,8 +1,13 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::pipe::recycle::RecycleBuf;
-use crate::pipe::tcp_pipe::{BytesInitCodec, InitCodec};
+use crate::pipe::tcp_pipe::{BytesInitCodec, InitCodec, PlainTcpTransport, TcpTransport};
 use crate::pipe::udp_pipe::Model;
+use crate::proxy::ProxyConfig;
+use crate::punch::{BirthdayPunchConfig, DefaultPunchPolicy, PunchPolicy};
+#[cfg(feature = "sim")]
+use crate::sim::SimHost;
 use crate::socket::LocalInterface;
 use anyhow::{anyhow, Context};
 
@@ -17,7 +22,12 @@ pub struct PipeConfig {
     pub route_idle_time: Duration,
     pub udp_pipe_config: Option<UdpPipeConfig>,
     pub tcp_pipe_config: Option<TcpPipeConfig>,
+    /// `QUIC` over the main `UDP` sockets, requires the `UDP` pipe
+    #[cfg(feature = "quic")]
+    pub quic_pipe_config: Option<QuicPipeConfig>,
     pub enable_extend: bool,
+    pub birthday_punch: BirthdayPunchConfig,
+    pub punch_policy: Arc<dyn PunchPolicy>,
 }
 
 impl Default for PipeConfig {
@@ -28,7 +38,11 @@ impl Default for PipeConfig {
             enable_extend: false,
             udp_pipe_config: Some(Default::default()),
             tcp_pipe_config: Some(Default::default()),
+            #[cfg(feature = "quic")]
+            quic_pipe_config: None,
             route_idle_time: ROUTE_IDLE_TIME,
+            birthday_punch: Default::default(),
+            punch_policy: Arc::new(DefaultPunchPolicy::default()),
         }
     }
 }
@@ -46,7 +60,11 @@ impl PipeConfig {
             enable_extend: false,
             udp_pipe_config,
             tcp_pipe_config,
+            #[cfg(feature = "quic")]
+            quic_pipe_config: None,
             route_idle_time: ROUTE_IDLE_TIME,
+            birthday_punch: Default::default(),
+            punch_policy: Arc::new(DefaultPunchPolicy::default()),
         }
     }
 }
@@ -63,7 +81,11 @@ impl PipeConfig {
             enable_extend: false,
             udp_pipe_config: None,
             tcp_pipe_config: None,
+            #[cfg(feature = "quic")]
+            quic_pipe_config: None,
             route_idle_time: ROUTE_IDLE_TIME,
+            birthday_punch: Default::default(),
+            punch_policy: Arc::new(DefaultPunchPolicy::default()),
         }
     }
     pub fn set_first_latency(mut self, first_latency: bool) -> Self {
@@ -86,6 +108,19 @@ impl PipeConfig {
         self.tcp_pipe_config.replace(tcp_pipe_config);
         self
     }
+    #[cfg(feature = "quic")]
+    pub fn set_quic_pipe_config(mut self, quic_pipe_config: QuicPipeConfig) -> Self {
+        self.quic_pipe_config.replace(quic_pipe_config);
+        self
+    }
+    pub fn set_birthday_punch(mut self, birthday_punch: BirthdayPunchConfig) -> Self {
+        self.birthday_punch = birthday_punch;
+        self
+    }
+    pub fn set_punch_policy(mut self, punch_policy: Arc<dyn PunchPolicy>) -> Self {
+        self.punch_policy = punch_policy;
+        self
+    }
     pub fn check(&self) -> anyhow::Result<()> {
         if let Some(udp_pipe_config) = self.udp_pipe_config.as_ref() {
             udp_pipe_config.check()?;
@@ -93,6 +128,13 @@ impl PipeConfig {
         if let Some(tcp_pipe_config) = self.tcp_pipe_config.as_ref() {
             tcp_pipe_config.check()?;
         }
+        #[cfg(feature = "quic")]
+        if let Some(quic_pipe_config) = self.quic_pipe_config.as_ref() {
+            if self.udp_pipe_config.is_none() {
+                return Err(anyhow!("the quic pipe requires the udp pipe"));
+            }
+            quic_pipe_config.check()?;
+        }
         Ok(())
     }
 }
@@ -105,7 +147,17 @@ pub struct TcpPipeConfig {
     pub tcp_port: u16,
     pub use_v6: bool,
     pub init_codec: Box<dyn InitCodec>,
+    /// Wraps the streams before the codec, such as TLS
+    pub transport: Arc<dyn TcpTransport>,
+    /// Dial out through a proxy, which rules out the simultaneous open
+    pub proxy: Option<ProxyConfig>,
     pub recycle_buf: Option<RecycleBuf>,
+    /// DSCP value marked on the TCP connections
+    pub dscp: Option<u8>,
+    /// Listen and connect on a host of the simulated network instead of the OS,
+    /// the streams skip the `transport`
+    #[cfg(feature = "sim")]
+    pub sim_host: Option<SimHost>,
 }
 
 impl Default for TcpPipeConfig {
@@ -117,7 +169,12 @@ impl Default for TcpPipeConfig {
             tcp_port: 0,
             use_v6: true,
             init_codec: Box::new(BytesInitCodec),
+            transport: Arc::new(PlainTcpTransport),
+            proxy: None,
             recycle_buf: None,
+            dscp: None,
+            #[cfg(feature = "sim")]
+            sim_host: None,
         }
     }
 }
@@ -131,7 +188,12 @@ impl TcpPipeConfig {
             tcp_port: 0,
             use_v6: true,
             init_codec,
+            transport: Arc::new(PlainTcpTransport),
+            proxy: None,
             recycle_buf: None,
+            dscp: None,
+            #[cfg(feature = "sim")]
+            sim_host: None,
         }
     }
     pub fn check(&self) -> anyhow::Result<()> {
@@ -141,6 +203,9 @@ impl TcpPipeConfig {
         if self.tcp_multiplexing_limit > MAX_MAIN_PIPELINE_NUM {
             return Err(anyhow!("tcp_multiplexing_limit cannot too large"));
         }
+        if matches!(self.dscp, Some(dscp) if dscp > 63) {
+            return Err(anyhow!("dscp cannot exceed 63"));
+        }
         if self.use_v6 {
             socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)
                 .context("Does not support IPV6")?;
@@ -167,6 +232,23 @@ impl TcpPipeConfig {
         self.use_v6 = use_v6;
         self
     }
+    pub fn set_dscp(mut self, dscp: u8) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+    pub fn set_transport(mut self, transport: Arc<dyn TcpTransport>) -> Self {
+        self.transport = transport;
+        self
+    }
+    pub fn set_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy.replace(proxy);
+        self
+    }
+    #[cfg(feature = "sim")]
+    pub fn set_sim_host(mut self, sim_host: SimHost) -> Self {
+        self.sim_host.replace(sim_host);
+        self
+    }
 }
 
 #[derive(Clone)]
@@ -178,6 +260,13 @@ pub struct UdpPipeConfig {
     pub udp_ports: Vec<u16>,
     pub use_v6: bool,
     pub recycle_buf: Option<RecycleBuf>,
+    /// DSCP value marked on the UDP sockets
+    pub dscp: Option<u8>,
+    /// Use `UDP_SEGMENT` (GSO) and `UDP_GRO` on Linux when the kernel supports them
+    pub offload: bool,
+    /// Bind the sockets on a host of the simulated network instead of the OS, `IPv4` only
+    #[cfg(feature = "sim")]
+    pub sim_host: Option<SimHost>,
 }
 
 impl Default for UdpPipeConfig {
@@ -190,6 +279,10 @@ impl Default for UdpPipeConfig {
             udp_ports: vec![0, 0],
             use_v6: true,
             recycle_buf: None,
+            dscp: None,
+            offload: true,
+            #[cfg(feature = "sim")]
+            sim_host: None,
         }
     }
 }
@@ -205,6 +298,9 @@ impl UdpPipeConfig {
         if self.sub_pipeline_num > MAX_SYMMETRIC_PIPELINE_NUM {
             return Err(anyhow!("symmetric_pipeline_num is too large"));
         }
+        if matches!(self.dscp, Some(dscp) if dscp > 63) {
+            return Err(anyhow!("dscp cannot exceed 63"));
+        }
         if self.use_v6 {
             socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None)
                 .context("Does not support IPV6")?;
@@ -239,4 +335,72 @@ impl UdpPipeConfig {
         self.use_v6 = use_v6;
         self
     }
+    pub fn set_dscp(mut self, dscp: u8) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+    pub fn set_offload(mut self, offload: bool) -> Self {
+        self.offload = offload;
+        self
+    }
+    #[cfg(feature = "sim")]
+    pub fn set_sim_host(mut self, sim_host: SimHost) -> Self {
+        self.sim_host.replace(sim_host);
+        self
+    }
+}
+
+#[cfg(feature = "quic")]
+pub(crate) const QUIC_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// With a `secret` every member of the group derives the same certificate from it,
+/// presents it on both sides of a connection and accepts no other.
+///
+/// **Without a `secret` the peer is not authenticated**: the certificates are self-signed
+/// and not verified, so `QUIC` encrypts the transport but anyone reaching the sockets
+/// is able to connect
+#[cfg(feature = "quic")]
+#[derive(Clone)]
+pub struct QuicPipeConfig {
+    /// The connection is closed after this long without receiving anything
+    pub route_idle_time: Duration,
+    /// Keeps the nat mapping of an idle connection
+    pub keep_alive_interval: Duration,
+    /// The secret shared by the group, the certificate is derived from it
+    pub secret: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "quic")]
+impl Default for QuicPipeConfig {
+    fn default() -> Self {
+        Self {
+            route_idle_time: ROUTE_IDLE_TIME,
+            keep_alive_interval: QUIC_KEEP_ALIVE_INTERVAL,
+            secret: None,
+        }
+    }
+}
+
+#[cfg(feature = "quic")]
+impl QuicPipeConfig {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.keep_alive_interval >= self.route_idle_time {
+            return Err(anyhow!(
+                "keep_alive_interval must be less than route_idle_time"
+            ));
+        }
+        Ok(())
+    }
+    pub fn set_route_idle_time(mut self, route_idle_time: Duration) -> Self {
+        self.route_idle_time = route_idle_time;
+        self
+    }
+    pub fn set_keep_alive_interval(mut self, keep_alive_interval: Duration) -> Self {
+        self.keep_alive_interval = keep_alive_interval;
+        self
+    }
+    pub fn set_secret(mut self, secret: Vec<u8>) -> Self {
+        self.secret = Some(secret);
+        self
+    }
 }
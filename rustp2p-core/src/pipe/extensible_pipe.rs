@@ -210,14 +210,33 @@ impl ExtensiblePipeWriter {
         &self,
         addr: SocketAddr,
         r: Box<dyn ExtendRead>,
+        w: Box<dyn ExtendWrite>,
+    ) -> anyhow::Result<RouteKey> {
+        self.add_pipe_(Index::Extend, addr, r, w).await
+    }
+    /// Add a pipe relayed by a TURN server, the route is marked as [`Index::Turn`]
+    #[cfg(feature = "turn")]
+    pub(crate) async fn add_turn_pipe(
+        &self,
+        addr: SocketAddr,
+        r: Box<dyn ExtendRead>,
+        w: Box<dyn ExtendWrite>,
+    ) -> anyhow::Result<RouteKey> {
+        self.add_pipe_(Index::Turn, addr, r, w).await
+    }
+    async fn add_pipe_(
+        &self,
+        index: fn(usize) -> Index,
+        addr: SocketAddr,
+        r: Box<dyn ExtendRead>,
         mut w: Box<dyn ExtendWrite>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<RouteKey> {
         let id = self.id.load();
         if id == 0 {
             Err(anyhow!("overflow"))?;
         }
-        let index = self.id.fetch_add(1);
-        let route_key = RouteKey::new(Index::Extend(index), addr);
+        let index = index(self.id.fetch_add(1));
+        let route_key = RouteKey::new(index, addr);
         let reader = ExtensibleReader { read: r };
         let (sender, mut receiver) = tokio::sync::mpsc::channel::<BytesMut>(32);
         let collect = self.write_half_collect.clone();
@@ -234,7 +253,7 @@ impl ExtensiblePipeWriter {
         if let Err(e) = self.connect_sender.send((route_key, reader, sender)).await {
             Err(anyhow!("{e}"))?
         }
-        Ok(())
+        Ok(route_key)
     }
 }
 
@@ -8,6 +8,9 @@ use crate::pipe::config::PipeConfig;
 use crate::pipe::extensible_pipe::{
     ExtensiblePipe, ExtensiblePipeLine, ExtensiblePipeWriter, ExtensiblePipeWriterRef,
 };
+use crate::pipe::priority::Priority;
+#[cfg(feature = "quic")]
+use crate::pipe::quic_pipe::{QuicPipe, QuicPipeLine, QuicPipeWriter, QuicPipeWriterRef};
 use crate::pipe::tcp_pipe::{TcpPipe, TcpPipeLine, TcpPipeWriter};
 use crate::pipe::udp_pipe::{UdpPipe, UdpPipeLine, UdpPipeWriter, UdpPipeWriterRef};
 use crate::punch::Puncher;
@@ -16,8 +19,13 @@ use crate::route::{ConnectProtocol, RouteKey};
 
 pub mod config;
 pub mod extensible_pipe;
+pub mod priority;
+#[cfg(feature = "quic")]
+pub mod quic_pipe;
 pub mod recycle;
 pub mod tcp_pipe;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod udp_pipe;
 pub const DEFAULT_ADDRESS_V4: SocketAddr =
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
@@ -42,6 +50,14 @@ pub fn pipe<PeerID: Hash + Eq + Clone>(
     } else {
         None
     };
+    #[cfg(feature = "quic")]
+    let quic_pipe = match (config.quic_pipe_config, udp_pipe.as_ref()) {
+        (Some(quic_pipe_config), Some(udp_pipe)) => {
+            Some(QuicPipe::new(quic_pipe_config, &udp_pipe.writer_ref())?)
+        }
+        (Some(_), None) => return Err(anyhow::anyhow!("the quic pipe requires the udp pipe")),
+        (None, _) => None,
+    };
     let extensible_pipe = if config.enable_extend {
         Some(ExtensiblePipe::new())
     } else {
@@ -51,9 +67,13 @@ pub fn pipe<PeerID: Hash + Eq + Clone>(
         route_table: route_table.clone(),
         udp_pipe,
         tcp_pipe,
+        #[cfg(feature = "quic")]
+        quic_pipe,
         extensible_pipe,
     };
-    let puncher = Puncher::from(&pipe);
+    let puncher = Puncher::from(&pipe)
+        .set_birthday_punch(config.birthday_punch)
+        .set_punch_policy(config.punch_policy);
     Ok((
         pipe,
         puncher,
@@ -65,12 +85,16 @@ pub struct Pipe<PeerID> {
     route_table: RouteTable<PeerID>,
     udp_pipe: Option<UdpPipe>,
     tcp_pipe: Option<TcpPipe>,
+    #[cfg(feature = "quic")]
+    quic_pipe: Option<QuicPipe>,
     extensible_pipe: Option<ExtensiblePipe>,
 }
 
 pub enum PipeLine {
     Udp(UdpPipeLine),
     Tcp(TcpPipeLine),
+    #[cfg(feature = "quic")]
+    Quic(QuicPipeLine),
     Extend(ExtensiblePipeLine),
 }
 
@@ -79,6 +103,8 @@ pub struct PipeWriter<PeerID> {
     route_table: RouteTable<PeerID>,
     udp_pipe_writer: Option<UdpPipeWriter>,
     tcp_pipe_writer: Option<TcpPipeWriter>,
+    #[cfg(feature = "quic")]
+    quic_pipe_writer: Option<QuicPipeWriter>,
     extensible_pipe_writer: Option<ExtensiblePipeWriter>,
 }
 
@@ -86,12 +112,18 @@ pub struct PipeWriterRef<'a, PeerID> {
     route_table: &'a RouteTable<PeerID>,
     udp_pipe_writer: Option<UdpPipeWriterRef<'a>>,
     tcp_pipe_writer: Option<TcpPipeWriterRef<'a>>,
+    #[cfg(feature = "quic")]
+    quic_pipe_writer: Option<QuicPipeWriterRef<'a>>,
     extensible_pipe_writer: Option<ExtensiblePipeWriterRef<'a>>,
 }
 
 impl<PeerID> Pipe<PeerID> {
     /// Accept pipelines from a given `pipe`
     pub async fn accept(&mut self) -> anyhow::Result<PipeLine> {
+        #[cfg(feature = "quic")]
+        let accept_quic = accept_quic(self.quic_pipe.as_mut());
+        #[cfg(not(feature = "quic"))]
+        let accept_quic = futures::future::pending();
         tokio::select! {
             rs=accept_udp(self.udp_pipe.as_mut())=>{
                 rs
@@ -99,6 +131,9 @@ impl<PeerID> Pipe<PeerID> {
             rs=accept_tcp(self.tcp_pipe.as_mut())=>{
                 rs
             }
+            rs=accept_quic=>{
+                rs
+            }
             rs=accept_extend(self.extensible_pipe.as_mut())=>{
                 rs
             }
@@ -119,6 +154,14 @@ async fn accept_udp(udp: Option<&mut UdpPipe>) -> anyhow::Result<PipeLine> {
         futures::future::pending().await
     }
 }
+#[cfg(feature = "quic")]
+async fn accept_quic(quic: Option<&mut QuicPipe>) -> anyhow::Result<PipeLine> {
+    if let Some(quic_pipe) = quic {
+        Ok(PipeLine::Quic(quic_pipe.accept().await?))
+    } else {
+        futures::future::pending().await
+    }
+}
 async fn accept_extend(extend: Option<&mut ExtensiblePipe>) -> anyhow::Result<PipeLine> {
     if let Some(extend) = extend {
         Ok(PipeLine::Extend(extend.accept().await?))
@@ -134,6 +177,10 @@ impl<PeerID> Pipe<PeerID> {
     pub fn tcp_pipe_ref(&mut self) -> Option<&mut TcpPipe> {
         self.tcp_pipe.as_mut()
     }
+    #[cfg(feature = "quic")]
+    pub fn quic_pipe_ref(&mut self) -> Option<&mut QuicPipe> {
+        self.quic_pipe.as_mut()
+    }
     /// Acquire the `route_table` associated with the `pipe`
     pub fn route_table(&self) -> &RouteTable<PeerID> {
         &self.route_table
@@ -144,6 +191,8 @@ impl<PeerID> Pipe<PeerID> {
             route_table: &self.route_table,
             udp_pipe_writer: self.udp_pipe.as_ref().map(|v| v.writer_ref()),
             tcp_pipe_writer: self.tcp_pipe.as_ref().map(|v| v.writer_ref()),
+            #[cfg(feature = "quic")]
+            quic_pipe_writer: self.quic_pipe.as_ref().map(|v| v.writer_ref()),
             extensible_pipe_writer: self.extensible_pipe.as_ref().map(|v| v.writer_ref()),
         }
     }
@@ -155,6 +204,8 @@ impl<'a, PeerID> PipeWriterRef<'a, PeerID> {
             route_table: self.route_table.clone(),
             udp_pipe_writer: self.udp_pipe_writer.as_ref().map(|v| v.to_owned()),
             tcp_pipe_writer: self.tcp_pipe_writer.as_ref().map(|v| v.to_owned()),
+            #[cfg(feature = "quic")]
+            quic_pipe_writer: self.quic_pipe_writer.as_ref().map(|v| v.to_owned()),
             extensible_pipe_writer: self.extensible_pipe_writer.as_ref().map(|v| v.to_owned()),
         }
     }
@@ -166,6 +217,11 @@ impl<'a, PeerID> PipeWriterRef<'a, PeerID> {
     pub fn udp_pipe_writer_ref(&self) -> Option<UdpPipeWriterRef<'_>> {
         self.udp_pipe_writer
     }
+    /// Acquire a shared reference for writing to the pipe established by `QUIC`
+    #[cfg(feature = "quic")]
+    pub fn quic_pipe_writer_ref(&self) -> Option<QuicPipeWriterRef<'_>> {
+        self.quic_pipe_writer
+    }
     /// Acquire a shared reference for writing to the pipe established by other extended protocols
     pub fn extensible_pipe_writer_ref(&self) -> Option<ExtensiblePipeWriterRef<'_>> {
         self.extensible_pipe_writer
@@ -181,6 +237,11 @@ impl<PeerID> PipeWriter<PeerID> {
     pub fn tcp_pipe_writer(&self) -> Option<&TcpPipeWriter> {
         self.tcp_pipe_writer.as_ref()
     }
+    /// Acquire a owned `writer` for writing to the pipe established by `QUIC`
+    #[cfg(feature = "quic")]
+    pub fn quic_pipe_writer(&self) -> Option<&QuicPipeWriter> {
+        self.quic_pipe_writer.as_ref()
+    }
     /// Acquire a owned `writer` for writing to the pipe established by other extended protocols
     pub fn extensible_pipe_writer(&self) -> Option<&ExtensiblePipeWriter> {
         self.extensible_pipe_writer.as_ref()
@@ -193,14 +254,32 @@ impl<PeerID> PipeWriter<PeerID> {
 impl<PeerID> PipeWriter<PeerID> {
     /// Writing `buf` to the target denoted by `route_key`
     pub async fn send_to(&self, buf: BytesMut, route_key: &RouteKey) -> crate::error::Result<()> {
+        self.send_to_priority(buf, route_key, Priority::default())
+            .await
+    }
+    /// Writing `buf` to the target denoted by `route_key` with the priority class,
+    /// the priority is ignored by extended protocols
+    pub async fn send_to_priority(
+        &self,
+        buf: BytesMut,
+        route_key: &RouteKey,
+        priority: Priority,
+    ) -> crate::error::Result<()> {
         match route_key.protocol() {
             ConnectProtocol::UDP => {
                 if let Some(w) = self.udp_pipe_writer.as_ref() {
-                    return w.send_buf_to(buf, route_key).await;
+                    return w.send_buf_to_priority(buf, route_key, priority).await;
                 }
             }
             ConnectProtocol::TCP => {
                 if let Some(w) = self.tcp_pipe_writer.as_ref() {
+                    return w.send_to_priority(buf, route_key, priority).await;
+                }
+            }
+            ConnectProtocol::QUIC =>
+            {
+                #[cfg(feature = "quic")]
+                if let Some(w) = self.quic_pipe_writer.as_ref() {
                     return w.send_to(buf, route_key).await;
                 }
             }
@@ -231,6 +310,13 @@ impl<PeerID> PipeWriter<PeerID> {
                     return w.send_to_addr(buf, addr).await;
                 }
             }
+            ConnectProtocol::QUIC =>
+            {
+                #[cfg(feature = "quic")]
+                if let Some(w) = self.quic_pipe_writer.as_ref() {
+                    return w.send_to_addr(buf, addr).await;
+                }
+            }
             ConnectProtocol::Extend => {}
         }
         Err(crate::error::Error::InvalidProtocol)
@@ -242,6 +328,17 @@ impl<PeerID: Hash + Eq> PipeWriter<PeerID> {
         let route = self.route_table.get_route_by_id(peer_id)?;
         self.send_to(buf, &route.route_key()).await
     }
+    /// Writing `buf` to the target named by `peer_id` with the priority class
+    pub async fn send_to_id_priority(
+        &self,
+        buf: BytesMut,
+        peer_id: &PeerID,
+        priority: Priority,
+    ) -> crate::error::Result<()> {
+        let route = self.route_table.get_route_by_id(peer_id)?;
+        self.send_to_priority(buf, &route.route_key(), priority)
+            .await
+    }
     /// Writing `buf` to the target named by `peer_id`
     pub async fn send_to_id_safe(
         &self,
@@ -271,13 +368,39 @@ impl PipeLine {
         match self {
             PipeLine::Udp(line) => line.recv_from(buf).await,
             PipeLine::Tcp(line) => Some(line.recv_from(buf).await),
+            #[cfg(feature = "quic")]
+            PipeLine::Quic(line) => Some(line.recv_from(buf).await),
             PipeLine::Extend(line) => Some(line.recv_from(buf).await),
         }
     }
+    /// Receiving several packets with one wakeup, the other pipelines than `UDP` receive one.
+    /// `bufs[i]` holds the packet of `received[i]`
+    pub async fn recv_multiple_from<B: AsMut<[u8]>>(
+        &mut self,
+        bufs: &mut [B],
+        received: &mut Vec<(usize, RouteKey)>,
+    ) -> Option<std::io::Result<usize>> {
+        if let PipeLine::Udp(line) = self {
+            return line.recv_multiple_from(bufs, received).await;
+        }
+        received.clear();
+        let Some(buf) = bufs.first_mut() else {
+            return Some(Ok(0));
+        };
+        match self.recv_from(buf.as_mut()).await? {
+            Ok(rs) => {
+                received.push(rs);
+                Some(Ok(1))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
     pub fn done(&mut self) {
         match self {
             PipeLine::Udp(line) => line.done(),
             PipeLine::Tcp(line) => line.done(),
+            #[cfg(feature = "quic")]
+            PipeLine::Quic(line) => line.done(),
             PipeLine::Extend(line) => line.done(),
         }
     }
@@ -289,6 +412,8 @@ impl PipeLine {
         match self {
             PipeLine::Udp(_) => ConnectProtocol::UDP,
             PipeLine::Tcp(_) => ConnectProtocol::TCP,
+            #[cfg(feature = "quic")]
+            PipeLine::Quic(_) => ConnectProtocol::QUIC,
             PipeLine::Extend(_) => ConnectProtocol::Extend,
         }
     }
@@ -296,7 +421,16 @@ impl PipeLine {
         match self {
             PipeLine::Udp(_) => None,
             PipeLine::Tcp(tcp) => Some(tcp.route_key().addr()),
+            #[cfg(feature = "quic")]
+            PipeLine::Quic(quic) => Some(quic.remote_addr()),
             PipeLine::Extend(_) => None,
         }
     }
+    /// The fingerprint of the certificate the peer presented to the `TCP` transport
+    pub fn peer_fingerprint(&self) -> Option<[u8; 32]> {
+        match self {
+            PipeLine::Tcp(tcp) => tcp.peer_fingerprint(),
+            _ => None,
+        }
+    }
 }
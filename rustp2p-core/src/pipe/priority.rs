@@ -0,0 +1,101 @@
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+/// Priority class of outgoing data.
+/// The writers always drain higher classes first.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Priority {
+    /// Heartbeats, route queries, punching
+    Control,
+    #[default]
+    Interactive,
+    Bulk,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Control, Priority::Interactive, Priority::Bulk];
+    #[inline]
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Create a group of bounded channels, one per priority class
+pub(crate) fn priority_channel<T>(buffer: usize) -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let (control_s, control_r) = tokio::sync::mpsc::channel(buffer);
+    let (interactive_s, interactive_r) = tokio::sync::mpsc::channel(buffer);
+    let (bulk_s, bulk_r) = tokio::sync::mpsc::channel(buffer);
+    (
+        PrioritySender {
+            senders: [control_s, interactive_s, bulk_s],
+        },
+        PriorityReceiver {
+            receivers: [control_r, interactive_r, bulk_r],
+        },
+    )
+}
+
+pub(crate) struct PrioritySender<T> {
+    senders: [Sender<T>; 3],
+}
+
+impl<T> Clone for PrioritySender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            senders: self.senders.clone(),
+        }
+    }
+}
+
+impl<T> PrioritySender<T> {
+    pub(crate) async fn send(&self, value: T, priority: Priority) -> Result<(), SendError<T>> {
+        self.senders[priority.index()].send(value).await
+    }
+}
+
+pub(crate) struct PriorityReceiver<T> {
+    receivers: [Receiver<T>; 3],
+}
+
+impl<T> PriorityReceiver<T> {
+    /// Wait for the next value, higher classes are returned first.
+    /// Returns None after all senders are dropped
+    pub(crate) async fn recv(&mut self) -> Option<T> {
+        let [control, interactive, bulk] = &mut self.receivers;
+        tokio::select! {
+            biased;
+            Some(v) = control.recv() => Some(v),
+            Some(v) = interactive.recv() => Some(v),
+            Some(v) = bulk.recv() => Some(v),
+            else => None,
+        }
+    }
+    pub(crate) fn try_recv(&mut self) -> Option<T> {
+        for receiver in &mut self.receivers {
+            if let Ok(v) = receiver.try_recv() {
+                return Some(v);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipe::priority::{priority_channel, Priority};
+
+    #[tokio::test]
+    async fn priority_order() {
+        let (sender, mut receiver) = priority_channel(8);
+        sender.send(1, Priority::Bulk).await.unwrap();
+        sender.send(2, Priority::Interactive).await.unwrap();
+        sender.send(3, Priority::Control).await.unwrap();
+        sender.send(4, Priority::Bulk).await.unwrap();
+        assert_eq!(receiver.recv().await, Some(3));
+        assert_eq!(receiver.try_recv(), Some(2));
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.try_recv(), Some(4));
+        drop(sender);
+        assert_eq!(receiver.recv().await, None);
+    }
+}
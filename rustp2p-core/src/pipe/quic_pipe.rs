@@ -0,0 +1,730 @@
+use std::io;
+use std::io::IoSliceMut;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use async_lock::Mutex as AsyncMutex;
+use bytes::BytesMut;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::rustls;
+use quinn::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use quinn::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{
+    AsyncUdpSocket, ClientConfig, Connection, Endpoint, EndpointConfig, IdleTimeout, ServerConfig,
+    TokioRuntime, TransportConfig, UdpPoller, VarInt,
+};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::pipe::config::QuicPipeConfig;
+use crate::pipe::udp_pipe::{PipeUdpSocket, SocketLayer as UdpSocketLayer, UDPIndex};
+use crate::route::{Index, RouteKey};
+
+/// The name in the self-signed certificates
+const SERVER_NAME: &str = "rustp2p";
+/// The packets waiting for an endpoint, more are dropped
+const ENDPOINT_QUEUE_CAP: usize = 1024;
+/// A packet sent on a stream is at most this long
+const MAX_STREAM_LEN: usize = u16::MAX as usize;
+/// A stream not finished in this time is dropped
+const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+/// The packets read from the streams waiting for `recv_from`
+const STREAM_QUEUE_CAP: usize = 64;
+
+pub struct QuicPipe {
+    connect_receiver: Receiver<QuicPipeLine>,
+    incoming_receiver: Receiver<(UDPIndex, Connection)>,
+    quic_pipe_writer: QuicPipeWriter,
+}
+
+impl QuicPipe {
+    /// Construct a `QUIC` pipe with an endpoint on each main socket of the `UDP` pipe,
+    /// so the connections go through the nat mappings punched by `UDP`
+    pub fn new(
+        config: QuicPipeConfig,
+        udp_socket_layer: &UdpSocketLayer,
+    ) -> anyhow::Result<QuicPipe> {
+        config.check()?;
+        let mut transport = TransportConfig::default();
+        transport.max_idle_timeout(Some(IdleTimeout::try_from(config.route_idle_time)?));
+        transport.keep_alive_interval(Some(config.keep_alive_interval));
+        let (server_config, client_config) =
+            crypto_config(Arc::new(transport), config.secret.as_deref())?;
+        let mut endpoint_config = EndpointConfig::default();
+        // The other protocols on the sockets are told apart by the fixed bit
+        endpoint_config.grease_quic_bit(false);
+
+        let (incoming_sender, incoming_receiver) = tokio::sync::mpsc::channel(64);
+        let mut endpoints = Vec::new();
+        for (index, udp) in udp_socket_layer.main_udp() {
+            let (sender, receiver) = tokio::sync::mpsc::channel(ENDPOINT_QUEUE_CAP);
+            let socket = Arc::new(QuicSocket {
+                udp,
+                receiver: Mutex::new(receiver),
+            });
+            let mut endpoint = Endpoint::new_with_abstract_socket(
+                endpoint_config.clone(),
+                Some(server_config.clone()),
+                socket,
+                Arc::new(TokioRuntime),
+            )?;
+            endpoint.set_default_client_config(client_config.clone());
+            udp_socket_layer.set_quic_sender(index, sender);
+            tokio::spawn(accept_loop(
+                index,
+                endpoint.clone(),
+                incoming_sender.clone(),
+            ));
+            endpoints.push((index, endpoint));
+        }
+        let (connect_sender, connect_receiver) = tokio::sync::mpsc::channel(64);
+        let quic_pipe_writer = QuicPipeWriter {
+            socket_layer: Arc::new(SocketLayer {
+                connect_locks: Default::default(),
+                endpoints,
+                connections: Default::default(),
+                next_id: AtomicUsize::new(0),
+                connect_sender,
+            }),
+        };
+        Ok(QuicPipe {
+            connect_receiver,
+            incoming_receiver,
+            quic_pipe_writer,
+        })
+    }
+    #[inline]
+    pub fn writer_ref(&self) -> QuicPipeWriterRef<'_> {
+        QuicPipeWriterRef {
+            shadow: &self.quic_pipe_writer.socket_layer,
+        }
+    }
+}
+
+impl QuicPipe {
+    /// Accept `QUIC` pipelines from this kind pipe
+    pub async fn accept(&mut self) -> anyhow::Result<QuicPipeLine> {
+        tokio::select! {
+            rs=self.connect_receiver.recv()=>{
+                rs.context("connect_receiver done")
+            }
+            rs=self.incoming_receiver.recv()=>{
+                let (index,connection) = rs.context("incoming_receiver done")?;
+                Ok(self.quic_pipe_writer.add_connection(index,connection))
+            }
+        }
+    }
+}
+
+impl Drop for QuicPipe {
+    fn drop(&mut self) {
+        for (_, endpoint) in &self.quic_pipe_writer.endpoints {
+            endpoint.close(VarInt::from_u32(0), b"");
+        }
+    }
+}
+
+async fn accept_loop(
+    index: UDPIndex,
+    endpoint: Endpoint,
+    incoming_sender: Sender<(UDPIndex, Connection)>,
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        let incoming_sender = incoming_sender.clone();
+        tokio::spawn(async move {
+            match incoming.await {
+                Ok(connection) => {
+                    let _ = incoming_sender.send((index, connection)).await;
+                }
+                Err(e) => {
+                    log::debug!("quic accept {index:?} {e:?}")
+                }
+            }
+        });
+    }
+}
+
+pub struct QuicPipeLine {
+    route_key: RouteKey,
+    connection: Connection,
+    connections: Arc<DashMap<usize, (UDPIndex, Connection)>>,
+    stream_receiver: Receiver<Vec<u8>>,
+}
+
+impl QuicPipeLine {
+    /// The index stays the same when the peer migrates to another address
+    #[inline]
+    pub fn route_key(&self) -> RouteKey {
+        self.route_key
+    }
+    /// The current address of the peer
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+    pub fn done(&mut self) {
+        self.connections.remove(&self.route_key.index_usize());
+        self.connection.close(VarInt::from_u32(0), b"");
+    }
+}
+
+impl Drop for QuicPipeLine {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+impl QuicPipeLine {
+    /// Receive a datagram, or a unidirectional stream carrying a packet too large for a datagram
+    /// `usize` in the `Ok` branch indicates how many bytes are received
+    /// `RouteKey` in the `Ok` branch denotes the source where these bytes are received from
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, RouteKey)> {
+        loop {
+            let len = tokio::select! {
+                rs=self.connection.read_datagram()=>{
+                    let data = rs?;
+                    if data.len() > buf.len() {
+                        log::debug!("quic datagram too long {}", data.len());
+                        continue;
+                    }
+                    buf[..data.len()].copy_from_slice(&data);
+                    data.len()
+                }
+                Some(data)=self.stream_receiver.recv()=>{
+                    if data.len() > buf.len() {
+                        log::debug!("quic stream too long {}", data.len());
+                        continue;
+                    }
+                    buf[..data.len()].copy_from_slice(&data);
+                    data.len()
+                }
+            };
+            return Ok((len, self.route_key));
+        }
+    }
+}
+
+/// Reads the unidirectional streams of the connection apart from the datagrams,
+/// so that a large or stalled stream does not hold up the other packets.
+/// It ends with the connection
+async fn accept_streams(connection: Connection, stream_sender: Sender<Vec<u8>>) {
+    while let Ok(mut stream) = connection.accept_uni().await {
+        let stream_sender = stream_sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(STREAM_TIMEOUT, stream.read_to_end(MAX_STREAM_LEN)).await {
+                Ok(Ok(data)) => {
+                    if stream_sender.try_send(data).is_err() {
+                        log::debug!("quic stream dropped");
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::debug!("quic stream {e:?}");
+                }
+                Err(_) => {
+                    log::debug!("quic stream timeout");
+                    _ = stream.stop(VarInt::from_u32(0));
+                }
+            }
+        });
+    }
+}
+
+pub struct SocketLayer {
+    /// One handshake at a time per address
+    connect_locks: DashMap<(UDPIndex, SocketAddr), Arc<AsyncMutex<()>>>,
+    endpoints: Vec<(UDPIndex, Endpoint)>,
+    connections: Arc<DashMap<usize, (UDPIndex, Connection)>>,
+    next_id: AtomicUsize,
+    connect_sender: Sender<QuicPipeLine>,
+}
+
+impl SocketLayer {
+    fn add_connection(&self, index: UDPIndex, connection: Connection) -> QuicPipeLine {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let route_key = RouteKey::new(Index::Quic(id), connection.remote_address());
+        self.connections.insert(id, (index, connection.clone()));
+        let (stream_sender, stream_receiver) = tokio::sync::mpsc::channel(STREAM_QUEUE_CAP);
+        tokio::spawn(accept_streams(connection.clone(), stream_sender));
+        QuicPipeLine {
+            route_key,
+            connection,
+            connections: self.connections.clone(),
+            stream_receiver,
+        }
+    }
+    fn get_route_key(&self, index: UDPIndex, addr: &SocketAddr) -> Option<RouteKey> {
+        self.connections.iter().find_map(|v| {
+            let (connection_index, connection) = v.value();
+            if *connection_index == index
+                && connection.remote_address() == *addr
+                && connection.close_reason().is_none()
+            {
+                Some(RouteKey::new(Index::Quic(*v.key()), *addr))
+            } else {
+                None
+            }
+        })
+    }
+    /// The local addresses of the endpoints
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.endpoints
+            .iter()
+            .filter_map(|(_, endpoint)| endpoint.local_addr().ok())
+            .collect()
+    }
+    /// Initiate a connection from the main `UDP` socket of `index`,
+    /// an established connection to `addr` is reused
+    pub async fn connect(&self, addr: SocketAddr, index: usize) -> crate::error::Result<RouteKey> {
+        let index = if addr.is_ipv4() {
+            UDPIndex::MainV4(index)
+        } else {
+            UDPIndex::MainV6(index)
+        };
+        self.connect0(index, addr).await
+    }
+    /// Initiate a connection over the socket of a punched `UDP` route,
+    /// which passes the nat through the same mapping
+    pub async fn connect_route(&self, route_key: &RouteKey) -> crate::error::Result<RouteKey> {
+        match route_key.index() {
+            Index::Udp(index @ (UDPIndex::MainV4(_) | UDPIndex::MainV6(_))) => {
+                self.connect0(index, route_key.addr()).await
+            }
+            _ => Err(crate::error::Error::InvalidProtocol),
+        }
+    }
+    async fn connect0(&self, index: UDPIndex, addr: SocketAddr) -> crate::error::Result<RouteKey> {
+        let lock = self.connect_locks.entry((index, addr)).or_default().clone();
+        let rs = {
+            let _guard = lock.lock().await;
+            self.connect_locked(index, addr).await
+        };
+        // Nobody else is waiting on the lock
+        self.connect_locks
+            .remove_if(&(index, addr), |_, v| Arc::strong_count(v) == 2);
+        rs
+    }
+    async fn connect_locked(
+        &self,
+        index: UDPIndex,
+        addr: SocketAddr,
+    ) -> crate::error::Result<RouteKey> {
+        if let Some(route_key) = self.get_route_key(index, &addr) {
+            return Ok(route_key);
+        }
+        let endpoint = self
+            .endpoints
+            .iter()
+            .find(|(v, _)| *v == index)
+            .map(|(_, endpoint)| endpoint)
+            .ok_or(crate::error::Error::IndexOutOfBounds {
+                len: self.endpoints.len(),
+                index: index.index(),
+            })?;
+        let connection = endpoint
+            .connect(addr, SERVER_NAME)
+            .map_err(io::Error::other)?
+            .await
+            .map_err(io::Error::from)?;
+        let pipe_line = self.add_connection(index, connection);
+        let route_key = pipe_line.route_key();
+        if let Err(_e) = self.connect_sender.send(pipe_line).await {
+            Err(crate::error::Error::Eof)?
+        }
+        Ok(route_key)
+    }
+}
+
+impl QuicPipeWriter {
+    /// Writing `buf` to the target denoted by `route_key`,
+    /// in a datagram if it fits or in a unidirectional stream otherwise
+    pub async fn send_to(&self, buf: BytesMut, route_key: &RouteKey) -> crate::error::Result<()> {
+        let Index::Quic(id) = route_key.index() else {
+            return Err(crate::error::Error::InvalidProtocol);
+        };
+        let Some(connection) = self.connections.get(&id).map(|v| v.value().1.clone()) else {
+            return Err(crate::error::Error::RouteNotFound(format!("{route_key:?}")));
+        };
+        if matches!(connection.max_datagram_size(), Some(max) if buf.len() <= max) {
+            connection
+                .send_datagram(buf.freeze())
+                .map_err(io::Error::other)?;
+            return Ok(());
+        }
+        let mut stream = connection.open_uni().await.map_err(io::Error::from)?;
+        stream.write_all(&buf).await.map_err(io::Error::from)?;
+        stream.finish().map_err(io::Error::other)?;
+        Ok(())
+    }
+    /// Writing `buf` to the target denoted by SocketAddr, connecting from the first main socket
+    pub async fn send_to_addr<A: Into<SocketAddr>>(
+        &self,
+        buf: BytesMut,
+        addr: A,
+    ) -> crate::error::Result<()> {
+        let route_key = self.connect(addr.into(), 0).await?;
+        self.send_to(buf, &route_key).await
+    }
+}
+
+#[derive(Clone)]
+pub struct QuicPipeWriter {
+    socket_layer: Arc<SocketLayer>,
+}
+
+impl Deref for QuicPipeWriter {
+    type Target = Arc<SocketLayer>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.socket_layer
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct QuicPipeWriterRef<'a> {
+    shadow: &'a Arc<SocketLayer>,
+}
+
+impl<'a> QuicPipeWriterRef<'a> {
+    pub fn to_owned(&self) -> QuicPipeWriter {
+        QuicPipeWriter {
+            socket_layer: self.shadow.clone(),
+        }
+    }
+}
+
+impl<'a> Deref for QuicPipeWriterRef<'a> {
+    type Target = Arc<SocketLayer>;
+
+    fn deref(&self) -> &Self::Target {
+        self.shadow
+    }
+}
+
+/// A main `UDP` socket shared with the `UDP` pipe,
+/// which forwards the received `QUIC` packets
+#[derive(Debug)]
+struct QuicSocket {
+    udp: Arc<PipeUdpSocket>,
+    receiver: Mutex<Receiver<(BytesMut, SocketAddr)>>,
+}
+
+impl AsyncUdpSocket for QuicSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(QuicPoller {
+            udp: self.udp.clone(),
+        })
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        // Only one segment per transmit, see `max_transmit_segments`
+        self.udp
+            .try_send_to(transmit.contents, transmit.destination)?;
+        Ok(())
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        match self.receiver.lock().poll_recv(cx) {
+            Poll::Ready(Some((data, addr))) => {
+                let len = data.len().min(bufs[0].len());
+                bufs[0][..len].copy_from_slice(&data[..len]);
+                meta[0] = RecvMeta {
+                    addr,
+                    len,
+                    stride: len,
+                    ecn: None,
+                    dst_ip: None,
+                };
+                Poll::Ready(Ok(1))
+            }
+            Poll::Ready(None) => Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+}
+
+#[derive(Debug)]
+struct QuicPoller {
+    udp: Arc<PipeUdpSocket>,
+}
+
+impl UdpPoller for QuicPoller {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.udp.poll_send_ready(cx)
+    }
+}
+
+fn crypto_config(
+    transport: Arc<TransportConfig>,
+    secret: Option<&[u8]>,
+) -> anyhow::Result<(ServerConfig, ClientConfig)> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let (cert, key) = match secret {
+        Some(secret) => group_identity(secret)?,
+        None => {
+            log::warn!("the quic pipe has no secret, the peers are not authenticated");
+            let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+            let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+            (cert.cert.der().clone(), key)
+        }
+    };
+    let verifier = Arc::new(GroupVerifier {
+        provider: provider.clone(),
+        group_cert: secret.map(|_| cert.clone()),
+    });
+    let server_crypto = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let server_crypto = if secret.is_some() {
+        server_crypto.with_client_cert_verifier(verifier.clone())
+    } else {
+        server_crypto.with_no_client_auth()
+    }
+    .with_single_cert(vec![cert.clone()], key.clone_key().into())?;
+    let mut server_config =
+        ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_crypto)?));
+    server_config.transport_config(transport.clone());
+    let client_crypto = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+    let client_crypto = if secret.is_some() {
+        client_crypto.with_client_auth_cert(vec![cert], key.into())?
+    } else {
+        client_crypto.with_no_client_auth()
+    };
+    let mut client_config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
+    client_config.transport_config(transport);
+    Ok((server_config, client_config))
+}
+
+/// The certificate of an Ed25519 key seeded by the secret,
+/// the signing and the serial number are deterministic, so every member gets the same one
+fn group_identity(
+    secret: &[u8],
+) -> anyhow::Result<(CertificateDer<'static>, PrivatePkcs8KeyDer<'static>)> {
+    /// The PKCS#8 v1 encoding of an Ed25519 private key, the 32 byte seed follows
+    const PKCS8_ED25519_PREFIX: [u8; 16] = [
+        0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04,
+        0x20,
+    ];
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"rustp2p quic identity");
+    let seed = ring::hmac::sign(&key, secret);
+    let mut pkcs8 = PKCS8_ED25519_PREFIX.to_vec();
+    pkcs8.extend_from_slice(seed.as_ref());
+    let key_pair = rcgen::KeyPair::try_from(pkcs8.as_slice())?;
+    let cert =
+        rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()])?.self_signed(&key_pair)?;
+    Ok((cert.der().clone(), PrivatePkcs8KeyDer::from(pkcs8)))
+}
+
+/// Accepts only the certificate derived from the group secret,
+/// or any certificate if there is no secret. The handshake signature is checked
+#[derive(Debug)]
+struct GroupVerifier {
+    provider: Arc<rustls::crypto::CryptoProvider>,
+    group_cert: Option<CertificateDer<'static>>,
+}
+
+impl GroupVerifier {
+    fn verify(&self, end_entity: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        match &self.group_cert {
+            Some(cert) if cert != end_entity => Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ServerCertVerifier for GroupVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for GroupVerifier {
+    fn root_hint_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        ServerCertVerifier::verify_tls12_signature(self, message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        ServerCertVerifier::verify_tls13_signature(self, message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        ServerCertVerifier::supported_verify_schemes(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::pipe::config::{QuicPipeConfig, UdpPipeConfig};
+    use crate::pipe::quic_pipe::QuicPipe;
+    use crate::pipe::udp_pipe::UdpPipe;
+
+    async fn udp_pipe() -> UdpPipe {
+        let config = UdpPipeConfig::default()
+            .set_main_pipeline_num(1)
+            .set_use_v6(false);
+        let mut udp_pipe = UdpPipe::new(config).unwrap();
+        let mut line = udp_pipe.accept().await.unwrap();
+        // The QUIC packets only reach the endpoint while the pipeline is read
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            while let Some(Ok(_)) = line.recv_from(&mut buf).await {}
+        });
+        udp_pipe
+    }
+
+    #[tokio::test]
+    pub async fn quic_pipe_send_recv() {
+        let udp_a = udp_pipe().await;
+        let udp_b = udp_pipe().await;
+        let quic_a = QuicPipe::new(QuicPipeConfig::default(), &udp_a.writer_ref()).unwrap();
+        let mut quic_b = QuicPipe::new(QuicPipeConfig::default(), &udp_b.writer_ref()).unwrap();
+        let port_b = udp_b.writer_ref().local_ports().unwrap()[0];
+
+        let writer = quic_a.writer_ref().to_owned();
+        let large = vec![7u8; 4000];
+        writer
+            .send_to_addr(BytesMut::from(&b"hello"[..]), ([127, 0, 0, 1], port_b))
+            .await
+            .unwrap();
+        let route_key = writer
+            .connect(([127, 0, 0, 1], port_b).into(), 0)
+            .await
+            .unwrap();
+        writer
+            .send_to(BytesMut::from(&large[..]), &route_key)
+            .await
+            .unwrap();
+
+        let mut line = quic_b.accept().await.unwrap();
+        let mut buf = [0; 8192];
+        // Datagrams and streams are not ordered with each other
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (len, _) = line.recv_from(&mut buf).await.unwrap();
+            received.push(buf[..len].to_vec());
+        }
+        received.sort_by_key(|v| v.len());
+        assert!(received[0] == b"hello");
+        assert!(received[1] == large);
+    }
+
+    #[test]
+    fn group_identity_is_deterministic() {
+        let (cert_a, key_a) = super::group_identity(b"secret").unwrap();
+        let (cert_b, key_b) = super::group_identity(b"secret").unwrap();
+        let (cert_c, _) = super::group_identity(b"other").unwrap();
+        assert_eq!(cert_a, cert_b);
+        assert_eq!(key_a.secret_pkcs8_der(), key_b.secret_pkcs8_der());
+        assert_ne!(cert_a, cert_c);
+    }
+
+    #[tokio::test]
+    pub async fn quic_pipe_secret() {
+        let udp_a = udp_pipe().await;
+        let udp_b = udp_pipe().await;
+        let udp_c = udp_pipe().await;
+        let config = QuicPipeConfig::default().set_secret(b"group".to_vec());
+        let quic_a = QuicPipe::new(config.clone(), &udp_a.writer_ref()).unwrap();
+        let _quic_b = QuicPipe::new(config, &udp_b.writer_ref()).unwrap();
+        let quic_c = QuicPipe::new(
+            QuicPipeConfig::default().set_secret(b"intruder".to_vec()),
+            &udp_c.writer_ref(),
+        )
+        .unwrap();
+        let port_b = udp_b.writer_ref().local_ports().unwrap()[0];
+
+        let addr = ([127, 0, 0, 1], port_b).into();
+        assert!(quic_a.writer_ref().connect(addr, 0).await.is_ok());
+        assert!(quic_c.writer_ref().connect(addr, 0).await.is_err());
+    }
+}
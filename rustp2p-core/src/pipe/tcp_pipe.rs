@@ -1,7 +1,13 @@
 use crate::pipe::config::TcpPipeConfig;
+use crate::pipe::priority::{priority_channel, Priority, PrioritySender};
 use crate::pipe::recycle::RecycleBuf;
+use crate::proxy::ProxyConfig;
 use crate::route::{Index, RouteKey};
-use crate::socket::{connect_tcp, create_tcp_listener, LocalInterface};
+#[cfg(feature = "sim")]
+use crate::sim::{SimHost, SimListener, SimStream};
+use crate::socket::{
+    connect_tcp, create_tcp_listener, handshake_timeout, set_dscp, LocalInterface,
+};
 use anyhow::Context;
 use async_lock::Mutex;
 use async_trait::async_trait;
@@ -12,22 +18,21 @@ use rand::Rng;
 use std::io;
 use std::io::IoSlice;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_util::sync::CancellationToken;
 
 pub struct TcpPipe {
     route_idle_time: Duration,
-    tcp_listener: TcpListener,
+    tcp_listener: PipeTcpListener,
     connect_receiver: Receiver<(RouteKey, ReadHalfBox)>,
     tcp_pipe_writer: TcpPipeWriter,
     write_half_collect: WriteHalfCollect,
-    init_codec: Arc<Box<dyn InitCodec>>,
 }
 
 impl TcpPipe {
@@ -40,9 +45,21 @@ impl TcpPipe {
             format!("0.0.0.0:{}", config.tcp_port).parse().unwrap()
         };
 
-        let tcp_listener = create_tcp_listener(address)?;
+        #[cfg(feature = "sim")]
+        let sim_listener = match &config.sim_host {
+            Some(sim_host) => Some(PipeTcpListener::Sim(sim_host.listen(config.tcp_port)?)),
+            None => None,
+        };
+        #[cfg(not(feature = "sim"))]
+        let sim_listener = None;
+        let tcp_listener = match sim_listener {
+            Some(sim_listener) => sim_listener,
+            None => {
+                let tcp_listener = create_tcp_listener(address)?;
+                PipeTcpListener::Os(TcpListener::from_std(tcp_listener)?)
+            }
+        };
         let local_addr = tcp_listener.local_addr()?;
-        let tcp_listener = TcpListener::from_std(tcp_listener)?;
         let (connect_sender, connect_receiver) = tokio::sync::mpsc::channel(64);
         let write_half_collect =
             WriteHalfCollect::new(config.tcp_multiplexing_limit, config.recycle_buf);
@@ -54,7 +71,12 @@ impl TcpPipe {
                 write_half_collect.clone(),
                 connect_sender,
                 config.default_interface,
-                init_codec.clone(),
+                init_codec,
+                config.transport,
+                config.proxy,
+                config.dscp,
+                #[cfg(feature = "sim")]
+                config.sim_host,
             )),
         };
         Ok(TcpPipe {
@@ -63,7 +85,6 @@ impl TcpPipe {
             connect_receiver,
             tcp_pipe_writer,
             write_half_collect,
-            init_codec,
         })
     }
     #[inline]
@@ -74,23 +95,72 @@ impl TcpPipe {
     }
 }
 
+impl Drop for TcpPipe {
+    fn drop(&mut self) {
+        self.tcp_pipe_writer.close.cancel();
+    }
+}
+
 impl TcpPipe {
-    /// Accept `TCP` pipelines from this kind pipe
+    /// Accept `TCP` pipelines from this kind pipe,
+    /// the accepted streams are wrapped by the transport in their own tasks, bounded by the handshake timeout
     pub async fn accept(&mut self) -> anyhow::Result<TcpPipeLine> {
-        tokio::select! {
-            rs=self.connect_receiver.recv()=>{
-                let (route_key,read_half) = rs.context("connect_receiver done")?;
-                Ok(TcpPipeLine::new(self.route_idle_time,route_key,read_half,self.write_half_collect.clone()))
+        loop {
+            tokio::select! {
+                rs=self.connect_receiver.recv()=>{
+                    let (route_key,read_half) = rs.context("connect_receiver done")?;
+                    return Ok(TcpPipeLine::new(self.route_idle_time,route_key,read_half,self.write_half_collect.clone()))
+                }
+                rs=self.tcp_listener.accept()=>{
+                    let (tcp_stream,addr) = rs?;
+                    let socket_layer = self.tcp_pipe_writer.socket_layer.clone();
+                    tokio::spawn(async move {
+                        let rs = match tcp_stream {
+                            PipeTcpStream::Os(tcp_stream) => socket_layer.accept_stream(tcp_stream, addr).await,
+                            #[cfg(feature = "sim")]
+                            PipeTcpStream::Sim(sim_stream) => socket_layer.add_sim_stream(sim_stream, addr, 0).await,
+                        };
+                        if let Err(e) = rs {
+                            log::debug!("tcp accept {addr} {e:?}");
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// A listener of the OS, or of the simulated network
+enum PipeTcpListener {
+    Os(TcpListener),
+    #[cfg(feature = "sim")]
+    Sim(SimListener),
+}
+
+enum PipeTcpStream {
+    Os(TcpStream),
+    #[cfg(feature = "sim")]
+    Sim(SimStream),
+}
+
+impl PipeTcpListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PipeTcpListener::Os(tcp_listener) => tcp_listener.local_addr(),
+            #[cfg(feature = "sim")]
+            PipeTcpListener::Sim(sim_listener) => Ok(sim_listener.local_addr()),
+        }
+    }
+    async fn accept(&mut self) -> io::Result<(PipeTcpStream, SocketAddr)> {
+        match self {
+            PipeTcpListener::Os(tcp_listener) => {
+                let (stream, addr) = tcp_listener.accept().await?;
+                Ok((PipeTcpStream::Os(stream), addr))
             }
-            rs=self.tcp_listener.accept()=>{
-                let (tcp_stream,addr) = rs?;
-                tcp_stream.set_nodelay(true)?;
-                let route_key = tcp_stream.route_key()?;
-                let (read_half,write_half) = tcp_stream.into_split();
-                let (decoder,encoder) = self.init_codec.codec(addr)?;
-                let read_half = ReadHalfBox::new(read_half,decoder);
-                self.write_half_collect.add_write_half(route_key,0, write_half,encoder);
-                Ok(TcpPipeLine::new(self.route_idle_time,route_key,read_half,self.write_half_collect.clone()))
+            #[cfg(feature = "sim")]
+            PipeTcpListener::Sim(sim_listener) => {
+                let (stream, addr) = sim_listener.accept().await?;
+                Ok((PipeTcpStream::Sim(stream), addr))
             }
         }
     }
@@ -99,8 +169,10 @@ impl TcpPipe {
 pub struct TcpPipeLine {
     route_key: RouteKey,
     route_idle_time: Duration,
-    tcp_read: OwnedReadHalf,
+    tcp_read: TcpReadHalf,
     decoder: Box<dyn Decoder>,
+    peer_fingerprint: Option<[u8; 32]>,
+    close: CancellationToken,
     write_half_collect: WriteHalfCollect,
 }
 
@@ -111,13 +183,18 @@ impl TcpPipeLine {
         read: ReadHalfBox,
         write_half_collect: WriteHalfCollect,
     ) -> Self {
-        let decoder = read.decoder;
-        let tcp_read = read.read_half;
+        let close = write_half_collect
+            .connections
+            .get(&route_key.index_usize())
+            .map(|v| v.close.clone())
+            .unwrap_or_default();
         Self {
             route_key,
             route_idle_time,
-            tcp_read,
-            decoder,
+            tcp_read: read.read_half,
+            decoder: read.decoder,
+            peer_fingerprint: read.peer_fingerprint,
+            close,
             write_half_collect,
         }
     }
@@ -125,6 +202,10 @@ impl TcpPipeLine {
     pub fn route_key(&self) -> RouteKey {
         self.route_key
     }
+    /// The SHA-256 fingerprint of the certificate the peer presented to the transport
+    pub fn peer_fingerprint(&self) -> Option<[u8; 32]> {
+        self.peer_fingerprint
+    }
     pub fn done(&mut self) {
         self.write_half_collect.remove(&self.route_key);
     }
@@ -147,12 +228,16 @@ impl TcpPipeLine {
     }
 
     pub(crate) async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match tokio::time::timeout(
-            self.route_idle_time,
-            self.decoder.decode(&mut self.tcp_read, buf),
-        )
-        .await
-        {
+        let rs = tokio::select! {
+            rs = tokio::time::timeout(
+                self.route_idle_time,
+                self.decoder.decode(&mut self.tcp_read, buf),
+            ) => rs,
+            _ = self.close.cancelled() => {
+                return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
+            }
+        };
+        match rs {
             Ok(rs) => rs,
             Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
         }
@@ -172,29 +257,48 @@ impl TcpPipeLine {
 pub struct WriteHalfCollect {
     tcp_multiplexing_limit: usize,
     addr_mapping: Arc<DashMap<SocketAddr, Vec<usize>>>,
-    write_half_map: Arc<DashMap<usize, Sender<BytesMut>>>,
+    write_half_map: Arc<DashMap<usize, PrioritySender<BytesMut>>>,
+    connections: Arc<DashMap<usize, ConnectionState>>,
     recycle_buf: Option<RecycleBuf>,
 }
 
+struct ConnectionState {
+    route_key: RouteKey,
+    /// `None` on the simulated network
+    local_ip: Option<IpAddr>,
+    /// Ends both halves of the connection
+    close: CancellationToken,
+}
+
 impl WriteHalfCollect {
     fn new(tcp_multiplexing_limit: usize, recycle_buf: Option<RecycleBuf>) -> Self {
         Self {
             tcp_multiplexing_limit,
             addr_mapping: Default::default(),
             write_half_map: Default::default(),
+            connections: Default::default(),
             recycle_buf,
         }
     }
 }
 
 pub(crate) struct ReadHalfBox {
-    read_half: OwnedReadHalf,
+    read_half: TcpReadHalf,
     decoder: Box<dyn Decoder>,
+    peer_fingerprint: Option<[u8; 32]>,
 }
 
 impl ReadHalfBox {
-    pub(crate) fn new(read_half: OwnedReadHalf, decoder: Box<dyn Decoder>) -> Self {
-        Self { read_half, decoder }
+    pub(crate) fn new(
+        read_half: TcpReadHalf,
+        decoder: Box<dyn Decoder>,
+        peer_fingerprint: Option<[u8; 32]>,
+    ) -> Self {
+        Self {
+            read_half,
+            decoder,
+            peer_fingerprint,
+        }
     }
 }
 
@@ -203,7 +307,8 @@ impl WriteHalfCollect {
         &self,
         route_key: RouteKey,
         index_offset: usize,
-        mut writer: OwnedWriteHalf,
+        local_ip: Option<IpAddr>,
+        mut writer: TcpWriteHalf,
         mut decoder: Box<dyn Encoder>,
     ) {
         assert!(index_offset < self.tcp_multiplexing_limit);
@@ -220,8 +325,17 @@ impl WriteHalfCollect {
                 v[index_offset] = index;
                 v
             });
-        let (s, mut r) = tokio::sync::mpsc::channel(32);
+        let (s, mut r) = priority_channel(32);
         self.write_half_map.insert(index, s);
+        let close = CancellationToken::new();
+        self.connections.insert(
+            index,
+            ConnectionState {
+                route_key,
+                local_ip,
+                close: close.clone(),
+            },
+        );
         let collect = self.clone();
         let recycle_buf = self.recycle_buf.clone();
         tokio::spawn(async move {
@@ -229,11 +343,18 @@ impl WriteHalfCollect {
             const IO_SLICE_CAPACITY: usize = 16;
             let mut io_buffer: Vec<IoSlice> = Vec::with_capacity(IO_SLICE_CAPACITY);
             let io_slice_storage = io_buffer.as_mut_slice();
-            while let Some(v) = r.recv().await {
-                if let Ok(buf) = r.try_recv() {
+            loop {
+                let v = tokio::select! {
+                    v = r.recv() => match v {
+                        Some(v) => v,
+                        None => break,
+                    },
+                    _ = close.cancelled() => break,
+                };
+                if let Some(buf) = r.try_recv() {
                     vec_buf.push(v);
                     vec_buf.push(buf);
-                    while let Ok(buf) = r.try_recv() {
+                    while let Some(buf) = r.try_recv() {
                         vec_buf.push(buf);
                         if vec_buf.len() == 16 {
                             break;
@@ -299,8 +420,20 @@ impl WriteHalfCollect {
             });
 
         self.write_half_map.remove(&index_usize);
+        self.connections.remove(&index_usize);
     }
-    pub(crate) fn get(&self, index: &usize) -> Option<Sender<BytesMut>> {
+    /// Closes the connections from the local addresses that `gone` picks and returns their route keys
+    fn close_local_ips(&self, gone: impl Fn(&IpAddr) -> bool) -> Vec<RouteKey> {
+        self.connections
+            .iter()
+            .filter(|v| matches!(&v.local_ip, Some(ip) if gone(ip)))
+            .map(|v| {
+                v.close.cancel();
+                v.route_key
+            })
+            .collect()
+    }
+    pub(crate) fn get(&self, index: &usize) -> Option<PrioritySender<BytesMut>> {
         self.write_half_map.get(index).map(|v| v.value().clone())
     }
 
@@ -326,12 +459,22 @@ impl WriteHalfCollect {
         None
     }
     pub async fn send_to(&self, buf: BytesMut, route_key: &RouteKey) -> crate::error::Result<()> {
+        self.send_to_priority(buf, route_key, Priority::default())
+            .await
+    }
+    /// Control traffic is queued ahead of the interactive and bulk traffic
+    pub async fn send_to_priority(
+        &self,
+        buf: BytesMut,
+        route_key: &RouteKey,
+        priority: Priority,
+    ) -> crate::error::Result<()> {
         match route_key.index() {
             Index::Tcp(index) => {
                 let write_half = self.get(&index).ok_or_else(|| {
                     crate::error::Error::RouteNotFound(format!("not found {route_key:?}"))
                 })?;
-                if let Err(_e) = write_half.send(buf).await {
+                if let Err(_e) = write_half.send(buf, priority).await {
                     Err(io::Error::from(io::ErrorKind::WriteZero))?
                 } else {
                     Ok(())
@@ -350,9 +493,17 @@ pub struct SocketLayer {
     connect_sender: Sender<(RouteKey, ReadHalfBox)>,
     default_interface: Option<LocalInterface>,
     init_codec: Arc<Box<dyn InitCodec>>,
+    transport: Arc<dyn TcpTransport>,
+    proxy: Option<ProxyConfig>,
+    dscp: Option<u8>,
+    #[cfg(feature = "sim")]
+    sim_host: Option<SimHost>,
+    /// Cancelled when the `TcpPipe` is dropped, ends the tasks that outlive a call on the writer
+    close: CancellationToken,
 }
 
 impl SocketLayer {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         local_addr: SocketAddr,
         tcp_multiplexing_limit: usize,
@@ -360,6 +511,10 @@ impl SocketLayer {
         connect_sender: Sender<(RouteKey, ReadHalfBox)>,
         default_interface: Option<LocalInterface>,
         init_codec: Arc<Box<dyn InitCodec>>,
+        transport: Arc<dyn TcpTransport>,
+        proxy: Option<ProxyConfig>,
+        dscp: Option<u8>,
+        #[cfg(feature = "sim")] sim_host: Option<SimHost>,
     ) -> Self {
         Self {
             local_addr,
@@ -369,11 +524,26 @@ impl SocketLayer {
             connect_sender,
             default_interface,
             init_codec,
+            transport,
+            proxy,
+            dscp,
+            #[cfg(feature = "sim")]
+            sim_host,
+            close: CancellationToken::new(),
         }
     }
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
+    /// Completes once the `TcpPipe` has been dropped
+    pub(crate) async fn closed(&self) {
+        self.close.cancelled().await
+    }
+    /// Closes the connections whose local address `gone` picks, such as the ones of an interface that went away.
+    /// Returns the route keys of the closed connections
+    pub fn close_local_ips(&self, gone: impl Fn(&IpAddr) -> bool) -> Vec<RouteKey> {
+        self.write_half_collect.close_local_ips(gone)
+    }
 }
 
 impl SocketLayer {
@@ -427,6 +597,19 @@ impl SocketLayer {
         &self,
         addr: SocketAddr,
     ) -> crate::error::Result<TcpStream> {
+        if self.proxy.is_some() {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the bound port is not reachable through a proxy",
+            ))?
+        }
+        #[cfg(feature = "sim")]
+        if self.sim_host.is_some() {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the simulated network does not reuse the bound port",
+            ))?
+        }
         let stream = connect_tcp(
             addr,
             self.local_addr.port(),
@@ -436,6 +619,20 @@ impl SocketLayer {
         .await?;
         Ok(stream)
     }
+    /// Initiate a connection from the bound port at the same moment as the peer does.
+    /// The SYNs of both sides cross in the nats (TCP simultaneous open),
+    /// which can penetrate the restricted cone network types.
+    pub async fn connect_simultaneous(&self, addr: SocketAddr) -> crate::error::Result<RouteKey> {
+        if let Some(route_key) = self.write_half_collect.get_one_route_key(&addr) {
+            return Ok(route_key);
+        }
+        let stream = self.connect_reuse_port_raw(addr).await?;
+        let (route_key, local_ip) = self.prepare_stream(&stream)?;
+        let connection =
+            handshake_timeout("tcp", self.transport.connect_simultaneous(stream, addr)).await?;
+        self.add_connection(route_key, connection, addr, 0, Some(local_ip))
+            .await
+    }
     async fn connect0(
         &self,
         bind_port: u16,
@@ -443,13 +640,79 @@ impl SocketLayer {
         index_offset: usize,
         ttl: Option<u32>,
     ) -> crate::error::Result<RouteKey> {
-        let stream = connect_tcp(addr, bind_port, self.default_interface.as_ref(), ttl).await?;
+        #[cfg(feature = "sim")]
+        if let Some(sim_host) = &self.sim_host {
+            let stream = sim_host.connect(addr).await?;
+            return self.add_sim_stream(stream, addr, index_offset).await;
+        }
+        let (stream, route_key, local_ip) = if let Some(proxy) = &self.proxy {
+            let stream = proxy.connect(addr, self.default_interface.as_ref()).await?;
+            // The peer of the stream is the proxy, the route is keyed by the target
+            let (route_key, local_ip) = self.prepare_stream(&stream)?;
+            (stream, RouteKey::new(route_key.index(), addr), local_ip)
+        } else {
+            let stream = connect_tcp(addr, bind_port, self.default_interface.as_ref(), ttl).await?;
+            let (route_key, local_ip) = self.prepare_stream(&stream)?;
+            (stream, route_key, local_ip)
+        };
+        let connection = handshake_timeout("tcp", self.transport.connect(stream, addr)).await?;
+        self.add_connection(route_key, connection, addr, index_offset, Some(local_ip))
+            .await
+    }
+    async fn accept_stream(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> crate::error::Result<RouteKey> {
+        stream.set_nodelay(true)?;
+        let (route_key, local_ip) = self.prepare_stream(&stream)?;
+        let connection = handshake_timeout("tcp", self.transport.accept(stream, addr)).await?;
+        self.add_connection(route_key, connection, addr, 0, Some(local_ip))
+            .await
+    }
+    /// The streams of the simulated network are not wrapped by the transport
+    #[cfg(feature = "sim")]
+    async fn add_sim_stream(
+        &self,
+        stream: SimStream,
+        addr: SocketAddr,
+        index_offset: usize,
+    ) -> crate::error::Result<RouteKey> {
+        let route_key = RouteKey::new(Index::Tcp(stream.index()), addr);
+        let (read, write) = stream.split();
+        let connection = TcpConnection::new(Box::new(read), Box::new(write));
+        self.add_connection(route_key, connection, addr, index_offset, None)
+            .await
+    }
+    /// The route key and the local address are taken from the raw stream, before the transport wraps it
+    fn prepare_stream(&self, stream: &TcpStream) -> crate::error::Result<(RouteKey, IpAddr)> {
         let route_key = stream.route_key()?;
-        let (read_half, write_half) = stream.into_split();
+        let local_ip = stream.local_addr()?.ip();
+        if let Some(dscp) = self.dscp {
+            let v4 = route_key.addr().is_ipv4();
+            if let Err(e) = set_dscp(&socket2::SockRef::from(stream), v4, dscp) {
+                log::warn!("set dscp {route_key:?} {e:?}");
+            }
+        }
+        Ok((route_key, local_ip))
+    }
+    async fn add_connection(
+        &self,
+        route_key: RouteKey,
+        connection: TcpConnection,
+        addr: SocketAddr,
+        index_offset: usize,
+        local_ip: Option<IpAddr>,
+    ) -> crate::error::Result<RouteKey> {
         let (decoder, encoder) = self.init_codec.codec(addr)?;
-        let read_half = ReadHalfBox::new(read_half, decoder);
-        self.write_half_collect
-            .add_write_half(route_key, index_offset, write_half, encoder);
+        let read_half = ReadHalfBox::new(connection.read, decoder, connection.peer_fingerprint);
+        self.write_half_collect.add_write_half(
+            route_key,
+            index_offset,
+            local_ip,
+            connection.write,
+            encoder,
+        );
         if let Err(_e) = self.connect_sender.send((route_key, read_half)).await {
             Err(crate::error::Error::Eof)?
         }
@@ -496,6 +759,17 @@ impl TcpPipeWriter {
     pub async fn send_to(&self, buf: BytesMut, route_key: &RouteKey) -> crate::error::Result<()> {
         self.write_half_collect.send_to(buf, route_key).await
     }
+    /// Writing `buf` to the target denoted by `route_key` with the priority class
+    pub async fn send_to_priority(
+        &self,
+        buf: BytesMut,
+        route_key: &RouteKey,
+        priority: Priority,
+    ) -> crate::error::Result<()> {
+        self.write_half_collect
+            .send_to_priority(buf, route_key, priority)
+            .await
+    }
     pub async fn get(
         &self,
         addr: SocketAddr,
@@ -556,13 +830,20 @@ impl<'a> Deref for TcpPipeWriterRef<'a> {
 }
 
 pub struct TcpPipeWriterIndex<'a> {
-    shadow: Sender<BytesMut>,
+    shadow: PrioritySender<BytesMut>,
     marker: PhantomData<&'a ()>,
 }
 
 impl<'a> TcpPipeWriterIndex<'a> {
     pub async fn send(&self, buf: BytesMut) -> crate::error::Result<()> {
-        if let Err(_e) = self.shadow.send(buf).await {
+        self.send_priority(buf, Priority::default()).await
+    }
+    pub async fn send_priority(
+        &self,
+        buf: BytesMut,
+        priority: Priority,
+    ) -> crate::error::Result<()> {
+        if let Err(_e) = self.shadow.send(buf, priority).await {
             Err(io::Error::from(io::ErrorKind::WriteZero))?
         } else {
             Ok(())
@@ -603,7 +884,7 @@ pub struct LengthPrefixedCodec;
 
 #[async_trait]
 impl Decoder for BytesCodec {
-    async fn decode(&mut self, read: &mut OwnedReadHalf, src: &mut [u8]) -> io::Result<usize> {
+    async fn decode(&mut self, read: &mut TcpReadHalf, src: &mut [u8]) -> io::Result<usize> {
         let len = read.read(src).await?;
         Ok(len)
     }
@@ -611,7 +892,7 @@ impl Decoder for BytesCodec {
 
 #[async_trait]
 impl Encoder for BytesCodec {
-    async fn encode(&mut self, write: &mut OwnedWriteHalf, data: &[u8]) -> io::Result<()> {
+    async fn encode(&mut self, write: &mut TcpWriteHalf, data: &[u8]) -> io::Result<()> {
         write.write_all(data).await?;
         Ok(())
     }
@@ -619,7 +900,7 @@ impl Encoder for BytesCodec {
 
 #[async_trait]
 impl Decoder for LengthPrefixedCodec {
-    async fn decode(&mut self, read: &mut OwnedReadHalf, src: &mut [u8]) -> io::Result<usize> {
+    async fn decode(&mut self, read: &mut TcpReadHalf, src: &mut [u8]) -> io::Result<usize> {
         let mut head = [0; 4];
         read.read_exact(&mut head).await?;
         let len = u32::from_be_bytes(head) as usize;
@@ -630,7 +911,7 @@ impl Decoder for LengthPrefixedCodec {
 
 #[async_trait]
 impl Encoder for LengthPrefixedCodec {
-    async fn encode(&mut self, write: &mut OwnedWriteHalf, data: &[u8]) -> io::Result<()> {
+    async fn encode(&mut self, write: &mut TcpWriteHalf, data: &[u8]) -> io::Result<()> {
         let head: [u8; 4] = (data.len() as u32).to_be_bytes();
         write.write_all(&head).await?;
         write.write_all(data).await?;
@@ -663,15 +944,15 @@ dyn_clone::clone_trait_object!(InitCodec);
 
 #[async_trait]
 pub trait Decoder: Send + Sync {
-    async fn decode(&mut self, read: &mut OwnedReadHalf, src: &mut [u8]) -> io::Result<usize>;
+    async fn decode(&mut self, read: &mut TcpReadHalf, src: &mut [u8]) -> io::Result<usize>;
 }
 
 #[async_trait]
 pub trait Encoder: Send + Sync {
-    async fn encode(&mut self, write: &mut OwnedWriteHalf, data: &[u8]) -> io::Result<()>;
+    async fn encode(&mut self, write: &mut TcpWriteHalf, data: &[u8]) -> io::Result<()>;
     async fn encode_multiple(
         &mut self,
-        write: &mut OwnedWriteHalf,
+        write: &mut TcpWriteHalf,
         bufs: &[IoSlice<'_>],
     ) -> io::Result<()> {
         for buf in bufs {
@@ -681,16 +962,69 @@ pub trait Encoder: Send + Sync {
     }
 }
 
+/// The read half of a `TCP` connection, as the [`TcpTransport`] wraps it
+pub type TcpReadHalf = Box<dyn AsyncRead + Send + Sync + Unpin>;
+/// The write half of a `TCP` connection, as the [`TcpTransport`] wraps it
+pub type TcpWriteHalf = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+pub struct TcpConnection {
+    pub read: TcpReadHalf,
+    pub write: TcpWriteHalf,
+    /// The SHA-256 fingerprint of the peer certificate, if the transport authenticates the peer
+    pub peer_fingerprint: Option<[u8; 32]>,
+}
+
+impl TcpConnection {
+    pub fn new(read: TcpReadHalf, write: TcpWriteHalf) -> Self {
+        Self {
+            read,
+            write,
+            peer_fingerprint: None,
+        }
+    }
+}
+
+/// Wraps the accepted and the dialled streams before the codec frames them, such as TLS
+#[async_trait]
+pub trait TcpTransport: Send + Sync {
+    async fn accept(&self, stream: TcpStream, addr: SocketAddr) -> io::Result<TcpConnection>;
+    async fn connect(&self, stream: TcpStream, addr: SocketAddr) -> io::Result<TcpConnection>;
+    /// Both sides dial in the TCP simultaneous open
+    async fn connect_simultaneous(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> io::Result<TcpConnection> {
+        self.connect(stream, addr).await
+    }
+}
+
+/// The streams are used as they are
+#[derive(Clone, Default)]
+pub struct PlainTcpTransport;
+
+#[async_trait]
+impl TcpTransport for PlainTcpTransport {
+    async fn accept(&self, stream: TcpStream, _addr: SocketAddr) -> io::Result<TcpConnection> {
+        let (read, write) = stream.into_split();
+        Ok(TcpConnection::new(Box::new(read), Box::new(write)))
+    }
+    async fn connect(&self, stream: TcpStream, _addr: SocketAddr) -> io::Result<TcpConnection> {
+        let (read, write) = stream.into_split();
+        Ok(TcpConnection::new(Box::new(read), Box::new(write)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use async_trait::async_trait;
     use std::io;
     use std::net::SocketAddr;
+    use std::time::Duration;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
     use crate::pipe::config::TcpPipeConfig;
-    use crate::pipe::tcp_pipe::{Decoder, Encoder, InitCodec, TcpPipe};
+    use crate::pipe::tcp_pipe::{Decoder, Encoder, InitCodec, TcpPipe, TcpReadHalf, TcpWriteHalf};
 
     #[tokio::test]
     pub async fn create_tcp_pipe() {
@@ -706,6 +1040,70 @@ mod tests {
         drop(tcp_pipe)
     }
 
+    #[tokio::test]
+    pub async fn close_local_ips() {
+        let mut a = TcpPipe::new(TcpPipeConfig::default()).unwrap();
+        let mut b = TcpPipe::new(TcpPipeConfig::default()).unwrap();
+        let b_port = b.writer_ref().local_addr().port();
+        let route_key = a
+            .writer_ref()
+            .connect(SocketAddr::from(([127, 0, 0, 1], b_port)))
+            .await
+            .unwrap();
+        let mut a_line = a.accept().await.unwrap();
+        let _b_line = b.accept().await.unwrap();
+        // Another address went away
+        assert!(a
+            .writer_ref()
+            .close_local_ips(|ip| !ip.is_loopback())
+            .is_empty());
+        assert_eq!(
+            a.writer_ref().close_local_ips(|ip| ip.is_loopback()),
+            vec![route_key]
+        );
+        let mut buf = [0; 64];
+        let rs = tokio::time::timeout(Duration::from_secs(1), a_line.recv_from(&mut buf))
+            .await
+            .unwrap();
+        assert_eq!(rs.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    /// Both sides dial from their bound ports at once and end up connected to each other.
+    /// A SYN that reaches the listener first is accepted, the crossing dial then fails or finds the route
+    #[tokio::test]
+    pub async fn connect_simultaneous() {
+        let mut a = TcpPipe::new(TcpPipeConfig::default()).unwrap();
+        let mut b = TcpPipe::new(TcpPipeConfig::default()).unwrap();
+        let a_addr = SocketAddr::from(([127, 0, 0, 1], a.writer_ref().local_addr().port()));
+        let b_addr = SocketAddr::from(([127, 0, 0, 1], b.writer_ref().local_addr().port()));
+        let (a_writer, b_writer) = (a.writer_ref().to_owned(), b.writer_ref().to_owned());
+        let (a_rs, b_rs) = tokio::join!(
+            a_writer.connect_simultaneous(b_addr),
+            b_writer.connect_simultaneous(a_addr)
+        );
+        assert!(a_rs.is_ok() || b_rs.is_ok(), "{a_rs:?} {b_rs:?}");
+        let mut a_line = tokio::time::timeout(Duration::from_secs(1), a.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut b_line = tokio::time::timeout(Duration::from_secs(1), b.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(a_line.route_key().addr().port(), b_addr.port());
+        assert_eq!(b_line.route_key().addr().port(), a_addr.port());
+        a_line.send(b"a"[..].into()).await.unwrap();
+        b_line.send(b"b"[..].into()).await.unwrap();
+        let mut buf = [0; 64];
+        for (line, data) in [(&mut a_line, b"b"), (&mut b_line, b"a")] {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(1), line.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..len], data);
+        }
+    }
+
     #[derive(Clone)]
     struct MyInitCodeC;
 
@@ -719,7 +1117,7 @@ mod tests {
 
     #[async_trait]
     impl Decoder for MyCodeC {
-        async fn decode(&mut self, read: &mut OwnedReadHalf, src: &mut [u8]) -> io::Result<usize> {
+        async fn decode(&mut self, read: &mut TcpReadHalf, src: &mut [u8]) -> io::Result<usize> {
             let mut head = [0; 2];
             read.read_exact(&mut head).await?;
             let len = u16::from_be_bytes(head) as usize;
@@ -730,7 +1128,7 @@ mod tests {
 
     #[async_trait]
     impl Encoder for MyCodeC {
-        async fn encode(&mut self, write: &mut OwnedWriteHalf, data: &[u8]) -> io::Result<()> {
+        async fn encode(&mut self, write: &mut TcpWriteHalf, data: &[u8]) -> io::Result<()> {
             let head: [u8; 2] = (data.len() as u16).to_be_bytes();
             write.write_all(&head).await?;
             write.write_all(data).await?;
@@ -0,0 +1,371 @@
+/*
+  TLS transport of the TCP pipe
+
+  Both sides present a certificate and the peer certificate is pinned by its SHA-256 fingerprint
+  instead of a certificate authority. With the SNI and ALPN of a web server and port 443,
+  the TCP pipelines look like ordinary HTTPS to the middleboxes.
+*/
+
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::{PrivatePkcs8KeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::pipe::tcp_pipe::{TcpConnection, TcpTransport};
+
+pub use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// The protocols a web server offers
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// The SHA-256 fingerprint that pins a certificate
+pub fn fingerprint(cert: &CertificateDer<'_>) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    let mut fingerprint = [0; 32];
+    fingerprint.copy_from_slice(digest.as_ref());
+    fingerprint
+}
+
+/// Generate a self-signed certificate for `name`, the peers pin it by its [`fingerprint`]
+pub fn self_signed(
+    name: &str,
+) -> anyhow::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let cert = rcgen::generate_simple_self_signed(vec![name.to_string()])?;
+    let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    Ok((cert.cert.der().clone(), key.into()))
+}
+
+/// Wraps the `TCP` streams in TLS 1.3 with a certificate on both sides
+pub struct TlsTransport {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl TlsTransport {
+    /// `pinned` are the fingerprints of the accepted peer certificates,
+    /// no peer is accepted if it is empty.
+    /// `server_name` is sent in the SNI and is not verified
+    pub fn new(
+        cert_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+        pinned: Vec<[u8; 32]>,
+        server_name: &str,
+    ) -> anyhow::Result<Self> {
+        Self::build(
+            cert_chain,
+            private_key,
+            Some(pinned.into_iter().collect()),
+            server_name,
+        )
+    }
+    /// Accepts any certificate, **the peers are not authenticated** and TLS only encrypts
+    pub fn unpinned(
+        cert_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+        server_name: &str,
+    ) -> anyhow::Result<Self> {
+        Self::build(cert_chain, private_key, None, server_name)
+    }
+    fn build(
+        cert_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+        pinned: Option<HashSet<[u8; 32]>>,
+        server_name: &str,
+    ) -> anyhow::Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = Arc::new(PinnedVerifier {
+            provider: provider.clone(),
+            pinned,
+        });
+        let alpn_protocols: Vec<Vec<u8>> = ALPN_PROTOCOLS.iter().map(|v| v.to_vec()).collect();
+        let mut server_config = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(verifier.clone())
+            .with_single_cert(cert_chain.clone(), private_key.clone_key())?;
+        server_config.alpn_protocols.clone_from(&alpn_protocols);
+        let mut client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_client_auth_cert(cert_chain, private_key)?;
+        client_config.alpn_protocols = alpn_protocols;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name: ServerName::try_from(server_name.to_string())?,
+        })
+    }
+}
+
+#[async_trait]
+impl TcpTransport for TlsTransport {
+    async fn accept(&self, stream: TcpStream, _addr: SocketAddr) -> io::Result<TcpConnection> {
+        let stream = self.acceptor.accept(stream).await?;
+        let peer_fingerprint = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|v| v.first())
+            .map(fingerprint);
+        let (read, write) = tokio::io::split(stream);
+        let mut connection = TcpConnection::new(Box::new(read), Box::new(write));
+        connection.peer_fingerprint = peer_fingerprint;
+        Ok(connection)
+    }
+    async fn connect(&self, stream: TcpStream, _addr: SocketAddr) -> io::Result<TcpConnection> {
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        let peer_fingerprint = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|v| v.first())
+            .map(fingerprint);
+        let (read, write) = tokio::io::split(stream);
+        let mut connection = TcpConnection::new(Box::new(read), Box::new(write));
+        connection.peer_fingerprint = peer_fingerprint;
+        Ok(connection)
+    }
+    /// Both sides would act as the client
+    async fn connect_simultaneous(
+        &self,
+        _stream: TcpStream,
+        _addr: SocketAddr,
+    ) -> io::Result<TcpConnection> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS does not support TCP simultaneous open",
+        ))
+    }
+}
+
+/// Accepts the pinned certificates, or any certificate if `pinned` is `None`.
+/// The handshake signature is checked
+#[derive(Debug)]
+struct PinnedVerifier {
+    provider: Arc<CryptoProvider>,
+    pinned: Option<HashSet<[u8; 32]>>,
+}
+
+impl PinnedVerifier {
+    fn verify(&self, end_entity: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        let accepted = match &self.pinned {
+            Some(pinned) => pinned.contains(&fingerprint(end_entity)),
+            None => true,
+        };
+        if accepted {
+            Ok(())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+    fn verify_tls13(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::PeerIncompatible(
+            rustls::PeerIncompatible::Tls12NotOffered,
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_tls13(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for PinnedVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::PeerIncompatible(
+            rustls::PeerIncompatible::Tls12NotOffered,
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_tls13(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::pipe::config::TcpPipeConfig;
+    use crate::pipe::tcp_pipe::{LengthPrefixedInitCodec, TcpPipe};
+    use crate::pipe::tls::{fingerprint, self_signed, TlsTransport};
+    use crate::pipe::tls::{CertificateDer, PrivateKeyDer};
+
+    fn tls_pipe(
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+        pin: [u8; 32],
+    ) -> TcpPipe {
+        let transport = TlsTransport::new(vec![cert], key, vec![pin], "example.com").unwrap();
+        let config = TcpPipeConfig::new(Box::new(LengthPrefixedInitCodec))
+            .set_use_v6(false)
+            .set_transport(Arc::new(transport));
+        TcpPipe::new(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tls_pinned_send_recv() {
+        let (a_cert, a_key) = self_signed("a").unwrap();
+        let (b_cert, b_key) = self_signed("b").unwrap();
+        let (a_fingerprint, b_fingerprint) = (fingerprint(&a_cert), fingerprint(&b_cert));
+        let mut a = tls_pipe(a_cert, a_key, b_fingerprint);
+        let mut b = tls_pipe(b_cert, b_key, a_fingerprint);
+        let b_addr = format!("127.0.0.1:{}", b.writer_ref().local_addr().port());
+        // The handshake is served while accepting
+        let b_accept = tokio::spawn(async move { b.accept().await.unwrap() });
+        let route_key = a
+            .writer_ref()
+            .connect(b_addr.parse().unwrap())
+            .await
+            .unwrap();
+        let a_line = a.accept().await.unwrap();
+        assert_eq!(a_line.peer_fingerprint(), Some(b_fingerprint));
+        a.writer_ref()
+            .to_owned()
+            .send_to(b"hello"[..].into(), &route_key)
+            .await
+            .unwrap();
+        let mut b_line = b_accept.await.unwrap();
+        assert_eq!(b_line.peer_fingerprint(), Some(a_fingerprint));
+        let mut buf = [0; 64];
+        let (len, _) = b_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+    }
+
+    #[tokio::test]
+    async fn tls_unpinned_rejected() {
+        let (a_cert, a_key) = self_signed("a").unwrap();
+        let (b_cert, b_key) = self_signed("b").unwrap();
+        let a = tls_pipe(a_cert, a_key, [0; 32]);
+        let mut b = tls_pipe(b_cert, b_key, [0; 32]);
+        let b_addr = format!("127.0.0.1:{}", b.writer_ref().local_addr().port());
+        tokio::spawn(async move { b.accept().await });
+        let rs = a.writer_ref().connect(b_addr.parse().unwrap()).await;
+        assert!(rs.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_empty_pins_rejected() {
+        let (a_cert, a_key) = self_signed("a").unwrap();
+        let (b_cert, b_key) = self_signed("b").unwrap();
+        let a = TlsTransport::new(
+            vec![a_cert.clone()],
+            a_key.clone_key(),
+            vec![],
+            "example.com",
+        )
+        .unwrap();
+        let config = TcpPipeConfig::new(Box::new(LengthPrefixedInitCodec))
+            .set_use_v6(false)
+            .set_transport(Arc::new(a));
+        let a = TcpPipe::new(config).unwrap();
+        let mut b = tls_pipe(b_cert.clone(), b_key.clone_key(), fingerprint(&a_cert));
+        let b_addr = format!("127.0.0.1:{}", b.writer_ref().local_addr().port());
+        tokio::spawn(async move { while b.accept().await.is_ok() {} });
+        let rs = a.writer_ref().connect(b_addr.parse().unwrap()).await;
+        assert!(rs.is_err());
+
+        // Opted in explicitly
+        let a = TlsTransport::unpinned(vec![a_cert.clone()], a_key, "example.com").unwrap();
+        let config = TcpPipeConfig::new(Box::new(LengthPrefixedInitCodec))
+            .set_use_v6(false)
+            .set_transport(Arc::new(a));
+        let a = TcpPipe::new(config).unwrap();
+        let mut b = tls_pipe(b_cert, b_key, fingerprint(&a_cert));
+        let b_addr = format!("127.0.0.1:{}", b.writer_ref().local_addr().port());
+        tokio::spawn(async move { while b.accept().await.is_ok() {} });
+        let rs = a.writer_ref().connect(b_addr.parse().unwrap()).await;
+        assert!(rs.is_ok());
+    }
+}
@@ -2,6 +2,8 @@ use std::io;
 use std::io::IoSlice;
 use std::net::SocketAddr;
 use std::ops::Deref;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use anyhow::{anyhow, Context};
@@ -9,15 +11,23 @@ use bytes::BytesMut;
 use dashmap::DashMap;
 use parking_lot::{Mutex, RwLock};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc::Sender;
 
 use crate::pipe::config::UdpPipeConfig;
+use crate::pipe::priority::{priority_channel, Priority, PrioritySender};
 use crate::pipe::recycle::RecycleBuf;
 use crate::pipe::{DEFAULT_ADDRESS_V4, DEFAULT_ADDRESS_V6};
 use crate::route::{Index, RouteKey};
-use crate::socket::{bind_udp, LocalInterface};
+#[cfg(feature = "sim")]
+use crate::sim::{SimHost, SimUdpSocket};
+use crate::socket::{bind_udp, set_dont_fragment, set_dscp, LocalInterface, VntSocketTrait};
 #[cfg(target_os = "linux")]
 const MAX_MESSAGES: usize = 16;
+/// The most bytes of one `UDP_SEGMENT` send
+#[cfg(target_os = "linux")]
+const MAX_GSO_LEN: usize = 64000;
+/// The most bytes `UDP_GRO` coalesces
+#[cfg(target_os = "linux")]
+const GRO_BUF_LEN: usize = u16::MAX as usize;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub enum Model {
@@ -51,19 +61,86 @@ impl UDPIndex {
     }
 }
 
+/// A socket of the OS, or of the simulated network.
+/// `sendmmsg`, `recvmmsg` and the offloads are only used with the sockets of the OS
+#[derive(Debug)]
+pub(crate) enum PipeUdpSocket {
+    Os(UdpSocket),
+    #[cfg(feature = "sim")]
+    Sim(SimUdpSocket),
+}
+
+impl PipeUdpSocket {
+    pub(crate) fn os(&self) -> Option<&UdpSocket> {
+        match self {
+            PipeUdpSocket::Os(udp) => Some(udp),
+            #[cfg(feature = "sim")]
+            PipeUdpSocket::Sim(_) => None,
+        }
+    }
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PipeUdpSocket::Os(udp) => udp.local_addr(),
+            #[cfg(feature = "sim")]
+            PipeUdpSocket::Sim(udp) => Ok(udp.local_addr()),
+        }
+    }
+    pub(crate) async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            PipeUdpSocket::Os(udp) => udp.send_to(buf, addr).await,
+            #[cfg(feature = "sim")]
+            PipeUdpSocket::Sim(udp) => udp.send_to(buf, addr).await,
+        }
+    }
+    pub(crate) fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            PipeUdpSocket::Os(udp) => udp.try_send_to(buf, addr),
+            #[cfg(feature = "sim")]
+            PipeUdpSocket::Sim(udp) => udp.try_send_to(buf, addr),
+        }
+    }
+    pub(crate) async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            PipeUdpSocket::Os(udp) => udp.recv_from(buf).await,
+            #[cfg(feature = "sim")]
+            PipeUdpSocket::Sim(udp) => udp.recv_from(buf).await,
+        }
+    }
+    /// The simulated network never blocks a send
+    #[cfg(feature = "quic")]
+    pub(crate) fn poll_send_ready(
+        &self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        match self {
+            PipeUdpSocket::Os(udp) => udp.poll_send_ready(cx),
+            #[cfg(feature = "sim")]
+            PipeUdpSocket::Sim(_) => std::task::Poll::Ready(Ok(())),
+        }
+    }
+}
+
 /// initialize udp pipe by config
 pub(crate) fn udp_pipe(config: UdpPipeConfig) -> anyhow::Result<UdpPipe> {
     config.check()?;
     let mut udp_ports = config.udp_ports;
     udp_ports.resize(config.main_pipeline_num, 0);
-    let mut main_udp_v4: Vec<Arc<UdpSocket>> = Vec::with_capacity(config.main_pipeline_num);
-    let mut main_udp_v6: Vec<Arc<UdpSocket>> = Vec::with_capacity(config.main_pipeline_num);
+    let mut main_udp_v4: Vec<Arc<PipeUdpSocket>> = Vec::with_capacity(config.main_pipeline_num);
+    let mut main_udp_v6: Vec<Arc<PipeUdpSocket>> = Vec::with_capacity(config.main_pipeline_num);
     // 因为在mac上v4和v6的对绑定网卡的处理不同，所以这里分开监听，并且分开监听更容易处理发送目标为v4的情况，因为双协议栈下发送v4目标需要转换成v6
     for port in &udp_ports {
+        #[cfg(feature = "sim")]
+        if let Some(sim_host) = &config.sim_host {
+            main_udp_v4.push(Arc::new(PipeUdpSocket::Sim(sim_host.bind_udp(*port)?)));
+            continue;
+        }
         loop {
             let mut addr_v4 = DEFAULT_ADDRESS_V4;
             addr_v4.set_port(*port);
             let socket_v4 = bind_udp(addr_v4, config.default_interface.as_ref())?;
+            if let Some(dscp) = config.dscp {
+                set_dscp(&socket_v4, true, dscp)?;
+            }
             let udp_v4: std::net::UdpSocket = socket_v4.into();
             if config.use_v6 {
                 let mut addr_v6 = DEFAULT_ADDRESS_V6;
@@ -78,13 +155,18 @@ pub(crate) fn udp_pipe(config: UdpPipeConfig) -> anyhow::Result<UdpPipe> {
                     addr_v6.set_port(*port);
                     bind_udp(addr_v6, config.default_interface.as_ref())?
                 };
+                if let Some(dscp) = config.dscp {
+                    set_dscp(&socket_v6, false, dscp)?;
+                }
                 let udp_v6: std::net::UdpSocket = socket_v6.into();
-                main_udp_v6.push(Arc::new(UdpSocket::from_std(udp_v6)?))
+                main_udp_v6.push(Arc::new(PipeUdpSocket::Os(UdpSocket::from_std(udp_v6)?)))
             }
-            main_udp_v4.push(Arc::new(UdpSocket::from_std(udp_v4)?));
+            main_udp_v4.push(Arc::new(PipeUdpSocket::Os(UdpSocket::from_std(udp_v4)?)));
             break;
         }
     }
+    #[cfg(target_os = "linux")]
+    let gso = config.offload && main_udp_v4[0].os().is_some_and(supports_gso);
     let (pipe_line_sender, pipe_line_receiver) = tokio::sync::mpsc::unbounded_channel();
     let socket_layer = Arc::new(SocketLayer {
         main_udp_v4,
@@ -94,7 +176,17 @@ pub(crate) fn udp_pipe(config: UdpPipeConfig) -> anyhow::Result<UdpPipe> {
         pipe_line_sender,
         sub_udp_num: config.sub_pipeline_num,
         default_interface: config.default_interface,
+        dscp: config.dscp,
+        #[cfg(feature = "sim")]
+        sim_host: config.sim_host,
         sender_map: Default::default(),
+        probe_sender_map: Default::default(),
+        #[cfg(feature = "quic")]
+        quic_sender_map: Default::default(),
+        #[cfg(target_os = "linux")]
+        offload: config.offload,
+        #[cfg(target_os = "linux")]
+        gso: AtomicBool::new(gso),
     });
     let udp_pipe = UdpPipe {
         pipe_line_receiver,
@@ -107,14 +199,27 @@ pub(crate) fn udp_pipe(config: UdpPipeConfig) -> anyhow::Result<UdpPipe> {
 }
 
 pub struct SocketLayer {
-    main_udp_v4: Vec<Arc<UdpSocket>>,
-    main_udp_v6: Vec<Arc<UdpSocket>>,
-    sub_udp: RwLock<Vec<Arc<UdpSocket>>>,
+    main_udp_v4: Vec<Arc<PipeUdpSocket>>,
+    main_udp_v6: Vec<Arc<PipeUdpSocket>>,
+    sub_udp: RwLock<Vec<Arc<PipeUdpSocket>>>,
     sub_close_notify: Mutex<Option<tokio::sync::broadcast::Sender<()>>>,
     pipe_line_sender: tokio::sync::mpsc::UnboundedSender<UdpPipeLine>,
     sub_udp_num: usize,
     default_interface: Option<LocalInterface>,
-    sender_map: DashMap<Index, Sender<(BytesMut, SocketAddr)>>,
+    dscp: Option<u8>,
+    #[cfg(feature = "sim")]
+    sim_host: Option<SimHost>,
+    sender_map: DashMap<Index, PrioritySender<(BytesMut, SocketAddr)>>,
+    /// The path MTU probes, sent by the same task as the data so that only they carry DF
+    probe_sender_map: DashMap<Index, tokio::sync::mpsc::Sender<(BytesMut, SocketAddr)>>,
+    /// The `QUIC` packets received by the main sockets go to their endpoints
+    #[cfg(feature = "quic")]
+    quic_sender_map: DashMap<Index, tokio::sync::mpsc::Sender<(BytesMut, SocketAddr)>>,
+    #[cfg(target_os = "linux")]
+    offload: bool,
+    /// Cleared when the kernel or the NIC rejects `UDP_SEGMENT`
+    #[cfg(target_os = "linux")]
+    gso: AtomicBool,
 }
 
 impl SocketLayer {
@@ -186,9 +291,17 @@ impl SocketLayer {
             tokio::sync::broadcast::channel(2);
         let mut sub_udp_list = Vec::with_capacity(self.sub_udp_num);
         for _ in 0..self.sub_udp_num {
+            #[cfg(feature = "sim")]
+            if let Some(sim_host) = &self.sim_host {
+                sub_udp_list.push(Arc::new(PipeUdpSocket::Sim(sim_host.bind_udp(0)?)));
+                continue;
+            }
             let udp = bind_udp(DEFAULT_ADDRESS_V4, self.default_interface.as_ref())?;
+            if let Some(dscp) = self.dscp {
+                set_dscp(&udp, true, dscp)?;
+            }
             let udp: std::net::UdpSocket = udp.into();
-            sub_udp_list.push(Arc::new(UdpSocket::from_std(udp)?));
+            sub_udp_list.push(Arc::new(PipeUdpSocket::Os(UdpSocket::from_std(udp)?)));
         }
         for (index, udp) in sub_udp_list.iter().enumerate() {
             let udp = udp.clone();
@@ -204,9 +317,32 @@ impl SocketLayer {
         Ok(())
     }
 
+    #[cfg(feature = "quic")]
+    pub(crate) fn main_udp(&self) -> Vec<(UDPIndex, Arc<PipeUdpSocket>)> {
+        let v4 = self
+            .main_udp_v4
+            .iter()
+            .enumerate()
+            .map(|(index, udp)| (UDPIndex::MainV4(index), udp.clone()));
+        let v6 = self
+            .main_udp_v6
+            .iter()
+            .enumerate()
+            .map(|(index, udp)| (UDPIndex::MainV6(index), udp.clone()));
+        v4.chain(v6).collect()
+    }
+    #[cfg(feature = "quic")]
+    pub(crate) fn set_quic_sender(
+        &self,
+        index: UDPIndex,
+        sender: tokio::sync::mpsc::Sender<(BytesMut, SocketAddr)>,
+    ) {
+        self.quic_sender_map.insert(Index::Udp(index), sender);
+    }
+
     /// Acquire the underlying `UDP` socket by the index
     #[inline]
-    fn get_sub_udp(&self, index: usize) -> crate::error::Result<Arc<UdpSocket>> {
+    fn get_sub_udp(&self, index: usize) -> crate::error::Result<Arc<PipeUdpSocket>> {
         let guard = self.sub_udp.read();
         let len = guard.len();
         if len <= index {
@@ -216,7 +352,7 @@ impl SocketLayer {
         }
     }
     #[inline]
-    fn get_udp(&self, udp_index: UDPIndex) -> crate::error::Result<Arc<UdpSocket>> {
+    fn get_udp(&self, udp_index: UDPIndex) -> crate::error::Result<Arc<PipeUdpSocket>> {
         Ok(match udp_index {
             UDPIndex::MainV4(index) => self
                 .main_udp_v4
@@ -239,7 +375,7 @@ impl SocketLayer {
     }
 
     #[inline]
-    fn get_udp_from_route(&self, route_key: &RouteKey) -> crate::error::Result<Arc<UdpSocket>> {
+    fn get_udp_from_route(&self, route_key: &RouteKey) -> crate::error::Result<Arc<PipeUdpSocket>> {
         Ok(match route_key.index() {
             Index::Udp(index) => self.get_udp(index)?,
             _ => return Err(crate::error::Error::InvalidProtocol),
@@ -308,6 +444,25 @@ impl SocketLayer {
             }
         }
     }
+    /// Binds the `IPv4` and `IPv6` sockets to the default interface again.
+    /// A binding holds the index of the interface, which a removed and re-added interface does not keep
+    pub fn rebind_interface(&self) -> crate::error::Result<()> {
+        let Some(default_interface) = &self.default_interface else {
+            return Ok(());
+        };
+        let sub_udp = self.sub_udp.read().clone();
+        for udp in self
+            .main_udp_v4
+            .iter()
+            .chain(self.main_udp_v6.iter())
+            .chain(sub_udp.iter())
+        {
+            if let Some(udp) = udp.os() {
+                socket2::SockRef::from(udp).set_ip_unicast_if(default_interface)?;
+            }
+        }
+        Ok(())
+    }
     /// Acquire the local ports `UDP` sockets bind on
     pub fn local_ports(&self) -> anyhow::Result<Vec<u16>> {
         let mut ports = Vec::with_capacity(self.v4_pipeline_len());
@@ -320,12 +475,39 @@ impl SocketLayer {
         &self,
         buf: BytesMut,
         route_key: &RouteKey,
+    ) -> crate::error::Result<()> {
+        self.send_buf_to_priority(buf, route_key, Priority::default())
+            .await
+    }
+    /// Queue `buf` for the target denoted by `route_key`, higher priority data is sent first
+    pub async fn send_buf_to_priority(
+        &self,
+        buf: BytesMut,
+        route_key: &RouteKey,
+        priority: Priority,
     ) -> crate::error::Result<()> {
         let sender = if let Some(sender) = self.sender_map.get(&route_key.index()) {
             sender.value().clone()
         } else {
             return Err(crate::error::Error::RouteNotFound("".into()));
         };
+        if let Err(_e) = sender.send((buf, route_key.addr()), priority).await {
+            Err(io::Error::from(io::ErrorKind::WriteZero))?
+        } else {
+            Ok(())
+        }
+    }
+    /// Queue a path MTU probe for the target denoted by `route_key`, DF is set on the probe alone
+    pub async fn send_probe_to(
+        &self,
+        buf: BytesMut,
+        route_key: &RouteKey,
+    ) -> crate::error::Result<()> {
+        let sender = if let Some(sender) = self.probe_sender_map.get(&route_key.index()) {
+            sender.value().clone()
+        } else {
+            return Err(crate::error::Error::RouteNotFound("".into()));
+        };
         if let Err(_e) = sender.send((buf, route_key.addr())).await {
             Err(io::Error::from(io::ErrorKind::WriteZero))?
         } else {
@@ -433,25 +615,29 @@ pub struct UdpPipe {
 impl UdpPipe {
     pub(crate) fn init(&self) -> anyhow::Result<()> {
         for (index, udp) in self.socket_layer.main_udp_v4.iter().enumerate() {
-            let udp = udp.clone();
-            let udp_pipe_line = UdpPipeLine::main_new(
-                UDPIndex::MainV4(index),
-                udp,
-                self.socket_layer.pipe_line_sender.clone(),
-            );
+            let udp_pipe_line = self.main_pipe_line(UDPIndex::MainV4(index), udp);
             self.socket_layer.pipe_line_sender.send(udp_pipe_line)?;
         }
         for (index, udp) in self.socket_layer.main_udp_v6.iter().enumerate() {
-            let udp = udp.clone();
-            let udp_pipe_line = UdpPipeLine::main_new(
-                UDPIndex::MainV6(index),
-                udp,
-                self.socket_layer.pipe_line_sender.clone(),
-            );
+            let udp_pipe_line = self.main_pipe_line(UDPIndex::MainV6(index), udp);
             self.socket_layer.pipe_line_sender.send(udp_pipe_line)?;
         }
         Ok(())
     }
+    /// `UDP_GRO` is only turned on for the main sockets, the sub sockets carry little traffic
+    fn main_pipe_line(&self, index: UDPIndex, udp: &Arc<PipeUdpSocket>) -> UdpPipeLine {
+        #[allow(unused_mut)]
+        let mut udp_pipe_line = UdpPipeLine::main_new(
+            index,
+            udp.clone(),
+            self.socket_layer.pipe_line_sender.clone(),
+        );
+        #[cfg(target_os = "linux")]
+        if self.socket_layer.offload && udp.os().is_some_and(enable_gro) {
+            udp_pipe_line.gro = Some(Box::new(GroBuf::new()));
+        }
+        udp_pipe_line
+    }
 }
 impl UdpPipe {
     /// Construct a `UDP` pipe with the specified configuration
@@ -469,9 +655,11 @@ impl UdpPipe {
         if line.socket_layer.is_some() {
             return Ok(line);
         }
-        let (s, mut r) = tokio::sync::mpsc::channel(32);
+        let (s, mut r) = priority_channel(32);
+        let (probe_s, mut probe_r) = tokio::sync::mpsc::channel(8);
         let index = line.index;
         self.socket_layer.sender_map.insert(index, s);
+        self.socket_layer.probe_sender_map.insert(index, probe_s);
         line.socket_layer.replace(self.socket_layer.clone());
         let socket_layer = self.socket_layer.clone();
         let udp = line.udp.clone().unwrap();
@@ -483,38 +671,54 @@ impl UdpPipe {
             #[cfg(target_os = "linux")]
             use std::os::fd::AsRawFd;
 
-            while let Some((buf, addr)) = r.recv().await {
+            loop {
+                let (buf, addr) = tokio::select! {
+                    biased;
+                    Some((buf, addr)) = probe_r.recv() => {
+                        if let Err(e) = send_probe(&udp, &buf, addr).await {
+                            log::debug!("probe {addr:?},{e:?}")
+                        }
+                        continue;
+                    }
+                    rs = r.recv() => match rs {
+                        Some(v) => v,
+                        None => break,
+                    },
+                };
                 #[cfg(target_os = "linux")]
                 {
                     vec_buf.push((buf, addr));
-                    while let Ok(tup) = r.try_recv() {
+                    while let Some(tup) = r.try_recv() {
                         vec_buf.push(tup);
                         if vec_buf.len() == MAX_MESSAGES {
                             break;
                         }
                     }
-                    if vec_buf.len() == 1 {
-                        let (buf, addr) = unsafe { vec_buf.get_unchecked(0) };
-                        if let Err(e) = udp.send_to(buf, addr).await {
-                            log::debug!("{addr:?},{e:?}")
-                        }
-                    } else if let Err(e) = loop {
-                        match sendmmsg(udp.as_raw_fd(), &mut vec_buf) {
-                            Ok(_) => break Ok(()),
-                            Err(e) => {
-                                if e.kind() == io::ErrorKind::WouldBlock {
-                                    if let Err(e) = udp.readable().await {
+                    if let Some(os_udp) = udp.os().filter(|_| vec_buf.len() > 1) {
+                        if let Err(e) = loop {
+                            match sendmmsg(os_udp.as_raw_fd(), &mut vec_buf, &socket_layer.gso) {
+                                Ok(_) => break Ok(()),
+                                Err(e) => {
+                                    if e.kind() == io::ErrorKind::WouldBlock {
+                                        if let Err(e) = os_udp.readable().await {
+                                            break Err(e);
+                                        }
+                                        continue;
+                                    } else {
                                         break Err(e);
                                     }
-                                    continue;
-                                } else {
-                                    break Err(e);
                                 }
+                            };
+                        } {
+                            log::debug!("sendmmsg {e:?}");
+                        }
+                    } else {
+                        for (buf, addr) in &vec_buf {
+                            if let Err(e) = udp.send_to(buf, *addr).await {
+                                log::debug!("{addr:?},{e:?}")
                             }
-                        };
-                    } {
-                        log::debug!("sendmmsg {e:?}");
-                    };
+                        }
+                    }
                     if let Some(recycle_buf) = recycle_buf.as_ref() {
                         while let Some((buf, _)) = vec_buf.pop() {
                             recycle_buf.push(buf);
@@ -535,6 +739,7 @@ impl UdpPipe {
                 }
             }
             socket_layer.sender_map.remove(&index);
+            socket_layer.probe_sender_map.remove(&index);
         });
         Ok(line)
     }
@@ -561,29 +766,243 @@ impl UdpPipe {
     }
 }
 
+/// Falls back to one datagram per message once `UDP_SEGMENT` fails
 #[cfg(target_os = "linux")]
-fn sendmmsg(fd: std::os::fd::RawFd, buf: &mut [(BytesMut, SocketAddr)]) -> io::Result<()> {
+fn sendmmsg(
+    fd: std::os::fd::RawFd,
+    buf: &mut [(BytesMut, SocketAddr)],
+    gso: &AtomicBool,
+) -> io::Result<()> {
+    if gso.load(Ordering::Relaxed) {
+        match sendmmsg0(fd, buf, true) {
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EIO | libc::EINVAL)) => {
+                log::info!("UDP_SEGMENT is not supported {e:?}");
+                gso.store(false, Ordering::Relaxed);
+            }
+            rs => return rs,
+        }
+    }
+    sendmmsg0(fd, buf, false)
+}
+/// With `gso` the consecutive datagrams of the same size to the same address are
+/// sent as one message, only the last one may be shorter
+#[cfg(target_os = "linux")]
+fn sendmmsg0(
+    fd: std::os::fd::RawFd,
+    buf: &mut [(BytesMut, SocketAddr)],
+    gso: bool,
+) -> io::Result<()> {
     assert!(buf.len() <= MAX_MESSAGES);
     let mut iov: [libc::iovec; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
     let mut msgs: [libc::mmsghdr; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
     let mut addrs: [libc::sockaddr_storage; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
-    for (i, (buf, addr)) in buf.iter_mut().enumerate() {
-        addrs[i] = socket_addr_to_sockaddr(addr);
+    // Room for one `UDP_SEGMENT` control message
+    let mut controls: [[libc::cmsghdr; 2]; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+    for (i, (buf, _)) in buf.iter_mut().enumerate() {
+        iov[i].iov_base = buf.as_mut_ptr() as *mut libc::c_void;
+        iov[i].iov_len = buf.len();
+    }
+    let mut msg_num = 0;
+    let mut start = 0;
+    while start < buf.len() {
+        let (first, addr) = &buf[start];
+        let segment = first.len();
+        let mut end = start + 1;
+        if gso {
+            let mut total = segment;
+            while end < buf.len()
+                && buf[end].1 == *addr
+                && buf[end - 1].0.len() == segment
+                && buf[end].0.len() <= segment
+                && total + buf[end].0.len() <= MAX_GSO_LEN
+            {
+                total += buf[end].0.len();
+                end += 1;
+            }
+        }
+        addrs[msg_num] = socket_addr_to_sockaddr(addr);
+        let msg = &mut msgs[msg_num].msg_hdr;
+        msg.msg_iov = &mut iov[start];
+        msg.msg_iovlen = (end - start) as _;
+        msg.msg_name = &mut addrs[msg_num] as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if end - start > 1 {
+            msg.msg_control = controls[msg_num].as_mut_ptr() as *mut libc::c_void;
+            unsafe {
+                msg.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<u16>() as _) as _;
+                let cmsg = libc::CMSG_FIRSTHDR(msg);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u16>() as _) as _;
+                std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment as u16);
+            }
+        }
+        msg_num += 1;
+        start = end;
+    }
+
+    unsafe {
+        let res = libc::sendmmsg(fd, msgs.as_mut_ptr(), msg_num as _, 0);
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+/// Receive up to [`MAX_MESSAGES`] datagrams with one syscall
+#[cfg(target_os = "linux")]
+fn recvmmsg<B: AsMut<[u8]>>(
+    fd: std::os::fd::RawFd,
+    bufs: &mut [B],
+    index: Index,
+    received: &mut Vec<(usize, RouteKey)>,
+) -> io::Result<()> {
+    let num = bufs.len().min(MAX_MESSAGES);
+    let mut iov: [libc::iovec; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+    let mut addrs: [libc::sockaddr_storage; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+    for (i, buf) in bufs[..num].iter_mut().enumerate() {
+        let buf = buf.as_mut();
         iov[i].iov_base = buf.as_mut_ptr() as *mut libc::c_void;
         iov[i].iov_len = buf.len();
         msgs[i].msg_hdr.msg_iov = &mut iov[i];
         msgs[i].msg_hdr.msg_iovlen = 1;
-
         msgs[i].msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
         msgs[i].msg_hdr.msg_namelen =
             std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
     }
-
+    let res = unsafe { libc::recvmmsg(fd, msgs.as_mut_ptr(), num as _, 0, std::ptr::null_mut()) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    for i in 0..res as usize {
+        let addr = sockaddr_to_socket_addr(addrs[i], msgs[i].msg_hdr.msg_namelen)?;
+        received.push((msgs[i].msg_len as usize, RouteKey::new(index, addr)));
+    }
+    Ok(())
+}
+#[cfg(target_os = "linux")]
+fn sockaddr_to_socket_addr(
+    storage: libc::sockaddr_storage,
+    len: libc::socklen_t,
+) -> io::Result<SocketAddr> {
+    unsafe { socket2::SockAddr::new(storage, len) }
+        .as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))
+}
+#[cfg(target_os = "linux")]
+fn supports_gso(udp: &UdpSocket) -> bool {
+    use std::os::fd::AsRawFd;
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
     unsafe {
-        let res = libc::sendmmsg(fd, msgs.as_mut_ptr(), buf.len() as _, 0);
-        if res == -1 {
+        libc::getsockopt(
+            udp.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        ) == 0
+    }
+}
+#[cfg(target_os = "linux")]
+fn enable_gro(udp: &UdpSocket) -> bool {
+    use std::os::fd::AsRawFd;
+    let value: libc::c_int = 1;
+    unsafe {
+        libc::setsockopt(
+            udp.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) == 0
+    }
+}
+
+/// DF is set for the probe and cleared again, the data sent by the same task never carries it.
+/// Path MTU discovery still works where DF can not be set, the probes may be fragmented then
+async fn send_probe(udp: &PipeUdpSocket, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    let Some(os_udp) = udp.os() else {
+        return udp.send_to(buf, addr).await;
+    };
+    let socket = socket2::SockRef::from(os_udp);
+    if let Err(e) = set_dont_fragment(&socket, addr.is_ipv4(), true) {
+        log::debug!("set_dont_fragment {e:?}");
+        return os_udp.send_to(buf, addr).await;
+    }
+    let rs = os_udp.send_to(buf, addr).await;
+    if let Err(e) = set_dont_fragment(&socket, addr.is_ipv4(), false) {
+        log::warn!("clear dont_fragment {e:?}");
+    }
+    rs
+}
+
+/// The datagrams `UDP_GRO` coalesced into one receive, handed out one by one
+#[cfg(target_os = "linux")]
+struct GroBuf {
+    buf: Box<[u8]>,
+    len: usize,
+    offset: usize,
+    segment: usize,
+    addr: SocketAddr,
+}
+
+#[cfg(target_os = "linux")]
+impl GroBuf {
+    fn new() -> Self {
+        Self {
+            buf: vec![0; GRO_BUF_LEN].into_boxed_slice(),
+            len: 0,
+            offset: 0,
+            segment: 0,
+            addr: DEFAULT_ADDRESS_V4,
+        }
+    }
+    fn next_segment(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        if self.offset >= self.len {
+            return None;
+        }
+        let end = (self.offset + self.segment).min(self.len);
+        let len = (end - self.offset).min(buf.len());
+        buf[..len].copy_from_slice(&self.buf[self.offset..self.offset + len]);
+        self.offset = end;
+        Some((len, self.addr))
+    }
+    fn recv(&mut self, fd: std::os::fd::RawFd) -> io::Result<()> {
+        let mut iov = libc::iovec {
+            iov_base: self.buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: self.buf.len(),
+        };
+        let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut control: [libc::cmsghdr; 2] = unsafe { std::mem::zeroed() };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+        if len == -1 {
             return Err(io::Error::last_os_error());
         }
+        let len = len as usize;
+        let mut segment = len;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    segment =
+                        std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) as _;
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        self.addr = sockaddr_to_socket_addr(addr, msg.msg_namelen)?;
+        self.len = len;
+        self.offset = 0;
+        self.segment = segment.max(1);
         Ok(())
     }
 }
@@ -694,16 +1113,19 @@ impl<'a> Deref for UdpPipeWriterRef<'a> {
 pub struct UdpPipeLine {
     active: bool,
     index: Index,
-    udp: Option<Arc<UdpSocket>>,
+    udp: Option<Arc<PipeUdpSocket>>,
     close_notify: Option<tokio::sync::broadcast::Receiver<()>>,
     re_sender: Option<tokio::sync::mpsc::UnboundedSender<UdpPipeLine>>,
     socket_layer: Option<Arc<SocketLayer>>,
+    #[cfg(target_os = "linux")]
+    gro: Option<Box<GroBuf>>,
 }
 impl Drop for UdpPipeLine {
     fn drop(&mut self) {
         if self.udp.is_none() || !self.active {
             if let Some(socket_layer) = self.socket_layer.take() {
                 socket_layer.sender_map.remove(&self.index);
+                socket_layer.probe_sender_map.remove(&self.index);
             }
             return;
         }
@@ -715,6 +1137,8 @@ impl Drop for UdpPipeLine {
                 close_notify: self.close_notify.take(),
                 re_sender: self.re_sender.clone(),
                 socket_layer: self.socket_layer.take(),
+                #[cfg(target_os = "linux")]
+                gro: self.gro.take(),
             });
         }
     }
@@ -723,7 +1147,7 @@ impl Drop for UdpPipeLine {
 impl UdpPipeLine {
     pub(crate) fn sub_new(
         index: UDPIndex,
-        udp: Arc<UdpSocket>,
+        udp: Arc<PipeUdpSocket>,
         close_notify: tokio::sync::broadcast::Receiver<()>,
     ) -> Self {
         Self {
@@ -733,11 +1157,13 @@ impl UdpPipeLine {
             close_notify: Some(close_notify),
             re_sender: None,
             socket_layer: None,
+            #[cfg(target_os = "linux")]
+            gro: None,
         }
     }
     pub(crate) fn main_new(
         index: UDPIndex,
-        udp: Arc<UdpSocket>,
+        udp: Arc<PipeUdpSocket>,
         re_sender: tokio::sync::mpsc::UnboundedSender<UdpPipeLine>,
     ) -> Self {
         Self {
@@ -747,6 +1173,8 @@ impl UdpPipeLine {
             close_notify: None,
             re_sender: Some(re_sender),
             socket_layer: None,
+            #[cfg(target_os = "linux")]
+            gro: None,
         }
     }
     pub fn done(&mut self) {
@@ -800,6 +1228,184 @@ impl UdpPipeLine {
         &mut self,
         buf: &mut [u8],
     ) -> Option<std::io::Result<(usize, RouteKey)>> {
+        #[cfg(feature = "quic")]
+        loop {
+            let rs = self.recv_from0(buf).await;
+            if let Some(Ok((len, route_key))) = &rs {
+                if self.forward_quic(&buf[..*len], route_key.addr()) {
+                    continue;
+                }
+            }
+            return rs;
+        }
+        #[cfg(not(feature = "quic"))]
+        self.recv_from0(buf).await
+    }
+    /// The fixed bit is set in every `QUIC` packet and never in the first byte of
+    /// the `NetPacket`s and the STUN messages
+    #[cfg(feature = "quic")]
+    fn forward_quic(&self, buf: &[u8], addr: SocketAddr) -> bool {
+        if !matches!(buf.first(), Some(v) if v & 0x40 != 0) {
+            return false;
+        }
+        let Some(socket_layer) = &self.socket_layer else {
+            return false;
+        };
+        let Some(sender) = socket_layer.quic_sender_map.get(&self.index) else {
+            return false;
+        };
+        // Lost like any other datagram when the endpoint falls behind
+        let _ = sender.try_send((BytesMut::from(buf), addr));
+        true
+    }
+    /// Receiving several datagrams with one wakeup,
+    /// `bufs[i]` holds the datagram of `received[i]`
+    pub async fn recv_multiple_from<B: AsMut<[u8]>>(
+        &mut self,
+        bufs: &mut [B],
+        received: &mut Vec<(usize, RouteKey)>,
+    ) -> Option<std::io::Result<usize>> {
+        #[cfg(feature = "quic")]
+        loop {
+            let rs = self.recv_multiple_from0(bufs, received).await;
+            if let Some(Ok(_)) = &rs {
+                self.forward_quic_multiple(bufs, received);
+                if received.is_empty() {
+                    continue;
+                }
+                return Some(Ok(received.len()));
+            }
+            return rs;
+        }
+        #[cfg(not(feature = "quic"))]
+        self.recv_multiple_from0(bufs, received).await
+    }
+    /// Moves the rest of the datagrams to the front
+    #[cfg(feature = "quic")]
+    fn forward_quic_multiple<B: AsMut<[u8]>>(
+        &self,
+        bufs: &mut [B],
+        received: &mut Vec<(usize, RouteKey)>,
+    ) {
+        let mut kept = 0;
+        for i in 0..received.len() {
+            let (len, route_key) = received[i];
+            if self.forward_quic(&bufs[i].as_mut()[..len], route_key.addr()) {
+                continue;
+            }
+            bufs.swap(kept, i);
+            received[kept] = received[i];
+            kept += 1;
+        }
+        received.truncate(kept);
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn recv_multiple_from0<B: AsMut<[u8]>>(
+        &mut self,
+        bufs: &mut [B],
+        received: &mut Vec<(usize, RouteKey)>,
+    ) -> Option<std::io::Result<usize>> {
+        received.clear();
+        let Some(buf) = bufs.first_mut() else {
+            return Some(Ok(0));
+        };
+        match self.recv_one(buf.as_mut()).await? {
+            Ok(rs) => {
+                received.push(rs);
+                Some(Ok(1))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+    /// `recvmmsg`, or the segments of one `UDP_GRO` receive
+    #[cfg(target_os = "linux")]
+    async fn recv_multiple_from0<B: AsMut<[u8]>>(
+        &mut self,
+        bufs: &mut [B],
+        received: &mut Vec<(usize, RouteKey)>,
+    ) -> Option<std::io::Result<usize>> {
+        use std::os::fd::AsRawFd;
+        received.clear();
+        if bufs.is_empty() {
+            return Some(Ok(0));
+        }
+        let udp = self.udp.clone()?;
+        let Some(os_udp) = udp.os() else {
+            return match self.recv_one(bufs[0].as_mut()).await? {
+                Ok(rs) => {
+                    received.push(rs);
+                    Some(Ok(1))
+                }
+                Err(e) => Some(Err(e)),
+            };
+        };
+        let index = self.index;
+        loop {
+            if let Some(gro) = &mut self.gro {
+                for buf in bufs.iter_mut() {
+                    let Some((len, addr)) = gro.next_segment(buf.as_mut()) else {
+                        break;
+                    };
+                    received.push((len, RouteKey::new(index, addr)));
+                }
+                if !received.is_empty() {
+                    return Some(Ok(received.len()));
+                }
+            }
+            if let Err(e) = self.readable(os_udp).await? {
+                return Some(Err(e));
+            }
+            let gro = &mut self.gro;
+            let rs = os_udp.try_io(tokio::io::Interest::READABLE, || match gro {
+                Some(gro) => gro.recv(os_udp.as_raw_fd()),
+                None => recvmmsg(os_udp.as_raw_fd(), bufs, index, received),
+            });
+            match rs {
+                Ok(()) => {
+                    if !received.is_empty() {
+                        return Some(Ok(received.len()));
+                    }
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock && !should_ignore_error(&e) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+    /// `None` when the pipeline is closed
+    #[cfg(target_os = "linux")]
+    async fn readable(&mut self, udp: &UdpSocket) -> Option<std::io::Result<()>> {
+        if let Some(close_notify) = &mut self.close_notify {
+            tokio::select! {
+                _=close_notify.recv()=>{
+                    self.done();
+                    None
+                }
+                rs=udp.readable()=>Some(rs)
+            }
+        } else {
+            Some(udp.readable().await)
+        }
+    }
+    async fn recv_from0(&mut self, buf: &mut [u8]) -> Option<std::io::Result<(usize, RouteKey)>> {
+        // The coalesced datagrams do not fit into `buf`
+        #[cfg(target_os = "linux")]
+        if self.gro.is_some() {
+            let mut received = Vec::with_capacity(1);
+            return match self
+                .recv_multiple_from0(std::slice::from_mut(&mut &mut *buf), &mut received)
+                .await?
+            {
+                Ok(_) => Some(Ok(received[0])),
+                Err(e) => Some(Err(e)),
+            };
+        }
+        self.recv_one(buf).await
+    }
+    /// One datagram without the `UDP_GRO`
+    async fn recv_one(&mut self, buf: &mut [u8]) -> Option<std::io::Result<(usize, RouteKey)>> {
         let udp = if let Some(udp) = &self.udp {
             udp
         } else {
@@ -854,8 +1460,11 @@ fn should_ignore_error(e: &std::io::Error) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
     use std::time::Duration;
 
+    use bytes::BytesMut;
+
     use crate::pipe::udp_pipe::{Model, UdpPipeLine};
 
     #[tokio::test]
@@ -908,6 +1517,126 @@ mod tests {
         assert_eq!(count, 12);
         assert_eq!(close_pipe_line_count, 10);
     }
+    #[tokio::test]
+    pub async fn send_recv_multiple() {
+        for offload in [true, false] {
+            let config = || {
+                crate::pipe::config::UdpPipeConfig::default()
+                    .set_main_pipeline_num(1)
+                    .set_use_v6(false)
+                    .set_offload(offload)
+            };
+            let mut a = crate::pipe::udp_pipe::udp_pipe(config()).unwrap();
+            let mut b = crate::pipe::udp_pipe::udp_pipe(config()).unwrap();
+            // Accepting starts the sending task
+            let _a_line = a.accept().await.unwrap();
+            let mut b_line = b.accept().await.unwrap();
+            let b_port = b.writer_ref().local_ports().unwrap()[0];
+            let route_key = a
+                .writer_ref()
+                .generate_route_key_from_addr(0, SocketAddr::from(([127, 0, 0, 1], b_port)))
+                .unwrap();
+            for i in 0..64u8 {
+                a.writer_ref()
+                    .send_buf_to(BytesMut::from(&[i; 100][..]), &route_key)
+                    .await
+                    .unwrap();
+            }
+            let mut bufs = vec![vec![0u8; 1500]; 16];
+            let mut received = Vec::new();
+            let mut next = 0u8;
+            while next < 64 {
+                tokio::time::timeout(
+                    Duration::from_secs(1),
+                    b_line.recv_multiple_from(&mut bufs, &mut received),
+                )
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+                for (i, (len, _)) in received.iter().enumerate() {
+                    assert_eq!(&bufs[i][..*len], &[next; 100][..]);
+                    next += 1;
+                }
+            }
+        }
+    }
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    pub async fn send_probe_alone_with_df() {
+        use std::os::fd::AsRawFd;
+        let config = || {
+            crate::pipe::config::UdpPipeConfig::default()
+                .set_main_pipeline_num(1)
+                .set_use_v6(false)
+        };
+        let mut a = crate::pipe::udp_pipe::udp_pipe(config()).unwrap();
+        let mut b = crate::pipe::udp_pipe::udp_pipe(config()).unwrap();
+        let _a_line = a.accept().await.unwrap();
+        let mut b_line = b.accept().await.unwrap();
+        let b_port = b.writer_ref().local_ports().unwrap()[0];
+        let route_key = a
+            .writer_ref()
+            .generate_route_key_from_addr(0, SocketAddr::from(([127, 0, 0, 1], b_port)))
+            .unwrap();
+        a.writer_ref()
+            .send_probe_to(BytesMut::from(&[1; 1000][..]), &route_key)
+            .await
+            .unwrap();
+        let mut buf = [0; 1500];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), b_line.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], &[1; 1000][..]);
+        // The socket is back to the default of the kernel for the data
+        let udp = a.socket_layer.main_udp_v4[0].os().unwrap();
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+        let rs = unsafe {
+            libc::getsockopt(
+                udp.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(rs, 0);
+        assert_eq!(value, libc::IP_PMTUDISC_WANT);
+    }
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    pub async fn rebind_interface() {
+        let config = crate::pipe::config::UdpPipeConfig::default()
+            .set_main_pipeline_num(2)
+            .set_sub_pipeline_num(2)
+            .set_model(Model::High)
+            .set_use_v6(true)
+            .set_default_interface(crate::socket::LocalInterface::new("lo".into()));
+        let udp_pipe = crate::pipe::udp_pipe::udp_pipe(config).unwrap();
+        let socket_layer = &udp_pipe.socket_layer;
+        assert_eq!(socket_layer.main_udp_v6.len(), 2);
+        let sockets: Vec<_> = socket_layer
+            .main_udp_v4
+            .iter()
+            .chain(socket_layer.main_udp_v6.iter())
+            .chain(socket_layer.sub_udp.read().iter())
+            .cloned()
+            .collect();
+        assert_eq!(sockets.len(), 6);
+        for udp in &sockets {
+            socket2::SockRef::from(udp.os().unwrap())
+                .bind_device(None)
+                .unwrap();
+        }
+        socket_layer.rebind_interface().unwrap();
+        for udp in &sockets {
+            let device = socket2::SockRef::from(udp.os().unwrap()).device().unwrap();
+            assert_eq!(device.as_deref(), Some(&b"lo"[..]));
+        }
+    }
     async fn pipe_line_recv(mut udp_pipe_line: UdpPipeLine) -> bool {
         let mut buf = [0; 1400];
         udp_pipe_line.recv_from(&mut buf).await.is_none()
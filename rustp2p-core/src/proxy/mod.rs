@@ -0,0 +1,526 @@
+/*
+  Outbound connections through proxies
+
+  SOCKS5 (RFC 1928, username/password authentication RFC 1929) CONNECT and UDP ASSOCIATE,
+  and HTTP CONNECT with basic authentication.
+  A SOCKS5 UDP association relays one peer and is exposed as a pipeline of the extensible pipe.
+
+  SOCKS5 UDP: RSV(16) | FRAG(8) | ATYP(8) | DST.ADDR | DST.PORT(16) | DATA
+*/
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::pipe::extensible_pipe::{ExtendRead, ExtendWrite, ExtensiblePipeWriter};
+use crate::route::RouteKey;
+use crate::socket::{bind_udp, connect_tcp, handshake_timeout, LocalInterface};
+
+const SOCKS_VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+/// The longest response header of an HTTP proxy
+const MAX_HTTP_HEAD_LEN: usize = 8192;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProxyProtocol {
+    Socks5,
+    HttpConnect,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    pub protocol: ProxyProtocol,
+    pub addr: SocketAddr,
+    /// Username and password
+    pub auth: Option<(String, String)>,
+}
+
+impl ProxyConfig {
+    pub fn socks5(addr: SocketAddr) -> Self {
+        Self {
+            protocol: ProxyProtocol::Socks5,
+            addr,
+            auth: None,
+        }
+    }
+    pub fn http_connect(addr: SocketAddr) -> Self {
+        Self {
+            protocol: ProxyProtocol::HttpConnect,
+            addr,
+            auth: None,
+        }
+    }
+    pub fn set_auth(mut self, username: String, password: String) -> Self {
+        self.auth.replace((username, password));
+        self
+    }
+    /// Dial the proxy and open a tunnel to `target`
+    pub async fn connect(
+        &self,
+        target: SocketAddr,
+        default_interface: Option<&LocalInterface>,
+    ) -> crate::error::Result<TcpStream> {
+        let mut stream = connect_tcp(self.addr, 0, default_interface, None).await?;
+        stream.set_nodelay(true)?;
+        let handshake = async {
+            match self.protocol {
+                ProxyProtocol::Socks5 => {
+                    self.socks5_auth(&mut stream).await?;
+                    socks5_request(&mut stream, CMD_CONNECT, target).await?;
+                }
+                ProxyProtocol::HttpConnect => self.http_connect0(&mut stream, target).await?,
+            }
+            io::Result::Ok(())
+        };
+        handshake_timeout("proxy", handshake).await?;
+        Ok(stream)
+    }
+    /// SOCKS5 UDP ASSOCIATE, returns the control connection and the relay address.
+    /// The association lasts as long as the control connection
+    pub async fn udp_associate(
+        &self,
+        default_interface: Option<&LocalInterface>,
+    ) -> crate::error::Result<(TcpStream, SocketAddr)> {
+        if self.protocol != ProxyProtocol::Socks5 {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "UDP requires a SOCKS5 proxy",
+            ))?
+        }
+        let mut stream = connect_tcp(self.addr, 0, default_interface, None).await?;
+        let unspecified = match self.addr {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let handshake = async {
+            self.socks5_auth(&mut stream).await?;
+            socks5_request(&mut stream, CMD_UDP_ASSOCIATE, unspecified).await
+        };
+        let mut relay_addr = handshake_timeout("proxy", handshake).await?;
+        // The proxy is reached on the address it was dialed
+        if relay_addr.ip().is_unspecified() {
+            relay_addr.set_ip(self.addr.ip());
+        }
+        Ok((stream, relay_addr))
+    }
+    async fn socks5_auth(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let method = if self.auth.is_some() {
+            USERNAME_PASSWORD
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION || reply[1] != method {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "socks5 method not accepted",
+            ));
+        }
+        if let Some((username, password)) = &self.auth {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            let mut request = vec![0x01, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "socks5 authentication failed",
+                ));
+            }
+        }
+        Ok(())
+    }
+    async fn http_connect0(&self, stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((username, password)) = &self.auth {
+            let credential = base64::engine::general_purpose::STANDARD
+                .encode(format!("{username}:{password}").as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {credential}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        // Byte by byte, the tunnel starts right after the header
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_HTTP_HEAD_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "http head too long",
+                ));
+            }
+            head.push(stream.read_u8().await?);
+        }
+        let status = head
+            .split(|v| *v == b' ')
+            .nth(1)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        if status != b"200" {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("http connect {}", String::from_utf8_lossy(status)),
+            ));
+        }
+        Ok(())
+    }
+}
+
+async fn socks5_request(
+    stream: &mut TcpStream,
+    command: u8,
+    addr: SocketAddr,
+) -> io::Result<SocketAddr> {
+    let mut request = vec![SOCKS_VERSION, command, 0];
+    put_addr(&mut request, addr);
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 3];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("socks5 reply {}", reply[1]),
+        ));
+    }
+    read_addr(stream).await
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn read_addr<R: AsyncRead + Unpin>(read: &mut R) -> io::Result<SocketAddr> {
+    let ip = match read.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            read.read_exact(&mut ip).await?;
+            IpAddr::from(ip)
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            read.read_exact(&mut ip).await?;
+            IpAddr::from(ip)
+        }
+        ATYP_DOMAIN => {
+            // Only bound by proxies that do not know their address
+            let len = read.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            read.read_exact(&mut domain).await?;
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
+    };
+    Ok(SocketAddr::new(ip, read.read_u16().await?))
+}
+
+/// The length of the header of a relayed datagram and the source in it
+fn parse_udp_head(buf: &[u8]) -> Option<(usize, SocketAddr)> {
+    // Fragments are not supported
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+    let (ip, offset): (IpAddr, usize) = match buf[3] {
+        ATYP_IPV4 if buf.len() >= 10 => (<[u8; 4]>::try_from(&buf[4..8]).ok()?.into(), 8),
+        ATYP_IPV6 if buf.len() >= 22 => (<[u8; 16]>::try_from(&buf[4..20]).ok()?.into(), 20),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+    Some((offset + 2, SocketAddr::new(ip, port)))
+}
+
+/// Reach `target` over `UDP` through a SOCKS5 proxy and add it to the extensible pipe
+pub async fn udp_associate(
+    proxy: &ProxyConfig,
+    target: SocketAddr,
+    default_interface: Option<&LocalInterface>,
+    pipe_writer: &ExtensiblePipeWriter,
+) -> anyhow::Result<RouteKey> {
+    let (control, relay_addr) = proxy.udp_associate(default_interface).await?;
+    let unspecified = match relay_addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let udp = UdpSocket::from_std(bind_udp(unspecified, default_interface)?.into())?;
+    udp.connect(relay_addr).await?;
+    let udp = Arc::new(udp);
+    let mut head = vec![0, 0, 0];
+    put_addr(&mut head, target);
+    pipe_writer
+        .add_pipe(
+            target,
+            Box::new(Socks5UdpRead {
+                udp: udp.clone(),
+                control,
+                target,
+                buf: vec![0; 65536],
+            }),
+            Box::new(Socks5UdpWrite {
+                udp,
+                buf: head.clone(),
+                head_len: head.len(),
+            }),
+        )
+        .await
+}
+
+struct Socks5UdpRead {
+    udp: Arc<UdpSocket>,
+    control: TcpStream,
+    target: SocketAddr,
+    buf: Vec<u8>,
+}
+
+#[async_trait]
+impl ExtendRead for Socks5UdpRead {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = tokio::select! {
+                rs = self.udp.recv(&mut self.buf) => rs?,
+                // The association ends with the control connection
+                _ = self.control.read_u8() => {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+                }
+            };
+            let Some((head_len, addr)) = parse_udp_head(&self.buf[..len]) else {
+                continue;
+            };
+            if addr != self.target {
+                continue;
+            }
+            let data = &self.buf[head_len..len];
+            if data.len() > buf.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "too long"));
+            }
+            buf[..data.len()].copy_from_slice(data);
+            return Ok(data.len());
+        }
+    }
+}
+
+struct Socks5UdpWrite {
+    udp: Arc<UdpSocket>,
+    buf: Vec<u8>,
+    head_len: usize,
+}
+
+#[async_trait]
+impl ExtendWrite for Socks5UdpWrite {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buf.truncate(self.head_len);
+        self.buf.extend_from_slice(buf);
+        self.udp.send(&self.buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+    use crate::pipe::config::TcpPipeConfig;
+    use crate::pipe::extensible_pipe::ExtensiblePipe;
+    use crate::pipe::tcp_pipe::{LengthPrefixedInitCodec, TcpPipe};
+    use base64::Engine;
+
+    use crate::proxy::{parse_udp_head, put_addr, read_addr, udp_associate, ProxyConfig};
+
+    fn base64(input: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(input)
+    }
+
+    /// A SOCKS5 and HTTP CONNECT proxy that accepts `user:pass`
+    async fn proxy_stand_in() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream));
+            }
+        });
+        addr
+    }
+
+    async fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+        if stream.read_u8().await? != 0x05 {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await?);
+            }
+            let head = String::from_utf8(head).unwrap();
+            let target: SocketAddr = head.split(' ').nth(1).unwrap().parse().unwrap();
+            assert!(head.contains(&format!("Basic {}", base64(b"user:pass"))));
+            let mut upstream = TcpStream::connect(target).await?;
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+            copy_bidirectional(&mut stream, &mut upstream).await?;
+            return Ok(());
+        }
+        let mut methods = vec![0u8; stream.read_u8().await? as usize];
+        stream.read_exact(&mut methods).await?;
+        stream.write_all(&[0x05, 0x02]).await?;
+        let mut auth = vec![0u8; 2];
+        stream.read_exact(&mut auth).await?;
+        let mut username = vec![0u8; auth[1] as usize];
+        stream.read_exact(&mut username).await?;
+        let mut password = vec![0u8; stream.read_u8().await? as usize];
+        stream.read_exact(&mut password).await?;
+        let ok = username == b"user" && password == b"pass";
+        stream.write_all(&[0x01, if ok { 0 } else { 1 }]).await?;
+        let mut request = [0u8; 3];
+        stream.read_exact(&mut request).await?;
+        let target = read_addr(&mut stream).await?;
+        if request[1] == 0x01 {
+            let mut upstream = TcpStream::connect(target).await?;
+            let mut reply = vec![0x05, 0, 0];
+            put_addr(&mut reply, upstream.local_addr()?);
+            stream.write_all(&reply).await?;
+            copy_bidirectional(&mut stream, &mut upstream).await?;
+            return Ok(());
+        }
+        let relay = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut reply = vec![0x05, 0, 0];
+        put_addr(&mut reply, relay.local_addr()?);
+        stream.write_all(&reply).await?;
+        let mut buf = [0u8; 2048];
+        let mut client = None;
+        loop {
+            let (len, from) = relay.recv_from(&mut buf).await?;
+            // The first sender is the client
+            let client = *client.get_or_insert(from);
+            if from == client {
+                let (head_len, to) = parse_udp_head(&buf[..len]).unwrap();
+                relay.send_to(&buf[head_len..len], to).await?;
+            } else {
+                let mut data = vec![0, 0, 0];
+                put_addr(&mut data, from);
+                data.extend_from_slice(&buf[..len]);
+                relay.send_to(&data, client).await?;
+            }
+        }
+    }
+
+    async fn tcp_through(proxy: ProxyConfig) {
+        let mut server =
+            TcpPipe::new(TcpPipeConfig::new(Box::new(LengthPrefixedInitCodec)).set_use_v6(false))
+                .unwrap();
+        let mut client = TcpPipe::new(
+            TcpPipeConfig::new(Box::new(LengthPrefixedInitCodec))
+                .set_use_v6(false)
+                .set_proxy(proxy),
+        )
+        .unwrap();
+        let server_addr: SocketAddr =
+            format!("127.0.0.1:{}", server.writer_ref().local_addr().port())
+                .parse()
+                .unwrap();
+        let route_key = client.writer_ref().connect(server_addr).await.unwrap();
+        // Keyed by the target, not by the proxy
+        assert_eq!(route_key.addr(), server_addr);
+        let _client_line = client.accept().await.unwrap();
+        client
+            .writer_ref()
+            .to_owned()
+            .send_to(b"hello"[..].into(), &route_key)
+            .await
+            .unwrap();
+        let mut server_line = server.accept().await.unwrap();
+        let mut buf = [0; 64];
+        let (len, _) = server_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+    }
+
+    #[tokio::test]
+    async fn socks5_and_http_connect() {
+        let proxy_addr = proxy_stand_in().await;
+        let auth = ("user".to_string(), "pass".to_string());
+        tcp_through(ProxyConfig::socks5(proxy_addr).set_auth(auth.0.clone(), auth.1.clone())).await;
+        tcp_through(ProxyConfig::http_connect(proxy_addr).set_auth(auth.0, auth.1)).await;
+        assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+        let rs = ProxyConfig::socks5(proxy_addr)
+            .set_auth("user".to_string(), "wrong".to_string())
+            .connect(proxy_addr, None)
+            .await;
+        assert!(rs.is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_handshake_bounded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // A silent proxy, then one with an endless header
+            let (_silent, _) = listener.accept().await.unwrap();
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            _ = stream.read(&mut buf).await;
+            stream.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+            while stream.write_all(b"X-Padding: 0\r\n").await.is_ok() {}
+        });
+        let rs = ProxyConfig::socks5(proxy_addr)
+            .connect(proxy_addr, None)
+            .await;
+        assert!(
+            matches!(rs, Err(crate::error::Error::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut)
+        );
+        let rs = ProxyConfig::http_connect(proxy_addr)
+            .connect(proxy_addr, None)
+            .await;
+        assert!(
+            matches!(rs, Err(crate::error::Error::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_udp_associate() {
+        let proxy = ProxyConfig::socks5(proxy_stand_in().await)
+            .set_auth("user".to_string(), "pass".to_string());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let mut pipe = ExtensiblePipe::new();
+        let writer = pipe.writer_ref().to_owned();
+        let route_key = udp_associate(&proxy, peer_addr, None, &writer)
+            .await
+            .unwrap();
+        assert_eq!(route_key.addr(), peer_addr);
+        let mut line = pipe.accept().await.unwrap();
+        writer
+            .send_to(b"hello"[..].into(), &route_key)
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        let (len, relay_addr) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        peer.send_to(b"world", relay_addr).await.unwrap();
+        let (len, _) = line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"world");
+    }
+}
@@ -3,6 +3,113 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::ops;
 use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Tuning of the birthday-paradox punching between two symmetric nats.
+/// The side that did not initiate the round opens mappings from its sub sockets,
+/// the initiating side sprays random ports of the peer from the main socket.
+#[derive(Copy, Clone, Debug)]
+pub struct BirthdayPunchConfig {
+    pub enable: bool,
+    /// The number of random ports sprayed per round
+    pub probes: usize,
+    /// The number of random ports each sub socket sends to per round, every one opens a new mapping
+    pub mappings_per_socket: usize,
+}
+
+impl Default for BirthdayPunchConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            probes: 600,
+            mappings_per_socket: 1,
+        }
+    }
+}
+
+impl BirthdayPunchConfig {
+    pub fn set_enable(mut self, enable: bool) -> Self {
+        self.enable = enable;
+        self
+    }
+    pub fn set_probes(mut self, probes: usize) -> Self {
+        self.probes = probes;
+        self
+    }
+    pub fn set_mappings_per_socket(mut self, mappings_per_socket: usize) -> Self {
+        self.mappings_per_socket = mappings_per_socket;
+        self
+    }
+}
+
+/// When and how many peers to punch
+pub trait PunchPolicy: Send + Sync {
+    /// The interval between two consulting rounds
+    fn interval(&self) -> Duration;
+    /// The maximum number of peers consulted per round,
+    /// the peers with user traffic are consulted first
+    fn fan_out(&self) -> usize;
+    /// Whether to punch on the `count`-th check since the peer needed punching
+    fn should_punch(&self, count: usize) -> bool;
+}
+
+/// Punches every check at first, then every `count / backoff_after`-th check
+#[derive(Copy, Clone, Debug)]
+pub struct DefaultPunchPolicy {
+    pub interval: Duration,
+    pub fan_out: usize,
+    pub backoff_after: usize,
+    /// The largest gap between two punches, in checks
+    pub max_backoff: usize,
+}
+
+impl Default for DefaultPunchPolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            fan_out: 4,
+            backoff_after: 8,
+            max_backoff: 360,
+        }
+    }
+}
+
+impl DefaultPunchPolicy {
+    pub fn set_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    pub fn set_fan_out(mut self, fan_out: usize) -> Self {
+        self.fan_out = fan_out;
+        self
+    }
+    pub fn set_backoff_after(mut self, backoff_after: usize) -> Self {
+        self.backoff_after = backoff_after;
+        self
+    }
+    pub fn set_max_backoff(mut self, max_backoff: usize) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+}
+
+impl PunchPolicy for DefaultPunchPolicy {
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+    fn fan_out(&self) -> usize {
+        self.fan_out
+    }
+    fn should_punch(&self, count: usize) -> bool {
+        let backoff_after = self.backoff_after.max(1);
+        if count > backoff_after {
+            let interval = (count / backoff_after).min(self.max_backoff).max(1);
+            return count % interval == 0;
+        }
+        true
+    }
+}
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
 pub enum PunchModel {
@@ -110,6 +217,18 @@ impl PunchModelBoxes {
 pub struct PunchConsultInfo {
     pub peer_punch_model: PunchModelBox,
     pub peer_nat_info: NatInfo,
+    /// Both sides connect `TCP` simultaneously after this delay from the consult reply
+    #[serde(default)]
+    pub tcp_punch_delay: Option<Duration>,
+    /// Requested by `punch_now`, the peer punches regardless of its backoff
+    #[serde(default)]
+    pub immediate: bool,
+    /// The peer accepts `QUIC`, the direct routes are upgraded only then
+    #[serde(default)]
+    pub quic: bool,
+    /// The peer answers the STUN Binding requests on its pipes
+    #[serde(default)]
+    pub stun_server: bool,
 }
 
 impl PunchConsultInfo {
@@ -117,8 +236,28 @@ impl PunchConsultInfo {
         Self {
             peer_punch_model,
             peer_nat_info,
+            tcp_punch_delay: None,
+            immediate: false,
+            quic: false,
+            stun_server: false,
         }
     }
+    pub fn set_tcp_punch_delay(mut self, tcp_punch_delay: Duration) -> Self {
+        self.tcp_punch_delay = Some(tcp_punch_delay);
+        self
+    }
+    pub fn set_immediate(mut self, immediate: bool) -> Self {
+        self.immediate = immediate;
+        self
+    }
+    pub fn set_quic(mut self, quic: bool) -> Self {
+        self.quic = quic;
+        self
+    }
+    pub fn set_stun_server(mut self, stun_server: bool) -> Self {
+        self.stun_server = stun_server;
+        self
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -126,6 +265,8 @@ pub struct PunchInfo {
     pub(crate) initiate_by_oneself: bool,
     pub(crate) punch_model: PunchModelBoxes,
     pub(crate) peer_nat_info: NatInfo,
+    pub(crate) local_nat_info: Option<NatInfo>,
+    pub(crate) tcp_punch_time: Option<Instant>,
 }
 
 impl PunchInfo {
@@ -138,6 +279,8 @@ impl PunchInfo {
             initiate_by_oneself,
             punch_model,
             peer_nat_info,
+            local_nat_info: None,
+            tcp_punch_time: None,
         }
     }
     pub fn new_by_oneself(punch_model: PunchModelBoxes, peer_nat_info: NatInfo) -> Self {
@@ -145,6 +288,8 @@ impl PunchInfo {
             initiate_by_oneself: true,
             punch_model,
             peer_nat_info,
+            local_nat_info: None,
+            tcp_punch_time: None,
         }
     }
     pub fn new_by_other(punch_model: PunchModelBoxes, peer_nat_info: NatInfo) -> Self {
@@ -152,8 +297,20 @@ impl PunchInfo {
             initiate_by_oneself: false,
             punch_model,
             peer_nat_info,
+            local_nat_info: None,
+            tcp_punch_time: None,
         }
     }
+    /// Connect `TCP` simultaneously with the peer at `tcp_punch_time`
+    pub fn set_tcp_punch_time(mut self, tcp_punch_time: Instant) -> Self {
+        self.tcp_punch_time = Some(tcp_punch_time);
+        self
+    }
+    /// The nat of oneself, the ways the replies of the peer cannot come back through are skipped
+    pub fn set_local_nat_info(mut self, local_nat_info: NatInfo) -> Self {
+        self.local_nat_info = Some(local_nat_info);
+        self
+    }
     pub(crate) fn use_ttl(&self) -> bool {
         self.initiate_by_oneself ^ (self.peer_nat_info.seq % 2 == 0)
     }
@@ -175,3 +332,22 @@ impl FromStr for PunchModel {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::punch::{DefaultPunchPolicy, PunchPolicy};
+
+    #[test]
+    fn default_punch_policy_backoff() {
+        let policy = DefaultPunchPolicy::default()
+            .set_backoff_after(4)
+            .set_max_backoff(3);
+        let punched: Vec<usize> = (0..=20).filter(|v| policy.should_punch(*v)).collect();
+        // Every check up to 7, then every second check up to 11, then every third
+        assert_eq!(punched, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 15, 18]);
+        // The backoff is capped by `max_backoff`
+        assert!(policy.should_punch(300) && !policy.should_punch(301));
+        let policy = DefaultPunchPolicy::default().set_backoff_after(0);
+        assert!(policy.should_punch(1) && policy.should_punch(2));
+    }
+}
@@ -9,13 +9,91 @@ use parking_lot::Mutex;
 use rand::seq::SliceRandom;
 use rand::Rng;
 
-use crate::nat::{NatInfo, NatType};
+use crate::nat::{NatBehavior, NatInfo, NatType};
+#[cfg(feature = "quic")]
+use crate::pipe::quic_pipe::QuicPipeWriter;
 use crate::pipe::tcp_pipe::TcpPipeWriter;
-use crate::pipe::udp_pipe::UdpPipeWriter;
+use crate::pipe::udp_pipe::{Model, UdpPipeWriter};
 use crate::pipe::Pipe;
 use crate::route::route_table::RouteTable;
 pub use config::*;
+pub use report::*;
 pub mod config;
+mod report;
+
+/// How to reach the public IPv4 address of the peer
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum UdpPunchStrategy {
+    /// The peer's nat forwards packets from any source, one packet from the main socket is enough
+    Direct,
+    /// The peer's nat only forwards packets from the addresses it has sent to,
+    /// the sub sockets (if any) open more mappings to be hit by the peer
+    MultipleMappings,
+    /// The peer's mapping changes with the destination, its public port has to be guessed
+    PortPrediction,
+    /// As `PortPrediction`, but the local nat only lets the peer's reply in from a port it was sent to,
+    /// or maps every guess anew, so a random guess is not answered and only the predicted ports are tried
+    PredictedPorts,
+}
+
+impl UdpPunchStrategy {
+    fn select(local_nat_info: Option<&NatInfo>, peer_nat_info: &NatInfo) -> Self {
+        let strategy = match peer_nat_info.mapping_behavior {
+            NatBehavior::EndpointIndependent => {
+                if peer_nat_info.filtering_behavior == NatBehavior::EndpointIndependent {
+                    UdpPunchStrategy::Direct
+                } else {
+                    UdpPunchStrategy::MultipleMappings
+                }
+            }
+            NatBehavior::AddressDependent | NatBehavior::AddressAndPortDependent => {
+                UdpPunchStrategy::PortPrediction
+            }
+            // Older peers, or the stun servers of the peer do not support RFC 5780
+            NatBehavior::Unknown => match peer_nat_info.nat_type {
+                NatType::Cone => UdpPunchStrategy::MultipleMappings,
+                NatType::Symmetric => UdpPunchStrategy::PortPrediction,
+            },
+        };
+        match local_nat_info {
+            Some(local_nat_info)
+                if strategy == UdpPunchStrategy::PortPrediction
+                    && !accepts_unguessed_ports(local_nat_info) =>
+            {
+                UdpPunchStrategy::PredictedPorts
+            }
+            _ => strategy,
+        }
+    }
+}
+
+/// Whether the peer's packets from a mapping the local side has not guessed reach the local socket,
+/// the unknown behaviours are assumed to let them in
+fn accepts_unguessed_ports(local_nat_info: &NatInfo) -> bool {
+    let symmetric = match local_nat_info.mapping_behavior {
+        NatBehavior::Unknown => local_nat_info.nat_type == NatType::Symmetric,
+        behavior => behavior != NatBehavior::EndpointIndependent,
+    };
+    !symmetric && local_nat_info.filtering_behavior != NatBehavior::AddressAndPortDependent
+}
+
+async fn connect_tcp_simultaneous(tcp_pipe_writer: &TcpPipeWriter, buf: &[u8], addr: SocketAddr) {
+    let rs = tokio::time::timeout(Duration::from_secs(5), async {
+        let route_key = tcp_pipe_writer.connect_simultaneous(addr).await?;
+        tcp_pipe_writer.send_to(buf.into(), &route_key).await
+    })
+    .await;
+    match rs {
+        Ok(rs) => {
+            if let Err(e) = rs {
+                log::warn!("tcp simultaneous open {addr},{e:?}");
+            }
+        }
+        Err(_) => {
+            log::warn!("tcp simultaneous open timeout {addr}");
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Puncher<PeerID> {
@@ -27,6 +105,11 @@ pub struct Puncher<PeerID> {
     count_record: Arc<Mutex<HashMap<PeerID, (usize, usize)>>>,
     udp_pipe_writer: Option<UdpPipeWriter>,
     tcp_pipe_writer: Option<TcpPipeWriter>,
+    #[cfg(feature = "quic")]
+    quic_pipe_writer: Option<QuicPipeWriter>,
+    birthday_punch: BirthdayPunchConfig,
+    policy: Arc<dyn PunchPolicy>,
+    reports: PunchReports<PeerID>,
 }
 
 impl<PeerID> From<&Pipe<PeerID>> for Puncher<PeerID> {
@@ -34,11 +117,15 @@ impl<PeerID> From<&Pipe<PeerID>> for Puncher<PeerID> {
         let writer_ref = value.writer_ref();
         let tcp_pipe_writer = writer_ref.tcp_pipe_writer_ref().map(|v| v.to_owned());
         let udp_pipe_writer = writer_ref.udp_pipe_writer_ref().map(|v| v.to_owned());
-        Self::new(
+        let puncher = Self::new(
             value.route_table().clone(),
             udp_pipe_writer,
             tcp_pipe_writer,
-        )
+        );
+        #[cfg(feature = "quic")]
+        let puncher =
+            puncher.set_quic_pipe_writer(writer_ref.quic_pipe_writer_ref().map(|v| v.to_owned()));
+        puncher
     }
 }
 
@@ -52,14 +139,39 @@ impl<PeerID> Puncher<PeerID> {
         let mut rng = rand::thread_rng();
         port_vec.shuffle(&mut rng);
         Self {
+            reports: PunchReports::new(route_table.clone()),
             route_table,
             port_vec,
             sym_record: Arc::new(Mutex::new(HashMap::new())),
             count_record: Arc::new(Mutex::new(HashMap::new())),
             udp_pipe_writer,
             tcp_pipe_writer,
+            #[cfg(feature = "quic")]
+            quic_pipe_writer: None,
+            birthday_punch: Default::default(),
+            policy: Arc::new(DefaultPunchPolicy::default()),
         }
     }
+    #[cfg(feature = "quic")]
+    pub fn set_quic_pipe_writer(mut self, quic_pipe_writer: Option<QuicPipeWriter>) -> Self {
+        self.quic_pipe_writer = quic_pipe_writer;
+        self
+    }
+    pub fn set_birthday_punch(mut self, birthday_punch: BirthdayPunchConfig) -> Self {
+        self.birthday_punch = birthday_punch;
+        self
+    }
+    pub fn set_punch_policy(mut self, policy: Arc<dyn PunchPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+    pub fn punch_policy(&self) -> &Arc<dyn PunchPolicy> {
+        &self.policy
+    }
+    /// The per-peer diagnostics of punching
+    pub fn reports(&self) -> PunchReports<PeerID> {
+        self.reports.clone()
+    }
 }
 
 impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
@@ -85,14 +197,28 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
             .entry(id.clone())
             .and_modify(|(v, _)| *v += 1)
             .or_insert((0, 0));
-        if count > 8 {
-            //降低频率
-            let interval = count / 8;
-            return count % interval.min(360) == 0;
-        }
-        true
+        self.policy.should_punch(count)
     }
 
+    /// Open a `QUIC` connection over a punched `UDP` route, it passes the nats through the
+    /// mappings of the route. `buf` is sent over the connection for the peer to add the route
+    #[cfg(feature = "quic")]
+    pub async fn punch_quic(
+        &self,
+        peer_id: &PeerID,
+        buf: &[u8],
+        route_key: &crate::route::RouteKey,
+    ) -> anyhow::Result<crate::route::RouteKey> {
+        let Some(quic_pipe_writer) = self.quic_pipe_writer.as_ref() else {
+            return Err(anyhow::anyhow!("the quic pipe is not enabled"));
+        };
+        self.reports.record(peer_id, PunchStrategy::Quic);
+        let quic_route_key = quic_pipe_writer.connect_route(route_key).await?;
+        quic_pipe_writer
+            .send_to(bytes::BytesMut::from(buf), &quic_route_key)
+            .await?;
+        Ok(quic_route_key)
+    }
     /// Call `punch` at a certain frequency
     pub async fn punch(
         &self,
@@ -117,14 +243,50 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
             .entry(peer_id.clone())
             .and_modify(|(_, v)| *v += 1)
             .or_insert((0, 0));
+        self.reports.attempt(&peer_id);
         let ttl = if punch_info.use_ttl() && count < 255 {
             Some(count.max(2) as u32)
         } else {
             None
         };
+        let initiate_by_oneself = punch_info.initiate_by_oneself;
+        let tcp_punch_time = punch_info.tcp_punch_time;
         let peer_nat_info = punch_info.peer_nat_info;
+        let local_nat_info = punch_info.local_nat_info;
         let punch_model = punch_info.punch_model;
 
+        if self.tcp_pipe_writer.is_some() {
+            let public_tcp = punch_model.is_match(PunchModel::IPv4Tcp)
+                && !peer_nat_info.public_ipv4_tcp().is_empty();
+            if public_tcp || !peer_nat_info.mapping_tcp_addr.is_empty() {
+                self.reports.record(&peer_id, PunchStrategy::Tcp);
+            }
+            if public_tcp && tcp_punch_time.is_some() {
+                self.reports
+                    .record(&peer_id, PunchStrategy::TcpSimultaneousOpen);
+            }
+        }
+        if let (Some(tcp_pipe_writer), Some(tcp_punch_time)) =
+            (self.tcp_pipe_writer.as_ref(), tcp_punch_time)
+        {
+            // Waits for the peer apart from the blocking scope below, so the UDP punching is not held up,
+            // the tasks end with the TCP pipe
+            if punch_model.is_match(PunchModel::IPv4Tcp) {
+                for addr in peer_nat_info.public_ipv4_tcp() {
+                    let tcp_pipe_writer = tcp_pipe_writer.clone();
+                    let buf = buf.to_vec();
+                    tokio::spawn(async move {
+                        tokio::select! {
+                            _ = tcp_pipe_writer.closed() => {}
+                            _ = async {
+                                tokio::time::sleep_until(tcp_punch_time).await;
+                                connect_tcp_simultaneous(&tcp_pipe_writer, &buf, addr).await;
+                            } => {}
+                        }
+                    });
+                }
+            }
+        }
         async_scoped::TokioScope::scope_and_block(|s| {
             if let Some(tcp_pipe_writer) = self.tcp_pipe_writer.as_ref() {
                 for addr in &peer_nat_info.mapping_tcp_addr {
@@ -153,8 +315,16 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
                 }
             }
         });
-        self.punch_udp(peer_id, count, buf, &peer_nat_info, &punch_model)
-            .await?;
+        self.punch_udp(
+            peer_id,
+            count,
+            initiate_by_oneself,
+            buf,
+            local_nat_info.as_ref(),
+            &peer_nat_info,
+            &punch_model,
+        )
+        .await?;
 
         Ok(())
     }
@@ -180,11 +350,14 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
             }
         }
     }
+    #[allow(clippy::too_many_arguments)]
     async fn punch_udp(
         &self,
         peer_id: PeerID,
         count: usize,
+        initiate_by_oneself: bool,
         buf: &[u8],
+        local_nat_info: Option<&NatInfo>,
         peer_nat_info: &NatInfo,
         punch_model: &PunchModelBoxes,
     ) -> anyhow::Result<()> {
@@ -209,15 +382,20 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
                 .copied()
                 .collect();
             udp_pipe_writer.try_main_send_to_addr(buf, &mapping_udp_v6_addr);
+            self.reports.record(&peer_id, PunchStrategy::UdpMapping);
         }
         let local_ipv4_addrs = peer_nat_info.local_ipv4_addrs();
         if !local_ipv4_addrs.is_empty() {
             udp_pipe_writer.try_main_send_to_addr(buf, &local_ipv4_addrs);
+            self.reports.record(&peer_id, PunchStrategy::UdpLan);
         }
 
         if punch_model.is_match(PunchModel::IPv6Udp) {
             let v6_addr = peer_nat_info.ipv6_addr();
-            udp_pipe_writer.try_main_send_to_addr(buf, &v6_addr);
+            if !v6_addr.is_empty() {
+                udp_pipe_writer.try_main_send_to_addr(buf, &v6_addr);
+                self.reports.record(&peer_id, PunchStrategy::UdpIpv6);
+            }
         }
         if !punch_model.is_match(PunchModel::IPv4Udp) {
             return Ok(());
@@ -226,8 +404,32 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
             return Ok(());
         }
 
-        match peer_nat_info.nat_type {
-            NatType::Symmetric => {
+        match UdpPunchStrategy::select(local_nat_info, peer_nat_info) {
+            UdpPunchStrategy::PortPrediction | UdpPunchStrategy::PredictedPorts
+                if self.birthday_punch.enable && udp_pipe_writer.model() == Model::High =>
+            {
+                // Oneself is symmetric too, guessing the port of the peer is pointless
+                self.reports.record(&peer_id, PunchStrategy::UdpBirthday);
+                self.punch_birthday(
+                    peer_id,
+                    initiate_by_oneself,
+                    udp_pipe_writer,
+                    buf,
+                    &peer_nat_info.public_ips,
+                    &peer_nat_info.predicted_ports,
+                )
+                .await?;
+            }
+            strategy @ (UdpPunchStrategy::PortPrediction | UdpPunchStrategy::PredictedPorts) => {
+                let predicted_only = strategy == UdpPunchStrategy::PredictedPorts;
+                self.reports.record(
+                    &peer_id,
+                    if predicted_only {
+                        PunchStrategy::UdpPredictedPorts
+                    } else {
+                        PunchStrategy::UdpPortPrediction
+                    },
+                );
                 // 假设对方绑定n个端口，通过NAT对外映射出n个 公网ip:公网端口，自己随机尝试k次的情况下
                 // 猜中的概率 p = 1-((65535-n)/65535)*((65535-n-1)/(65535-1))*...*((65535-n-k+1)/(65535-k+1))
                 // n取76，k取600，猜中的概率就超过50%了
@@ -241,6 +443,15 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
                     //递减探测规模
                     max_k2 = max_k2.mul(8).div(count).max(max_k1 as usize);
                 }
+                // The ports following the allocation pattern of the peer's nat are the most likely
+                self.punch_symmetric(
+                    udp_pipe_writer,
+                    &peer_nat_info.predicted_ports,
+                    buf,
+                    &peer_nat_info.public_ips,
+                    usize::MAX,
+                )
+                .await?;
                 let port = peer_nat_info.public_ports.first().copied().unwrap_or(0);
                 if peer_nat_info.public_port_range < max_k1 * 3 {
                     //端口变化不大时，在预测的范围内随机发送
@@ -268,6 +479,9 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
                     )
                     .await?;
                 }
+                if predicted_only {
+                    return Ok(());
+                }
                 let start = self
                     .sym_record
                     .lock()
@@ -294,11 +508,18 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
                 // 记录这个IP的打洞记录
                 self.sym_record.lock().insert(peer_id, index);
             }
-            NatType::Cone => {
+            UdpPunchStrategy::Direct => {
+                self.reports.record(&peer_id, PunchStrategy::UdpDirect);
+                let addr = peer_nat_info.public_ipv4_addr();
+                udp_pipe_writer.try_main_send_to_addr(buf, &addr);
+            }
+            UdpPunchStrategy::MultipleMappings => {
                 let addr = peer_nat_info.public_ipv4_addr();
                 if addr.is_empty() {
                     return Ok(());
                 }
+                self.reports
+                    .record(&peer_id, PunchStrategy::UdpMultipleMappings);
                 udp_pipe_writer.try_main_send_to_addr(buf, &addr);
                 udp_pipe_writer.try_sub_send_to_addr_v4(buf, addr[0]);
             }
@@ -306,6 +527,56 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
         Ok(())
     }
 
+    /// Both sides are symmetric, one side opens many mappings towards the peer from the sub sockets
+    /// and the other side sprays random ports. A probe hitting one of the mappings reaches the sub
+    /// socket, whose route is then installed as a direct route.
+    /// The peer's nat likely allocates those mappings on the predicted ports, so they are sprayed first
+    async fn punch_birthday(
+        &self,
+        peer_id: PeerID,
+        spray: bool,
+        udp_pipe_writer: &UdpPipeWriter,
+        buf: &[u8],
+        ips: &Vec<Ipv4Addr>,
+        predicted_ports: &[u16],
+    ) -> anyhow::Result<()> {
+        if !spray {
+            let mut rng = rand::thread_rng();
+            for _ in 0..self.birthday_punch.mappings_per_socket {
+                for ip in ips {
+                    let addr = SocketAddr::V4(SocketAddrV4::new(*ip, rng.gen_range(1..=65535)));
+                    udp_pipe_writer.try_sub_send_to_addr_v4(buf, addr);
+                }
+            }
+            return Ok(());
+        }
+        self.punch_symmetric(udp_pipe_writer, predicted_ports, buf, ips, usize::MAX)
+            .await?;
+        let probes = self.birthday_punch.probes;
+        let start = self
+            .sym_record
+            .lock()
+            .get(&peer_id)
+            .cloned()
+            .unwrap_or_default();
+        let end = (start + probes).min(self.port_vec.len());
+        let mut index = start
+            + self
+                .punch_symmetric(
+                    udp_pipe_writer,
+                    &self.port_vec[start..end],
+                    buf,
+                    ips,
+                    probes,
+                )
+                .await?;
+        if index >= self.port_vec.len() {
+            index = 0
+        }
+        self.sym_record.lock().insert(peer_id, index);
+        Ok(())
+    }
+
     async fn punch_symmetric(
         &self,
         udp_pipe_writer: &UdpPipeWriter,
@@ -331,3 +602,77 @@ impl<PeerID: Hash + Eq + Clone> Puncher<PeerID> {
         Ok(ports.len())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::time::Instant;
+
+    use crate::nat::{NatInfo, NatType};
+    use crate::pipe::config::{TcpPipeConfig, UdpPipeConfig};
+    use crate::pipe::tcp_pipe::TcpPipe;
+    use crate::pipe::udp_pipe::UdpPipe;
+    use crate::punch::{PunchInfo, PunchModelBoxes, PunchStrategy, Puncher};
+    use crate::route::route_table::RouteTable;
+
+    /// The simultaneous open waits for its time on its own, the `UDP` punching goes out at once
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_punch_time_does_not_hold_up_udp() {
+        let udp_pipe = UdpPipe::new(
+            UdpPipeConfig::default()
+                .set_main_pipeline_num(1)
+                .set_use_v6(false),
+        )
+        .unwrap();
+        let tcp_pipe = TcpPipe::new(TcpPipeConfig::default()).unwrap();
+        let puncher: Puncher<u32> = Puncher::new(
+            RouteTable::new(false, 1),
+            Some(udp_pipe.writer_ref().to_owned()),
+            Some(tcp_pipe.writer_ref().to_owned()),
+        );
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // Nothing listens on the TCP port of the peer
+        let tcp_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let peer_nat_info = NatInfo {
+            nat_type: NatType::Cone,
+            public_ips: vec![Ipv4Addr::LOCALHOST],
+            public_ports: vec![],
+            mapping_tcp_addr: vec![],
+            mapping_udp_addr: vec![],
+            public_port_range: 0,
+            local_ipv4: Ipv4Addr::LOCALHOST,
+            ipv6: None,
+            local_udp_ports: vec![peer.local_addr().unwrap().port()],
+            local_tcp_port: 0,
+            public_tcp_port: tcp_port,
+            seq: 0,
+            mapping_behavior: Default::default(),
+            filtering_behavior: Default::default(),
+            hairpinning: None,
+            mapping_lifetime: None,
+            predicted_ports: vec![],
+        };
+        let tcp_punch_time = Instant::now() + Duration::from_secs(2);
+        let punch_info = PunchInfo::new(true, PunchModelBoxes::all(), peer_nat_info)
+            .set_tcp_punch_time(tcp_punch_time);
+        puncher.punch_now(1, b"punch", punch_info).await.unwrap();
+        assert!(Instant::now() < tcp_punch_time);
+        let mut buf = [0; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], b"punch");
+        let report = puncher.reports().get(&1).unwrap();
+        assert!(report
+            .strategies
+            .contains(&PunchStrategy::TcpSimultaneousOpen));
+    }
+}
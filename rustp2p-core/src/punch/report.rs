@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::route::route_table::RouteTable;
+use crate::route::{RouteClass, RouteKey};
+
+/// A way of reaching the peer tried while punching
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PunchStrategy {
+    /// Connecting to the mapped and public `TCP` addresses
+    Tcp,
+    /// Connecting at the same time as the peer
+    TcpSimultaneousOpen,
+    /// Sending to the mapping addresses announced by the peer
+    UdpMapping,
+    /// Sending to the addresses of the peer's LAN
+    UdpLan,
+    UdpIpv6,
+    /// One packet to the public address
+    UdpDirect,
+    /// Opening more mappings from the sub sockets
+    UdpMultipleMappings,
+    /// Guessing the public port of a symmetric nat
+    UdpPortPrediction,
+    /// Only the predicted ports of a symmetric nat, the local nat drops the replies from other ports
+    UdpPredictedPorts,
+    /// Both sides are symmetric
+    UdpBirthday,
+    /// Relaying through a TURN server
+    Turn,
+    /// Upgrading a punched `UDP` route to `QUIC`
+    Quic,
+}
+
+/// Diagnostics of punching one peer
+#[derive(Clone, Debug)]
+pub struct PunchReport {
+    /// The rounds of punching since the peer became unreachable directly
+    pub attempts: usize,
+    pub first_attempt: Instant,
+    pub last_attempt: Instant,
+    /// The strategies in the order they were first tried
+    pub strategies: Vec<PunchStrategy>,
+    /// The route on which the peer answered
+    pub answered: Option<RouteKey>,
+    /// From the first attempt to the answer
+    pub time_to_success: Option<Duration>,
+    /// How the peer is reached now, `None` if there is no route
+    pub route_class: Option<RouteClass>,
+}
+
+impl PunchReport {
+    fn new(now: Instant) -> Self {
+        Self {
+            attempts: 0,
+            first_attempt: now,
+            last_attempt: now,
+            strategies: Vec::new(),
+            answered: None,
+            time_to_success: None,
+            route_class: None,
+        }
+    }
+}
+
+/// The punch reports of all peers, shared by the [`Puncher`](super::Puncher) and the receiver
+pub struct PunchReports<PeerID> {
+    route_table: RouteTable<PeerID>,
+    reports: Arc<Mutex<HashMap<PeerID, PunchReport>>>,
+}
+
+impl<PeerID> Clone for PunchReports<PeerID> {
+    fn clone(&self) -> Self {
+        Self {
+            route_table: self.route_table.clone(),
+            reports: self.reports.clone(),
+        }
+    }
+}
+
+impl<PeerID> PunchReports<PeerID> {
+    pub(crate) fn new(route_table: RouteTable<PeerID>) -> Self {
+        Self {
+            route_table,
+            reports: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<PeerID: Hash + Eq + Clone> PunchReports<PeerID> {
+    /// A new round starts over once the previous rounds were answered
+    pub(crate) fn attempt(&self, peer_id: &PeerID) {
+        let now = Instant::now();
+        let mut guard = self.reports.lock();
+        guard.retain(|id, _| self.route_table.route_one(id).is_some());
+        let report = guard
+            .entry(peer_id.clone())
+            .or_insert_with(|| PunchReport::new(now));
+        if report.answered.is_some() {
+            *report = PunchReport::new(now);
+        }
+        report.attempts += 1;
+        report.last_attempt = now;
+    }
+    pub fn record(&self, peer_id: &PeerID, strategy: PunchStrategy) {
+        if let Some(report) = self.reports.lock().get_mut(peer_id) {
+            if !report.strategies.contains(&strategy) {
+                report.strategies.push(strategy);
+            }
+        }
+    }
+    /// A punch packet of the peer arrived directly on `route_key`
+    pub fn answered(&self, peer_id: &PeerID, route_key: RouteKey) {
+        let protocol = route_key.protocol();
+        if !protocol.is_udp() && !protocol.is_tcp() {
+            return;
+        }
+        if let Some(report) = self.reports.lock().get_mut(peer_id) {
+            if report.answered.is_none() {
+                report.answered = Some(route_key);
+                report.time_to_success = Some(report.first_attempt.elapsed());
+            }
+        }
+    }
+    pub fn get(&self, peer_id: &PeerID) -> Option<PunchReport> {
+        let mut report = self.reports.lock().get(peer_id).cloned()?;
+        report.route_class = self.route_table.route_one(peer_id).map(|v| v.class());
+        Some(report)
+    }
+    pub fn all(&self) -> Vec<(PeerID, PunchReport)> {
+        let reports: Vec<(PeerID, PunchReport)> = self
+            .reports
+            .lock()
+            .iter()
+            .map(|(id, report)| (id.clone(), report.clone()))
+            .collect();
+        reports
+            .into_iter()
+            .map(|(id, mut report)| {
+                report.route_class = self.route_table.route_one(&id).map(|v| v.class());
+                (id, report)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pipe::udp_pipe::UDPIndex;
+    use crate::punch::report::{PunchReports, PunchStrategy};
+    use crate::route::route_table::RouteTable;
+    use crate::route::{Index, Route, RouteClass, RouteKey};
+
+    #[test]
+    fn test_punch_report() {
+        let route_table = RouteTable::<u32>::new(false, 1);
+        let relay = RouteKey::new(
+            Index::Udp(UDPIndex::MainV4(0)),
+            "1.1.1.1:1".parse().unwrap(),
+        );
+        route_table.add_route(1, Route::from_default_rt(relay, 1));
+        let reports = PunchReports::new(route_table.clone());
+        reports.attempt(&1);
+        reports.record(&1, PunchStrategy::UdpDirect);
+        reports.attempt(&1);
+        reports.record(&1, PunchStrategy::UdpDirect);
+        let report = reports.get(&1).unwrap();
+        assert_eq!(report.attempts, 2);
+        assert_eq!(report.strategies, vec![PunchStrategy::UdpDirect]);
+        assert_eq!(report.route_class, Some(RouteClass::Relay));
+
+        let direct = RouteKey::new(
+            Index::Udp(UDPIndex::MainV4(0)),
+            "2.2.2.2:2".parse().unwrap(),
+        );
+        route_table.add_route(1, Route::from_default_rt(direct, 0));
+        reports.answered(&1, direct);
+        let report = reports.get(&1).unwrap();
+        assert_eq!(report.answered, Some(direct));
+        assert!(report.time_to_success.is_some());
+        assert_eq!(report.route_class, Some(RouteClass::Direct));
+
+        // A new round after the success
+        reports.attempt(&1);
+        assert_eq!(reports.get(&1).unwrap().attempts, 1);
+    }
+}
@@ -10,6 +10,7 @@ pub struct Route {
     addr: SocketAddr,
     metric: u8,
     rtt: u32,
+    mtu: Option<u16>,
 }
 impl Route {
     pub fn from(route_key: RouteKey, metric: u8, rtt: u32) -> Self {
@@ -18,6 +19,7 @@ impl Route {
             addr: route_key.addr,
             metric,
             rtt,
+            mtu: None,
         }
     }
     pub fn from_default_rt(route_key: RouteKey, metric: u8) -> Self {
@@ -26,6 +28,7 @@ impl Route {
             addr: route_key.addr,
             metric,
             rtt: DEFAULT_RTT,
+            mtu: None,
         }
     }
     pub fn route_key(&self) -> RouteKey {
@@ -36,13 +39,27 @@ impl Route {
     }
     pub fn sort_key(&self) -> RouteSortKey {
         RouteSortKey {
+            class: self.class(),
             metric: self.metric,
             rtt: self.rtt,
         }
     }
+    pub fn class(&self) -> RouteClass {
+        if self.metric > 0 {
+            RouteClass::Relay
+        } else if let Index::Turn(_) = self.index {
+            RouteClass::Turn
+        } else {
+            RouteClass::Direct
+        }
+    }
     pub fn is_direct(&self) -> bool {
         self.metric == 0
     }
+    /// The route is a direct channel to the peer through a TURN server
+    pub fn is_turn(&self) -> bool {
+        self.class() == RouteClass::Turn
+    }
     pub fn is_relay(&self) -> bool {
         self.metric > 0
     }
@@ -52,6 +69,10 @@ impl Route {
     pub fn metric(&self) -> u8 {
         self.metric
     }
+    /// The largest IP packet that passed the route, `None` until probed
+    pub fn mtu(&self) -> Option<u16> {
+        self.mtu
+    }
 }
 
 impl From<(RouteKey, u8)> for Route {
@@ -67,6 +88,10 @@ pub enum Index {
     Udp(UDPIndex),
     Tcp(usize),
     Extend(usize),
+    /// A channel allocated on a TURN server, written by the extensible pipe
+    Turn(usize),
+    /// A `QUIC` connection over one of the main `UDP` sockets
+    Quic(usize),
 }
 impl Index {
     pub fn index(&self) -> usize {
@@ -74,13 +99,16 @@ impl Index {
             Index::Udp(index) => index.index(),
             Index::Tcp(index) => *index,
             Index::Extend(index) => *index,
+            Index::Turn(index) => *index,
+            Index::Quic(index) => *index,
         }
     }
     pub fn protocol(&self) -> ConnectProtocol {
         match self {
             Index::Tcp(_) => ConnectProtocol::TCP,
             Index::Udp(_) => ConnectProtocol::UDP,
-            Index::Extend(_) => ConnectProtocol::Extend,
+            Index::Extend(_) | Index::Turn(_) => ConnectProtocol::Extend,
+            Index::Quic(_) => ConnectProtocol::QUIC,
         }
     }
 }
@@ -110,8 +138,17 @@ impl RouteKey {
         self.addr
     }
 }
+/// How the peer is reached, in the order of preference without `first_latency`,
+/// a TURN channel is the last resort
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub enum RouteClass {
+    Direct,
+    Relay,
+    Turn,
+}
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
 pub struct RouteSortKey {
+    class: RouteClass,
     metric: u8,
     rtt: u32,
 }
@@ -121,6 +158,7 @@ pub enum ConnectProtocol {
     UDP,
     TCP,
     Extend,
+    QUIC,
 }
 impl ConnectProtocol {
     #[inline]
@@ -131,4 +169,8 @@ impl ConnectProtocol {
     pub fn is_udp(&self) -> bool {
         self == &ConnectProtocol::UDP
     }
+    #[inline]
+    pub fn is_quic(&self) -> bool {
+        self == &ConnectProtocol::QUIC
+    }
 }
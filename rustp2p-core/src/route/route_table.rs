@@ -98,6 +98,19 @@ impl<PeerID: Hash + Eq + Clone> RouteTable<PeerID> {
         }
         false
     }
+    /// Store the path MTU found for the route, it is kept when the route is updated
+    pub fn update_mtu(&self, id: &PeerID, route_key: &RouteKey, mtu: u16) -> bool {
+        if let Some(mut entry) = self.route_table.get_mut(id) {
+            let (_, routes) = entry.value_mut();
+            for (route, _) in routes {
+                if &route.route_key() == route_key {
+                    route.mtu = Some(mtu);
+                    return true;
+                }
+            }
+        }
+        false
+    }
     /// Remove specified route
     pub fn remove_route(&self, id: &PeerID, route_key: &RouteKey) {
         self.route_table.remove_if_mut(id, |_, (_, routes)| {
@@ -165,7 +178,7 @@ impl<PeerID: Hash + Eq + Clone> RouteTable<PeerID> {
         if let Some(entry) = self.route_table.get(id) {
             let (_, routes) = entry.value();
             //p2p的通道数符合要求
-            return !routes.iter().any(|(k, _)| k.is_direct());
+            return !routes.iter().any(|(k, _)| k.is_direct() && !k.is_turn());
         }
         true
     }
@@ -175,7 +188,10 @@ impl<PeerID: Hash + Eq + Clone> RouteTable<PeerID> {
     pub fn p2p_num(&self, id: &PeerID) -> usize {
         if let Some(entry) = self.route_table.get(id) {
             let (_, routes) = entry.value();
-            routes.iter().filter(|(k, _)| k.is_direct()).count()
+            routes
+                .iter()
+                .filter(|(k, _)| k.is_direct() && !k.is_turn())
+                .count()
         } else {
             0
         }
@@ -320,7 +336,8 @@ impl<PeerID: Hash + Eq + Clone> RouteTable<PeerID> {
         let (peer_id, (_, list)) = route_table.pair_mut();
         let mut exist = false;
         for (x, time) in list.iter_mut() {
-            if x.metric < route.metric && !self.first_latency {
+            // A TURN channel does not keep out the relayed routes, it is the last resort
+            if x.metric < route.metric && !x.is_turn() && !self.first_latency {
                 //非优先延迟的情况下 不能比当前的路径更长
                 return false;
             }
@@ -336,13 +353,14 @@ impl<PeerID: Hash + Eq + Clone> RouteTable<PeerID> {
             }
         }
         if exist {
-            list.sort_by_key(|(k, _)| k.rtt);
+            // Relayed routes compete on rtt, a TURN channel is the last resort
+            list.sort_by_key(|(k, _)| (k.is_turn(), k.rtt));
         } else {
-            if !self.first_latency && route.is_direct() {
+            if !self.first_latency && route.is_direct() && !route.is_turn() {
                 //非优先延迟的情况下 添加了直连的则排除非直连的
                 list.retain(|(k, _)| k.is_direct());
             };
-            list.sort_by_key(|(k, _)| k.rtt);
+            list.sort_by_key(|(k, _)| (k.is_turn(), k.rtt));
             if route.is_direct() {
                 self.route_key_table
                     .insert(route.route_key(), peer_id.clone());
@@ -352,3 +370,74 @@ impl<PeerID: Hash + Eq + Clone> RouteTable<PeerID> {
         true
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::route::route_table::RouteTable;
+    use crate::route::{Index, Route, RouteKey};
+
+    fn route(index: Index, port: u16, metric: u8, rtt: u32) -> Route {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        Route::from(RouteKey::new(index, addr), metric, rtt)
+    }
+
+    #[test]
+    fn route_order() {
+        let direct = route(Index::Tcp(1), 1, 0, 50);
+        let relay = route(Index::Tcp(2), 2, 1, 20);
+        let turn = route(Index::Turn(3), 3, 0, 10);
+
+        // A faster relayed route comes first, the TURN channel comes last
+        let table = RouteTable::new(true, 1);
+        for route in [turn, direct, relay] {
+            table.add_route(1, route);
+        }
+        // A new route is appended, updating one sorts them all
+        table.add_route(1, direct);
+        let order: Vec<_> = table
+            .route(&1)
+            .unwrap()
+            .iter()
+            .map(|v| v.route_key())
+            .collect();
+        assert_eq!(
+            order,
+            vec![relay.route_key(), direct.route_key(), turn.route_key()]
+        );
+
+        // The relayed route is dropped for the direct one, which is preferred to the TURN channel
+        let table = RouteTable::new(false, 1);
+        for route in [relay, turn, direct] {
+            table.add_route(1, route);
+        }
+        table.add_route(1, direct);
+        let order: Vec<_> = table
+            .route(&1)
+            .unwrap()
+            .iter()
+            .map(|v| v.route_key())
+            .collect();
+        assert_eq!(order, vec![direct.route_key(), turn.route_key()]);
+
+        // Without a direct route, the TURN channel is kept behind the relayed route
+        let table = RouteTable::new(false, 1);
+        for route in [relay, turn] {
+            table.add_route(1, route);
+        }
+        let relay2 = route(Index::Tcp(4), 4, 1, 30);
+        assert!(table.add_route(1, relay2));
+        table.add_route(1, relay);
+        let order: Vec<_> = table
+            .route(&1)
+            .unwrap()
+            .iter()
+            .map(|v| v.route_key())
+            .collect();
+        assert_eq!(
+            order,
+            vec![relay.route_key(), relay2.route_key(), turn.route_key()]
+        );
+    }
+}
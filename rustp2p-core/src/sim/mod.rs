@@ -0,0 +1,922 @@
+/*
+  In-process simulated network
+
+  Virtual hosts exchange datagrams and message streams through one shared network state,
+  with emulated NATs, per-link latency, jitter, loss, MTU and partitions.
+  The packets reorder when the jitter is larger than their spacing.
+  A seeded network drops and delays the same packets on every run.
+
+  The `UDP` and `TCP` pipes run on a host of the network instead of the OS sockets,
+  see `UdpPipeConfig::set_sim_host` and `TcpPipeConfig::set_sim_host`.
+  The streams are byte streams to the `TCP` pipe and keep the boundaries of the writes
+  when they are added to the extensible pipe, the same way as the WebSocket connections.
+  The NAT mappings never expire, the streams skip the `TcpTransport`
+  and a host behind a NAT cannot be reached by `TCP`.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{Instant, Sleep};
+
+use crate::pipe::extensible_pipe::{ExtendRead, ExtendWrite, ExtensiblePipeWriter};
+use crate::route::RouteKey;
+
+const FIRST_MAPPED_PORT: u16 = 20000;
+const FIRST_EPHEMERAL_PORT: u16 = 40000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum NatType {
+    /// The host owns its public address
+    #[default]
+    Public,
+    /// One mapping per local port, anyone can send to it
+    Cone,
+    /// One mapping per local port, only the addresses it sent to can reply
+    PortRestricted,
+    /// One mapping per local port and destination, only the destination can reply
+    Symmetric,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConfig {
+    pub latency: Duration,
+    /// Every packet is delayed by up to `jitter` more
+    pub jitter: Duration,
+    /// The probability of a datagram being dropped, the streams are not lossy
+    pub loss: f64,
+    /// The largest IP packet of a datagram, the larger ones are dropped as if DF was set
+    pub mtu: Option<u16>,
+}
+
+impl LinkConfig {
+    pub fn new(latency: Duration) -> Self {
+        Self {
+            latency,
+            ..Default::default()
+        }
+    }
+    pub fn set_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+    pub fn set_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+    pub fn set_mtu(mut self, mtu: u16) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+}
+
+/// The shared state of the simulated network, hosts are addressed by their public `IP`
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    rng: StdRng,
+    default_link: LinkConfig,
+    links: HashMap<(IpAddr, IpAddr), LinkConfig>,
+    partitions: HashSet<(IpAddr, IpAddr)>,
+    hosts: HashMap<IpAddr, Host>,
+    host_num: u32,
+    /// Tells the streams apart in the route keys of the `TCP` pipe, never 0
+    stream_num: usize,
+}
+
+struct Host {
+    nat: NatType,
+    udp: HashMap<u16, UnboundedSender<(BytesMut, SocketAddr)>>,
+    listeners: HashMap<u16, UnboundedSender<(SimStream, SocketAddr)>>,
+    /// (local port, destination of a symmetric mapping) -> public port
+    mappings: HashMap<(u16, Option<SocketAddr>), u16>,
+    /// public port -> (local port, the addresses it sent to)
+    reverse_mappings: HashMap<u16, (u16, HashSet<SocketAddr>)>,
+    next_mapped_port: u16,
+    next_ephemeral_port: u16,
+}
+
+fn pair(a: IpAddr, b: IpAddr) -> (IpAddr, IpAddr) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                rng: StdRng::seed_from_u64(seed),
+                default_link: LinkConfig::default(),
+                links: HashMap::new(),
+                partitions: HashSet::new(),
+                hosts: HashMap::new(),
+                host_num: 0,
+                stream_num: 0,
+            })),
+        }
+    }
+    /// Add a host, a host behind a NAT gets a private local `IP`.
+    /// The public `IP`s are global, so the pipes take them as the addresses of the NATs
+    pub fn add_host(&self, nat: NatType) -> SimHost {
+        let mut state = self.state.lock();
+        state.host_num += 1;
+        let [_, _, high, low] = state.host_num.to_be_bytes();
+        let public_ip = IpAddr::V4(Ipv4Addr::new(11, 0, high, low));
+        let local_ip = if nat == NatType::Public {
+            public_ip
+        } else {
+            IpAddr::V4(Ipv4Addr::new(192, 168, high, low))
+        };
+        state.hosts.insert(
+            public_ip,
+            Host {
+                nat,
+                udp: HashMap::new(),
+                listeners: HashMap::new(),
+                mappings: HashMap::new(),
+                reverse_mappings: HashMap::new(),
+                next_mapped_port: FIRST_MAPPED_PORT,
+                next_ephemeral_port: FIRST_EPHEMERAL_PORT,
+            },
+        );
+        SimHost {
+            network: self.clone(),
+            public_ip,
+            local_ip,
+            nat,
+        }
+    }
+    /// The link between hosts without their own [`LinkConfig`]
+    pub fn set_default_link(&self, link: LinkConfig) {
+        self.state.lock().default_link = link;
+    }
+    pub fn set_link(&self, a: IpAddr, b: IpAddr, link: LinkConfig) {
+        self.state.lock().links.insert(pair(a, b), link);
+    }
+    /// Drop everything between the two hosts, the streams between them are reset
+    pub fn partition(&self, a: IpAddr, b: IpAddr) {
+        self.state.lock().partitions.insert(pair(a, b));
+    }
+    pub fn heal(&self, a: IpAddr, b: IpAddr) {
+        self.state.lock().partitions.remove(&pair(a, b));
+    }
+    pub fn heal_all(&self) {
+        self.state.lock().partitions.clear();
+    }
+}
+
+impl State {
+    fn is_partitioned(&self, a: IpAddr, b: IpAddr) -> bool {
+        self.partitions.contains(&pair(a, b))
+    }
+    fn link(&self, a: IpAddr, b: IpAddr) -> LinkConfig {
+        self.links
+            .get(&pair(a, b))
+            .copied()
+            .unwrap_or(self.default_link)
+    }
+    /// The `IPv4` and `UDP` headers count towards the MTU
+    fn exceeds_mtu(&self, a: IpAddr, b: IpAddr, len: usize) -> bool {
+        matches!(self.link(a, b).mtu, Some(mtu) if len + 28 > mtu as usize)
+    }
+    /// The delay of the next packet on the link, `None` if it is lost
+    fn delay(&mut self, a: IpAddr, b: IpAddr, lossy: bool) -> Option<Duration> {
+        let link = self.link(a, b);
+        if lossy && link.loss > 0.0 && self.rng.gen_bool(link.loss.min(1.0)) {
+            return None;
+        }
+        let jitter = if link.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=link.jitter)
+        };
+        Some(link.latency + jitter)
+    }
+    /// The public address of a local port sending to `dest`
+    fn map_outbound(
+        &mut self,
+        public_ip: IpAddr,
+        port: u16,
+        dest: SocketAddr,
+    ) -> io::Result<SocketAddr> {
+        let host = self
+            .hosts
+            .get_mut(&public_ip)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        let key = match host.nat {
+            NatType::Public => return Ok(SocketAddr::new(public_ip, port)),
+            NatType::Cone | NatType::PortRestricted => (port, None),
+            NatType::Symmetric => (port, Some(dest)),
+        };
+        let mapped_port = match host.mappings.get(&key) {
+            Some(mapped_port) => *mapped_port,
+            None => {
+                let mapped_port = host.next_mapped_port;
+                host.next_mapped_port = host
+                    .next_mapped_port
+                    .checked_add(1)
+                    .ok_or(io::ErrorKind::AddrNotAvailable)?;
+                host.mappings.insert(key, mapped_port);
+                host.reverse_mappings
+                    .insert(mapped_port, (port, HashSet::new()));
+                mapped_port
+            }
+        };
+        if let Some((_, sent_to)) = host.reverse_mappings.get_mut(&mapped_port) {
+            sent_to.insert(dest);
+        }
+        Ok(SocketAddr::new(public_ip, mapped_port))
+    }
+    /// The host and local port that receive from `src`, `None` if the NAT filters it
+    fn map_inbound(&self, src: SocketAddr, dest: SocketAddr) -> Option<(&Host, u16)> {
+        let host = self.hosts.get(&dest.ip())?;
+        let port = match host.nat {
+            NatType::Public => dest.port(),
+            nat => {
+                let (port, sent_to) = host.reverse_mappings.get(&dest.port())?;
+                if nat != NatType::Cone && !sent_to.contains(&src) {
+                    return None;
+                }
+                *port
+            }
+        };
+        Some((host, port))
+    }
+    fn ephemeral_port(&mut self, public_ip: IpAddr) -> io::Result<u16> {
+        let host = self
+            .hosts
+            .get_mut(&public_ip)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        loop {
+            let port = host.next_ephemeral_port;
+            host.next_ephemeral_port = host
+                .next_ephemeral_port
+                .checked_add(1)
+                .ok_or(io::ErrorKind::AddrNotAvailable)?;
+            if !host.udp.contains_key(&port) && !host.listeners.contains_key(&port) {
+                return Ok(port);
+            }
+        }
+    }
+}
+
+/// A host of the simulated network
+#[derive(Clone)]
+pub struct SimHost {
+    network: SimNetwork,
+    public_ip: IpAddr,
+    local_ip: IpAddr,
+    nat: NatType,
+}
+
+impl SimHost {
+    pub fn public_ip(&self) -> IpAddr {
+        self.public_ip
+    }
+    pub fn local_ip(&self) -> IpAddr {
+        self.local_ip
+    }
+    pub fn nat_type(&self) -> NatType {
+        self.nat
+    }
+    /// The number of mappings the NAT has allocated, a symmetric NAT allocates one per destination
+    pub fn mapping_num(&self) -> usize {
+        self.network
+            .state
+            .lock()
+            .hosts
+            .get(&self.public_ip)
+            .map_or(0, |host| host.mappings.len())
+    }
+    /// Bind a `UDP` socket, an ephemeral port is picked for port 0
+    pub fn bind_udp(&self, port: u16) -> io::Result<SimUdpSocket> {
+        let mut state = self.network.state.lock();
+        let port = if port == 0 {
+            state.ephemeral_port(self.public_ip)?
+        } else {
+            port
+        };
+        let host = state
+            .hosts
+            .get_mut(&self.public_ip)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        if host.udp.contains_key(&port) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (sender, receiver) = unbounded_channel();
+        host.udp.insert(port, sender);
+        Ok(SimUdpSocket {
+            host: self.clone(),
+            port,
+            receiver: tokio::sync::Mutex::new(receiver),
+        })
+    }
+    /// Listen for streams, an ephemeral port is picked for port 0
+    pub fn listen(&self, port: u16) -> io::Result<SimListener> {
+        let mut state = self.network.state.lock();
+        let port = if port == 0 {
+            state.ephemeral_port(self.public_ip)?
+        } else {
+            port
+        };
+        let host = state
+            .hosts
+            .get_mut(&self.public_ip)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        if host.listeners.contains_key(&port) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (sender, receiver) = unbounded_channel();
+        host.listeners.insert(port, sender);
+        Ok(SimListener {
+            host: self.clone(),
+            port,
+            receiver,
+        })
+    }
+    /// Open a stream to a listener, the connection takes a round trip.
+    /// The listener behind a NAT is only reached through a mapping that lets `addr` in
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<SimStream> {
+        let (local, delay) = {
+            let mut state = self.network.state.lock();
+            if state.is_partitioned(self.public_ip, addr.ip()) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let port = state.ephemeral_port(self.public_ip)?;
+            let src = state.map_outbound(self.public_ip, port, addr)?;
+            let delay = state
+                .delay(self.public_ip, addr.ip(), false)
+                .unwrap_or_default();
+            let (host, port) = state
+                .map_inbound(src, addr)
+                .ok_or(io::ErrorKind::ConnectionRefused)?;
+            let listener = host
+                .listeners
+                .get(&port)
+                .ok_or(io::ErrorKind::ConnectionRefused)?
+                .clone();
+            state.stream_num += 2;
+            let index = state.stream_num - 1;
+            let (local, remote) =
+                SimStream::pair(&self.network, index, (self.public_ip, addr.ip()));
+            listener
+                .send((remote, src))
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+            (local, delay)
+        };
+        tokio::time::sleep(delay * 2).await;
+        Ok(local)
+    }
+}
+
+/// A `UDP` socket of a simulated host
+pub struct SimUdpSocket {
+    host: SimHost,
+    port: u16,
+    receiver: tokio::sync::Mutex<UnboundedReceiver<(BytesMut, SocketAddr)>>,
+}
+
+impl Drop for SimUdpSocket {
+    fn drop(&mut self) {
+        if let Some(host) = self
+            .host
+            .network
+            .state
+            .lock()
+            .hosts
+            .get_mut(&self.host.public_ip)
+        {
+            host.udp.remove(&self.port);
+        }
+    }
+}
+
+impl std::fmt::Debug for SimUdpSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimUdpSocket")
+            .field("local_addr", &self.local_addr())
+            .finish()
+    }
+}
+
+impl SimUdpSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host.local_ip, self.port)
+    }
+    /// The datagrams that are lost, partitioned or filtered are dropped silently
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.try_send_to(buf, addr)
+    }
+    /// Never blocks, the network has no send buffer
+    pub fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let mut state = self.host.network.state.lock();
+        let src = state.map_outbound(self.host.public_ip, self.port, addr)?;
+        if state.is_partitioned(self.host.public_ip, addr.ip())
+            || state.exceeds_mtu(self.host.public_ip, addr.ip(), buf.len())
+        {
+            return Ok(buf.len());
+        }
+        let Some(delay) = state.delay(self.host.public_ip, addr.ip(), true) else {
+            return Ok(buf.len());
+        };
+        let Some(sender) = state
+            .map_inbound(src, addr)
+            .and_then(|(host, port)| host.udp.get(&port).cloned())
+        else {
+            return Ok(buf.len());
+        };
+        drop(state);
+        let packet = (BytesMut::from(buf), src);
+        if delay.is_zero() {
+            let _ = sender.send(packet);
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = sender.send(packet);
+            });
+        }
+        Ok(buf.len())
+    }
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (packet, addr) = self
+            .receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::NotConnected)?;
+        if packet.len() > buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too long"));
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok((packet.len(), addr))
+    }
+}
+
+pub struct SimListener {
+    host: SimHost,
+    port: u16,
+    receiver: UnboundedReceiver<(SimStream, SocketAddr)>,
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        if let Some(host) = self
+            .host
+            .network
+            .state
+            .lock()
+            .hosts
+            .get_mut(&self.host.public_ip)
+        {
+            host.listeners.remove(&self.port);
+        }
+    }
+}
+
+impl SimListener {
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host.local_ip, self.port)
+    }
+    pub async fn accept(&mut self) -> io::Result<(SimStream, SocketAddr)> {
+        self.receiver
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// A reliable, ordered stream that keeps the boundaries of the writes
+pub struct SimStream {
+    index: usize,
+    read: SimStreamRead,
+    write: SimStreamWrite,
+}
+
+pub struct SimStreamRead {
+    receiver: UnboundedReceiver<(Instant, BytesMut)>,
+    /// The write being read and the wait for its arrival
+    pending: Option<BytesMut>,
+    arrival: Option<Pin<Box<Sleep>>>,
+}
+
+pub struct SimStreamWrite {
+    network: SimNetwork,
+    local_ip: IpAddr,
+    peer_ip: IpAddr,
+    sender: Option<UnboundedSender<(Instant, BytesMut)>>,
+    last_arrival: Instant,
+}
+
+impl SimStream {
+    /// The streams get the indexes `index` and `index + 1`
+    fn pair(
+        network: &SimNetwork,
+        index: usize,
+        (a, b): (IpAddr, IpAddr),
+    ) -> (SimStream, SimStream) {
+        let (a_sender, a_receiver) = unbounded_channel();
+        let (b_sender, b_receiver) = unbounded_channel();
+        let now = Instant::now();
+        let stream = |index, local_ip, peer_ip, sender, receiver| SimStream {
+            index,
+            read: SimStreamRead {
+                receiver,
+                pending: None,
+                arrival: None,
+            },
+            write: SimStreamWrite {
+                network: network.clone(),
+                local_ip,
+                peer_ip,
+                sender: Some(sender),
+                last_arrival: now,
+            },
+        };
+        (
+            stream(index, a, b, b_sender, a_receiver),
+            stream(index + 1, b, a, a_sender, b_receiver),
+        )
+    }
+    /// Unique in the network and never 0
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    pub fn split(self) -> (SimStreamRead, SimStreamWrite) {
+        (self.read, self.write)
+    }
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read.read(buf).await
+    }
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write.write(buf).await
+    }
+}
+
+impl SimStreamRead {
+    /// Read one write of the peer, 0 when the peer is closed
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !poll_fn(|cx| self.poll_pending(cx)).await {
+            return Ok(0);
+        }
+        let data = self.pending.take().unwrap_or_default();
+        if data.len() > buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too long"));
+        }
+        let len = data.len();
+        buf[..len].copy_from_slice(&data);
+        Ok(len)
+    }
+    /// Ready when the rest of a write has arrived in `pending`, false when the peer is closed
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        if self.pending.is_none() {
+            let Some((arrival, data)) = ready!(self.receiver.poll_recv(cx)) else {
+                return Poll::Ready(false);
+            };
+            self.pending = Some(data);
+            self.arrival = Some(Box::pin(tokio::time::sleep_until(arrival)));
+        }
+        if let Some(arrival) = &mut self.arrival {
+            ready!(Future::poll(arrival.as_mut(), cx));
+            self.arrival = None;
+        }
+        Poll::Ready(true)
+    }
+}
+
+impl SimStreamWrite {
+    /// The writes fail once the hosts are partitioned
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write0(buf)
+    }
+    fn write0(&mut self, buf: &[u8]) -> io::Result<()> {
+        let delay = {
+            let mut state = self.network.state.lock();
+            if state.is_partitioned(self.local_ip, self.peer_ip) {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            state
+                .delay(self.local_ip, self.peer_ip, false)
+                .unwrap_or_default()
+        };
+        let sender = self.sender.as_ref().ok_or(io::ErrorKind::BrokenPipe)?;
+        // The jitter never reorders a stream
+        self.last_arrival = self.last_arrival.max(Instant::now() + delay);
+        sender
+            .send((self.last_arrival, BytesMut::from(buf)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+#[async_trait]
+impl ExtendRead for SimStreamRead {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match SimStreamRead::read(self, buf).await? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            len => Ok(len),
+        }
+    }
+}
+
+#[async_trait]
+impl ExtendWrite for SimStreamWrite {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write(buf).await
+    }
+}
+
+/// A byte stream to the `TCP` pipe, a write may be read in parts
+impl AsyncRead for SimStreamRead {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !ready!(this.poll_pending(cx)) {
+            return Poll::Ready(Ok(()));
+        }
+        let Some(data) = &mut this.pending else {
+            return Poll::Ready(Ok(()));
+        };
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data.split_to(len));
+        if data.is_empty() {
+            this.pending = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SimStreamWrite {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().write0(buf).map(|_| buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// The peer reads the end of the stream
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().sender.take();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Add a stream to the extensible pipe as a pipeline to `addr`
+pub async fn add_pipe(
+    stream: SimStream,
+    addr: SocketAddr,
+    pipe_writer: &ExtensiblePipeWriter,
+) -> anyhow::Result<RouteKey> {
+    let (read, write) = stream.split();
+    pipe_writer
+        .add_pipe(addr, Box::new(read), Box::new(write))
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use crate::pipe::config::{TcpPipeConfig, UdpPipeConfig};
+    use crate::pipe::extensible_pipe::ExtensiblePipe;
+    use crate::pipe::tcp_pipe::TcpPipe;
+    use crate::pipe::udp_pipe::UdpPipe;
+    use crate::sim::{add_pipe, LinkConfig, NatType, SimHost, SimNetwork, SimUdpSocket};
+
+    async fn recv_timeout(udp: &SimUdpSocket) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0; 64];
+        let (len, addr) = tokio::time::timeout(Duration::from_millis(50), udp.recv_from(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        Some((buf[..len].to_vec(), addr))
+    }
+
+    /// Learn the public addresses from a server, then send to each other and reply
+    async fn punch(a_nat: NatType, b_nat: NatType) -> bool {
+        let network = SimNetwork::new(1);
+        let server = network.add_host(NatType::Public).bind_udp(3478).unwrap();
+        let a = network.add_host(a_nat).bind_udp(0).unwrap();
+        let b = network.add_host(b_nat).bind_udp(0).unwrap();
+        let server_addr = server.local_addr();
+        let mut public_addrs = Vec::new();
+        for udp in [&a, &b] {
+            udp.send_to(b"stun", server_addr).await.unwrap();
+            public_addrs.push(recv_timeout(&server).await.unwrap().1);
+        }
+        let mut a_received = false;
+        let mut b_received = false;
+        for _ in 0..2 {
+            a.send_to(b"a", public_addrs[1]).await.unwrap();
+            b.send_to(b"b", public_addrs[0]).await.unwrap();
+            // Reply to where the packet came from, a symmetric NAT maps a new port
+            if let Some((_, addr)) = recv_timeout(&a).await {
+                a_received = true;
+                a.send_to(b"a", addr).await.unwrap();
+            }
+            if let Some((_, addr)) = recv_timeout(&b).await {
+                b_received = true;
+                b.send_to(b"b", addr).await.unwrap();
+            }
+        }
+        a_received && b_received
+    }
+
+    #[tokio::test]
+    async fn nat_punching() {
+        assert!(punch(NatType::Public, NatType::Cone).await);
+        assert!(punch(NatType::Cone, NatType::Cone).await);
+        assert!(punch(NatType::PortRestricted, NatType::PortRestricted).await);
+        assert!(punch(NatType::Cone, NatType::Symmetric).await);
+        assert!(!punch(NatType::PortRestricted, NatType::Symmetric).await);
+        assert!(!punch(NatType::Symmetric, NatType::Symmetric).await);
+    }
+
+    #[tokio::test]
+    async fn link_loss_jitter_partition() {
+        let network = SimNetwork::new(7);
+        let a_host = network.add_host(NatType::Public);
+        let b_host = network.add_host(NatType::Public);
+        let a = a_host.bind_udp(0).unwrap();
+        let b = b_host.bind_udp(0).unwrap();
+        let (a_ip, b_ip) = (a_host.public_ip(), b_host.public_ip());
+
+        network.set_link(a_ip, b_ip, LinkConfig::default().set_loss(0.5));
+        for i in 0..200u8 {
+            a.send_to(&[i], b.local_addr()).await.unwrap();
+        }
+        let mut received = 0;
+        while recv_timeout(&b).await.is_some() {
+            received += 1;
+        }
+        assert!((50..150).contains(&received), "{received}");
+
+        network.set_link(
+            a_ip,
+            b_ip,
+            LinkConfig::new(Duration::from_millis(5)).set_jitter(Duration::from_millis(20)),
+        );
+        for i in 0..50u8 {
+            a.send_to(&[i], b.local_addr()).await.unwrap();
+        }
+        let mut order = Vec::new();
+        while let Some((buf, _)) = recv_timeout(&b).await {
+            order.push(buf[0]);
+        }
+        assert_eq!(order.len(), 50);
+        assert!(order.windows(2).any(|v| v[0] > v[1]));
+
+        network.set_link(a_ip, b_ip, LinkConfig::default().set_mtu(60));
+        a.send_to(&[0; 33], b.local_addr()).await.unwrap();
+        a.send_to(&[0; 32], b.local_addr()).await.unwrap();
+        assert_eq!(recv_timeout(&b).await.unwrap().0.len(), 32);
+        assert!(recv_timeout(&b).await.is_none());
+
+        network.partition(a_ip, b_ip);
+        a.send_to(b"lost", b.local_addr()).await.unwrap();
+        assert!(recv_timeout(&b).await.is_none());
+        network.heal_all();
+        a.send_to(b"healed", b.local_addr()).await.unwrap();
+        assert_eq!(recv_timeout(&b).await.unwrap().0, b"healed");
+    }
+
+    #[tokio::test]
+    async fn stream_over_extensible_pipe() {
+        let network = SimNetwork::new(3);
+        network.set_default_link(LinkConfig::new(Duration::from_millis(2)));
+        let server_host = network.add_host(NatType::Public);
+        let client_host = network.add_host(NatType::Symmetric);
+        let mut listener = server_host.listen(8080).unwrap();
+        let server_addr = SocketAddr::new(server_host.public_ip(), 8080);
+        // A listener behind a NAT is not reachable without a mapping
+        let nat_host = network.add_host(NatType::Cone);
+        let _nat_listener = nat_host.listen(8080).unwrap();
+        assert!(client_host
+            .connect(SocketAddr::new(nat_host.public_ip(), 8080))
+            .await
+            .is_err());
+
+        let mut server_pipe = ExtensiblePipe::new();
+        let mut client_pipe = ExtensiblePipe::new();
+        let client_stream = client_host.connect(server_addr).await.unwrap();
+        let (server_stream, client_addr) = listener.accept().await.unwrap();
+        assert_eq!(client_addr.ip(), client_host.public_ip());
+
+        let client_writer = client_pipe.writer_ref().to_owned();
+        let route_key = add_pipe(client_stream, server_addr, &client_writer)
+            .await
+            .unwrap();
+        add_pipe(
+            server_stream,
+            client_addr,
+            &server_pipe.writer_ref().to_owned(),
+        )
+        .await
+        .unwrap();
+        let mut client_line = client_pipe.accept().await.unwrap();
+        let mut server_line = server_pipe.accept().await.unwrap();
+        client_writer
+            .send_to(b"hello"[..].into(), &route_key)
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        let (len, server_route_key) = server_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        server_line
+            .send_to(b"world"[..].into(), &server_route_key)
+            .await
+            .unwrap();
+        let (len, _) = client_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"world");
+
+        let mut client_stream = client_host.connect(server_addr).await.unwrap();
+        network.partition(client_host.public_ip(), server_host.public_ip());
+        assert!(client_stream.write(b"reset").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pipes_on_sim_hosts() {
+        let network = SimNetwork::new(4);
+        network.set_default_link(LinkConfig::new(Duration::from_millis(2)));
+        let server_host = network.add_host(NatType::Public);
+        let client_host = network.add_host(NatType::PortRestricted);
+
+        let mut server_tcp =
+            TcpPipe::new(TcpPipeConfig::default().set_sim_host(server_host.clone())).unwrap();
+        let mut client_tcp =
+            TcpPipe::new(TcpPipeConfig::default().set_sim_host(client_host.clone())).unwrap();
+        let server_addr = SocketAddr::new(
+            server_host.public_ip(),
+            server_tcp.writer_ref().local_addr().port(),
+        );
+        client_tcp
+            .writer_ref()
+            .to_owned()
+            .send_to_addr(b"hello"[..].into(), server_addr)
+            .await
+            .unwrap();
+        let mut server_line = server_tcp.accept().await.unwrap();
+        let mut buf = [0; 64];
+        let (len, route_key) = server_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(route_key.addr().ip(), client_host.public_ip());
+        server_line.send(b"world"[..].into()).await.unwrap();
+        let mut client_line = client_tcp.accept().await.unwrap();
+        let (len, _) = client_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"world");
+        assert!(client_tcp
+            .writer_ref()
+            .connect_reuse_port_raw(server_addr)
+            .await
+            .is_err());
+
+        let udp_config = |host: &SimHost| {
+            UdpPipeConfig::default()
+                .set_use_v6(false)
+                .set_sim_host(host.clone())
+        };
+        let mut server_udp = UdpPipe::new(udp_config(&server_host)).unwrap();
+        let mut client_udp = UdpPipe::new(udp_config(&client_host)).unwrap();
+        let server_addr = SocketAddr::new(
+            server_host.public_ip(),
+            server_udp.writer_ref().local_ports().unwrap()[0],
+        );
+        client_udp
+            .writer_ref()
+            .send_to_addr(b"hello", server_addr)
+            .await
+            .unwrap();
+        let mut server_line = server_udp.accept().await.unwrap();
+        let _client_line = client_udp.accept().await.unwrap();
+        let (len, route_key) = server_line.recv_from(&mut buf).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(route_key.addr().ip(), client_host.public_ip());
+    }
+}
@@ -2,7 +2,10 @@
 use crate::socket::windows::ignore_conn_reset;
 use anyhow::Context;
 use socket2::Protocol;
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 #[cfg(unix)]
 mod unix;
@@ -34,6 +37,22 @@ impl LocalInterface {
     }
 }
 
+/// A peer or a proxy that accepts the connection but never answers holds neither a task nor the dialer
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) async fn handshake_timeout<T, E: From<io::Error>>(
+    name: &str,
+    handshake: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(rs) => rs,
+        Err(_) => Err(E::from(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{name} handshake timeout"),
+        ))),
+    }
+}
+
 #[allow(dead_code)]
 pub(crate) async fn connect_tcp(
     addr: SocketAddr,
@@ -155,6 +174,40 @@ pub(crate) fn bind_udp_ops(
     Ok(socket)
 }
 
+/// Mark the packets sent by this socket with the DSCP value
+pub(crate) fn set_dscp(socket: &socket2::Socket, v4: bool, dscp: u8) -> std::io::Result<()> {
+    let tos = (dscp as u32) << 2;
+    if v4 {
+        socket.set_tos(tos)
+    } else {
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos"
+        ))]
+        socket.set_tclass_v6(tos)?;
+        Ok(())
+    }
+}
+
+/// Set or clear the Don't Fragment bit, the packets over the path MTU are dropped on the way instead of fragmented
+pub(crate) fn set_dont_fragment(
+    socket: &socket2::Socket,
+    v4: bool,
+    dont_fragment: bool,
+) -> std::io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return unix::set_dont_fragment(socket, v4, dont_fragment);
+    #[cfg(windows)]
+    return windows::set_dont_fragment(socket, v4, dont_fragment);
+    #[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+    {
+        let _ = (socket, v4, dont_fragment);
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
 pub fn bind_udp(
     addr: SocketAddr,
     default_interface: Option<&LocalInterface>,
@@ -22,3 +22,50 @@ impl VntSocketTrait for socket2::Socket {
         Ok(())
     }
 }
+
+/// `IP_PMTUDISC_PROBE` sets DF and ignores the cached path MTU,
+/// so the probes larger than it still leave.
+/// `IP_PMTUDISC_WANT` is the default of the kernel
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_dont_fragment(
+    socket: &socket2::Socket,
+    v4: bool,
+    dont_fragment: bool,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let (level, name, value) = match (v4, dont_fragment) {
+        (true, true) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        ),
+        (true, false) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_WANT,
+        ),
+        (false, true) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        ),
+        (false, false) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_WANT,
+        ),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -3,7 +3,8 @@ use std::os::windows::io::AsRawSocket;
 
 use windows_sys::core::PCSTR;
 use windows_sys::Win32::Networking::WinSock::{
-    htonl, setsockopt, WSAIoctl, IPPROTO_IP, IP_UNICAST_IF, SIO_UDP_CONNRESET, SOCKET_ERROR,
+    htonl, setsockopt, WSAIoctl, IPPROTO_IP, IPPROTO_IPV6, IPV6_DONTFRAG, IP_DONTFRAGMENT,
+    IP_UNICAST_IF, SIO_UDP_CONNRESET, SOCKET_ERROR,
 };
 
 use crate::socket::{LocalInterface, VntSocketTrait};
@@ -54,3 +55,29 @@ pub(crate) fn ignore_conn_reset(socket: &socket2::Socket) -> io::Result<()> {
     }
     Ok(())
 }
+
+pub(crate) fn set_dont_fragment(
+    socket: &socket2::Socket,
+    v4: bool,
+    dont_fragment: bool,
+) -> io::Result<()> {
+    let (level, name) = if v4 {
+        (IPPROTO_IP, IP_DONTFRAGMENT)
+    } else {
+        (IPPROTO_IPV6, IPV6_DONTFRAG)
+    };
+    let value = dont_fragment as u32;
+    let result = unsafe {
+        setsockopt(
+            socket.as_raw_socket() as usize,
+            level,
+            name,
+            &value as *const _ as PCSTR,
+            std::mem::size_of_val(&value) as i32,
+        )
+    };
+    if result == SOCKET_ERROR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
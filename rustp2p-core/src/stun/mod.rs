@@ -1,184 +1,590 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::Duration;
 
-use crate::nat::NatType;
+use crate::nat::{NatBehavior, NatType};
+use crate::pipe::udp_pipe::PipeUdpSocket;
+#[cfg(feature = "sim")]
+use crate::sim::SimHost;
 use crate::socket::{bind_udp, LocalInterface};
 use rand::RngCore;
-use stun_format::Attr;
+use stun_format::{Attr, MsgType};
 use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+/// The idle durations probed by `stun_test_mapping_lifetime` by default.
+/// The probes run concurrently, so the test takes about as long as the longest one
+pub const MAPPING_LIFETIME_PROBES: [Duration; 4] = [
+    Duration::from_secs(5),
+    Duration::from_secs(15),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+];
+
+/// Nat behaviour discovered according to RFC 5780
+#[derive(Clone, Debug, Default)]
+pub struct NatTestResult {
+    pub nat_type: NatType,
+    pub public_ips: Vec<Ipv4Addr>,
+    pub port_range: u16,
+    pub mapping_behavior: NatBehavior,
+    pub filtering_behavior: NatBehavior,
+    pub hairpinning: Option<bool>,
+    /// The next public ports predicted from the allocation pattern of a symmetric nat
+    pub predicted_ports: Vec<u16>,
+}
+
+impl NatTestResult {
+    fn merge(&mut self, other: NatTestResult) {
+        if other.nat_type == NatType::Symmetric {
+            self.nat_type = NatType::Symmetric;
+        }
+        for ip in other.public_ips {
+            if !self.public_ips.contains(&ip) {
+                self.public_ips.push(ip);
+            }
+        }
+        self.port_range = self.port_range.max(other.port_range);
+        self.mapping_behavior = self.mapping_behavior.merge(other.mapping_behavior);
+        self.filtering_behavior = self.filtering_behavior.merge(other.filtering_behavior);
+        // A single packet looped back proves that hairpinning works
+        self.hairpinning = match (self.hairpinning, other.hairpinning) {
+            (Some(a), Some(b)) => Some(a || b),
+            (a, b) => a.or(b),
+        };
+    }
+}
 
 /// Obtain nat information with the option specified interface from the stun servers
 pub async fn stun_test_nat(
     stun_servers: Vec<String>,
     default_interface: Option<&LocalInterface>,
 ) -> anyhow::Result<(NatType, Vec<Ipv4Addr>, u16)> {
-    let mut nat_type = NatType::Cone;
-    let mut port_range = 0;
-    let mut hash_set = HashSet::new();
+    let rs = stun_test_nat_behavior(stun_servers, default_interface).await?;
+    Ok((rs.nat_type, rs.public_ips, rs.port_range))
+}
+
+/// Like `stun_test_nat`, but additionally discovers the mapping, filtering and hairpinning behaviour.
+/// The behaviours stay unknown if none of the stun servers supports RFC 5780
+pub async fn stun_test_nat_behavior(
+    stun_servers: Vec<String>,
+    default_interface: Option<&LocalInterface>,
+) -> anyhow::Result<NatTestResult> {
+    stun_test_nat_behavior0(stun_servers, Binder::Os(default_interface)).await
+}
+
+/// `stun_test_nat_behavior` from a host of the simulated network
+#[cfg(feature = "sim")]
+pub async fn sim_stun_test_nat_behavior(
+    stun_servers: Vec<String>,
+    sim_host: &SimHost,
+) -> anyhow::Result<NatTestResult> {
+    stun_test_nat_behavior0(stun_servers, Binder::Sim(sim_host)).await
+}
+
+/// Where the sockets of the tests are bound
+#[derive(Clone, Copy)]
+enum Binder<'a> {
+    Os(Option<&'a LocalInterface>),
+    #[cfg(feature = "sim")]
+    Sim(&'a SimHost),
+}
+
+impl Binder<'_> {
+    fn bind(&self) -> anyhow::Result<PipeUdpSocket> {
+        match self {
+            Binder::Os(default_interface) => {
+                let udp = bind_udp("0.0.0.0:0".parse().unwrap(), *default_interface)?;
+                Ok(PipeUdpSocket::Os(UdpSocket::from_std(udp.into())?))
+            }
+            #[cfg(feature = "sim")]
+            Binder::Sim(sim_host) => Ok(PipeUdpSocket::Sim(sim_host.bind_udp(0)?)),
+        }
+    }
+}
+
+async fn stun_test_nat_behavior0(
+    stun_servers: Vec<String>,
+    binder: Binder<'_>,
+) -> anyhow::Result<NatTestResult> {
+    let mut result = NatTestResult::default();
     for _ in 0..2 {
         let stun_servers = stun_servers.clone();
-        match stun_test_nat0(stun_servers, default_interface).await {
-            Ok((nat_type_t, ip_list_t, port_range_t)) => {
-                if nat_type_t == NatType::Symmetric {
-                    nat_type = NatType::Symmetric;
-                }
-                for x in ip_list_t {
-                    hash_set.insert(x);
-                }
-                if port_range < port_range_t {
-                    port_range = port_range_t;
-                }
-            }
+        match stun_test_nat0(stun_servers, binder).await {
+            Ok(rs) => result.merge(rs),
             Err(e) => {
                 log::warn!("{:?}", e);
             }
         }
     }
-    Ok((nat_type, hash_set.into_iter().collect(), port_range))
+    if result.nat_type == NatType::Symmetric {
+        match predict_ports0(&stun_servers, binder).await {
+            Ok(ports) => result.predicted_ports = ports,
+            Err(e) => log::warn!("stun_predict_ports {:?}", e),
+        }
+    }
+    Ok(result)
 }
 
-pub(crate) async fn stun_test_nat0(
-    stun_servers: Vec<String>,
+/// Samples how a symmetric nat allocates ports and predicts the next ones, see `predict_ports`.
+/// Other flows take ports too, so the prediction is only good shortly before punching
+pub async fn stun_predict_ports(
+    stun_servers: &[String],
     default_interface: Option<&LocalInterface>,
-) -> anyhow::Result<(NatType, Vec<Ipv4Addr>, u16)> {
-    let udp = bind_udp("0.0.0.0:0".parse().unwrap(), default_interface)?;
-    let udp = UdpSocket::from_std(udp.into())?;
-    let mut nat_type = NatType::Cone;
-    let mut min_port = u16::MAX;
-    let mut max_port = 0;
-    let mut hash_set = HashSet::new();
+) -> anyhow::Result<Vec<u16>> {
+    predict_ports0(stun_servers, Binder::Os(default_interface)).await
+}
+
+async fn predict_ports0(stun_servers: &[String], binder: Binder<'_>) -> anyhow::Result<Vec<u16>> {
+    for stun_server in stun_servers {
+        match test_port_sequence(stun_server, PREDICTION_SAMPLES, binder).await {
+            Ok(ports) => {
+                let predicted_ports = predict_ports(&ports, PREDICTED_PORTS);
+                log::debug!(
+                    "stun {} port sequence {:?} predicted {:?}",
+                    stun_server,
+                    ports,
+                    predicted_ports
+                );
+                return Ok(predicted_ports);
+            }
+            Err(e) => {
+                log::warn!("stun {} error {:?} ", stun_server, e);
+            }
+        }
+    }
+    Err(anyhow::anyhow!("no stun server sampled the port sequence"))
+}
+
+/// The number of consecutive mappings sampled for the port prediction
+const PREDICTION_SAMPLES: usize = 5;
+/// The number of ports predicted
+const PREDICTED_PORTS: usize = 20;
+
+/// Record the public ports of consecutive new flows in the order the nat allocates them
+async fn test_port_sequence(
+    stun_server: &str,
+    samples: usize,
+    binder: Binder<'_>,
+) -> anyhow::Result<Vec<u16>> {
+    let server = lookup_ipv4(stun_server).await?;
+    let mut ports = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        let udp = binder.bind()?;
+        let tid = rand::thread_rng().next_u64() as u128;
+        let response = test_nat_(&udp, server, false, false, tid, REQUEST_TIMEOUT).await?;
+        ports.push(response.mapped_addr.port());
+    }
+    Ok(ports)
+}
+
+/// Detect the increment of consecutively allocated ports and predict the following `count` ports.
+/// Other flows passing the nat in between may skip ports, so the most frequent increment is used.
+/// Returns an empty list if there is no clear pattern
+pub fn predict_ports(ports: &[u16], count: usize) -> Vec<u16> {
+    if ports.len() < 3 {
+        return vec![];
+    }
+    let mut strides: HashMap<i32, usize> = HashMap::new();
+    for pair in ports.windows(2) {
+        *strides.entry(pair[1] as i32 - pair[0] as i32).or_default() += 1;
+    }
+    let Some((stride, num)) = strides.into_iter().max_by_key(|(_, num)| *num) else {
+        return vec![];
+    };
+    if stride == 0 || num * 2 < ports.len() {
+        return vec![];
+    }
+    let last = ports[ports.len() - 1] as i32;
+    (1..=count as i32)
+        .map(|i| last + stride * i)
+        .take_while(|port| (1..=u16::MAX as i32).contains(port))
+        .map(|port| port as u16)
+        .collect()
+}
+
+async fn stun_test_nat0(
+    stun_servers: Vec<String>,
+    binder: Binder<'_>,
+) -> anyhow::Result<NatTestResult> {
+    let udp = binder.bind()?;
+    let mut responded = 0;
+    let mut mapping_behavior = NatBehavior::Unknown;
+    let mut filtering_behavior = NatBehavior::Unknown;
     let mut pub_addrs = HashSet::new();
     for x in &stun_servers {
-        match test_nat(&udp, x).await {
-            Ok(addr) => {
-                pub_addrs.extend(addr);
+        match test_nat(&udp, x, filtering_behavior.is_unknown()).await {
+            Ok(rs) => {
+                responded += 1;
+                pub_addrs.extend(rs.mapped_addrs);
+                mapping_behavior = mapping_behavior.merge(rs.mapping_behavior);
+                filtering_behavior = filtering_behavior.merge(rs.filtering_behavior);
             }
             Err(e) => {
                 log::warn!("stun {} error {:?} ", x, e);
             }
         }
     }
+    let hairpinning = match pub_addrs.iter().find(|addr| addr.is_ipv4()) {
+        Some(addr) => test_hairpinning(&udp, *addr).await.ok(),
+        None => None,
+    };
+    Ok(summarize(
+        responded,
+        &pub_addrs,
+        mapping_behavior,
+        filtering_behavior,
+        hairpinning,
+    ))
+}
+
+fn summarize(
+    responded: usize,
+    pub_addrs: &HashSet<SocketAddr>,
+    mut mapping_behavior: NatBehavior,
+    filtering_behavior: NatBehavior,
+    hairpinning: Option<bool>,
+) -> NatTestResult {
     if pub_addrs.len() > 1 {
-        nat_type = NatType::Symmetric;
+        // The servers have different IPs, so it can not be told whether the mapping depends on the port
+        if mapping_behavior <= NatBehavior::EndpointIndependent {
+            mapping_behavior = NatBehavior::AddressAndPortDependent;
+        }
+    } else if pub_addrs.len() == 1 && responded > 1 && mapping_behavior.is_unknown() {
+        mapping_behavior = NatBehavior::EndpointIndependent;
     }
-    for addr in &pub_addrs {
+    let nat_type = if mapping_behavior > NatBehavior::EndpointIndependent {
+        NatType::Symmetric
+    } else {
+        NatType::Cone
+    };
+    let mut public_ips = Vec::new();
+    let mut min_port = u16::MAX;
+    let mut max_port = 0;
+    for addr in pub_addrs {
         if let SocketAddr::V4(addr) = addr {
-            hash_set.insert(*addr.ip());
-            if min_port > addr.port() {
-                min_port = addr.port()
-            }
-            if max_port < addr.port() {
-                max_port = addr.port()
+            if !public_ips.contains(addr.ip()) {
+                public_ips.push(*addr.ip());
             }
+            min_port = min_port.min(addr.port());
+            max_port = max_port.max(addr.port());
         }
     }
-    if hash_set.is_empty() {
-        Ok((nat_type, vec![], 0))
+    let port_range = if public_ips.is_empty() {
+        0
     } else {
-        Ok((
-            nat_type,
-            hash_set.into_iter().collect(),
-            max_port - min_port,
-        ))
+        max_port - min_port
+    };
+    NatTestResult {
+        nat_type,
+        public_ips,
+        port_range,
+        mapping_behavior,
+        filtering_behavior,
+        hairpinning,
+        predicted_ports: vec![],
+    }
+}
+
+/// Estimate how long an idle `UDP` mapping stays alive.
+/// Every probe uses its own socket, which stays silent for the probe duration and then queries the
+/// stun server again. The mapping is considered alive if the server observes the same address,
+/// so a nat that recreates the expired mapping on the same port is indistinguishable.
+/// Returns the longest probe that all shorter probes survived
+pub async fn stun_test_mapping_lifetime(
+    stun_server: String,
+    probes: &[Duration],
+    default_interface: Option<&LocalInterface>,
+) -> anyhow::Result<Option<Duration>> {
+    let server = lookup_ipv4(&stun_server).await?;
+    let mut probes = probes.to_vec();
+    probes.sort();
+    let tasks = probes.iter().map(|&probe| async move {
+        let udp = Binder::Os(default_interface).bind()?;
+        let tid = rand::thread_rng().next_u64() as u128;
+        let before = test_nat_(&udp, server, false, false, tid, REQUEST_TIMEOUT).await?;
+        tokio::time::sleep(probe).await;
+        let after = test_nat_(&udp, server, false, false, tid + 1, REQUEST_TIMEOUT).await?;
+        anyhow::Ok(before.mapped_addr == after.mapped_addr)
+    });
+    let results = futures::future::join_all(tasks).await;
+    let mut lifetime = None;
+    for (probe, alive) in probes.into_iter().zip(results) {
+        if !alive? {
+            break;
+        }
+        lifetime.replace(probe);
     }
+    log::info!("stun {} mapping lifetime {:?}", stun_server, lifetime);
+    Ok(lifetime)
+}
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+/// The filtering tests expect that responses are possibly dropped by the nat, so they fail faster
+const FILTERING_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct ServerTestResult {
+    mapped_addrs: HashSet<SocketAddr>,
+    mapping_behavior: NatBehavior,
+    filtering_behavior: NatBehavior,
 }
 
-async fn test_nat(udp: &UdpSocket, stun_server: &String) -> io::Result<HashSet<SocketAddr>> {
-    udp.connect(stun_server).await?;
+struct StunResponse {
+    mapped_addr: SocketAddr,
+    other_addr: Option<SocketAddr>,
+    source: SocketAddr,
+}
+
+async fn lookup_ipv4(stun_server: &str) -> io::Result<SocketAddr> {
+    tokio::net::lookup_host(stun_server)
+        .await?
+        .find(|addr| addr.is_ipv4())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no ipv4 address"))
+}
+
+fn is_timeout(rs: &io::Result<StunResponse>) -> bool {
+    matches!(rs, Err(e) if e.kind() == io::ErrorKind::TimedOut)
+}
+
+async fn test_nat(
+    udp: &PipeUdpSocket,
+    stun_server: &str,
+    test_filtering: bool,
+) -> io::Result<ServerTestResult> {
+    let server = lookup_ipv4(stun_server).await?;
     let tid = rand::thread_rng().next_u64() as u128;
-    let mut addr = HashSet::new();
-    let (mapped_addr1, changed_addr1) = test_nat_(udp, stun_server, true, true, tid).await?;
+    let mut rs = ServerTestResult {
+        mapped_addrs: HashSet::new(),
+        mapping_behavior: NatBehavior::Unknown,
+        filtering_behavior: NatBehavior::Unknown,
+    };
+    let response1 = test_nat_(udp, server, false, false, tid, REQUEST_TIMEOUT).await?;
+    let mapped_addr1 = response1.mapped_addr;
     if mapped_addr1.is_ipv4() {
-        addr.insert(mapped_addr1);
-    }
-    if let Some(changed_addr1) = changed_addr1 {
-        if udp.connect(changed_addr1).await.is_ok() {
-            match test_nat_(udp, stun_server, false, false, tid + 1).await {
-                Ok((mapped_addr2, _)) => {
-                    if mapped_addr2.is_ipv4() {
-                        addr.insert(mapped_addr1);
+        rs.mapped_addrs.insert(mapped_addr1);
+    }
+    let other_addr = match response1.other_addr {
+        Some(addr) if addr.ip() != server.ip() && addr.port() != server.port() => addr,
+        // A server with a single IP, such as a node of the mesh
+        Some(addr) if addr.ip() == server.ip() && addr.port() != server.port() => {
+            test_nat_port_only(
+                udp,
+                server,
+                addr,
+                mapped_addr1,
+                test_filtering,
+                tid,
+                &mut rs,
+            )
+            .await?;
+            log::info!(
+                "stun {} mapped_addr {:?} other_port {} mapping {:?} filtering {:?}",
+                stun_server,
+                rs.mapped_addrs,
+                addr.port(),
+                rs.mapping_behavior,
+                rs.filtering_behavior,
+            );
+            return Ok(rs);
+        }
+        _ => {
+            log::info!("stun {} does not support behaviour discovery", stun_server);
+            return Ok(rs);
+        }
+    };
+    // The filtering tests must precede the mapping tests,
+    // the nat would accept the responses from the other address once it has been sent to
+    if test_filtering {
+        let response2 = test_nat_(udp, server, true, true, tid + 1, FILTERING_TIMEOUT).await;
+        rs.filtering_behavior = if is_timeout(&response2) {
+            let response3 = test_nat_(udp, server, false, true, tid + 2, FILTERING_TIMEOUT).await;
+            if is_timeout(&response3) {
+                NatBehavior::AddressAndPortDependent
+            } else if response3?.source == server {
+                NatBehavior::Unknown
+            } else {
+                NatBehavior::AddressDependent
+            }
+        } else if response2?.source == server {
+            // The server ignored the change request
+            NatBehavior::Unknown
+        } else {
+            NatBehavior::EndpointIndependent
+        };
+    }
+    let alternate = SocketAddr::new(other_addr.ip(), server.port());
+    match test_nat_(udp, alternate, false, false, tid + 3, REQUEST_TIMEOUT).await {
+        Ok(response2) => {
+            let mapped_addr2 = response2.mapped_addr;
+            if mapped_addr2.is_ipv4() {
+                rs.mapped_addrs.insert(mapped_addr2);
+            }
+            rs.mapping_behavior = if mapped_addr2 == mapped_addr1 {
+                NatBehavior::EndpointIndependent
+            } else {
+                match test_nat_(udp, other_addr, false, false, tid + 4, REQUEST_TIMEOUT).await {
+                    Ok(response3) => {
+                        if response3.mapped_addr.is_ipv4() {
+                            rs.mapped_addrs.insert(response3.mapped_addr);
+                        }
+                        if response3.mapped_addr == mapped_addr2 {
+                            NatBehavior::AddressDependent
+                        } else {
+                            NatBehavior::AddressAndPortDependent
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("stun {} error {:?} ", other_addr, e);
+                        NatBehavior::Unknown
                     }
                 }
-                Err(e) => {
-                    log::warn!("stun {} error {:?} ", stun_server, e);
-                }
-            }
+            };
+        }
+        Err(e) => {
+            log::warn!("stun {} error {:?} ", alternate, e);
         }
     }
     log::info!(
-        "stun {} mapped_addr {:?}  changed_addr {:?}",
+        "stun {} mapped_addr {:?} other_addr {:?} mapping {:?} filtering {:?}",
         stun_server,
-        addr,
-        changed_addr1,
+        rs.mapped_addrs,
+        other_addr,
+        rs.mapping_behavior,
+        rs.filtering_behavior,
     );
 
-    Ok(addr)
+    Ok(rs)
+}
+
+/// The behaviour discovery with a server that only changes the port.
+/// It tells whether the behaviours depend on the port, the rest is left open:
+/// the filtering that lets the other port in is taken as `AddressDependent`,
+/// and the mapping that stays the same is unknown
+async fn test_nat_port_only(
+    udp: &PipeUdpSocket,
+    server: SocketAddr,
+    other_addr: SocketAddr,
+    mapped_addr1: SocketAddr,
+    test_filtering: bool,
+    tid: u128,
+    rs: &mut ServerTestResult,
+) -> io::Result<()> {
+    if test_filtering {
+        let response2 = test_nat_(udp, server, false, true, tid + 1, FILTERING_TIMEOUT).await;
+        rs.filtering_behavior = if is_timeout(&response2) {
+            NatBehavior::AddressAndPortDependent
+        } else if response2?.source == server {
+            // The server ignored the change request
+            NatBehavior::Unknown
+        } else {
+            NatBehavior::AddressDependent
+        };
+    }
+    match test_nat_(udp, other_addr, false, false, tid + 2, REQUEST_TIMEOUT).await {
+        Ok(response3) => {
+            if response3.mapped_addr.is_ipv4() {
+                rs.mapped_addrs.insert(response3.mapped_addr);
+            }
+            if response3.mapped_addr != mapped_addr1 {
+                rs.mapping_behavior = NatBehavior::AddressAndPortDependent;
+            }
+        }
+        Err(e) => {
+            log::warn!("stun {} error {:?} ", other_addr, e);
+        }
+    }
+    Ok(())
+}
+
+/// Send a binding request to our own public address and check whether the nat loops it back
+async fn test_hairpinning(udp: &PipeUdpSocket, mapped_addr: SocketAddr) -> io::Result<bool> {
+    let tid = rand::thread_rng().next_u64() as u128;
+    let mut buf = [0u8; 28];
+    udp.send_to(binding_request(&mut buf, tid, false, false), mapped_addr)
+        .await?;
+    let deadline = Instant::now() + FILTERING_TIMEOUT;
+    let mut buf = [0; 10240];
+    while let Ok(rs) = tokio::time::timeout_at(deadline, udp.recv_from(&mut buf)).await {
+        let (len, _addr) = rs?;
+        let msg = stun_format::Msg::from(&buf[..len]);
+        if matches!(msg.typ(), Some(MsgType::BindingRequest)) && msg.tid() == Some(tid) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn binding_request(buf: &mut [u8; 28], tid: u128, change_ip: bool, change_port: bool) -> &[u8] {
+    let mut msg = stun_format::MsgBuilder::from(buf.as_mut_slice());
+    msg.typ(MsgType::BindingRequest);
+    msg.tid(tid);
+    msg.add_attr(Attr::ChangeRequest {
+        change_ip,
+        change_port,
+    });
+    let len = msg.as_bytes().len();
+    &buf[..len]
 }
 
 async fn test_nat_(
-    udp: &UdpSocket,
-    stun_server: &String,
+    udp: &PipeUdpSocket,
+    target: SocketAddr,
     change_ip: bool,
     change_port: bool,
     tid: u128,
-) -> io::Result<(SocketAddr, Option<SocketAddr>)> {
+    timeout: Duration,
+) -> io::Result<StunResponse> {
     for _ in 0..2 {
         let mut buf = [0u8; 28];
-        let mut msg = stun_format::MsgBuilder::from(buf.as_mut_slice());
-        msg.typ(stun_format::MsgType::BindingRequest);
-        msg.tid(tid);
-        msg.add_attr(Attr::ChangeRequest {
-            change_ip,
-            change_port,
-        });
-        udp.send(msg.as_bytes()).await?;
+        udp.send_to(
+            binding_request(&mut buf, tid, change_ip, change_port),
+            target,
+        )
+        .await?;
+        let deadline = Instant::now() + timeout;
         let mut buf = [0; 10240];
-        let (len, _addr) =
-            match tokio::time::timeout(Duration::from_secs(3), udp.recv_from(&mut buf)).await {
-                Ok(rs) => rs?,
-                Err(e) => {
-                    log::warn!("stun {} error {:?}", stun_server, e);
-                    continue;
-                }
-            };
-        let msg = stun_format::Msg::from(&buf[..len]);
-        let mut mapped_addr = None;
-        let mut changed_addr = None;
-        for x in msg.attrs_iter() {
-            match x {
-                Attr::MappedAddress(addr) => {
-                    if mapped_addr.is_none() {
+        while let Ok(rs) = tokio::time::timeout_at(deadline, udp.recv_from(&mut buf)).await {
+            let (len, source) = rs?;
+            let msg = stun_format::Msg::from(&buf[..len]);
+            if msg.tid() != Some(tid) {
+                // Late responses of the previous requests
+                continue;
+            }
+            let mut mapped_addr = None;
+            let mut other_addr = None;
+            for x in msg.attrs_iter() {
+                match x {
+                    Attr::MappedAddress(addr) | Attr::XorMappedAddress(addr)
+                        if mapped_addr.is_none() =>
+                    {
                         let _ = mapped_addr.insert(stun_addr(addr));
                     }
-                }
-                Attr::ChangedAddress(addr) => {
-                    if changed_addr.is_none() {
-                        let _ = changed_addr.insert(stun_addr(addr));
-                    }
-                }
-                Attr::XorMappedAddress(addr) => {
-                    if mapped_addr.is_none() {
-                        let _ = mapped_addr.insert(stun_addr(addr));
+                    Attr::ChangedAddress(addr) | Attr::OtherAddress(addr)
+                        if other_addr.is_none() =>
+                    {
+                        let _ = other_addr.insert(stun_addr(addr));
                     }
+                    _ => {}
                 }
-                _ => {}
             }
             if let Some(mapped_addr) = mapped_addr {
-                if changed_addr.is_some() {
-                    return Ok((mapped_addr, changed_addr));
-                }
+                return Ok(StunResponse {
+                    mapped_addr,
+                    other_addr,
+                    source,
+                });
             }
         }
-        if let Some(addr) = mapped_addr {
-            return Ok((addr, changed_addr));
-        }
+        log::debug!("stun {} request timeout", target);
     }
-    Err(io::Error::new(io::ErrorKind::Other, "stun response err"))
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("stun {target} no response"),
+    ))
 }
 
-fn stun_addr(addr: stun_format::SocketAddr) -> SocketAddr {
+pub(crate) fn stun_addr(addr: stun_format::SocketAddr) -> SocketAddr {
     match addr {
         stun_format::SocketAddr::V4(ip, port) => {
             SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port))
@@ -206,6 +612,79 @@ pub fn send_stun_request() -> Vec<u8> {
 pub fn is_stun_response(buf: &[u8]) -> bool {
     buf[0] == 0x01
 }
+/// A Binding request carrying the magic cookie
+pub fn is_stun_request(buf: &[u8]) -> bool {
+    buf.len() >= 20 && buf[..2] == [0x00, 0x01] && buf[4..8] == [0x21, 0x12, 0xA4, 0x42]
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StunRequest {
+    pub tid: u128,
+    pub change_ip: bool,
+    pub change_port: bool,
+}
+
+pub fn parse_stun_request(buf: &[u8]) -> Option<StunRequest> {
+    if !is_stun_request(buf) {
+        return None;
+    }
+    let msg = stun_format::Msg::from(buf);
+    let mut request = StunRequest {
+        tid: msg.tid()?,
+        change_ip: false,
+        change_port: false,
+    };
+    for x in msg.attrs_iter() {
+        if let Attr::ChangeRequest {
+            change_ip,
+            change_port,
+        } = x
+        {
+            request.change_ip = change_ip;
+            request.change_port = change_port;
+        }
+    }
+    Some(request)
+}
+
+/// The Binding success response reflecting `source`,
+/// `other_addr` is advertised as OTHER-ADDRESS for the behaviour discovery
+pub fn stun_binding_response(
+    request: &StunRequest,
+    source: SocketAddr,
+    other_addr: Option<SocketAddr>,
+) -> Vec<u8> {
+    let mut buf = [0u8; 128];
+    let mut msg = stun_format::MsgBuilder::from(buf.as_mut_slice());
+    msg.typ(MsgType::BindingResponse);
+    msg.tid(request.tid);
+    msg.add_attr(Attr::XorMappedAddress(to_stun_addr(source)));
+    msg.add_attr(Attr::MappedAddress(to_stun_addr(source)));
+    if let Some(addr) = other_addr {
+        msg.add_attr(Attr::OtherAddress(to_stun_addr(addr)));
+    }
+    msg.as_bytes().to_vec()
+}
+
+/// The Binding error response, e.g. 420 to a CHANGE-REQUEST the server can not serve
+pub fn stun_error_response(request: &StunRequest, code: u16, reason: &str) -> Vec<u8> {
+    let mut buf = [0u8; 128];
+    let mut msg = stun_format::MsgBuilder::from(buf.as_mut_slice());
+    msg.typ(MsgType::BindingErrorResponse);
+    msg.tid(request.tid);
+    msg.add_attr(Attr::ErrorCode {
+        code: stun_format::ErrorCode::from(code),
+        desc: reason,
+    });
+    msg.as_bytes().to_vec()
+}
+
+fn to_stun_addr(addr: SocketAddr) -> stun_format::SocketAddr {
+    match addr {
+        SocketAddr::V4(addr) => stun_format::SocketAddr::V4(addr.ip().octets(), addr.port()),
+        SocketAddr::V6(addr) => stun_format::SocketAddr::V6(addr.ip().octets(), addr.port()),
+    }
+}
 pub fn recv_stun_response(buf: &[u8]) -> Option<SocketAddr> {
     let msg = stun_format::Msg::from(buf);
     if let Some(tid) = msg.tid() {
@@ -226,3 +705,88 @@ pub fn recv_stun_response(buf: &[u8]) -> Option<SocketAddr> {
     }
     None
 }
+
+#[cfg(test)]
+mod test {
+    use crate::nat::{NatBehavior, NatType};
+    use crate::stun::{
+        is_stun_response, parse_stun_request, predict_ports, recv_stun_response, send_stun_request,
+        stun_binding_response, summarize,
+    };
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_binding_response() {
+        let request = parse_stun_request(&send_stun_request()).unwrap();
+        assert!(!request.change_ip && !request.change_port);
+        let source: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+        let response = stun_binding_response(&request, source, None);
+        assert!(parse_stun_request(&response).is_none());
+        assert!(is_stun_response(&response));
+        assert_eq!(recv_stun_response(&response), Some(source));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let addr1: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+        let addr2: SocketAddr = "1.1.1.1:1010".parse().unwrap();
+        let one = HashSet::from([addr1]);
+        let two = HashSet::from([addr1, addr2]);
+
+        let rs = summarize(
+            2,
+            &one,
+            NatBehavior::Unknown,
+            NatBehavior::AddressDependent,
+            None,
+        );
+        assert_eq!(rs.nat_type, NatType::Cone);
+        assert_eq!(rs.mapping_behavior, NatBehavior::EndpointIndependent);
+        assert_eq!(rs.filtering_behavior, NatBehavior::AddressDependent);
+        assert_eq!(rs.port_range, 0);
+
+        let rs = summarize(1, &one, NatBehavior::Unknown, NatBehavior::Unknown, None);
+        assert_eq!(rs.mapping_behavior, NatBehavior::Unknown);
+
+        let rs = summarize(
+            2,
+            &two,
+            NatBehavior::EndpointIndependent,
+            NatBehavior::Unknown,
+            Some(true),
+        );
+        assert_eq!(rs.nat_type, NatType::Symmetric);
+        assert_eq!(rs.mapping_behavior, NatBehavior::AddressAndPortDependent);
+        assert_eq!(rs.public_ips.len(), 1);
+        assert_eq!(rs.port_range, 10);
+
+        let rs = summarize(
+            1,
+            &two,
+            NatBehavior::AddressDependent,
+            NatBehavior::Unknown,
+            None,
+        );
+        assert_eq!(rs.mapping_behavior, NatBehavior::AddressDependent);
+        assert_eq!(rs.nat_type, NatType::Symmetric);
+    }
+
+    #[test]
+    fn test_predict_ports() {
+        assert_eq!(
+            predict_ports(&[1000, 1001, 1002, 1003], 3),
+            [1004, 1005, 1006]
+        );
+        // Another flow took 1006 in between
+        assert_eq!(
+            predict_ports(&[1000, 1002, 1004, 1008, 1010], 2),
+            [1012, 1014]
+        );
+        assert_eq!(predict_ports(&[5000, 4990, 4980], 2), [4970, 4960]);
+        assert_eq!(predict_ports(&[65533, 65534, 65535], 2), Vec::<u16>::new());
+        assert!(predict_ports(&[1000, 3000, 1500, 60000], 3).is_empty());
+        assert!(predict_ports(&[1000, 1000, 1000], 3).is_empty());
+        assert!(predict_ports(&[1000, 1001], 3).is_empty());
+    }
+}
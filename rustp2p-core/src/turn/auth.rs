@@ -0,0 +1,108 @@
+/*
+  Long-term credentials (RFC 8489 9.2)
+
+  key = MD5(username ":" realm ":" password)
+  MESSAGE-INTEGRITY = HMAC-SHA1(key, message up to the attribute),
+  the length in the header already counts the MESSAGE-INTEGRITY attribute
+*/
+
+use md5::Digest;
+use ring::hmac;
+
+pub(crate) const INTEGRITY_LEN: usize = 20;
+/// The MESSAGE-INTEGRITY attribute including its header
+pub(crate) const INTEGRITY_ATTR_LEN: usize = 4 + INTEGRITY_LEN;
+const HEADER_LEN: usize = 20;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+
+pub(crate) fn long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+    md5(format!("{username}:{realm}:{password}").as_bytes())
+}
+
+/// `msg` is the message up to the MESSAGE-INTEGRITY attribute
+pub(crate) fn message_integrity(key: &[u8], msg: &[u8]) -> [u8; INTEGRITY_LEN] {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    let tag = hmac::sign(&key, msg);
+    let mut rs = [0; INTEGRITY_LEN];
+    rs.copy_from_slice(tag.as_ref());
+    rs
+}
+
+/// Checks the MESSAGE-INTEGRITY of a received message, attributes after it such as FINGERPRINT
+/// are excluded by the length in the header
+pub(crate) fn verify_integrity(key: &[u8], msg: &[u8]) -> bool {
+    let Some(offset) = integrity_offset(msg) else {
+        return false;
+    };
+    let mut head = msg[..offset].to_vec();
+    let len = (offset + INTEGRITY_ATTR_LEN - HEADER_LEN) as u16;
+    head[2..4].copy_from_slice(&len.to_be_bytes());
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    let tag = &msg[offset + 4..offset + INTEGRITY_ATTR_LEN];
+    hmac::verify(&key, &head, tag).is_ok()
+}
+
+/// The offset of the MESSAGE-INTEGRITY attribute
+fn integrity_offset(msg: &[u8]) -> Option<usize> {
+    let mut offset = HEADER_LEN;
+    while offset + 4 <= msg.len() {
+        let typ = u16::from_be_bytes([msg[offset], msg[offset + 1]]);
+        let len = u16::from_be_bytes([msg[offset + 2], msg[offset + 3]]) as usize;
+        if typ == ATTR_MESSAGE_INTEGRITY {
+            return (len == INTEGRITY_LEN && offset + INTEGRITY_ATTR_LEN <= msg.len())
+                .then_some(offset);
+        }
+        offset += 4 + len.next_multiple_of(4);
+    }
+    None
+}
+
+fn md5(data: &[u8]) -> [u8; 16] {
+    md5::Md5::digest(data).into()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::turn::auth::{md5, message_integrity, verify_integrity, INTEGRITY_LEN};
+    use stun_format::{Attr, MsgBuilder, MsgType};
+
+    #[test]
+    fn test_md5() {
+        let hex = |v: &[u8]| v.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            hex(&md5(b"The quick brown fox jumps over the lazy dog")),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        // RFC 2202 test case 2
+        assert_eq!(
+            hex(&message_integrity(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+    }
+
+    #[test]
+    fn test_verify_integrity() {
+        let mut buf = [0u8; 256];
+        let mut msg = MsgBuilder::from(buf.as_mut_slice());
+        msg.typ(MsgType::AllocateResponse);
+        msg.tid(7);
+        msg.add_attr(Attr::Realm("rustp2p")).unwrap();
+        msg.add_attr(Attr::MessageIntegrity(&[0; INTEGRITY_LEN]))
+            .unwrap();
+        let bytes = msg.as_bytes();
+        let len = bytes.len();
+        let integrity = message_integrity(b"key", &bytes[..len - INTEGRITY_LEN - 4]);
+        bytes[len - INTEGRITY_LEN..].copy_from_slice(&integrity);
+        let mut signed = bytes.to_vec();
+        assert!(verify_integrity(b"key", &signed));
+        assert!(!verify_integrity(b"other", &signed));
+        // A FINGERPRINT after the integrity is not covered
+        signed.extend_from_slice(&[0x80, 0x28, 0, 4, 1, 2, 3, 4]);
+        let len = (signed.len() - 20) as u16;
+        signed[2..4].copy_from_slice(&len.to_be_bytes());
+        assert!(verify_integrity(b"key", &signed));
+        signed[25] ^= 1;
+        assert!(!verify_integrity(b"key", &signed));
+    }
+}
@@ -0,0 +1,699 @@
+/*
+  TURN client (RFC 8656) over UDP
+
+  The allocation on the TURN server relays the traffic of peers that are permitted by IP,
+  each peer gets a channel and is exposed as a pipeline of the extensible pipe.
+
+  ChannelData: channel number(16) = 0x4000..=0x4FFF | length(16) | application data
+*/
+
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use crossbeam_utils::atomic::AtomicCell;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use rand::RngCore;
+use stun_format::{Attr, MsgType, TransportProtocol};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
+
+use crate::pipe::extensible_pipe::{ExtendRead, ExtendWrite, ExtensiblePipeWriter};
+use crate::route::RouteKey;
+use crate::stun::stun_addr;
+
+mod auth;
+
+const CHANNEL_MIN: u16 = 0x4000;
+const CHANNEL_MAX: u16 = 0x4FFF;
+const CHANNEL_HEAD_LEN: usize = 4;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+/// Permissions expire after 5 minutes and channels after 10 minutes
+const REFRESH_INTERVAL: Duration = Duration::from_secs(4 * 60);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Debug)]
+pub struct TurnConfig {
+    /// `host:port`, the port defaults to 3478
+    pub server: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl TurnConfig {
+    pub fn new(server: String, username: String, password: String) -> Self {
+        Self {
+            server,
+            username,
+            password,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Credential {
+    realm: String,
+    nonce: String,
+    key: [u8; 16],
+}
+
+/// A channel number is bound to one peer until the binding expires,
+/// so a released channel goes back to the same peer or waits out its lifetime
+struct ChannelPool {
+    next: u16,
+    released: VecDeque<(u16, SocketAddr, Instant)>,
+}
+
+impl ChannelPool {
+    fn alloc(&mut self, peer: SocketAddr) -> anyhow::Result<u16> {
+        if let Some(i) = self.released.iter().position(|(_, addr, _)| *addr == peer) {
+            return Ok(self.released.remove(i).unwrap().0);
+        }
+        if self
+            .released
+            .front()
+            .is_some_and(|(_, _, time)| time.elapsed() >= CHANNEL_LIFETIME)
+        {
+            return Ok(self.released.pop_front().unwrap().0);
+        }
+        if self.next > CHANNEL_MAX {
+            Err(anyhow!("turn channels exhausted"))?
+        }
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+    fn release(&mut self, channel: u16, peer: SocketAddr) {
+        self.released.push_back((channel, peer, Instant::now()));
+    }
+}
+
+#[derive(Clone)]
+struct Peer {
+    route_key: RouteKey,
+    sender: Sender<BytesMut>,
+}
+
+struct Inner {
+    udp: Arc<UdpSocket>,
+    username: String,
+    password: String,
+    credential: Mutex<Option<Credential>>,
+    pipe_writer: ExtensiblePipeWriter,
+    relayed_addr: AtomicCell<Option<SocketAddr>>,
+    lifetime: AtomicCell<Duration>,
+    transactions: DashMap<u128, oneshot::Sender<Vec<u8>>>,
+    peers: DashMap<SocketAddr, Peer>,
+    channels: DashMap<u16, SocketAddr>,
+    permissions: Mutex<HashSet<IpAddr>>,
+    channel_pool: Mutex<ChannelPool>,
+    connect_lock: tokio::sync::Mutex<()>,
+}
+
+/// Relays the traffic to peers through a TURN server,
+/// [`run`](Self::run) has to be polled for any request to complete
+#[derive(Clone)]
+pub struct TurnClient {
+    inner: Arc<Inner>,
+}
+
+impl TurnClient {
+    pub async fn new(config: &TurnConfig, pipe_writer: ExtensiblePipeWriter) -> io::Result<Self> {
+        let mut server = config.server.clone();
+        if !server.contains(':') {
+            server.push_str(":3478");
+        }
+        let server = tokio::net::lookup_host(&server)
+            .await?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "invalid turn server")
+            })?;
+        let udp = if server.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        udp.connect(server).await?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                udp: Arc::new(udp),
+                username: config.username.clone(),
+                password: config.password.clone(),
+                credential: Mutex::new(None),
+                pipe_writer,
+                relayed_addr: AtomicCell::new(None),
+                lifetime: AtomicCell::new(DEFAULT_LIFETIME),
+                transactions: DashMap::new(),
+                peers: DashMap::new(),
+                channels: DashMap::new(),
+                permissions: Mutex::new(HashSet::new()),
+                channel_pool: Mutex::new(ChannelPool {
+                    next: CHANNEL_MIN,
+                    released: VecDeque::new(),
+                }),
+                connect_lock: tokio::sync::Mutex::new(()),
+            }),
+        })
+    }
+    /// The address the TURN server relays for us, known after [`allocate`](Self::allocate)
+    pub fn relayed_addr(&self) -> Option<SocketAddr> {
+        self.inner.relayed_addr.load()
+    }
+    /// How often [`refresh`](Self::refresh) has to be called
+    pub fn refresh_interval(&self) -> Duration {
+        (self.inner.lifetime.load() / 2).min(REFRESH_INTERVAL)
+    }
+    /// Receive from the TURN server, the data of peers is dispatched to their pipelines
+    pub async fn run(&self) -> io::Result<()> {
+        let mut buf = vec![0; 65536];
+        loop {
+            let len = self.inner.udp.recv(&mut buf).await?;
+            let buf = &buf[..len];
+            if len >= CHANNEL_HEAD_LEN && buf[0] & 0xC0 == 0x40 {
+                let channel = u16::from_be_bytes([buf[0], buf[1]]);
+                let data_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+                let Some(data) = buf.get(CHANNEL_HEAD_LEN..CHANNEL_HEAD_LEN + data_len) else {
+                    continue;
+                };
+                let peer = self.inner.channels.get(&channel).map(|v| *v.value());
+                if let Some(peer) = peer {
+                    self.dispatch(peer, data);
+                }
+                continue;
+            }
+            let msg = stun_format::Msg::from(buf);
+            match msg.typ() {
+                Some(MsgType::DataIndication) => {
+                    let mut peer = None;
+                    let mut data = None;
+                    for attr in msg.attrs_iter() {
+                        match attr {
+                            Attr::XorPeerAddress(addr) => peer = Some(stun_addr(addr)),
+                            Attr::Data(v) => data = Some(v),
+                            _ => {}
+                        }
+                    }
+                    if let (Some(peer), Some(data)) = (peer, data) {
+                        self.dispatch(peer, data);
+                    }
+                }
+                Some(_) => {
+                    let transaction = msg
+                        .tid()
+                        .and_then(|tid| self.inner.transactions.remove(&tid));
+                    if let Some((_, sender)) = transaction {
+                        let _ = sender.send(buf.to_vec());
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+    /// Allocate the relayed address on the TURN server
+    pub async fn allocate(&self) -> anyhow::Result<SocketAddr> {
+        let response = self
+            .request(
+                MsgType::AllocateRequest,
+                &[
+                    Attr::RequestedTransport(TransportProtocol::UDP),
+                    Attr::Lifetime(DEFAULT_LIFETIME),
+                ],
+            )
+            .await?;
+        let msg = stun_format::Msg::from(response.as_slice());
+        let mut relayed_addr = None;
+        for attr in msg.attrs_iter() {
+            match attr {
+                Attr::XorRelayedAddress(addr) => relayed_addr = Some(stun_addr(addr)),
+                Attr::Lifetime(lifetime) => self.inner.lifetime.store(lifetime),
+                _ => {}
+            }
+        }
+        let relayed_addr = relayed_addr.context("no relayed address")?;
+        self.inner.relayed_addr.store(Some(relayed_addr));
+        Ok(relayed_addr)
+    }
+    /// Refresh the allocation, the permissions and the channels
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let response = self
+            .request(MsgType::RefreshRequest, &[Attr::Lifetime(DEFAULT_LIFETIME)])
+            .await?;
+        let msg = stun_format::Msg::from(response.as_slice());
+        for attr in msg.attrs_iter() {
+            if let Attr::Lifetime(lifetime) = attr {
+                self.inner.lifetime.store(lifetime);
+            }
+        }
+        let ips: Vec<IpAddr> = self.inner.permissions.lock().iter().copied().collect();
+        if !ips.is_empty() {
+            self.create_permission(&ips).await?;
+        }
+        // Channels of closed pipelines expire
+        self.inner.peers.retain(|_, peer| !peer.sender.is_closed());
+        self.inner.channels.retain(|channel, addr| {
+            let open = self.inner.peers.contains_key(addr);
+            if !open {
+                self.inner.channel_pool.lock().release(*channel, *addr);
+            }
+            open
+        });
+        let channels: Vec<(u16, SocketAddr)> = self
+            .inner
+            .channels
+            .iter()
+            .map(|v| (*v.key(), *v.value()))
+            .collect();
+        for (channel, peer) in channels {
+            self.bind_channel(channel, peer).await?;
+        }
+        Ok(())
+    }
+    /// Permit the peers to send to the relayed address
+    pub async fn create_permission(&self, ips: &[IpAddr]) -> anyhow::Result<()> {
+        let attrs: Vec<Attr> = ips
+            .iter()
+            .map(|ip| Attr::XorPeerAddress(to_stun_addr(SocketAddr::new(*ip, 0))))
+            .collect();
+        self.request(MsgType::CreatePermissionRequest, &attrs)
+            .await?;
+        self.inner.permissions.lock().extend(ips);
+        Ok(())
+    }
+    /// Open a pipeline to the peer through the relay, returns the route of the pipeline
+    pub async fn connect(&self, peer: SocketAddr) -> anyhow::Result<RouteKey> {
+        let _guard = self.inner.connect_lock.lock().await;
+        if let Some(v) = self.inner.peers.get(&peer) {
+            if !v.sender.is_closed() {
+                return Ok(v.route_key);
+            }
+        }
+        if !self.inner.permissions.lock().contains(&peer.ip()) {
+            self.create_permission(&[peer.ip()]).await?;
+        }
+        // The channel of a closed pipeline is still bound to the peer until the next refresh
+        let bound = self
+            .inner
+            .channels
+            .iter()
+            .find(|v| *v.value() == peer)
+            .map(|v| *v.key());
+        let channel = match bound {
+            Some(channel) => channel,
+            None => self.inner.channel_pool.lock().alloc(peer)?,
+        };
+        if let Err(e) = self.bind_channel(channel, peer).await {
+            if bound.is_none() {
+                self.inner.channel_pool.lock().release(channel, peer);
+            }
+            Err(e)?
+        }
+        let (sender, receiver) = tokio::sync::mpsc::channel(128);
+        let route_key = self
+            .inner
+            .pipe_writer
+            .add_turn_pipe(
+                peer,
+                Box::new(TurnRead { receiver }),
+                Box::new(TurnWrite {
+                    udp: self.inner.udp.clone(),
+                    channel,
+                }),
+            )
+            .await?;
+        self.inner.peers.insert(peer, Peer { route_key, sender });
+        self.inner.channels.insert(channel, peer);
+        Ok(route_key)
+    }
+    async fn bind_channel(&self, channel: u16, peer: SocketAddr) -> anyhow::Result<()> {
+        self.request(
+            MsgType::ChannelBindRequest,
+            &[
+                Attr::ChannelNumber(channel),
+                Attr::XorPeerAddress(to_stun_addr(peer)),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+    fn dispatch(&self, peer: SocketAddr, data: &[u8]) {
+        let sender = self.inner.peers.get(&peer).map(|v| v.sender.clone());
+        match sender {
+            Some(sender) if !sender.is_closed() => {
+                // Dropped like an overflowing udp socket buffer
+                let _ = sender.try_send(BytesMut::from(data));
+            }
+            _ => {
+                // A peer that is permitted by IP, but has no channel yet
+                let client = self.clone();
+                let data = BytesMut::from(data);
+                tokio::spawn(async move {
+                    if let Err(e) = client.connect(peer).await {
+                        log::debug!("turn connect {peer} {e:?}");
+                        return;
+                    }
+                    if let Some(v) = client.inner.peers.get(&peer) {
+                        let _ = v.sender.try_send(data);
+                    }
+                });
+            }
+        }
+    }
+    /// Send a request, the credential is updated by the 401 and 438 errors.
+    /// The success responses to authenticated requests must carry a valid MESSAGE-INTEGRITY
+    async fn request(&self, typ: MsgType, attrs: &[Attr<'_>]) -> anyhow::Result<Vec<u8>> {
+        let typ = u16::from(typ);
+        for _ in 0..2 {
+            let tid = rand::thread_rng().next_u64() as u128;
+            let credential = self.inner.credential.lock().clone();
+            let request = self.build(typ, tid, attrs, credential.as_ref())?;
+            let response = self.transaction(tid, &request).await?;
+            let msg = stun_format::Msg::from(response.as_slice());
+            let mut code = None;
+            let mut realm = None;
+            let mut nonce = None;
+            for attr in msg.attrs_iter() {
+                match attr {
+                    Attr::ErrorCode { code: v, .. } => code = Some(u16::from(v)),
+                    Attr::Realm(v) => realm = Some(v.to_string()),
+                    Attr::Nonce(v) => nonce = Some(v.to_string()),
+                    _ => {}
+                }
+            }
+            match (code, nonce) {
+                (None, _) => {
+                    if let Some(credential) = credential {
+                        if !auth::verify_integrity(&credential.key, &response) {
+                            Err(anyhow!(
+                                "turn {:?} invalid message integrity",
+                                MsgType::from(typ)
+                            ))?
+                        }
+                    }
+                    return Ok(response);
+                }
+                (Some(401 | 438), Some(nonce)) => {
+                    let mut guard = self.inner.credential.lock();
+                    let realm = match realm {
+                        Some(realm) => realm,
+                        None => guard
+                            .as_ref()
+                            .map(|v| v.realm.clone())
+                            .context("turn no realm")?,
+                    };
+                    let key =
+                        auth::long_term_key(&self.inner.username, &realm, &self.inner.password);
+                    guard.replace(Credential { realm, nonce, key });
+                }
+                (Some(code), _) => Err(anyhow!("turn {:?} error {code}", MsgType::from(typ)))?,
+            }
+        }
+        Err(anyhow!("turn {:?} unauthorized", MsgType::from(typ)))
+    }
+    fn build(
+        &self,
+        typ: u16,
+        tid: u128,
+        attrs: &[Attr<'_>],
+        credential: Option<&Credential>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut buf = [0u8; 1024];
+        let mut msg = stun_format::MsgBuilder::from(buf.as_mut_slice());
+        msg.typ(MsgType::from(typ));
+        msg.tid(tid);
+        for attr in attrs {
+            msg.add_attr(*attr).context("turn message too long")?;
+        }
+        if let Some(credential) = credential {
+            msg.add_attr(Attr::Username(&self.inner.username))
+                .context("turn message too long")?;
+            msg.add_attr(Attr::Realm(&credential.realm))
+                .context("turn message too long")?;
+            msg.add_attr(Attr::Nonce(&credential.nonce))
+                .context("turn message too long")?;
+            msg.add_attr(Attr::MessageIntegrity(&[0; auth::INTEGRITY_LEN]))
+                .context("turn message too long")?;
+            let bytes = msg.as_bytes();
+            let len = bytes.len();
+            let integrity =
+                auth::message_integrity(&credential.key, &bytes[..len - auth::INTEGRITY_ATTR_LEN]);
+            bytes[len - auth::INTEGRITY_LEN..].copy_from_slice(&integrity);
+        }
+        Ok(msg.as_bytes().to_vec())
+    }
+    async fn transaction(&self, tid: u128, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (sender, mut receiver) = oneshot::channel();
+        self.inner.transactions.insert(tid, sender);
+        for _ in 0..3 {
+            self.inner.udp.send(request).await?;
+            match tokio::time::timeout(REQUEST_TIMEOUT, &mut receiver).await {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(_)) => break,
+                Err(_) => continue,
+            }
+        }
+        self.inner.transactions.remove(&tid);
+        Err(anyhow!("turn request timeout"))
+    }
+}
+
+struct TurnRead {
+    receiver: Receiver<BytesMut>,
+}
+
+#[async_trait]
+impl ExtendRead for TurnRead {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self
+            .receiver
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+}
+
+struct TurnWrite {
+    udp: Arc<UdpSocket>,
+    channel: u16,
+}
+
+#[async_trait]
+impl ExtendWrite for TurnWrite {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let len = u16::try_from(buf.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too long"))?;
+        let mut data = Vec::with_capacity(CHANNEL_HEAD_LEN + buf.len());
+        data.extend_from_slice(&self.channel.to_be_bytes());
+        data.extend_from_slice(&len.to_be_bytes());
+        data.extend_from_slice(buf);
+        self.udp.send(&data).await?;
+        Ok(())
+    }
+}
+
+fn to_stun_addr(addr: SocketAddr) -> stun_format::SocketAddr {
+    match addr {
+        SocketAddr::V4(addr) => stun_format::SocketAddr::V4(addr.ip().octets(), addr.port()),
+        SocketAddr::V6(addr) => stun_format::SocketAddr::V6(addr.ip().octets(), addr.port()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use bytes::BytesMut;
+    use stun_format::{Attr, ErrorCode, MsgType};
+    use tokio::net::UdpSocket;
+
+    use crate::pipe::extensible_pipe::ExtensiblePipe;
+    use crate::route::Index;
+    use crate::stun::stun_addr;
+    use crate::turn::{
+        auth, to_stun_addr, ChannelPool, TurnClient, TurnConfig, CHANNEL_LIFETIME, CHANNEL_MIN,
+        DEFAULT_LIFETIME,
+    };
+
+    const REALM: &str = "rustp2p";
+    const NONCE: &str = "e0d2a1f3";
+
+    /// A TURN server that relays between one client and the permitted peers
+    async fn turn_server(server: UdpSocket, relay: UdpSocket) {
+        let key = auth::long_term_key("user", REALM, "pass");
+        let mut client = None;
+        let mut permissions = HashSet::new();
+        let mut channels: HashMap<u16, SocketAddr> = HashMap::new();
+        let mut buf = [0u8; 1500];
+        let mut relay_buf = [0u8; 1500];
+        loop {
+            tokio::select! {
+                rs = server.recv_from(&mut buf) => {
+                    let (len, addr) = rs.unwrap();
+                    client = Some(addr);
+                    let buf = &buf[..len];
+                    if buf[0] & 0xC0 == 0x40 {
+                        let peer = channels[&u16::from_be_bytes([buf[0], buf[1]])];
+                        relay.send_to(&buf[4..], peer).await.unwrap();
+                        continue;
+                    }
+                    let msg = stun_format::Msg::from(buf);
+                    let typ = msg.typ().unwrap();
+                    let mut integrity = None;
+                    let mut peers = vec![];
+                    let mut channel = None;
+                    for attr in msg.attrs_iter() {
+                        match attr {
+                            Attr::MessageIntegrity(v) => integrity = Some(*v),
+                            Attr::XorPeerAddress(v) => peers.push(stun_addr(v)),
+                            Attr::ChannelNumber(v) => channel = Some(v),
+                            _ => {}
+                        }
+                    }
+                    let mut out = [0u8; 1500];
+                    let mut rs = stun_format::MsgBuilder::from(out.as_mut_slice());
+                    rs.tid(msg.tid().unwrap());
+                    let Some(integrity) = integrity else {
+                        rs.typ(MsgType::AllocateErrorResponse);
+                        rs.add_attr(Attr::ErrorCode { code: ErrorCode::from(401), desc: "" });
+                        rs.add_attr(Attr::Realm(REALM));
+                        rs.add_attr(Attr::Nonce(NONCE));
+                        server.send_to(rs.as_bytes(), addr).await.unwrap();
+                        continue;
+                    };
+                    let expected =
+                        auth::message_integrity(&key, &buf[..len - auth::INTEGRITY_ATTR_LEN]);
+                    assert_eq!(integrity, expected);
+                    match typ {
+                        MsgType::AllocateRequest => {
+                            rs.typ(MsgType::AllocateResponse);
+                            rs.add_attr(Attr::XorRelayedAddress(to_stun_addr(
+                                relay.local_addr().unwrap(),
+                            )));
+                            rs.add_attr(Attr::Lifetime(DEFAULT_LIFETIME));
+                        }
+                        MsgType::RefreshRequest => {
+                            rs.typ(MsgType::RefreshResponse);
+                            rs.add_attr(Attr::Lifetime(DEFAULT_LIFETIME));
+                        }
+                        MsgType::CreatePermissionRequest => {
+                            rs.typ(MsgType::CreatePermissionResponse);
+                            permissions.extend(peers.iter().map(|v| v.ip()));
+                        }
+                        MsgType::ChannelBindRequest => {
+                            rs.typ(MsgType::ChannelBindResponse);
+                            channels.insert(channel.unwrap(), peers[0]);
+                        }
+                        typ => panic!("unexpected {typ:?}"),
+                    }
+                    rs.add_attr(Attr::MessageIntegrity(&[0; auth::INTEGRITY_LEN]));
+                    let out = rs.as_bytes();
+                    let len = out.len();
+                    let integrity =
+                        auth::message_integrity(&key, &out[..len - auth::INTEGRITY_ATTR_LEN]);
+                    out[len - auth::INTEGRITY_LEN..].copy_from_slice(&integrity);
+                    server.send_to(out, addr).await.unwrap();
+                }
+                rs = relay.recv_from(&mut relay_buf) => {
+                    let (len, peer) = rs.unwrap();
+                    let (Some(client), true) = (client, permissions.contains(&peer.ip())) else {
+                        continue;
+                    };
+                    let data = &relay_buf[..len];
+                    if let Some((channel, _)) = channels.iter().find(|(_, v)| **v == peer) {
+                        let mut channel_data = channel.to_be_bytes().to_vec();
+                        channel_data.extend_from_slice(&(len as u16).to_be_bytes());
+                        channel_data.extend_from_slice(data);
+                        server.send_to(&channel_data, client).await.unwrap();
+                    } else {
+                        let mut out = [0u8; 1500];
+                    let mut rs = stun_format::MsgBuilder::from(out.as_mut_slice());
+                        rs.typ(MsgType::DataIndication);
+                        rs.tid(1);
+                        rs.add_attr(Attr::XorPeerAddress(to_stun_addr(peer)));
+                        rs.add_attr(Attr::Data(data));
+                        server.send_to(rs.as_bytes(), client).await.unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_turn_relay() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn(turn_server(server, relay));
+
+        let mut pipe = ExtensiblePipe::new();
+        let pipe_writer = pipe.writer_ref().to_owned();
+        let config = TurnConfig::new(server_addr.to_string(), "user".into(), "pass".into());
+        let client = TurnClient::new(&config, pipe_writer.clone()).await.unwrap();
+        let run = client.clone();
+        tokio::spawn(async move { run.run().await });
+        assert_eq!(client.allocate().await.unwrap(), relay_addr);
+        assert_eq!(client.relayed_addr(), Some(relay_addr));
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let route_key = client.connect(peer.local_addr().unwrap()).await.unwrap();
+        assert!(matches!(route_key.index(), Index::Turn(_)));
+        let mut line = pipe.accept().await.unwrap();
+        pipe_writer
+            .send_to(BytesMut::from(&b"hello"[..]), &route_key)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let (len, addr) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], addr), (&b"hello"[..], relay_addr));
+        peer.send_to(b"world", relay_addr).await.unwrap();
+        let (len, key) = line.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], key), (&b"world"[..], route_key));
+
+        // A permitted peer without a channel is delivered by the data indication
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .create_permission(&[IpAddr::V4(Ipv4Addr::LOCALHOST)])
+            .await
+            .unwrap();
+        other.send_to(b"other", relay_addr).await.unwrap();
+        let mut other_line = pipe.accept().await.unwrap();
+        let (len, key) = other_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"other");
+        assert_eq!(key.addr(), other.local_addr().unwrap());
+
+        client.refresh().await.unwrap();
+    }
+
+    #[test]
+    fn test_channel_pool() {
+        let mut pool = ChannelPool {
+            next: CHANNEL_MIN,
+            released: Default::default(),
+        };
+        let a: SocketAddr = "192.0.2.1:1".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:2".parse().unwrap();
+        assert_eq!(pool.alloc(a).unwrap(), CHANNEL_MIN);
+        pool.release(CHANNEL_MIN, a);
+        // Still bound to `a` on the server
+        assert_eq!(pool.alloc(b).unwrap(), CHANNEL_MIN + 1);
+        assert_eq!(pool.alloc(a).unwrap(), CHANNEL_MIN);
+        pool.released.push_back((
+            CHANNEL_MIN + 1,
+            b,
+            std::time::Instant::now() - CHANNEL_LIFETIME,
+        ));
+        assert_eq!(pool.alloc(a).unwrap(), CHANNEL_MIN + 1);
+    }
+}
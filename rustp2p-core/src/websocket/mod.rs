@@ -0,0 +1,279 @@
+/*
+  WebSocket transport (RFC 6455)
+
+  Every connection is a pipeline of the extensible pipe and every packet is a binary message,
+  so the nodes behind proxies that only let HTTP(S) through can join the mesh.
+*/
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+use crate::pipe::extensible_pipe::{ExtendRead, ExtendWrite, ExtensiblePipeWriter};
+use crate::route::RouteKey;
+use crate::socket::handshake_timeout;
+
+pub use tokio_rustls::rustls;
+
+#[derive(Clone, Debug, Default)]
+pub struct WebSocketConfig {
+    /// Accept `ws://` connections on this address, or `wss://` with a server TLS config
+    pub listen_addr: Option<SocketAddr>,
+    pub server_tls_config: Option<Arc<ServerConfig>>,
+    /// Used to dial `wss://` urls, defaults to the webpki roots
+    pub client_tls_config: Option<Arc<ClientConfig>>,
+}
+
+impl WebSocketConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set_listen_addr(mut self, listen_addr: SocketAddr) -> Self {
+        self.listen_addr.replace(listen_addr);
+        self
+    }
+    pub fn set_server_tls_config(mut self, server_tls_config: Arc<ServerConfig>) -> Self {
+        self.server_tls_config.replace(server_tls_config);
+        self
+    }
+    pub fn set_client_tls_config(mut self, client_tls_config: Arc<ClientConfig>) -> Self {
+        self.client_tls_config.replace(client_tls_config);
+        self
+    }
+}
+
+/// Accepts the WebSocket upgrades and adds them to the extensible pipe
+pub struct WebSocketListener {
+    listener: TcpListener,
+    tls_acceptor: Option<TlsAcceptor>,
+    pipe_writer: ExtensiblePipeWriter,
+}
+
+impl WebSocketListener {
+    pub async fn bind(
+        addr: SocketAddr,
+        tls_config: Option<Arc<ServerConfig>>,
+        pipe_writer: ExtensiblePipeWriter,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener,
+            tls_acceptor: tls_config.map(TlsAcceptor::from),
+            pipe_writer,
+        })
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    /// Accept connections until the listener fails, every handshake runs in its own task with a timeout
+    pub async fn run(&self) -> io::Result<()> {
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            let tls_acceptor = self.tls_acceptor.clone();
+            let pipe_writer = self.pipe_writer.clone();
+            tokio::spawn(async move {
+                if let Err(e) = accept(stream, addr, tls_acceptor, &pipe_writer).await {
+                    log::debug!("websocket accept {addr} {e:?}");
+                }
+            });
+        }
+    }
+}
+
+async fn accept(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tls_acceptor: Option<TlsAcceptor>,
+    pipe_writer: &ExtensiblePipeWriter,
+) -> anyhow::Result<RouteKey> {
+    stream.set_nodelay(true)?;
+    if let Some(tls_acceptor) = tls_acceptor {
+        let stream = handshake_timeout("websocket", async {
+            let stream = tls_acceptor.accept(stream).await?;
+            tokio_tungstenite::accept_async(stream).await
+        })
+        .await?;
+        add_pipe(stream, addr, pipe_writer).await
+    } else {
+        let stream =
+            handshake_timeout("websocket", tokio_tungstenite::accept_async(stream)).await?;
+        add_pipe(stream, addr, pipe_writer).await
+    }
+}
+
+/// Dial a `ws://` or `wss://` url and add the connection to the extensible pipe
+pub async fn connect(
+    url: &str,
+    tls_config: Option<Arc<ClientConfig>>,
+    pipe_writer: &ExtensiblePipeWriter,
+) -> anyhow::Result<RouteKey> {
+    let tls_config = match tls_config {
+        Some(tls_config) => tls_config,
+        None => default_client_config()?,
+    };
+    let (stream, _) = handshake_timeout(
+        "websocket",
+        tokio_tungstenite::connect_async_tls_with_config(
+            url,
+            None,
+            true,
+            Some(Connector::Rustls(tls_config)),
+        ),
+    )
+    .await
+    .with_context(|| format!("websocket connect {url}"))?;
+    let addr = match stream.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream.peer_addr()?,
+        MaybeTlsStream::Rustls(stream) => stream.get_ref().0.peer_addr()?,
+        _ => Err(anyhow::anyhow!("unsupported stream"))?,
+    };
+    add_pipe(stream, addr, pipe_writer).await
+}
+
+fn default_client_config() -> anyhow::Result<Arc<ClientConfig>> {
+    let root_store = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+async fn add_pipe<S>(
+    stream: WebSocketStream<S>,
+    addr: SocketAddr,
+    pipe_writer: &ExtensiblePipeWriter,
+) -> anyhow::Result<RouteKey>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sink, stream) = stream.split();
+    pipe_writer
+        .add_pipe(
+            addr,
+            Box::new(WebSocketRead { stream }),
+            Box::new(WebSocketWrite { sink }),
+        )
+        .await
+}
+
+struct WebSocketRead<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+}
+
+#[async_trait]
+impl<S> ExtendRead for WebSocketRead<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let message = self
+                .stream
+                .next()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?
+                .map_err(io::Error::other)?;
+            match message {
+                Message::Binary(data) => {
+                    if data.len() > buf.len() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "too long"));
+                    }
+                    buf[..data.len()].copy_from_slice(&data);
+                    return Ok(data.len());
+                }
+                Message::Close(_) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                // The pings are answered by the stream itself
+                _ => {}
+            }
+        }
+    }
+}
+
+struct WebSocketWrite<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+}
+
+#[async_trait]
+impl<S> ExtendWrite for WebSocketWrite<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.sink
+            .send(Message::binary(buf))
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pipe::extensible_pipe::ExtensiblePipe;
+    use crate::websocket::{connect, WebSocketListener};
+
+    #[tokio::test]
+    async fn websocket_send_recv() {
+        let mut server_pipe = ExtensiblePipe::new();
+        let mut client_pipe = ExtensiblePipe::new();
+        let listener = WebSocketListener::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            None,
+            server_pipe.writer_ref().to_owned(),
+        )
+        .await
+        .unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { listener.run().await });
+
+        let client_writer = client_pipe.writer_ref().to_owned();
+        let route_key = connect(&url, None, &client_writer).await.unwrap();
+        let mut client_line = client_pipe.accept().await.unwrap();
+        client_writer
+            .send_to(b"hello"[..].into(), &route_key)
+            .await
+            .unwrap();
+
+        let mut server_line = server_pipe.accept().await.unwrap();
+        let mut buf = [0; 64];
+        let (len, server_route_key) = server_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        server_line
+            .send_to(b"world"[..].into(), &server_route_key)
+            .await
+            .unwrap();
+        let (len, _) = client_line.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"world");
+    }
+
+    /// A server that accepts the connection but never answers does not hold the dialer
+    #[tokio::test]
+    async fn websocket_connect_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let client_pipe = ExtensiblePipe::new();
+        let client_writer = client_pipe.writer_ref().to_owned();
+        let rs = tokio::time::timeout(
+            std::time::Duration::from_secs(60),
+            connect(&url, None, &client_writer),
+        )
+        .await
+        .expect("no handshake timeout");
+        assert!(rs.is_err());
+        drop(listener);
+    }
+}
@@ -19,6 +19,20 @@ pub enum Algorithm {
     #[cfg(feature = "chacha20-poly1305")]
     ChaCha20Poly1305(String),
 }
+impl Algorithm {
+    #[cfg(all(
+        feature = "quic",
+        any(feature = "aes-gcm", feature = "chacha20-poly1305")
+    ))]
+    pub(crate) fn password(&self) -> &str {
+        match self {
+            #[cfg(feature = "aes-gcm")]
+            Algorithm::AesGcm(p) => p,
+            #[cfg(feature = "chacha20-poly1305")]
+            Algorithm::ChaCha20Poly1305(p) => p,
+        }
+    }
+}
 impl From<Algorithm> for Cipher {
     fn from(value: Algorithm) -> Self {
         match value {
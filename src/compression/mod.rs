@@ -0,0 +1,106 @@
+use std::io;
+
+/// Compression algorithm of user data.
+/// The compressed payload starts with the id of the algorithm
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Algorithm {
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// zstd with compression level
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 0b01;
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 0b10;
+
+const MAX_LEN: usize = u16::MAX as usize;
+
+impl Algorithm {
+    pub fn id(&self) -> u8 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Algorithm::Lz4 => LZ4,
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd(_) => ZSTD,
+        }
+    }
+    /// Bit mask of all algorithms that can be decompressed
+    pub fn supported() -> u8 {
+        let mut mask = 0;
+        #[cfg(feature = "lz4")]
+        {
+            mask |= LZ4;
+        }
+        #[cfg(feature = "zstd")]
+        {
+            mask |= ZSTD;
+        }
+        mask
+    }
+    /// Returns None if the payload does not shrink
+    pub fn compress(&self, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![self.id()];
+        match self {
+            #[cfg(feature = "lz4")]
+            Algorithm::Lz4 => {
+                buf.extend_from_slice(&lz4_flex::compress_prepend_size(payload));
+            }
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd(level) => {
+                buf.extend_from_slice(&zstd::bulk::compress(payload, *level)?);
+            }
+        }
+        if buf.len() >= payload.len() {
+            return Ok(None);
+        }
+        Ok(Some(buf))
+    }
+    pub fn decompress(payload: &[u8]) -> io::Result<Vec<u8>> {
+        let Some((id, data)) = payload.split_first() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty payload"));
+        };
+        match *id {
+            #[cfg(feature = "lz4")]
+            LZ4 => {
+                if data.len() < 4
+                    || u32::from_le_bytes(data[..4].try_into().unwrap()) as usize > MAX_LEN
+                {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "lz4 size error"));
+                }
+                lz4_flex::decompress_size_prepended(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            #[cfg(feature = "zstd")]
+            ZSTD => zstd::bulk::decompress(data, MAX_LEN),
+            id => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported compression algorithm {id}"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compression::Algorithm;
+
+    #[test]
+    fn test_compress() {
+        let payload = b"telemetry telemetry telemetry telemetry telemetry telemetry".repeat(10);
+        let algorithms = [
+            #[cfg(feature = "lz4")]
+            Algorithm::Lz4,
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd(3),
+        ];
+        for algorithm in algorithms {
+            let compressed = algorithm.compress(&payload).unwrap().unwrap();
+            assert!(compressed.len() < payload.len());
+            assert_eq!(Algorithm::decompress(&compressed).unwrap(), payload);
+            assert!(algorithm.compress(b"abc").unwrap().is_none());
+        }
+    }
+}
@@ -1,23 +1,42 @@
+#[cfg(feature = "tls")]
+use std::collections::HashMap;
 use std::io;
 use std::io::IoSlice;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
 use bytes::{Buf, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
+use crate::congestion::CongestionConfig;
+use crate::obfuscation::{is_stun_message, Obfuscator, OBFUSCATION_HEAD_LEN};
 use crate::pipe::{NodeAddress, PeerNodeAddress, RecvResult};
 use crate::protocol::node_id::{GroupCode, NodeID};
 use crate::protocol::{NetPacket, HEAD_LEN};
 pub use rust_p2p_core::nat::*;
+#[cfg(feature = "quic")]
+pub use rust_p2p_core::pipe::config::QuicPipeConfig;
+pub use rust_p2p_core::pipe::priority::Priority;
 use rust_p2p_core::pipe::recycle::RecycleBuf;
-use rust_p2p_core::pipe::tcp_pipe::{Decoder, Encoder, InitCodec};
+use rust_p2p_core::pipe::tcp_pipe::{
+    Decoder, Encoder, InitCodec, PlainTcpTransport, TcpReadHalf, TcpWriteHalf,
+};
+#[cfg(feature = "tls")]
+pub use rust_p2p_core::pipe::tls::{fingerprint, self_signed, CertificateDer, PrivateKeyDer};
 pub use rust_p2p_core::pipe::udp_pipe::Model;
-pub use rust_p2p_core::punch::config::{PunchModel, PunchModelBox};
+pub use rust_p2p_core::proxy::{ProxyConfig, ProxyProtocol};
+pub use rust_p2p_core::punch::config::{BirthdayPunchConfig, PunchModel, PunchModelBox};
+pub use rust_p2p_core::punch::{DefaultPunchPolicy, PunchPolicy, PunchReport, PunchStrategy};
 pub use rust_p2p_core::route::*;
+#[cfg(feature = "sim")]
+pub use rust_p2p_core::sim::SimHost;
 pub use rust_p2p_core::socket::LocalInterface;
+#[cfg(feature = "turn")]
+pub use rust_p2p_core::turn::TurnConfig;
+#[cfg(feature = "websocket")]
+pub use rust_p2p_core::websocket::{rustls, WebSocketConfig};
 
 pub(crate) mod punch_info;
 
@@ -29,6 +48,9 @@ pub struct PipeConfig {
     pub route_idle_time: Duration,
     pub udp_pipe_config: Option<UdpPipeConfig>,
     pub tcp_pipe_config: Option<TcpPipeConfig>,
+    /// `QUIC` over the `UDP` sockets, the punched `UDP` routes are upgraded to it
+    #[cfg(feature = "quic")]
+    pub quic_pipe_config: Option<QuicPipeConfig>,
     pub enable_extend: bool,
     pub group_code: Option<GroupCode>,
     pub self_id: Option<NodeID>,
@@ -38,6 +60,8 @@ pub struct PipeConfig {
     pub query_id_interval: Duration,
     pub query_id_max_num: usize,
     pub heartbeat_interval: Duration,
+    /// Interval of announcing the subscribed topics
+    pub topic_announce_interval: Duration,
     pub tcp_stun_servers: Option<Vec<String>>,
     pub udp_stun_servers: Option<Vec<String>>,
     pub mapping_addrs: Option<Vec<NodeAddress>>,
@@ -45,8 +69,46 @@ pub struct PipeConfig {
     pub recycle_buf_cap: usize,
     #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
     pub encryption: Option<crate::cipher::Algorithm>,
+    /// Compress user data for peers that support the algorithm.
+    /// The data is compressed before it is encrypted, so the length of an encrypted packet
+    /// reveals how well it compressed. Leave it off if secrets are sent along with
+    /// data an attacker can influence (CRIME/BREACH)
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub compression: Option<crate::compression::Algorithm>,
     pub default_interface: Option<LocalInterface>,
     pub use_v6: bool,
+    /// DSCP value marked on the UDP sockets and TCP connections
+    pub dscp: Option<u8>,
+    /// Punching between two symmetric nats
+    pub birthday_punch: BirthdayPunchConfig,
+    /// The schedule of punching
+    pub punch_policy: Arc<dyn PunchPolicy>,
+    /// Forward the listening ports on the gateway via PCP, NAT-PMP or UPnP IGD
+    pub port_mapping: bool,
+    /// Answer the STUN Binding requests of the other nodes on the `UDP`/`TCP` pipes, off by default
+    pub stun_server: bool,
+    /// Relay through a TURN server to the peers that can not be punched
+    #[cfg(feature = "turn")]
+    pub turn: Option<TurnConfig>,
+    /// Accept the `WebSocket` connections and configure dialing the `ws://`/`wss://` direct addresses
+    #[cfg(feature = "websocket")]
+    pub websocket: Option<WebSocketConfig>,
+    /// TLS on the `TCP` pipe
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// Hide the packets from protocol fingerprinting on the `UDP` and `TCP` pipes
+    pub obfuscation: Option<Obfuscator>,
+    /// Congestion control and pacing of the user data to each peer on the `UDP` routes
+    pub congestion: Option<CongestionConfig>,
+    /// Probe the path MTU of the direct `UDP` routes with DF-set probes,
+    /// the user data is sent as before and may still be fragmented
+    pub pmtu_discovery: bool,
+    /// Watch the local interfaces, and on a change drop the routes of the vanished addresses,
+    /// rerun the NAT test at once and consult all the peers again
+    pub interface_watch: bool,
+    /// Run the `UDP` and `TCP` pipes on a host of the simulated network instead of the OS
+    #[cfg(feature = "sim")]
+    pub sim_host: Option<SimHost>,
 }
 
 impl Default for PipeConfig {
@@ -57,6 +119,8 @@ impl Default for PipeConfig {
             enable_extend: false,
             udp_pipe_config: Some(Default::default()),
             tcp_pipe_config: Some(Default::default()),
+            #[cfg(feature = "quic")]
+            quic_pipe_config: None,
             route_idle_time: ROUTE_IDLE_TIME,
             group_code: None,
             self_id: None,
@@ -66,6 +130,7 @@ impl Default for PipeConfig {
             query_id_interval: Duration::from_secs(17),
             query_id_max_num: 3,
             heartbeat_interval: Duration::from_secs(5),
+            topic_announce_interval: Duration::from_secs(10),
             tcp_stun_servers: Some(vec![
                 "stun.flashdance.cx".to_string(),
                 "stun.sipnet.net".to_string(),
@@ -84,11 +149,30 @@ impl Default for PipeConfig {
             recycle_buf_cap: 64,
             #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
             encryption: None,
+            #[cfg(any(feature = "lz4", feature = "zstd"))]
+            compression: None,
             default_interface: None,
             use_v6: rust_p2p_core::pipe::config::UdpPipeConfig::default()
                 .set_use_v6(true)
                 .check()
                 .is_ok(),
+            dscp: None,
+            birthday_punch: Default::default(),
+            punch_policy: Arc::new(DefaultPunchPolicy::default()),
+            port_mapping: false,
+            stun_server: false,
+            #[cfg(feature = "turn")]
+            turn: None,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            obfuscation: None,
+            congestion: None,
+            pmtu_discovery: false,
+            interface_watch: true,
+            #[cfg(feature = "sim")]
+            sim_host: None,
         }
     }
 }
@@ -158,6 +242,10 @@ impl PipeConfig {
         self.heartbeat_interval = heartbeat_interval;
         self
     }
+    pub fn set_topic_announce_interval(mut self, topic_announce_interval: Duration) -> Self {
+        self.topic_announce_interval = topic_announce_interval;
+        self
+    }
     pub fn set_tcp_stun_servers(mut self, tcp_stun_servers: Vec<String>) -> Self {
         self.tcp_stun_servers.replace(tcp_stun_servers);
         self
@@ -183,6 +271,12 @@ impl PipeConfig {
         self.encryption.replace(encryption);
         self
     }
+    /// See [`PipeConfig::compression`], compressed data leaks through the length of the encrypted packet
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub fn set_compression(mut self, compression: crate::compression::Algorithm) -> Self {
+        self.compression.replace(compression);
+        self
+    }
     pub fn set_default_interface(mut self, default_interface: LocalInterface) -> Self {
         self.default_interface = Some(default_interface.clone());
         self
@@ -191,12 +285,137 @@ impl PipeConfig {
         self.use_v6 = use_v6;
         self
     }
+    pub fn set_dscp(mut self, dscp: u8) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+    pub fn set_birthday_punch(mut self, birthday_punch: BirthdayPunchConfig) -> Self {
+        self.birthday_punch = birthday_punch;
+        self
+    }
+    pub fn set_punch_policy<P: PunchPolicy + 'static>(mut self, punch_policy: P) -> Self {
+        self.punch_policy = Arc::new(punch_policy);
+        self
+    }
+    pub fn set_port_mapping(mut self, port_mapping: bool) -> Self {
+        self.port_mapping = port_mapping;
+        self
+    }
+    #[cfg(feature = "quic")]
+    pub fn set_quic_pipe_config(mut self, quic_pipe_config: QuicPipeConfig) -> Self {
+        self.quic_pipe_config.replace(quic_pipe_config);
+        self
+    }
+    pub fn set_stun_server(mut self, stun_server: bool) -> Self {
+        self.stun_server = stun_server;
+        self
+    }
+    /// The extensible pipe is enabled for the relayed pipelines
+    #[cfg(feature = "turn")]
+    pub fn set_turn(mut self, turn: TurnConfig) -> Self {
+        self.turn.replace(turn);
+        self
+    }
+    /// The extensible pipe is enabled for the `WebSocket` pipelines
+    #[cfg(feature = "websocket")]
+    pub fn set_websocket(mut self, websocket: WebSocketConfig) -> Self {
+        self.websocket.replace(websocket);
+        self
+    }
+    #[cfg(feature = "tls")]
+    pub fn set_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls.replace(tls);
+        self
+    }
+    pub fn set_obfuscation(mut self, obfuscation: Obfuscator) -> Self {
+        self.obfuscation.replace(obfuscation);
+        self
+    }
+    pub fn set_congestion(mut self, congestion: CongestionConfig) -> Self {
+        self.congestion.replace(congestion);
+        self
+    }
+    pub fn set_pmtu_discovery(mut self, pmtu_discovery: bool) -> Self {
+        self.pmtu_discovery = pmtu_discovery;
+        self
+    }
+    pub fn set_interface_watch(mut self, interface_watch: bool) -> Self {
+        self.interface_watch = interface_watch;
+        self
+    }
+    #[cfg(feature = "sim")]
+    pub fn set_sim_host(mut self, sim_host: SimHost) -> Self {
+        self.sim_host.replace(sim_host);
+        self
+    }
+    /// The relayed, `WebSocket` and `SOCKS5` `UDP` pipelines are carried by the extensible pipe
+    pub(crate) fn requires_extend(&self) -> bool {
+        #[cfg(feature = "turn")]
+        if self.turn.is_some() {
+            return true;
+        }
+        #[cfg(feature = "websocket")]
+        if self.websocket.is_some() || self.direct_addrs.iter().flatten().any(|v| v.is_websocket())
+        {
+            return true;
+        }
+        if matches!(&self.udp_pipe_config, Some(v) if v.proxy.is_some()) {
+            return true;
+        }
+        self.enable_extend
+    }
+}
+
+/// Both sides present a certificate, the certificate of a peer is pinned per [`NodeID`].
+/// The `TCP` simultaneous open is not available with TLS
+#[cfg(feature = "tls")]
+pub struct TlsConfig {
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub private_key: PrivateKeyDer<'static>,
+    /// The SHA-256 [`fingerprint`] of the certificate of every peer,
+    /// no peer is accepted if it is empty unless `allow_unpinned` is set
+    pub pins: HashMap<NodeID, [u8; 32]>,
+    /// Accept any certificate if `pins` is empty,
+    /// **the peers are not authenticated then** and TLS only encrypts
+    pub allow_unpinned: bool,
+    /// Sent in the SNI, such as the name of a web site
+    pub server_name: String,
+}
+
+#[cfg(feature = "tls")]
+impl TlsConfig {
+    pub fn new(
+        cert_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Self {
+        Self {
+            cert_chain,
+            private_key,
+            pins: HashMap::new(),
+            allow_unpinned: false,
+            server_name: "localhost".to_string(),
+        }
+    }
+    pub fn set_pins(mut self, pins: HashMap<NodeID, [u8; 32]>) -> Self {
+        self.pins = pins;
+        self
+    }
+    pub fn set_allow_unpinned(mut self, allow_unpinned: bool) -> Self {
+        self.allow_unpinned = allow_unpinned;
+        self
+    }
+    pub fn set_server_name(mut self, server_name: String) -> Self {
+        self.server_name = server_name;
+        self
+    }
 }
 
 pub struct TcpPipeConfig {
     pub route_idle_time: Duration,
     pub tcp_multiplexing_limit: usize,
     pub tcp_port: u16,
+    /// Dial out through a `SOCKS5` or `HTTP CONNECT` proxy, `TCP` punching is not possible then
+    pub proxy: Option<ProxyConfig>,
 }
 
 impl Default for TcpPipeConfig {
@@ -205,6 +424,7 @@ impl Default for TcpPipeConfig {
             route_idle_time: ROUTE_IDLE_TIME,
             tcp_multiplexing_limit: MULTI_PIPELINE,
             tcp_port: 0,
+            proxy: None,
         }
     }
 }
@@ -222,6 +442,10 @@ impl TcpPipeConfig {
         self.tcp_port = tcp_port;
         self
     }
+    pub fn set_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy.replace(proxy);
+        self
+    }
 }
 
 #[derive(Clone)]
@@ -230,6 +454,10 @@ pub struct UdpPipeConfig {
     pub sub_pipeline_num: usize,
     pub model: Model,
     pub udp_ports: Vec<u16>,
+    /// Reach the direct nodes through a `SOCKS5` `UDP ASSOCIATE`
+    pub proxy: Option<ProxyConfig>,
+    /// Use `UDP_SEGMENT` (GSO) and `UDP_GRO` on Linux when the kernel supports them
+    pub offload: bool,
 }
 
 impl Default for UdpPipeConfig {
@@ -239,6 +467,8 @@ impl Default for UdpPipeConfig {
             sub_pipeline_num: UDP_SUB_PIPELINE_NUM,
             model: Model::Low,
             udp_ports: vec![0, 0],
+            proxy: None,
+            offload: true,
         }
     }
 }
@@ -265,10 +495,19 @@ impl UdpPipeConfig {
         self.udp_ports = vec![udp_port];
         self
     }
+    pub fn set_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy.replace(proxy);
+        self
+    }
+    pub fn set_offload(mut self, offload: bool) -> Self {
+        self.offload = offload;
+        self
+    }
 }
 
 impl From<PipeConfig> for rust_p2p_core::pipe::config::PipeConfig {
     fn from(value: PipeConfig) -> Self {
+        let enable_extend = value.requires_extend();
         let recycle_buf = if value.recycle_buf_cap > 0 {
             Some(RecycleBuf::new(
                 value.recycle_buf_cap,
@@ -281,27 +520,55 @@ impl From<PipeConfig> for rust_p2p_core::pipe::config::PipeConfig {
             let mut config: rust_p2p_core::pipe::config::UdpPipeConfig = v.into();
             config.recycle_buf.clone_from(&recycle_buf);
             config.use_v6 = value.use_v6;
+            config.dscp = value.dscp;
             config
                 .default_interface
                 .clone_from(&value.default_interface);
+            #[cfg(feature = "sim")]
+            config.sim_host.clone_from(&value.sim_host);
             config
         });
         let tcp_pipe_config = value.tcp_pipe_config.map(|v| {
             let mut config: rust_p2p_core::pipe::config::TcpPipeConfig = v.into();
+            if let Some(obfuscator) = &value.obfuscation {
+                config.init_codec = Box::new(LengthPrefixedInitCodec {
+                    obfuscator: Some(obfuscator.clone()),
+                });
+            }
             config.recycle_buf = recycle_buf;
             config.use_v6 = value.use_v6;
+            config.dscp = value.dscp;
             config
                 .default_interface
                 .clone_from(&value.default_interface);
+            #[cfg(feature = "sim")]
+            config.sim_host.clone_from(&value.sim_host);
             config
         });
+        // The group shares the password, so it authenticates the QUIC peers too
+        #[cfg(feature = "quic")]
+        let quic_pipe_config = value.quic_pipe_config;
+        #[cfg(all(
+            feature = "quic",
+            any(feature = "aes-gcm", feature = "chacha20-poly1305")
+        ))]
+        let quic_pipe_config = quic_pipe_config.map(|mut v| {
+            if let (None, Some(encryption)) = (&v.secret, &value.encryption) {
+                v.secret = Some(encryption.password().as_bytes().to_vec());
+            }
+            v
+        });
         rust_p2p_core::pipe::config::PipeConfig {
             first_latency: value.first_latency,
             multi_pipeline: value.multi_pipeline,
             route_idle_time: value.route_idle_time,
             udp_pipe_config,
             tcp_pipe_config,
-            enable_extend: value.enable_extend,
+            #[cfg(feature = "quic")]
+            quic_pipe_config,
+            enable_extend,
+            birthday_punch: value.birthday_punch,
+            punch_policy: value.punch_policy,
         }
     }
 }
@@ -316,6 +583,10 @@ impl From<UdpPipeConfig> for rust_p2p_core::pipe::config::UdpPipeConfig {
             udp_ports: value.udp_ports,
             use_v6: false,
             recycle_buf: None,
+            dscp: None,
+            offload: value.offload,
+            #[cfg(feature = "sim")]
+            sim_host: None,
         }
     }
 }
@@ -328,49 +599,79 @@ impl From<TcpPipeConfig> for rust_p2p_core::pipe::config::TcpPipeConfig {
             default_interface: None,
             tcp_port: value.tcp_port,
             use_v6: false,
-            init_codec: Box::new(LengthPrefixedInitCodec),
+            init_codec: Box::new(LengthPrefixedInitCodec::default()),
+            transport: Arc::new(PlainTcpTransport),
+            proxy: value.proxy,
             recycle_buf: None,
+            dscp: None,
+            #[cfg(feature = "sim")]
+            sim_host: None,
         }
     }
 }
 
+/// The bytes needed to know the length of a frame
+const FRAME_HEAD_LEN: usize = 4;
+
+/// The obfuscated frames carry their length behind the obfuscation head
+fn frame_head_len(obfuscator: Option<&Obfuscator>) -> usize {
+    match obfuscator {
+        Some(_) => OBFUSCATION_HEAD_LEN + FRAME_HEAD_LEN,
+        None => FRAME_HEAD_LEN,
+    }
+}
+
+/// STUN messages (the top two bits are zero) share the stream with the packets,
+/// so that the node can serve address discovery on its `TCP` port
+fn frame_length(head: &[u8], obfuscator: Option<&Obfuscator>) -> usize {
+    match obfuscator {
+        Some(obfuscator) if !is_stun_message(head) => obfuscator.frame_length(head),
+        None if head[0] & 0x80 != 0 => NetPacket::unchecked(head).data_length() as usize,
+        _ => 20 + u16::from_be_bytes([head[2], head[3]]) as usize,
+    }
+}
+
 /// Fixed-length prefix encoder/decoder.
-pub(crate) struct LengthPrefixedEncoder {}
+pub(crate) struct LengthPrefixedEncoder {
+    obfuscator: Option<Obfuscator>,
+}
 
 pub(crate) struct LengthPrefixedDecoder {
     buf: BytesMut,
+    obfuscator: Option<Obfuscator>,
 }
 
 impl LengthPrefixedEncoder {
-    pub(crate) fn new() -> Self {
-        Self {}
+    pub(crate) fn new(obfuscator: Option<Obfuscator>) -> Self {
+        Self { obfuscator }
     }
 }
 
 impl LengthPrefixedDecoder {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(obfuscator: Option<Obfuscator>) -> Self {
         Self {
             buf: Default::default(),
+            obfuscator,
         }
     }
 }
 
 #[async_trait]
 impl Decoder for LengthPrefixedDecoder {
-    async fn decode(&mut self, read: &mut OwnedReadHalf, src: &mut [u8]) -> io::Result<usize> {
+    async fn decode(&mut self, read: &mut TcpReadHalf, src: &mut [u8]) -> io::Result<usize> {
         if src.len() < HEAD_LEN {
             return Err(io::Error::new(io::ErrorKind::Other, "too short"));
         }
+        let head_len = frame_head_len(self.obfuscator.as_ref());
         let mut offset = 0;
         loop {
             if self.buf.is_empty() {
                 let len = read.read(&mut src[offset..]).await?;
                 offset += len;
-                if offset < HEAD_LEN {
+                if offset < head_len {
                     continue;
                 }
-                let packet = NetPacket::unchecked(&src);
-                let data_length = packet.data_length() as usize;
+                let data_length = frame_length(src, self.obfuscator.as_ref());
                 if data_length > src.len() {
                     return Err(io::Error::new(io::ErrorKind::Other, "too short"));
                 }
@@ -388,14 +689,13 @@ impl Decoder for LengthPrefixedDecoder {
                 }
             } else {
                 let len = self.buf.len();
-                if len < HEAD_LEN {
+                if len < head_len {
                     src[..len].copy_from_slice(self.buf.as_ref());
                     offset += len;
                     self.buf.clear();
                     continue;
                 }
-                let packet = NetPacket::unchecked(self.buf.as_ref());
-                let data_length = packet.data_length() as usize;
+                let data_length = frame_length(self.buf.as_ref(), self.obfuscator.as_ref());
                 if data_length > src.len() {
                     return Err(io::Error::new(io::ErrorKind::Other, "too short"));
                 }
@@ -420,10 +720,9 @@ impl Decoder for LengthPrefixedDecoder {
 
 #[async_trait]
 impl Encoder for LengthPrefixedEncoder {
-    async fn encode(&mut self, write: &mut OwnedWriteHalf, data: &[u8]) -> io::Result<()> {
-        let len = data.len();
-        let packet = NetPacket::unchecked(data);
-        if packet.data_length() as usize != len {
+    async fn encode(&mut self, write: &mut TcpWriteHalf, data: &[u8]) -> io::Result<()> {
+        let obfuscator = self.obfuscator.as_ref();
+        if data.len() < frame_head_len(obfuscator) || frame_length(data, obfuscator) != data.len() {
             return Err(io::Error::from(io::ErrorKind::InvalidData));
         }
         write.write_all(data).await
@@ -431,7 +730,7 @@ impl Encoder for LengthPrefixedEncoder {
 
     async fn encode_multiple(
         &mut self,
-        write: &mut OwnedWriteHalf,
+        write: &mut TcpWriteHalf,
         bufs: &[IoSlice<'_>],
     ) -> io::Result<()> {
         let mut index = 0;
@@ -471,14 +770,16 @@ impl Encoder for LengthPrefixedEncoder {
     }
 }
 
-#[derive(Clone)]
-pub(crate) struct LengthPrefixedInitCodec;
+#[derive(Clone, Default)]
+pub(crate) struct LengthPrefixedInitCodec {
+    obfuscator: Option<Obfuscator>,
+}
 
 impl InitCodec for LengthPrefixedInitCodec {
     fn codec(&self, _addr: SocketAddr) -> io::Result<(Box<dyn Decoder>, Box<dyn Encoder>)> {
         Ok((
-            Box::new(LengthPrefixedDecoder::new()),
-            Box::new(LengthPrefixedEncoder::new()),
+            Box::new(LengthPrefixedDecoder::new(self.obfuscator.clone())),
+            Box::new(LengthPrefixedEncoder::new(self.obfuscator.clone())),
         ))
     }
 }
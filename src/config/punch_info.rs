@@ -1,8 +1,10 @@
-use rust_p2p_core::nat::{NatInfo, NatType};
+use rust_p2p_core::nat::{NatBehavior, NatInfo, NatType};
 use rust_p2p_core::pipe::udp_pipe::UDPIndex;
 use rust_p2p_core::punch::{PunchConsultInfo, PunchModelBox};
 use rust_p2p_core::route::Index;
+use serde::{Deserialize, Serialize};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct NodePunchInfo {
@@ -19,6 +21,14 @@ pub struct NodePunchInfo {
     pub ipv6: Option<Ipv6Addr>,
     pub nat_type: NatType,
     pub public_ips: Vec<Ipv4Addr>,
+    pub mapping_behavior: NatBehavior,
+    pub filtering_behavior: NatBehavior,
+    pub hairpinning: Option<bool>,
+    pub mapping_lifetime: Option<Duration>,
+    pub predicted_ports: Vec<u16>,
+    /// Advertised to the peers in the punch consult
+    pub quic: bool,
+    pub stun_server: bool,
 }
 
 impl NodePunchInfo {
@@ -37,6 +47,13 @@ impl NodePunchInfo {
             ipv6: None,
             nat_type: Default::default(),
             public_ips: vec![],
+            mapping_behavior: Default::default(),
+            filtering_behavior: Default::default(),
+            hairpinning: None,
+            mapping_lifetime: None,
+            predicted_ports: vec![],
+            quic: false,
+            stun_server: false,
         }
     }
     pub fn exists_nat_info(&self) -> bool {
@@ -110,9 +127,229 @@ impl NodePunchInfo {
             local_tcp_port: self.local_tcp_port,
             public_tcp_port: self.public_tcp_port,
             seq,
+            mapping_behavior: self.mapping_behavior,
+            filtering_behavior: self.filtering_behavior,
+            hairpinning: self.hairpinning,
+            mapping_lifetime: self.mapping_lifetime,
+            predicted_ports: self.predicted_ports.clone(),
         }
     }
     pub fn punch_consult_info(&self, seq: u32) -> PunchConsultInfo {
         PunchConsultInfo::new(self.punch_model_box.clone(), self.nat_info(seq))
+            .set_quic(self.quic)
+            .set_stun_server(self.stun_server)
+    }
+}
+
+/// The punch consult of the nodes that predate the NAT behaviour discovery.
+/// It is frozen on the wire, those nodes stop reading after it and ignore the extension
+#[derive(Serialize, Deserialize)]
+struct ConsultInfoV1 {
+    peer_punch_model: PunchModelBox,
+    peer_nat_info: NatInfoV1,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NatInfoV1 {
+    nat_type: NatType,
+    public_ips: Vec<Ipv4Addr>,
+    public_ports: Vec<u16>,
+    mapping_tcp_addr: Vec<SocketAddr>,
+    mapping_udp_addr: Vec<SocketAddr>,
+    public_port_range: u16,
+    local_ipv4: Ipv4Addr,
+    ipv6: Option<Ipv6Addr>,
+    local_udp_ports: Vec<u16>,
+    local_tcp_port: u16,
+    public_tcp_port: u16,
+    seq: u32,
+}
+
+/// The fields added after v1, encoded by name so that a node skips the fields it does not know
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct ConsultExtension {
+    mapping_behavior: NatBehavior,
+    filtering_behavior: NatBehavior,
+    hairpinning: Option<bool>,
+    mapping_lifetime: Option<Duration>,
+    predicted_ports: Vec<u16>,
+    tcp_punch_delay: Option<Duration>,
+    immediate: bool,
+    quic: bool,
+    stun_server: bool,
+}
+
+/// The payload of `PunchConsultRequest` and `PunchConsultReply`,
+/// the v1 fields followed by the extension
+pub(crate) fn encode_consult_info(info: &PunchConsultInfo) -> crate::error::Result<Vec<u8>> {
+    let nat_info = &info.peer_nat_info;
+    let v1 = ConsultInfoV1 {
+        peer_punch_model: info.peer_punch_model.clone(),
+        peer_nat_info: NatInfoV1 {
+            nat_type: nat_info.nat_type,
+            public_ips: nat_info.public_ips.clone(),
+            public_ports: nat_info.public_ports.clone(),
+            mapping_tcp_addr: nat_info.mapping_tcp_addr.clone(),
+            mapping_udp_addr: nat_info.mapping_udp_addr.clone(),
+            public_port_range: nat_info.public_port_range,
+            local_ipv4: nat_info.local_ipv4,
+            ipv6: nat_info.ipv6,
+            local_udp_ports: nat_info.local_udp_ports.clone(),
+            local_tcp_port: nat_info.local_tcp_port,
+            public_tcp_port: nat_info.public_tcp_port,
+            seq: nat_info.seq,
+        },
+    };
+    let extension = ConsultExtension {
+        mapping_behavior: nat_info.mapping_behavior,
+        filtering_behavior: nat_info.filtering_behavior,
+        hairpinning: nat_info.hairpinning,
+        mapping_lifetime: nat_info.mapping_lifetime,
+        predicted_ports: nat_info.predicted_ports.clone(),
+        tcp_punch_delay: info.tcp_punch_delay,
+        immediate: info.immediate,
+        quic: info.quic,
+        stun_server: info.stun_server,
+    };
+    let mut data = rmp_serde::to_vec(&v1)?;
+    data.extend_from_slice(&rmp_serde::to_vec_named(&extension)?);
+    Ok(data)
+}
+
+pub(crate) fn decode_consult_info(mut data: &[u8]) -> crate::error::Result<PunchConsultInfo> {
+    let v1: ConsultInfoV1 = rmp_serde::from_read(&mut data)?;
+    // Sent by a node that predates the extension
+    let extension: ConsultExtension = if data.is_empty() {
+        ConsultExtension::default()
+    } else {
+        rmp_serde::from_read(&mut data)?
+    };
+    let nat_info = v1.peer_nat_info;
+    Ok(PunchConsultInfo {
+        peer_punch_model: v1.peer_punch_model,
+        peer_nat_info: NatInfo {
+            nat_type: nat_info.nat_type,
+            public_ips: nat_info.public_ips,
+            public_ports: nat_info.public_ports,
+            mapping_tcp_addr: nat_info.mapping_tcp_addr,
+            mapping_udp_addr: nat_info.mapping_udp_addr,
+            public_port_range: nat_info.public_port_range,
+            local_ipv4: nat_info.local_ipv4,
+            ipv6: nat_info.ipv6,
+            local_udp_ports: nat_info.local_udp_ports,
+            local_tcp_port: nat_info.local_tcp_port,
+            public_tcp_port: nat_info.public_tcp_port,
+            seq: nat_info.seq,
+            mapping_behavior: extension.mapping_behavior,
+            filtering_behavior: extension.filtering_behavior,
+            hairpinning: extension.hairpinning,
+            mapping_lifetime: extension.mapping_lifetime,
+            predicted_ports: extension.predicted_ports,
+        },
+        tcp_punch_delay: extension.tcp_punch_delay,
+        immediate: extension.immediate,
+        quic: extension.quic,
+        stun_server: extension.stun_server,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::punch_info::{decode_consult_info, encode_consult_info, NodePunchInfo};
+    use rust_p2p_core::nat::{NatBehavior, NatType};
+    use rust_p2p_core::punch::PunchModelBox;
+    use serde::{Deserialize, Serialize};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::time::Duration;
+
+    /// `NatInfo` before the behaviour discovery
+    #[derive(Serialize, Deserialize)]
+    struct OldNatInfo {
+        nat_type: NatType,
+        public_ips: Vec<Ipv4Addr>,
+        public_ports: Vec<u16>,
+        mapping_tcp_addr: Vec<SocketAddr>,
+        mapping_udp_addr: Vec<SocketAddr>,
+        public_port_range: u16,
+        local_ipv4: Ipv4Addr,
+        ipv6: Option<Ipv6Addr>,
+        local_udp_ports: Vec<u16>,
+        local_tcp_port: u16,
+        public_tcp_port: u16,
+        seq: u32,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct OldPunchConsultInfo {
+        peer_punch_model: PunchModelBox,
+        peer_nat_info: OldNatInfo,
+    }
+
+    #[test]
+    fn decode_old_consult_info() {
+        let old = OldPunchConsultInfo {
+            peer_punch_model: PunchModelBox::all(),
+            peer_nat_info: OldNatInfo {
+                nat_type: NatType::Symmetric,
+                public_ips: vec![Ipv4Addr::new(1, 2, 3, 4)],
+                public_ports: vec![1000],
+                mapping_tcp_addr: vec![],
+                mapping_udp_addr: vec![],
+                public_port_range: 10,
+                local_ipv4: Ipv4Addr::new(192, 168, 1, 2),
+                ipv6: None,
+                local_udp_ports: vec![2000],
+                local_tcp_port: 3000,
+                public_tcp_port: 3000,
+                seq: 7,
+            },
+        };
+        let data = rmp_serde::to_vec(&old).unwrap();
+        let info = decode_consult_info(&data).unwrap();
+        let nat_info = info.peer_nat_info;
+        assert_eq!(nat_info.nat_type, NatType::Symmetric);
+        assert_eq!(nat_info.seq, 7);
+        assert_eq!(nat_info.mapping_behavior, NatBehavior::default());
+        assert!(nat_info.hairpinning.is_none());
+        assert!(nat_info.mapping_lifetime.is_none());
+        assert!(nat_info.predicted_ports.is_empty());
+        assert!(info.tcp_punch_delay.is_none());
+        assert!(!info.immediate);
+        assert!(!info.quic);
+        assert!(!info.stun_server);
+    }
+
+    #[test]
+    fn decode_new_consult_info() {
+        let mut punch_info = NodePunchInfo::new(vec![2000], 3000);
+        punch_info.nat_type = NatType::Symmetric;
+        punch_info.public_ips = vec![Ipv4Addr::new(1, 2, 3, 4)];
+        punch_info.public_udp_ports = vec![1000];
+        punch_info.hairpinning = Some(true);
+        punch_info.predicted_ports = vec![1001, 1002];
+        punch_info.quic = true;
+        punch_info.stun_server = true;
+        let info = punch_info
+            .punch_consult_info(7)
+            .set_tcp_punch_delay(Duration::from_millis(300))
+            .set_immediate(true);
+        let data = encode_consult_info(&info).unwrap();
+
+        // A node that predates the extension reads the v1 fields and ignores the rest
+        let old = rmp_serde::from_slice::<OldPunchConsultInfo>(&data).unwrap();
+        assert_eq!(old.peer_nat_info.nat_type, NatType::Symmetric);
+        assert_eq!(old.peer_nat_info.public_ports, vec![1000]);
+        assert_eq!(old.peer_nat_info.local_tcp_port, 3000);
+        assert_eq!(old.peer_nat_info.seq, 7);
+
+        let new = decode_consult_info(&data).unwrap();
+        assert_eq!(new.peer_nat_info.seq, 7);
+        assert_eq!(new.peer_nat_info.hairpinning, Some(true));
+        assert_eq!(new.peer_nat_info.predicted_ports, vec![1001, 1002]);
+        assert_eq!(new.tcp_punch_delay, Some(Duration::from_millis(300)));
+        assert!(new.immediate);
+        assert!(new.quic);
+        assert!(new.stun_server);
     }
 }
@@ -0,0 +1,387 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+use crate::protocol::node_id::NodeID;
+
+/// The receivers acknowledge at most this often
+pub(crate) const ACK_INTERVAL: Duration = Duration::from_millis(20);
+/// A peer that stops acknowledging is no longer limited
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+/// The round-trip time until the first `TimestampReply`
+const INITIAL_RTT: Duration = Duration::from_millis(100);
+const MSS: f64 = 1400.0;
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+/// Random loss below this ratio is not taken as congestion
+const LOSS_TOLERANCE: f64 = 0.01;
+/// The resolution of the send history
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+/// How early the paced packets may leave
+const PACING_SLACK: Duration = Duration::from_millis(1);
+/// A receiver forgets the senders silent for this long
+const RECEIVER_IDLE: Duration = Duration::from_secs(60);
+
+/// CUBIC-like congestion control with pacing on the `UDP` routes, per peer.
+/// The window opens with the first `CongestionAck`, so the peers that do not acknowledge are not limited
+#[derive(Clone, Debug)]
+pub struct CongestionConfig {
+    pub initial_window: usize,
+    pub min_window: usize,
+    pub max_window: usize,
+}
+
+impl Default for CongestionConfig {
+    fn default() -> Self {
+        Self {
+            initial_window: 10 * MSS as usize,
+            min_window: 4 * MSS as usize,
+            max_window: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl CongestionConfig {
+    /// The window in bytes before the first loss
+    pub fn set_initial_window(mut self, initial_window: usize) -> Self {
+        self.initial_window = initial_window;
+        self
+    }
+    pub fn set_min_window(mut self, min_window: usize) -> Self {
+        self.min_window = min_window;
+        self
+    }
+    pub fn set_max_window(mut self, max_window: usize) -> Self {
+        self.max_window = max_window;
+        self
+    }
+}
+
+/// The controllers of the peers we send to
+#[derive(Clone)]
+pub(crate) struct CongestionControl {
+    config: CongestionConfig,
+    peers: Arc<DashMap<NodeID, Controller>>,
+}
+
+impl CongestionControl {
+    pub(crate) fn new(config: CongestionConfig) -> Self {
+        Self {
+            config,
+            peers: Default::default(),
+        }
+    }
+    /// Waits until the window and the pacing rate allow `len` more bytes to the peer.
+    /// `rtt` is the end-to-end round trip in milliseconds as measured by `TimestampRequest`,
+    /// it is only used until the acks of the peer have been timed
+    pub(crate) async fn wait(&self, node_id: NodeID, len: usize, rtt: u32) {
+        let rtt = if rtt == rust_p2p_core::route::DEFAULT_RTT {
+            INITIAL_RTT
+        } else {
+            Duration::from_millis(rtt.max(1) as u64)
+        };
+        loop {
+            let delay = self
+                .peers
+                .entry(node_id)
+                .or_insert_with(|| Controller::new(&self.config))
+                .poll_send(len as u64, rtt, Instant::now());
+            match delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return,
+            }
+        }
+    }
+    pub(crate) fn on_ack(&self, node_id: &NodeID, total: u64) {
+        if let Some(mut controller) = self.peers.get_mut(node_id) {
+            controller.on_ack(total, Instant::now());
+        }
+    }
+    /// The round trip timed by the acks of the peer
+    #[cfg(all(test, feature = "sim"))]
+    pub(crate) fn srtt(&self, node_id: &NodeID) -> Option<Duration> {
+        self.peers.get(node_id)?.srtt
+    }
+}
+
+struct Controller {
+    window: f64,
+    ssthresh: f64,
+    w_max: f64,
+    min_window: f64,
+    max_window: f64,
+    /// Start of the current cubic growth
+    epoch: Instant,
+    /// Only the loss of the bytes sent after this reduces the window again
+    recovery: u64,
+    rtt: Duration,
+    /// Smoothed from the time the acks take, which covers the relays on the way
+    srtt: Option<Duration>,
+    sent: u64,
+    acked: u64,
+    /// The most bytes found missing, the late ones lower it for a while
+    lost: u64,
+    expected: u64,
+    /// The total sent bytes at each sample time
+    history: VecDeque<(Instant, u64)>,
+    last_ack: Option<Instant>,
+    next_send: Instant,
+}
+
+impl Controller {
+    fn new(config: &CongestionConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            window: config.initial_window as f64,
+            ssthresh: f64::MAX,
+            w_max: 0.0,
+            min_window: config.min_window as f64,
+            max_window: config.max_window as f64,
+            epoch: now,
+            recovery: 0,
+            rtt: INITIAL_RTT,
+            srtt: None,
+            sent: 0,
+            acked: 0,
+            lost: 0,
+            expected: 0,
+            history: VecDeque::new(),
+            last_ack: None,
+            next_send: now,
+        }
+    }
+    /// The packets sent before the deadline are either acknowledged or lost
+    fn deadline(&self, now: Instant) -> Option<Instant> {
+        now.checked_sub(self.rtt * 5 / 4 + ACK_INTERVAL * 2)
+    }
+    fn sent_before(&mut self, time: Option<Instant>) -> u64 {
+        let Some(time) = time else {
+            return self.acked;
+        };
+        while self.history.len() > 1 && self.history[1].0 <= time {
+            self.history.pop_front();
+        }
+        match self.history.front() {
+            Some((t, sent)) if *t <= time => *sent,
+            _ => self.acked,
+        }
+    }
+    /// When the byte at `total` was sent, unless the history has dropped it
+    fn sent_at(&self, total: u64) -> Option<Instant> {
+        let index = self.history.partition_point(|(_, sent)| *sent < total);
+        if index == 0 {
+            return None;
+        }
+        Some(self.history[index - 1].0)
+    }
+    /// The receiver holds the acks for up to `ACK_INTERVAL`
+    fn sample_rtt(&mut self, sent_at: Instant, now: Instant) {
+        let sample = now
+            .duration_since(sent_at)
+            .saturating_sub(ACK_INTERVAL)
+            .max(SAMPLE_INTERVAL);
+        let srtt = match self.srtt {
+            Some(srtt) => (srtt * 7 + sample) / 8,
+            None => sample,
+        };
+        self.srtt = Some(srtt);
+        self.rtt = srtt;
+    }
+    fn in_flight(&mut self, now: Instant) -> u64 {
+        let settled = self.sent_before(self.deadline(now)).max(self.acked);
+        self.sent.saturating_sub(settled)
+    }
+    /// Records the send and returns `None`, or the time to wait before asking again
+    fn poll_send(&mut self, len: u64, rtt: Duration, now: Instant) -> Option<Duration> {
+        let rtt = self.srtt.unwrap_or(rtt);
+        self.rtt = rtt;
+        let limited = matches!(self.last_ack, Some(t) if now.duration_since(t) < ACK_TIMEOUT);
+        if limited {
+            if self.in_flight(now) + len > self.window as u64 {
+                return Some((rtt / 4).clamp(PACING_SLACK, ACK_INTERVAL / 4));
+            }
+            if self.next_send > now + PACING_SLACK {
+                return Some(self.next_send - now);
+            }
+            let gain = if self.window < self.ssthresh {
+                2.0
+            } else {
+                1.25
+            };
+            let rate = self.window * gain / rtt.as_secs_f64();
+            self.next_send = self.next_send.max(now) + Duration::from_secs_f64(len as f64 / rate);
+        }
+        if !matches!(self.history.back(), Some((t, _)) if now.duration_since(*t) < SAMPLE_INTERVAL)
+        {
+            self.history.push_back((now, self.sent));
+        }
+        self.sent += len;
+        None
+    }
+    fn on_ack(&mut self, total: u64, now: Instant) {
+        if total < self.acked || total > self.sent {
+            // One of the two sides has restarted
+            self.sent = total;
+            self.acked = total;
+            self.lost = 0;
+            self.recovery = total;
+            self.expected = total;
+            self.history.clear();
+            self.last_ack = Some(now);
+            return;
+        }
+        let newly_acked = (total - self.acked) as f64;
+        // Looked up before the history is pruned
+        let sent_at = self.sent_at(total);
+        self.acked = total;
+        self.last_ack = Some(now);
+        let expected = self.sent_before(self.deadline(now)).max(self.acked);
+        let lost = expected - self.acked;
+        let newly_lost = lost.saturating_sub(self.lost);
+        let newly_expected = expected.saturating_sub(self.expected);
+        self.lost = self.lost.max(lost);
+        self.expected = expected;
+        if newly_lost > 0 && newly_lost as f64 > newly_expected as f64 * LOSS_TOLERANCE {
+            if expected > self.recovery {
+                self.w_max = self.window;
+                self.window = (self.window * CUBIC_BETA).max(self.min_window);
+                self.ssthresh = self.window;
+                self.epoch = now;
+                self.recovery = self.sent;
+            }
+            return;
+        }
+        // The total lags behind the bytes sent once some are lost, so only the clean acks are timed
+        if let Some(sent_at) = sent_at {
+            self.sample_rtt(sent_at, now);
+        }
+        if self.window < self.ssthresh {
+            self.window += newly_acked;
+        } else {
+            let t = now.duration_since(self.epoch).as_secs_f64() + self.rtt.as_secs_f64();
+            let k = (self.w_max / MSS * (1.0 - CUBIC_BETA) / CUBIC_C).cbrt();
+            let target = (CUBIC_C * (t - k).powi(3) + self.w_max / MSS) * MSS;
+            let step = (target - self.window).max(MSS);
+            self.window += step * newly_acked / self.window;
+        }
+        self.window = self.window.clamp(self.min_window, self.max_window);
+    }
+}
+
+/// The bytes received from the senders that request acknowledgement
+#[derive(Clone, Default)]
+pub(crate) struct AckTable {
+    received: Arc<DashMap<NodeID, (u64, bool, Instant)>>,
+    notify: Arc<Notify>,
+}
+
+impl AckTable {
+    pub(crate) fn received(&self, node_id: NodeID, len: usize) {
+        let mut entry = self
+            .received
+            .entry(node_id)
+            .or_insert_with(|| (0, false, Instant::now()));
+        let (total, pending, last) = entry.value_mut();
+        *total += len as u64;
+        *last = Instant::now();
+        if !*pending {
+            *pending = true;
+            self.notify.notify_one();
+        }
+    }
+    pub(crate) async fn notified(&self) {
+        self.notify.notified().await
+    }
+    /// The totals not yet acknowledged
+    pub(crate) fn take_pending(&self) -> Vec<(NodeID, u64)> {
+        self.received
+            .retain(|_, (_, _, last)| last.elapsed() < RECEIVER_IDLE);
+        self.received
+            .iter_mut()
+            .filter_map(|mut v| {
+                let node_id = *v.key();
+                let (total, pending, _) = v.value_mut();
+                if *pending {
+                    *pending = false;
+                    Some((node_id, *total))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CongestionConfig, Controller, ACK_INTERVAL, MSS};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn window_grows_and_backs_off() {
+        let config = CongestionConfig::default();
+        let mut controller = Controller::new(&config);
+        let rtt = Duration::from_millis(50);
+        let mut now = Instant::now();
+        let len = MSS as u64;
+        // Not limited before the first ack
+        for _ in 0..100 {
+            assert!(controller.poll_send(len, rtt, now).is_none());
+        }
+        controller.on_ack(controller.sent, now);
+        let initial = controller.window;
+        assert!(initial > config.initial_window as f64);
+        // The window holds back the sender until the acks arrive
+        let mut blocked = false;
+        for _ in 0..1000 {
+            if controller.poll_send(len, rtt, now).is_some() {
+                blocked = true;
+                break;
+            }
+        }
+        assert!(blocked);
+        now += rtt;
+        controller.on_ack(controller.sent, now);
+        assert!(controller.window > initial);
+
+        // Half of what was sent a round trip ago never arrives
+        let window = controller.window;
+        let acked = controller.acked;
+        for _ in 0..20 {
+            now += Duration::from_millis(2);
+            controller.next_send = now;
+            let _ = controller.poll_send(len, rtt, now);
+        }
+        now += rtt * 2 + ACK_INTERVAL * 2;
+        controller.on_ack(acked + (controller.sent - acked) / 2, now);
+        assert!(controller.window < window);
+        assert!(controller.window >= config.min_window as f64);
+    }
+
+    #[test]
+    fn acks_time_the_round_trip() {
+        let config = CongestionConfig::default();
+        let mut controller = Controller::new(&config);
+        // The hint is the round trip to a relay, far shorter than to the peer
+        let hint = Duration::from_millis(10);
+        let mut now = Instant::now();
+        let len = MSS as u64;
+        for _ in 0..10 {
+            assert!(controller.poll_send(len, hint, now).is_none());
+        }
+        assert!(controller.srtt.is_none());
+        now += Duration::from_millis(80) + ACK_INTERVAL;
+        controller.on_ack(controller.sent, now);
+        assert_eq!(controller.srtt, Some(Duration::from_millis(80)));
+        // The hint is ignored once the acks have been timed
+        now += Duration::from_millis(1);
+        let _ = controller.poll_send(len, hint, now);
+        assert_eq!(controller.rtt, Duration::from_millis(80));
+        now += Duration::from_millis(40) + ACK_INTERVAL;
+        controller.on_ack(controller.sent, now);
+        assert_eq!(controller.srtt, Some(Duration::from_millis(75)));
+    }
+}
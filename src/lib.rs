@@ -1,7 +1,11 @@
 pub mod protocol;
 
 pub mod cipher;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub mod compression;
 pub mod config;
+pub mod congestion;
 pub mod error;
 pub mod extend;
+pub mod obfuscation;
 pub mod pipe;
@@ -0,0 +1,169 @@
+/*
+   0                                            15                                              31
+   0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                         nonce(56)                                           |
+  +                                                                   +-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                                                   |   padding len(8) ^ k    |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                   packet header(256) ^ k                                    |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                       payload(n)                                            |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                   random padding(n)                                         |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  k = SHA-512(key | nonce), the payload is left to the cipher.
+  The first byte never has the QUIC bit (0x40) and is never 0x00 or 0x01,
+  so the STUN messages and the QUIC packets on the same sockets are still told apart.
+*/
+
+use bytes::{BufMut, BytesMut};
+use rand::Rng;
+use sha2::Digest;
+
+use crate::error::{Error, Result};
+use crate::protocol::{NetPacket, HEAD_LEN};
+
+const NONCE_LEN: usize = 7;
+/// The bytes before the packet
+pub const OBFUSCATION_HEAD_LEN: usize = NONCE_LEN + 1;
+/// The most bytes an obfuscated packet grows by
+pub const MAX_OVERHEAD: usize = OBFUSCATION_HEAD_LEN + u8::MAX as usize;
+const STUN_MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xA4, 0x42];
+
+/// Hides the packet header from protocol fingerprinting.
+/// All nodes, including the relays of other groups, must share the secret
+#[derive(Clone)]
+pub struct Obfuscator {
+    key: [u8; 32],
+    max_padding: u8,
+}
+
+impl Obfuscator {
+    pub fn new(secret: String) -> Self {
+        let key: [u8; 32] = {
+            let mut hasher = sha2::Sha256::new();
+            hasher.update(b"rustp2p obfuscation");
+            hasher.update(secret.as_bytes());
+            hasher.finalize().into()
+        };
+        Self {
+            key,
+            max_padding: 64,
+        }
+    }
+    /// Random padding of up to `max_padding` bytes is appended to every packet
+    pub fn set_max_padding(mut self, max_padding: u8) -> Self {
+        self.max_padding = max_padding;
+        self
+    }
+    fn keystream(&self, nonce: &[u8]) -> [u8; 64] {
+        let mut hasher = sha2::Sha512::new();
+        hasher.update(self.key);
+        hasher.update(&nonce[..NONCE_LEN]);
+        hasher.finalize().into()
+    }
+    pub fn obfuscate(&self, packet: &[u8]) -> BytesMut {
+        let padding = rand::thread_rng().gen_range(0..=self.max_padding);
+        self.obfuscate_with_padding(packet, padding)
+    }
+    /// The path MTU probes are sent without random padding, so their size is exact
+    pub(crate) fn obfuscate_with_padding(&self, packet: &[u8], padding: u8) -> BytesMut {
+        let mut rng = rand::thread_rng();
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut nonce[..]);
+        nonce[0] &= !0x40;
+        if nonce[0] < 0x02 {
+            nonce[0] |= 0x80;
+        }
+        // A `TCP` stream tells the STUN messages by the magic cookie
+        if nonce[4] == STUN_MAGIC_COOKIE[0] {
+            nonce[4] = !nonce[4];
+        }
+        let keystream = self.keystream(&nonce);
+        let len = OBFUSCATION_HEAD_LEN + packet.len();
+        let mut buf = BytesMut::with_capacity(len + padding as usize);
+        buf.extend_from_slice(&nonce);
+        buf.put_u8(padding ^ keystream[0]);
+        buf.extend_from_slice(packet);
+        for (v, k) in buf[OBFUSCATION_HEAD_LEN..]
+            .iter_mut()
+            .zip(&keystream[1..=HEAD_LEN])
+        {
+            *v ^= k;
+        }
+        buf.resize(len + padding as usize, 0);
+        rng.fill(&mut buf[len..]);
+        buf
+    }
+    /// Restore the packet to the front of `buf`, returns the length of the packet
+    pub fn deobfuscate(&self, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < OBFUSCATION_HEAD_LEN + HEAD_LEN {
+            return Err(Error::Overflow {
+                cap: buf.len(),
+                required: OBFUSCATION_HEAD_LEN + HEAD_LEN,
+            });
+        }
+        let keystream = self.keystream(buf);
+        let padding = (buf[NONCE_LEN] ^ keystream[0]) as usize;
+        for (v, k) in buf[OBFUSCATION_HEAD_LEN..OBFUSCATION_HEAD_LEN + HEAD_LEN]
+            .iter_mut()
+            .zip(&keystream[1..=HEAD_LEN])
+        {
+            *v ^= k;
+        }
+        let len = NetPacket::unchecked(&buf[OBFUSCATION_HEAD_LEN..]).data_length() as usize;
+        if len < HEAD_LEN || OBFUSCATION_HEAD_LEN + len + padding != buf.len() {
+            return Err(Error::InvalidArgument(
+                "obfuscated packet len invalid".into(),
+            ));
+        }
+        buf.copy_within(OBFUSCATION_HEAD_LEN..OBFUSCATION_HEAD_LEN + len, 0);
+        Ok(len)
+    }
+    /// The length of an obfuscated packet on a stream,
+    /// `head` needs [`OBFUSCATION_HEAD_LEN`] + 3 bytes
+    pub fn frame_length(&self, head: &[u8]) -> usize {
+        let keystream = self.keystream(head);
+        let padding = (head[NONCE_LEN] ^ keystream[0]) as usize;
+        let len = u16::from_be_bytes([
+            head[OBFUSCATION_HEAD_LEN + 1] ^ keystream[2],
+            head[OBFUSCATION_HEAD_LEN + 2] ^ keystream[3],
+        ]) as usize;
+        OBFUSCATION_HEAD_LEN + len + padding
+    }
+}
+
+/// STUN messages have the top two bits clear and the magic cookie
+pub(crate) fn is_stun_message(head: &[u8]) -> bool {
+    head.len() >= 8 && head[0] & 0xC0 == 0 && head[4..8] == STUN_MAGIC_COOKIE
+}
+
+#[cfg(test)]
+mod test {
+    use crate::obfuscation::{is_stun_message, Obfuscator, OBFUSCATION_HEAD_LEN};
+    use crate::protocol::{NetPacket, HEAD_LEN};
+
+    #[test]
+    fn obfuscate_deobfuscate() {
+        let obfuscator = Obfuscator::new("secret".to_string()).set_max_padding(200);
+        let mut packet = NetPacket::unchecked(vec![0u8; HEAD_LEN + 10]);
+        packet.set_high_flag();
+        packet.reset_data_len();
+        packet.set_ttl(5);
+        packet.payload_mut().copy_from_slice(b"0123456789");
+        let packet = packet.into_buffer();
+        for _ in 0..100 {
+            let mut buf = obfuscator.obfuscate(&packet);
+            assert!(buf[0] & 0x40 == 0 && buf[0] > 0x01);
+            assert!(!is_stun_message(&buf));
+            assert_ne!(&buf[OBFUSCATION_HEAD_LEN..][..4], &packet[..4]);
+            assert_eq!(obfuscator.frame_length(&buf), buf.len());
+            let len = obfuscator.deobfuscate(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &packet[..]);
+        }
+        let mut buf = obfuscator.obfuscate(&packet);
+        let other = Obfuscator::new("other".to_string());
+        assert!(other.deobfuscate(&mut buf).is_err());
+    }
+}
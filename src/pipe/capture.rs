@@ -0,0 +1,270 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rust_p2p_core::route::RouteKey;
+
+use crate::protocol::node_id::NodeID;
+use crate::protocol::protocol_type::ProtocolType;
+use crate::protocol::NetPacket;
+
+/// Packets waiting to be written, excess packets are dropped instead of blocking the pipe
+const CAPTURE_QUEUE_CAPACITY: usize = 1024;
+/// LINKTYPE_USER0, the frames are raw `NetPacket`s
+const LINKTYPE_USER0: u16 = 147;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn epb_flags(&self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+}
+
+/// Selects the packets to capture, an empty filter captures everything
+#[derive(Clone, Default, Debug)]
+pub struct CaptureFilter {
+    pub protocols: Option<Vec<ProtocolType>>,
+    /// Matches either the source or the destination of the packet
+    pub node_ids: Option<Vec<NodeID>>,
+}
+
+impl CaptureFilter {
+    pub fn set_protocols(mut self, protocols: Vec<ProtocolType>) -> Self {
+        self.protocols.replace(protocols);
+        self
+    }
+    pub fn set_node_ids(mut self, node_ids: Vec<NodeID>) -> Self {
+        self.node_ids.replace(node_ids);
+        self
+    }
+    fn matches(&self, buf: &[u8]) -> bool {
+        let Ok(packet) = NetPacket::new(buf) else {
+            return false;
+        };
+        if let Some(protocols) = self.protocols.as_ref() {
+            let Ok(protocol) = packet.protocol() else {
+                return false;
+            };
+            if !protocols.contains(&protocol) {
+                return false;
+            }
+        }
+        if let Some(node_ids) = self.node_ids.as_ref() {
+            let (Ok(src_id), Ok(dest_id)) = (
+                NodeID::try_from(packet.src_id()),
+                NodeID::try_from(packet.dest_id()),
+            ) else {
+                return false;
+            };
+            if !node_ids.contains(&src_id) && !node_ids.contains(&dest_id) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+struct Record {
+    direction: Direction,
+    route_key: RouteKey,
+    time: SystemTime,
+    data: Vec<u8>,
+}
+
+/// Writes the captured packets to a pcapng file on a dedicated thread.
+/// [`finish`](Self::finish) waits for the queued packets to be written and the file flushed,
+/// a dropped capture closes the queue and leaves the thread to flush on its own
+pub(crate) struct Capture {
+    filter: CaptureFilter,
+    sender: SyncSender<Record>,
+    thread: JoinHandle<()>,
+}
+
+impl Capture {
+    pub(crate) fn create<P: AsRef<Path>>(path: P, filter: CaptureFilter) -> io::Result<Self> {
+        let mut writer = PcapngWriter::new(BufWriter::new(File::create(path)?))?;
+        let (sender, receiver) = sync_channel::<Record>(CAPTURE_QUEUE_CAPACITY);
+        let thread = std::thread::Builder::new()
+            .name("rustp2p-capture".into())
+            .spawn(move || {
+                while let Ok(record) = receiver.recv() {
+                    if let Err(e) = writer.write_packet(&record) {
+                        log::warn!("capture write {e:?}");
+                        return;
+                    }
+                }
+                if let Err(e) = writer.flush() {
+                    log::warn!("capture flush {e:?}");
+                }
+            })?;
+        Ok(Self {
+            filter,
+            sender,
+            thread,
+        })
+    }
+    /// Closes the queue and waits for the writer thread
+    pub(crate) fn finish(self) {
+        drop(self.sender);
+        if self.thread.join().is_err() {
+            log::warn!("capture thread panicked");
+        }
+    }
+    pub(crate) fn capture(&self, direction: Direction, route_key: RouteKey, buf: &[u8]) {
+        if !self.filter.matches(buf) {
+            return;
+        }
+        let record = Record {
+            direction,
+            route_key,
+            time: SystemTime::now(),
+            data: buf.to_vec(),
+        };
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(record) {
+            log::debug!("capture queue is full, drop packet");
+        }
+    }
+}
+
+struct PcapngWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    fn new(mut writer: W) -> io::Result<Self> {
+        // Section Header Block, the section length is unspecified
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, SECTION_HEADER_BLOCK, &body)?;
+        // Interface Description Block with the default microsecond resolution
+        let mut body = Vec::with_capacity(32);
+        body.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        write_option(&mut body, OPT_IF_NAME, b"rustp2p");
+        write_option(&mut body, OPT_ENDOFOPT, &[]);
+        write_block(&mut writer, INTERFACE_DESCRIPTION_BLOCK, &body)?;
+        Ok(Self { writer })
+    }
+    fn write_packet(&mut self, record: &Record) -> io::Result<()> {
+        // The underlying address is kept in the comment of each packet
+        let comment = format!(
+            "{:?} {} index={}",
+            record.route_key.protocol(),
+            record.route_key.addr(),
+            record.route_key.index_usize()
+        );
+        self.write_enhanced_packet(record.direction, record.time, &record.data, &comment)
+    }
+    fn write_enhanced_packet(
+        &mut self,
+        direction: Direction,
+        time: SystemTime,
+        data: &[u8],
+        comment: &str,
+    ) -> io::Result<()> {
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let len = data.len() as u32;
+        let mut body = Vec::with_capacity(data.len() + comment.len() + 48);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&len.to_le_bytes());
+        body.extend_from_slice(&len.to_le_bytes());
+        body.extend_from_slice(data);
+        pad(&mut body);
+        write_option(
+            &mut body,
+            OPT_EPB_FLAGS,
+            &direction.epb_flags().to_le_bytes(),
+        );
+        write_option(&mut body, OPT_COMMENT, comment.as_bytes());
+        write_option(&mut body, OPT_ENDOFOPT, &[]);
+        write_block(&mut self.writer, ENHANCED_PACKET_BLOCK, &body)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn write_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let total_len = (body.len() + 12) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pipe::capture::{CaptureFilter, Direction, PcapngWriter};
+    use crate::pipe::SendPacket;
+    use crate::protocol::node_id::NodeID;
+    use crate::protocol::protocol_type::ProtocolType;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_pcapng() {
+        let mut packet = SendPacket::from(&b"hello"[..]);
+        packet.set_src_id(&NodeID::from(1u32));
+        packet.set_dest_id(&NodeID::from(2u32));
+        let data = packet.into_buf().to_vec();
+
+        let filter = CaptureFilter::default().set_node_ids(vec![NodeID::from(2u32)]);
+        assert!(filter.matches(&data));
+        let filter = CaptureFilter::default().set_protocols(vec![ProtocolType::EchoRequest]);
+        assert!(!filter.matches(&data));
+
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        let header_len = writer.writer.len();
+        writer
+            .write_enhanced_packet(Direction::Outbound, SystemTime::now(), &data, "UDP")
+            .unwrap();
+        let block = &writer.writer[header_len..];
+        assert_eq!(block.len() % 4, 0);
+        assert_eq!(&block[..4], &6u32.to_le_bytes());
+        let total_len = block.len() as u32;
+        assert_eq!(&block[4..8], &total_len.to_le_bytes());
+        assert_eq!(&block[block.len() - 4..], &total_len.to_le_bytes());
+        assert_eq!(&block[28..28 + data.len()], &data[..]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::error::Result;
+use crate::pipe::{PipeWriter, RecvUserData, SendPacket};
+use crate::protocol::node_id::NodeID;
+
+/// Channel 0 is the default channel, its data is returned by `PipeLine::next`
+pub const DEFAULT_CHANNEL: u16 = 0;
+pub(crate) const CHANNEL_CAPACITY: usize = 128;
+
+pub(crate) type ChannelMap<T = RecvUserData> = Arc<DashMap<u16, Sender<T>>>;
+
+/// Hands the data to the receiver of its channel without waiting, so a slow receiver
+/// never holds up the `PipeLine`. The data of a full channel is dropped.
+/// Returns the data that goes to `PipeLine::next` instead
+pub(crate) fn dispatch<T>(channels: &ChannelMap<T>, channel: u16, data: T) -> Option<T> {
+    if channel == DEFAULT_CHANNEL {
+        return Some(data);
+    }
+    let Some(sender) = channels.get(&channel).map(|v| v.value().clone()) else {
+        return Some(data);
+    };
+    match sender.try_send(data) {
+        Ok(_) => None,
+        Err(TrySendError::Full(_)) => {
+            log::debug!("channel {channel} is full, data dropped");
+            None
+        }
+        // The receiver of this channel has been dropped
+        Err(TrySendError::Closed(data)) => Some(data),
+    }
+}
+
+/// Sends user data tagged with a channel number
+#[derive(Clone)]
+pub struct ChannelSender {
+    channel: u16,
+    pipe_writer: PipeWriter,
+}
+
+impl ChannelSender {
+    pub(crate) fn new(channel: u16, pipe_writer: PipeWriter) -> Self {
+        Self {
+            channel,
+            pipe_writer,
+        }
+    }
+    pub fn channel(&self) -> u16 {
+        self.channel
+    }
+    pub fn allocate_send_packet(&self) -> SendPacket {
+        let mut packet = self.pipe_writer.allocate_send_packet();
+        packet.set_channel(self.channel);
+        packet
+    }
+    pub async fn send_packet_to(&self, mut packet: SendPacket, dest_id: &NodeID) -> Result<()> {
+        packet.set_channel(self.channel);
+        self.pipe_writer.send_packet_to(packet, dest_id).await
+    }
+    pub async fn broadcast_packet(&self, mut packet: SendPacket) -> Result<()> {
+        packet.set_channel(self.channel);
+        self.pipe_writer.broadcast_packet(packet).await
+    }
+}
+
+/// Receives the user data of one channel.
+/// Data is dispatched by `PipeLine`, so at least one `PipeLine` must be driven.
+/// The data arriving while the receiver is full is dropped.
+pub struct ChannelReceiver {
+    channel: u16,
+    receiver: Receiver<RecvUserData>,
+    channels: ChannelMap,
+}
+
+impl ChannelReceiver {
+    pub(crate) fn new(
+        channel: u16,
+        receiver: Receiver<RecvUserData>,
+        channels: ChannelMap,
+    ) -> Self {
+        Self {
+            channel,
+            receiver,
+            channels,
+        }
+    }
+    pub fn channel(&self) -> u16 {
+        self.channel
+    }
+    pub async fn recv(&mut self) -> Option<RecvUserData> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for ChannelReceiver {
+    fn drop(&mut self) {
+        self.channels.remove(&self.channel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dispatch, ChannelMap, DEFAULT_CHANNEL};
+
+    #[test]
+    fn full_channel_does_not_block() {
+        let channels: ChannelMap<usize> = Default::default();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+        channels.insert(1, sender);
+        for i in 0..2 {
+            assert!(dispatch(&channels, 1, i).is_none());
+        }
+        // Dropped, the channel is not drained
+        assert!(dispatch(&channels, 1, 2).is_none());
+        assert_eq!(dispatch(&channels, DEFAULT_CHANNEL, 3), Some(3));
+        assert_eq!(dispatch(&channels, 7, 4), Some(4));
+        assert_eq!(receiver.try_recv().ok(), Some(0));
+        assert_eq!(receiver.try_recv().ok(), Some(1));
+        assert!(receiver.try_recv().is_err());
+        drop(receiver);
+        assert_eq!(dispatch(&channels, 1, 5), Some(5));
+    }
+}
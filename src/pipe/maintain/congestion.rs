@@ -0,0 +1,24 @@
+use crate::congestion::ACK_INTERVAL;
+use crate::pipe::PipeWriter;
+use crate::protocol::protocol_type::ProtocolType;
+
+/// Acknowledges the bytes received from the congestion controlled senders,
+/// once per `ACK_INTERVAL` while they are sending
+pub(crate) async fn congestion_ack_loop(pipe_writer: PipeWriter) {
+    let acks = pipe_writer.pipe_context.acks.clone();
+    loop {
+        acks.notified().await;
+        tokio::time::sleep(ACK_INTERVAL).await;
+        for (node_id, total) in acks.take_pending() {
+            let mut packet =
+                match pipe_writer.allocate_send_packet_proto(ProtocolType::CongestionAck, 8) {
+                    Ok(packet) => packet,
+                    Err(_) => break,
+                };
+            packet.set_payload(&total.to_be_bytes());
+            if let Err(e) = pipe_writer.send_packet_to(packet, &node_id).await {
+                log::debug!("congestion_ack_loop {e:?},node_id={node_id:?}");
+            }
+        }
+    }
+}
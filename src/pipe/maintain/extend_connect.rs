@@ -0,0 +1,58 @@
+use crate::pipe::maintain::id_route::send_id_route_query;
+use crate::pipe::PipeWriter;
+use rust_p2p_core::pipe::extensible_pipe::ExtensiblePipeWriter;
+use rust_p2p_core::route::RouteKey;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::Hash;
+use std::time::Duration;
+
+/// Keeps a connection of the extensible pipe to every target `direct_nodes` returns,
+/// the id query over it maps the connection to the node id.
+/// A connection is made again once its id query cannot be sent
+pub(crate) async fn extend_connect_loop<T, F, Fut>(
+    name: &str,
+    pipe_writer: PipeWriter,
+    interval: Duration,
+    direct_nodes: impl Fn(&PipeWriter) -> Vec<(T, u16)>,
+    connect: F,
+) where
+    T: Eq + Hash + Clone + Display,
+    F: Fn(T, ExtensiblePipeWriter) -> Fut,
+    Fut: Future<Output = anyhow::Result<RouteKey>>,
+{
+    let Some(extensible_pipe_writer) = pipe_writer.pipe_writer.extensible_pipe_writer().cloned()
+    else {
+        return;
+    };
+    let mut connections: HashMap<T, RouteKey> = HashMap::new();
+    loop {
+        let direct_nodes = direct_nodes(&pipe_writer);
+        connections.retain(|target, _| direct_nodes.iter().any(|(v, _)| v == target));
+        for (target, id) in direct_nodes {
+            if let Some(route_key) = connections.get(&target) {
+                if send_id_route_query(&pipe_writer, id, route_key)
+                    .await
+                    .is_ok()
+                {
+                    continue;
+                }
+                connections.remove(&target);
+            }
+            let route_key = match connect(target.clone(), extensible_pipe_writer.clone()).await {
+                Ok(route_key) => route_key,
+                Err(e) => {
+                    log::warn!("{name} {e:?},target={target}");
+                    continue;
+                }
+            };
+            if let Err(e) = send_id_route_query(&pipe_writer, id, &route_key).await {
+                log::warn!("{name} id_route_query {e:?},target={target}");
+                continue;
+            }
+            connections.insert(target, route_key);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -4,12 +4,21 @@ use crate::pipe::PipeWriter;
 use crate::protocol::node_id::{GroupCode, NodeID};
 use crate::protocol::protocol_type::ProtocolType;
 use crate::protocol::NetPacket;
+use rust_p2p_core::extend::addr::is_ip_global;
 use std::collections::HashSet;
 use std::time::{Duration, UNIX_EPOCH};
 
+/// The observed addresses are queried once every this many heartbeats
+const ADDR_REQUEST_ROUNDS: usize = 6;
+
 pub async fn heartbeat_loop(pipe_writer: PipeWriter, heartbeat_interval: Duration) {
     let mut count = 0;
     loop {
+        if count % ADDR_REQUEST_ROUNDS == 0 {
+            if let Err(e) = addr_request(&pipe_writer).await {
+                log::warn!("addr_request e={e:?}");
+            }
+        }
         if count % 3 == 2 {
             if let Err(e) = timestamp_request(&pipe_writer).await {
                 log::warn!("timestamp_request e={e:?}");
@@ -63,6 +72,43 @@ async fn timestamp_request(pipe_writer: &PipeWriter) -> Result<()> {
     Ok(())
 }
 
+/// Ask the direct `UDP` peers on a global address which source address they observe,
+/// so that the public addresses stay accurate without STUN servers.
+/// The `TCP` connections mostly use ephemeral ports, the public `TCP` port is left to STUN
+async fn addr_request(pipe_writer: &PipeWriter) -> Result<()> {
+    let mut packet =
+        if let Ok(packet) = pipe_writer.allocate_send_packet_proto(ProtocolType::AddrRequest, 0) {
+            packet
+        } else {
+            return Ok(());
+        };
+    let Some(udp) = pipe_writer.pipe_writer.udp_pipe_writer() else {
+        return Ok(());
+    };
+    let observed_addrs = &pipe_writer.pipe_context().observed_addrs;
+    observed_addrs.new_round();
+    for (node_id, route) in pipe_writer.pipe_writer.route_table().route_table_p2p() {
+        let route_key = route.route_key();
+        let addr = route_key.addr();
+        if !route_key.protocol().is_udp() || !is_ip_global(&addr.ip()) {
+            continue;
+        }
+        packet.set_dest_id(&node_id);
+        observed_addrs.requested(node_id, addr);
+        // Every main socket has its own mapping
+        let rs = udp
+            .detect_pub_addrs(
+                &pipe_writer.pipe_context().obfuscate(packet.buf().into()),
+                addr,
+            )
+            .await;
+        if let Err(e) = rs {
+            log::warn!("addr_request e={e:?},node_id={node_id:?}");
+        }
+    }
+    Ok(())
+}
+
 async fn direct_heartbeat_request(
     direct_nodes: Vec<(NodeAddress, Option<(GroupCode, NodeID)>)>,
     sent_ids: &HashSet<NodeID>,
@@ -80,7 +126,8 @@ async fn direct_heartbeat_request(
             NodeAddress::Tcp(addr) => match pipe_writer.pipe_writer.tcp_pipe_writer() {
                 None => {}
                 Some(tcp) => {
-                    if let Err(e) = tcp.send_to_addr(buf.into(), addr).await {
+                    let buf = pipe_writer.pipe_context().obfuscate(buf.into());
+                    if let Err(e) = tcp.send_to_addr(buf, addr).await {
                         log::warn!("direct_heartbeat_request tcp, e={e:?},addr={addr:?}");
                     }
                 }
@@ -88,7 +135,8 @@ async fn direct_heartbeat_request(
             NodeAddress::Udp(addr) => match pipe_writer.pipe_writer.udp_pipe_writer() {
                 None => {}
                 Some(udp) => {
-                    if let Err(e) = udp.send_to_addr(buf, addr).await {
+                    let buf = pipe_writer.pipe_context().obfuscate(buf.into());
+                    if let Err(e) = udp.send_to_addr(&buf, addr).await {
                         log::warn!("direct_heartbeat_request udp, e={e:?},addr={addr:?}");
                     }
                 }
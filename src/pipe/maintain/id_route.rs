@@ -4,6 +4,7 @@ use crate::protocol::node_id::NodeID;
 use crate::protocol::protocol_type::ProtocolType;
 use crate::protocol::NetPacket;
 use rand::seq::SliceRandom;
+use rust_p2p_core::route::RouteKey;
 use std::collections::HashSet;
 use std::time::Duration;
 
@@ -64,7 +65,8 @@ async fn poll_direct_peer_node(
             NodeAddress::Tcp(addr) => match pipe_writer.pipe_writer.tcp_pipe_writer() {
                 None => {}
                 Some(tcp) => {
-                    if let Err(e) = tcp.send_to_addr(packet.buffer().into(), addr).await {
+                    let buf = pipe_writer.pipe_context().obfuscate(packet.buffer().into());
+                    if let Err(e) = tcp.send_to_addr(buf, addr).await {
                         log::warn!("poll_direct_peer_node tcp, e={e:?},addr={addr:?}");
                     }
                 }
@@ -72,7 +74,8 @@ async fn poll_direct_peer_node(
             NodeAddress::Udp(addr) => match pipe_writer.pipe_writer.udp_pipe_writer() {
                 None => {}
                 Some(udp) => {
-                    if let Err(e) = udp.send_to_addr(packet.buffer(), addr).await {
+                    let buf = pipe_writer.pipe_context().obfuscate(packet.buffer().into());
+                    if let Err(e) = udp.send_to_addr(&buf, addr).await {
                         log::warn!("poll_direct_peer_node udp, e={e:?},addr={addr:?}");
                     }
                 }
@@ -82,6 +85,22 @@ async fn poll_direct_peer_node(
     }
 }
 
+/// Query the id of a direct node over one connection, which maps the connection to the node
+pub(crate) async fn send_id_route_query(
+    pipe_writer: &PipeWriter,
+    id: u16,
+    route_key: &RouteKey,
+) -> crate::error::Result<()> {
+    let mut packet = pipe_writer.allocate_send_packet_proto(ProtocolType::IDRouteQuery, 4)?;
+    unsafe {
+        packet.set_payload_len(4);
+    }
+    packet.set_ttl(1);
+    let mut net_packet = NetPacket::unchecked(packet.buf_mut());
+    net_packet.payload_mut()[2..4].copy_from_slice(&id.to_be_bytes());
+    pipe_writer.send_to_route(packet.buf(), route_key).await
+}
+
 async fn poll_route_table_peer_node(
     pipe_writer: &PipeWriter,
     buf: &[u8],
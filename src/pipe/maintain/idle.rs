@@ -1,13 +1,25 @@
 use std::time::Duration;
 
 use crate::pipe::pipe_context::PipeContext;
+use crate::pipe::PipeWriter;
 use crate::protocol::node_id::NodeID;
 
-pub async fn idle_check_loop(idle_route_manager: rust_p2p_core::idle::IdleRouteManager<NodeID>) {
+pub async fn idle_check_loop(
+    pipe_writer: PipeWriter,
+    idle_route_manager: rust_p2p_core::idle::IdleRouteManager<NodeID>,
+) {
     loop {
         let (node_id, route, _) = idle_route_manager.next_idle().await;
         idle_route_manager.remove_route(&node_id, &route.route_key());
         log::info!("idle {node_id:?},{route:?}");
+        if pipe_writer
+            .pipe_writer
+            .route_table()
+            .route(&node_id)
+            .is_none()
+        {
+            pipe_writer.pipe_context.clear_peer(&node_id);
+        }
     }
 }
 
@@ -0,0 +1,109 @@
+use crate::pipe::maintain::nat_query::nat_test_random;
+use crate::pipe::PipeWriter;
+use rust_p2p_core::extend::addr::local_ip_to;
+use rust_p2p_core::extend::if_watch::InterfaceWatcher;
+use rust_p2p_core::route::RouteKey;
+use rust_p2p_core::socket::LocalInterface;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// After a roam the NAT is tested again at once instead of on the `nat_test_loop` timer,
+/// then `consult_all` makes every peer punch the new addresses.
+/// The sockets are bound to the unspecified address, only the ones bound to `default_interface` are bound again
+pub(crate) async fn interface_watch_loop(
+    pipe_writer: PipeWriter,
+    mut udp_stun_servers: Vec<String>,
+    default_interface: Option<LocalInterface>,
+    consult_all: Arc<Notify>,
+) {
+    let mut watcher = match InterfaceWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            log::warn!("interface_watch_loop {e:?}");
+            return;
+        }
+    };
+    loop {
+        let old_ips = ips(watcher.addrs().iter().map(|(_, ip)| ip));
+        let new_ips = match watcher.changed().await {
+            Ok(addrs) => {
+                log::info!("local interfaces changed {addrs:?}");
+                ips(addrs.iter().map(|(_, ip)| ip))
+            }
+            Err(e) => {
+                log::warn!("interface_watch_loop {e:?}");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        if let Some(udp_pipe_writer) = pipe_writer.pipe_writer.udp_pipe_writer() {
+            if let Err(e) = udp_pipe_writer.rebind_interface() {
+                log::warn!("rebind_interface {e:?}");
+            }
+        }
+        remove_stale_routes(&pipe_writer, &old_ips, &new_ips, default_interface.as_ref());
+        nat_test_random(
+            &pipe_writer,
+            &mut udp_stun_servers,
+            default_interface.as_ref(),
+        )
+        .await;
+        // The new network may have another DNS
+        if let Err(e) = pipe_writer.pipe_context().update_direct_nodes().await {
+            log::debug!("update_direct_nodes {e:?}");
+        }
+        consult_all.notify_one();
+    }
+}
+
+fn ips<'a>(ips: impl Iterator<Item = &'a IpAddr>) -> HashSet<IpAddr> {
+    ips.map(|ip| ip.to_canonical()).collect()
+}
+
+/// The `TCP` connections from a vanished address are closed.
+/// A direct `UDP` route the OS now sends from an address that did not exist before
+/// has lost its NAT mapping, so it is dropped as well and the peer is punched again
+fn remove_stale_routes(
+    pipe_writer: &PipeWriter,
+    old_ips: &HashSet<IpAddr>,
+    new_ips: &HashSet<IpAddr>,
+    default_interface: Option<&LocalInterface>,
+) {
+    let mut stale: HashSet<RouteKey> = HashSet::new();
+    if let Some(tcp_pipe_writer) = pipe_writer.pipe_writer.tcp_pipe_writer() {
+        stale.extend(tcp_pipe_writer.close_local_ips(|ip| {
+            let ip = ip.to_canonical();
+            old_ips.contains(&ip) && !new_ips.contains(&ip)
+        }));
+    }
+    let pipe_context = pipe_writer.pipe_context();
+    let route_tables = std::iter::once(pipe_writer.pipe_writer.route_table().clone()).chain(
+        pipe_context
+            .other_route_table
+            .iter()
+            .map(|v| v.value().clone()),
+    );
+    for route_table in route_tables {
+        for (node_id, routes) in route_table.route_table() {
+            for route in routes {
+                let route_key = route.route_key();
+                let moved = route.is_direct()
+                    && route_key.protocol().is_udp()
+                    && match local_ip_to(route_key.addr(), default_interface) {
+                        Ok(ip) => {
+                            let ip = ip.to_canonical();
+                            !ip.is_loopback() && !old_ips.contains(&ip)
+                        }
+                        Err(_) => true,
+                    };
+                if moved || stale.contains(&route_key) {
+                    log::info!("remove stale route {route_key:?},node_id={node_id:?}");
+                    route_table.remove_route(&node_id, &route_key);
+                }
+            }
+        }
+    }
+}
@@ -2,17 +2,34 @@ use tokio::task::JoinSet;
 
 use crate::pipe::PipeWriter;
 use crate::protocol::node_id::NodeID;
+use rust_p2p_core::proxy::ProxyConfig;
 use rust_p2p_core::punch::{PunchConsultInfo, Puncher};
 use rust_p2p_core::socket::LocalInterface;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::Notify;
+use tokio::time::Instant;
 
+mod congestion;
+mod extend_connect;
 mod heartbeat;
 mod id_route;
 mod idle;
+mod if_watch;
 mod nat_query;
+mod pmtu;
+mod port_mapping;
+mod proxy;
 mod punch_consult;
 mod query_public_addr;
+#[cfg(feature = "quic")]
+mod quic;
+mod topic;
+#[cfg(feature = "turn")]
+mod turn;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn start_task(
@@ -22,19 +39,40 @@ pub(crate) fn start_task(
     query_id_interval: Duration,
     query_id_max_num: usize,
     heartbeat_interval: Duration,
+    topic_announce_interval: Duration,
     route_idle_time: Duration,
     tcp_stun_servers: Vec<String>,
+    tcp_mesh_stun: bool,
     udp_stun_servers: Vec<String>,
     default_interface: Option<LocalInterface>,
-    active_receiver: Receiver<(NodeID, PunchConsultInfo)>,
-    passive_receiver: Receiver<(NodeID, PunchConsultInfo)>,
+    port_mapping: bool,
+    pmtu_discovery: bool,
+    interface_watch: bool,
+    active_receiver: Receiver<(NodeID, PunchConsultInfo, Instant)>,
+    passive_receiver: Receiver<(NodeID, PunchConsultInfo, Instant)>,
+    punch_now_receiver: Receiver<NodeID>,
+    udp_proxy: Option<ProxyConfig>,
+    #[cfg(feature = "websocket")] websocket_listener: Option<
+        rust_p2p_core::websocket::WebSocketListener,
+    >,
+    #[cfg(feature = "websocket")] websocket_tls_config: Option<
+        std::sync::Arc<rust_p2p_core::websocket::rustls::ClientConfig>,
+    >,
 ) -> JoinSet<()> {
     let mut join_set = JoinSet::new();
     join_set.spawn(heartbeat::heartbeat_loop(
         pipe_writer.clone(),
         heartbeat_interval,
     ));
-    join_set.spawn(idle::idle_check_loop(idle_route_manager));
+    join_set.spawn(topic::topic_announce_loop(
+        pipe_writer.clone(),
+        topic_announce_interval,
+    ));
+    join_set.spawn(congestion::congestion_ack_loop(pipe_writer.clone()));
+    join_set.spawn(idle::idle_check_loop(
+        pipe_writer.clone(),
+        idle_route_manager,
+    ));
     join_set.spawn(idle::other_group_idle_check_loop(
         pipe_writer.pipe_context.clone(),
         route_idle_time,
@@ -45,6 +83,36 @@ pub(crate) fn start_task(
         query_id_max_num,
     ));
     join_set.spawn(nat_query::nat_test_loop(
+        pipe_writer.clone(),
+        udp_stun_servers.clone(),
+        default_interface.clone(),
+    ));
+    let consult_all = Arc::new(Notify::new());
+    let predictor =
+        nat_query::PortPredictor::new(udp_stun_servers.clone(), default_interface.clone());
+    if interface_watch {
+        join_set.spawn(if_watch::interface_watch_loop(
+            pipe_writer.clone(),
+            udp_stun_servers.clone(),
+            default_interface.clone(),
+            consult_all.clone(),
+        ));
+    }
+    if let Some(proxy) = udp_proxy {
+        join_set.spawn(proxy::socks5_udp_loop(
+            pipe_writer.clone(),
+            proxy,
+            default_interface.clone(),
+            heartbeat_interval,
+        ));
+    }
+    if port_mapping {
+        join_set.spawn(port_mapping::port_mapping_loop(
+            pipe_writer.clone(),
+            default_interface.clone(),
+        ));
+    }
+    join_set.spawn(nat_query::mapping_lifetime_loop(
         pipe_writer.clone(),
         udp_stun_servers.clone(),
         default_interface,
@@ -57,21 +125,59 @@ pub(crate) fn start_task(
         pipe_writer.clone(),
         udp_stun_servers,
     ));
+    join_set.spawn(query_public_addr::query_mesh_public_addr_loop(
+        pipe_writer.clone(),
+        tcp_mesh_stun,
+    ));
+    if pmtu_discovery {
+        join_set.spawn(pmtu::pmtu_probe_loop(
+            pipe_writer.clone(),
+            heartbeat_interval,
+        ));
+    }
+    #[cfg(feature = "turn")]
+    if let Some(client) = pipe_writer.pipe_context.turn_client.clone() {
+        join_set.spawn(turn::turn_loop(pipe_writer.clone(), client));
+    }
+    #[cfg(feature = "websocket")]
+    if let Some(listener) = websocket_listener {
+        join_set.spawn(websocket::websocket_accept_loop(listener));
+    }
+    #[cfg(feature = "websocket")]
+    if pipe_writer.pipe_writer.extensible_pipe_writer().is_some() {
+        join_set.spawn(websocket::websocket_connect_loop(
+            pipe_writer.clone(),
+            websocket_tls_config,
+            heartbeat_interval,
+        ));
+    }
+    #[cfg(feature = "quic")]
+    if pipe_writer.pipe_writer.quic_pipe_writer().is_some() {
+        join_set.spawn(quic::quic_upgrade_loop(
+            pipe_writer.clone(),
+            puncher.clone(),
+        ));
+    }
     join_set.spawn(punch_consult::punch_consult_loop(
         pipe_writer.clone(),
         puncher.clone(),
+        punch_now_receiver,
+        consult_all,
+        predictor.clone(),
     ));
     join_set.spawn(punch_consult::punch_loop(
         true,
         active_receiver,
         pipe_writer.clone(),
         puncher.clone(),
+        predictor.clone(),
     ));
     join_set.spawn(punch_consult::punch_loop(
         false,
         passive_receiver,
         pipe_writer.clone(),
         puncher,
+        predictor,
     ));
     join_set
 }
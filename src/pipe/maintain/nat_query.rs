@@ -1,8 +1,16 @@
 use crate::pipe::pipe_context::PipeContext;
 use crate::pipe::PipeWriter;
+use parking_lot::Mutex;
 use rand::seq::SliceRandom;
 use rust_p2p_core::socket::LocalInterface;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The time the stun requests of the lifetime test may take on top of the longest probe
+const MAPPING_LIFETIME_SLACK: Duration = Duration::from_secs(10);
+/// The predicted ports are sampled again before a punch consult if they are older than this
+const PREDICTION_MAX_AGE: Duration = Duration::from_secs(10);
+const PREDICTION_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub(crate) async fn nat_test_loop(
     pipe_writer: PipeWriter,
@@ -11,16 +19,9 @@ pub(crate) async fn nat_test_loop(
 ) {
     let pipe_context = pipe_writer.pipe_context();
     loop {
-        udp_stun_servers.shuffle(&mut rand::thread_rng());
-        let udp_stun_servers = if udp_stun_servers.len() > 3 {
-            &udp_stun_servers[..3]
-        } else {
-            &udp_stun_servers
-        };
-        nat_test(
+        nat_test_random(
             &pipe_writer,
-            pipe_writer.pipe_context(),
-            udp_stun_servers,
+            &mut udp_stun_servers,
             default_interface.as_ref(),
         )
         .await;
@@ -32,6 +33,23 @@ pub(crate) async fn nat_test_loop(
     }
 }
 
+/// Tests with up to three of the servers picked at random
+pub(crate) async fn nat_test_random(
+    pipe_writer: &PipeWriter,
+    udp_stun_servers: &mut [String],
+    default_interface: Option<&LocalInterface>,
+) {
+    udp_stun_servers.shuffle(&mut rand::thread_rng());
+    let len = udp_stun_servers.len().min(3);
+    nat_test(
+        pipe_writer,
+        pipe_writer.pipe_context(),
+        &udp_stun_servers[..len],
+        default_interface,
+    )
+    .await;
+}
+
 async fn nat_test(
     pipe_writer: &PipeWriter,
     pipe_context: &PipeContext,
@@ -61,20 +79,135 @@ async fn nat_test(
             }
         }
     }
-    let rs = rust_p2p_core::stun::stun_test_nat(udp_stun_servers.to_vec(), default_interface).await;
+    // Nothing is learned without a server, the nat info is kept as it is
+    if udp_stun_servers.is_empty() {
+        return;
+    }
+    let rs =
+        rust_p2p_core::stun::stun_test_nat_behavior(udp_stun_servers.to_vec(), default_interface)
+            .await;
 
     match rs {
-        Ok((nat_type, ips, port_range)) => {
+        Ok(rs) => {
+            let nat_type = rs.nat_type;
             if let Err(err) = pipe_writer.switch_model(nat_type) {
                 log::error!("switch to {nat_type:?} model error:{err:?}");
             }
             let mut guard = pipe_context.punch_info().write();
             guard.nat_type = nat_type;
-            guard.set_public_ip(ips);
-            guard.public_port_range = port_range;
+            guard.set_public_ip(rs.public_ips);
+            guard.public_port_range = rs.port_range;
+            guard.mapping_behavior = rs.mapping_behavior;
+            guard.filtering_behavior = rs.filtering_behavior;
+            guard.hairpinning = rs.hairpinning;
+            guard.predicted_ports = rs.predicted_ports;
         }
         Err(e) => {
             log::debug!("stun_test_nat {e:?} {udp_stun_servers:?}")
         }
     }
 }
+
+/// The mapping lifetime rarely changes and the probes take a minute, so it is measured separately,
+/// once the NAT test has finished
+pub(crate) async fn mapping_lifetime_loop(
+    pipe_writer: PipeWriter,
+    mut udp_stun_servers: Vec<String>,
+    default_interface: Option<LocalInterface>,
+) {
+    let pipe_context = pipe_writer.pipe_context();
+    while !pipe_context.exists_nat_info() {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    }
+    let limit = rust_p2p_core::stun::MAPPING_LIFETIME_PROBES
+        .iter()
+        .max()
+        .copied()
+        .unwrap_or_default()
+        + MAPPING_LIFETIME_SLACK;
+    loop {
+        udp_stun_servers.shuffle(&mut rand::thread_rng());
+        if let Some(stun_server) = udp_stun_servers.first() {
+            match tokio::time::timeout(
+                limit,
+                rust_p2p_core::stun::stun_test_mapping_lifetime(
+                    stun_server.clone(),
+                    &rust_p2p_core::stun::MAPPING_LIFETIME_PROBES,
+                    default_interface.as_ref(),
+                ),
+            )
+            .await
+            {
+                Ok(Ok(lifetime)) => {
+                    pipe_context.punch_info().write().mapping_lifetime = lifetime;
+                }
+                Ok(Err(e)) => {
+                    log::debug!("stun_test_mapping_lifetime {e:?} {stun_server:?}")
+                }
+                Err(_) => {
+                    log::debug!("stun_test_mapping_lifetime timeout {stun_server:?}")
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+/// Samples the port allocation of our symmetric nat again right before a punch consult,
+/// the prediction of the last NAT test is stale by then
+#[derive(Clone)]
+pub(crate) struct PortPredictor {
+    udp_stun_servers: Arc<Vec<String>>,
+    default_interface: Option<LocalInterface>,
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl PortPredictor {
+    pub(crate) fn new(
+        udp_stun_servers: Vec<String>,
+        default_interface: Option<LocalInterface>,
+    ) -> Self {
+        Self {
+            udp_stun_servers: Arc::new(udp_stun_servers),
+            default_interface,
+            last: Default::default(),
+        }
+    }
+    /// As `refresh` without waiting, so that the current prediction is sent meanwhile
+    pub(crate) fn refresh_in_background(&self, pipe_context: &PipeContext) {
+        let predictor = self.clone();
+        let pipe_context = pipe_context.clone();
+        tokio::spawn(async move { predictor.refresh(&pipe_context).await });
+    }
+    pub(crate) async fn refresh(&self, pipe_context: &PipeContext) {
+        if !pipe_context.punch_info().read().nat_type.is_symmetric() {
+            return;
+        }
+        {
+            let mut last = self.last.lock();
+            if matches!(*last, Some(time) if time.elapsed() < PREDICTION_MAX_AGE) {
+                return;
+            }
+            last.replace(Instant::now());
+        }
+        let rs = tokio::time::timeout(
+            PREDICTION_TIMEOUT,
+            rust_p2p_core::stun::stun_predict_ports(
+                &self.udp_stun_servers,
+                self.default_interface.as_ref(),
+            ),
+        )
+        .await;
+        match rs {
+            Ok(Ok(ports)) => {
+                pipe_context.punch_info().write().predicted_ports = ports;
+            }
+            Ok(Err(e)) => {
+                log::debug!("stun_predict_ports {e:?}")
+            }
+            Err(_) => {
+                log::debug!("stun_predict_ports timeout")
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use crate::pipe::pmtu::probe_route;
+use crate::pipe::PipeWriter;
+use crate::protocol::node_id::NodeID;
+use rust_p2p_core::route::RouteKey;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+/// The paths may change under the route, so it is probed again after this
+const PMTU_REFRESH: Duration = Duration::from_secs(600);
+
+/// Probes the path MTU of every direct `UDP` route, the new ones first
+pub(crate) async fn pmtu_probe_loop(pipe_writer: PipeWriter, interval: Duration) {
+    let mut probed: HashMap<(NodeID, RouteKey), Instant> = HashMap::new();
+    loop {
+        let route_table = pipe_writer.pipe_writer.route_table();
+        let routes: Vec<_> = route_table
+            .route_table()
+            .into_iter()
+            .flat_map(|(id, routes)| routes.into_iter().map(move |route| (id, route)))
+            .filter(|(_, route)| route.is_direct() && route.route_key().protocol().is_udp())
+            .collect();
+        probed.retain(|k, _| {
+            routes
+                .iter()
+                .any(|(id, r)| (id, &r.route_key()) == (&k.0, &k.1))
+        });
+        // The routes are probed at the same time, one slow peer does not hold back the others
+        let mut join_set = JoinSet::new();
+        for (node_id, route) in routes {
+            let key = (node_id, route.route_key());
+            if matches!(probed.get(&key), Some(time) if time.elapsed() < PMTU_REFRESH) {
+                continue;
+            }
+            probed.insert(key, Instant::now());
+            let pipe_writer = pipe_writer.clone();
+            join_set.spawn(async move {
+                let mtu = probe_route(&pipe_writer, node_id, &route).await;
+                (key, mtu)
+            });
+        }
+        while let Some(rs) = join_set.join_next().await {
+            let Ok(((node_id, route_key), mtu)) = rs else {
+                continue;
+            };
+            match mtu {
+                Some(mtu) => {
+                    log::debug!("path mtu {mtu},node_id={node_id:?},route={route_key:?}");
+                    route_table.update_mtu(&node_id, &route_key, mtu);
+                }
+                None => {
+                    log::debug!("path mtu probe unanswered,node_id={node_id:?}");
+                }
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,122 @@
+use crate::pipe::PipeWriter;
+use parking_lot::Mutex;
+use rust_p2p_core::mapping::{PortMapping, PortMappingClient};
+use rust_p2p_core::route::ConnectProtocol;
+use rust_p2p_core::socket::LocalInterface;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(30);
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Maps the listening ports on the gateway and keeps the leases alive,
+/// the mappings are deleted when the pipe shuts down
+pub(crate) async fn port_mapping_loop(
+    pipe_writer: PipeWriter,
+    default_interface: Option<LocalInterface>,
+) {
+    let gateway = match rust_p2p_core::mapping::default_gateway() {
+        Ok(gateway) => Some(gateway),
+        Err(e) => {
+            log::debug!("default_gateway {e:?}");
+            None
+        }
+    };
+    let client = PortMappingClient::new(gateway).set_default_interface(default_interface);
+    let current: Arc<Mutex<Vec<PortMapping>>> = Default::default();
+    release_on_shutdown(&pipe_writer, client.clone(), current.clone());
+    let (local_udp_ports, local_tcp_port) = {
+        let guard = pipe_writer.pipe_context.punch_info().read();
+        (guard.local_udp_ports.clone(), guard.local_tcp_port)
+    };
+    let mut mappings: Vec<PortMapping> = Vec::new();
+    let mut auto_addrs: Vec<SocketAddr> = Vec::new();
+    loop {
+        let mut renewed = Vec::with_capacity(mappings.len());
+        for mapping in &mappings {
+            if mapping.is_permanent() {
+                renewed.push(mapping.clone());
+                continue;
+            }
+            match client.renew(mapping).await {
+                Ok(mapping) => renewed.push(mapping),
+                Err(e) => log::debug!("renew port mapping {mapping:?} {e:?}"),
+            }
+        }
+        // The ports whose renewal failed are mapped again as well
+        let ports = local_udp_ports
+            .iter()
+            .map(|port| (ConnectProtocol::UDP, *port))
+            .chain(Some((ConnectProtocol::TCP, local_tcp_port)));
+        for (protocol, port) in ports {
+            if port == 0
+                || renewed
+                    .iter()
+                    .any(|v| v.protocol == protocol && v.internal_port == port)
+            {
+                continue;
+            }
+            match client.map(protocol, port).await {
+                Ok(mapping) => renewed.push(mapping),
+                Err(e) => {
+                    log::debug!("port mapping {protocol:?} {port} {e:?}");
+                    // the gateway does not support any of the methods
+                    break;
+                }
+            }
+        }
+        mappings = renewed;
+        current.lock().clone_from(&mappings);
+        auto_addrs = update_mapping_addrs(&pipe_writer, &mappings, auto_addrs);
+        let interval = mappings
+            .iter()
+            .filter(|v| !v.is_permanent())
+            .map(|v| v.lifetime / 2)
+            .min()
+            .map_or(RETRY_INTERVAL, |v| v.max(MIN_RENEW_INTERVAL));
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// The maintain tasks are aborted by the shutdown, so the mappings are released by a task of its own
+fn release_on_shutdown(
+    pipe_writer: &PipeWriter,
+    client: PortMappingClient,
+    current: Arc<Mutex<Vec<PortMapping>>>,
+) {
+    let shutdown_manager = pipe_writer.shutdown_manager.clone();
+    tokio::spawn(async move {
+        shutdown_manager.wait_shutdown_triggered().await;
+        let mappings = std::mem::take(&mut *current.lock());
+        for mapping in &mappings {
+            if let Err(e) = client.unmap(mapping).await {
+                log::debug!("release port mapping {mapping:?} {e:?}");
+            }
+        }
+    });
+}
+
+/// Replaces the addresses added by the previous round and keeps the configured ones
+fn update_mapping_addrs(
+    pipe_writer: &PipeWriter,
+    mappings: &[PortMapping],
+    auto_addrs: Vec<SocketAddr>,
+) -> Vec<SocketAddr> {
+    let mut guard = pipe_writer.pipe_context.punch_info().write();
+    guard.mapping_udp_addr.retain(|v| !auto_addrs.contains(v));
+    guard.mapping_tcp_addr.retain(|v| !auto_addrs.contains(v));
+    let mut addrs = Vec::with_capacity(mappings.len());
+    for mapping in mappings {
+        let addr = mapping.external_addr;
+        let list = match mapping.protocol {
+            ConnectProtocol::TCP => &mut guard.mapping_tcp_addr,
+            _ => &mut guard.mapping_udp_addr,
+        };
+        if !list.contains(&addr) {
+            list.push(addr);
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
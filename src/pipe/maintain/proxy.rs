@@ -0,0 +1,47 @@
+use crate::pipe::maintain::extend_connect::extend_connect_loop;
+use crate::pipe::{NodeAddress, PipeWriter};
+use rust_p2p_core::proxy::ProxyConfig;
+use rust_p2p_core::socket::LocalInterface;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Keeps a `SOCKS5` `UDP` association to every `UDP` direct node
+pub(crate) async fn socks5_udp_loop(
+    pipe_writer: PipeWriter,
+    proxy: ProxyConfig,
+    default_interface: Option<LocalInterface>,
+    interval: Duration,
+) {
+    let direct_nodes = |pipe_writer: &PipeWriter| {
+        pipe_writer
+            .pipe_context
+            .get_direct_nodes_and_id()
+            .into_iter()
+            .filter_map(|(addr, id, _)| match addr {
+                NodeAddress::Udp(addr) => Some((addr, id)),
+                NodeAddress::Tcp(_) => None,
+            })
+            .collect()
+    };
+    let associate = |addr: SocketAddr, extensible_pipe_writer| {
+        let proxy = &proxy;
+        let default_interface = default_interface.as_ref();
+        async move {
+            rust_p2p_core::proxy::udp_associate(
+                proxy,
+                addr,
+                default_interface,
+                &extensible_pipe_writer,
+            )
+            .await
+        }
+    };
+    extend_connect_loop(
+        "socks5_udp_loop",
+        pipe_writer,
+        interval,
+        direct_nodes,
+        associate,
+    )
+    .await
+}
@@ -1,28 +1,66 @@
-use crate::pipe::PipeWriter;
+use crate::config::punch_info::encode_consult_info;
+use crate::pipe::maintain::nat_query::PortPredictor;
+use crate::pipe::{PipeWriter, TCP_PUNCH_DELAY};
 use crate::protocol::node_id::NodeID;
 use crate::protocol::protocol_type::ProtocolType;
 use rand::seq::SliceRandom;
 use rust_p2p_core::punch::{PunchConsultInfo, PunchInfo, Puncher};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::Notify;
+use tokio::time::Instant;
 
-pub async fn punch_consult_loop(pipe_writer: PipeWriter, puncher: Puncher<NodeID>) {
+/// The peers with user traffic within this window are consulted first
+const ACTIVE_WINDOW: Duration = Duration::from_secs(60);
+
+enum Trigger {
+    Interval,
+    /// `PipeWriter::punch_now`
+    Peer(NodeID),
+    /// Our addresses changed, the peers reached directly are consulted too
+    All,
+}
+
+pub async fn punch_consult_loop(
+    pipe_writer: PipeWriter,
+    puncher: Puncher<NodeID>,
+    mut punch_now_receiver: Receiver<NodeID>,
+    consult_all: Arc<Notify>,
+    predictor: PortPredictor,
+) {
     let mut seq = 0;
     tokio::time::sleep(Duration::from_secs(1)).await;
     let route_table = pipe_writer.pipe_writer.route_table();
+    let policy = puncher.punch_policy().clone();
+    // Only the rounds of the interval reset it, the other triggers do not hold it up
+    let interval = tokio::time::sleep(policy.interval());
+    tokio::pin!(interval);
     loop {
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        let trigger = tokio::select! {
+            _ = &mut interval => {
+                interval.as_mut().reset(Instant::now() + policy.interval());
+                Trigger::Interval
+            }
+            Some(node_id) = punch_now_receiver.recv() => Trigger::Peer(node_id),
+            _ = consult_all.notified() => Trigger::All,
+        };
+        let immediate = !matches!(trigger, Trigger::Interval);
         seq += 1;
         let self_id = if let Some(self_id) = pipe_writer.pipe_context.load_id() {
             self_id
         } else {
             continue;
         };
-        let consult_info = pipe_writer.pipe_context().gen_punch_info(seq);
-        let data = match rmp_serde::to_vec(&consult_info) {
+        predictor.refresh(pipe_writer.pipe_context()).await;
+        let consult_info = pipe_writer
+            .pipe_context()
+            .gen_punch_info(seq)
+            .set_immediate(immediate);
+        let data = match encode_consult_info(&consult_info) {
             Ok(data) => data,
             Err(e) => {
-                log::warn!("punch_consult_loop rmp_serde {e:?}");
+                log::warn!("punch_consult_loop encode {e:?}");
                 continue;
             }
         };
@@ -37,15 +75,41 @@ pub async fn punch_consult_loop(pipe_writer: PipeWriter, puncher: Puncher<NodeID
         };
         send_packet.set_payload(&data);
 
-        let mut node_ids = route_table.route_table_ids();
-        node_ids.shuffle(&mut rand::thread_rng());
+        let (node_ids, fan_out) = match trigger {
+            Trigger::Peer(node_id) => {
+                // Start over the backoff
+                puncher.reset_record(&node_id);
+                (vec![node_id], 1)
+            }
+            Trigger::All => {
+                let node_ids = route_table.route_table_ids();
+                for node_id in &node_ids {
+                    puncher.reset_record(node_id);
+                }
+                let fan_out = node_ids.len();
+                (node_ids, fan_out)
+            }
+            Trigger::Interval => {
+                let mut node_ids = route_table.route_table_ids();
+                node_ids.shuffle(&mut rand::thread_rng());
+                let active_peers = pipe_writer.pipe_context().active_peers(ACTIVE_WINDOW);
+                node_ids.sort_by_key(|id| !active_peers.contains(id));
+                (node_ids, policy.fan_out())
+            }
+        };
         let mut count = 0;
         for node_id in node_ids {
-            if node_id <= self_id {
-                continue;
+            if count >= fan_out {
+                break;
             }
-            if !puncher.need_punch(&node_id) {
-                continue;
+            match trigger {
+                Trigger::Interval if node_id <= self_id => continue,
+                // The direct routes of the peers lead to our old addresses
+                Trigger::All => {}
+                // The peers reached directly learn what we advertise too
+                _ if !pipe_writer.pipe_context().peer_consulted(&node_id) => {}
+                _ if !puncher.need_punch(&node_id) => continue,
+                _ => {}
             }
             if pipe_writer
                 .send_packet_to(send_packet.clone(), &node_id)
@@ -55,28 +119,143 @@ pub async fn punch_consult_loop(pipe_writer: PipeWriter, puncher: Puncher<NodeID
                 log::debug!("punch_consult {:?}", node_id);
                 count += 1;
             }
-            if count > 3 {
-                break;
-            }
         }
     }
 }
 pub async fn punch_loop(
     active: bool,
-    mut receiver: Receiver<(NodeID, PunchConsultInfo)>,
+    mut receiver: Receiver<(NodeID, PunchConsultInfo, Instant)>,
     pipe_writer: PipeWriter,
     puncher: Puncher<NodeID>,
+    predictor: PortPredictor,
 ) {
-    while let Some((node_id, info)) = receiver.recv().await {
-        let punch_info = PunchInfo::new(
+    #[cfg(feature = "turn")]
+    let mut punch_attempts: std::collections::HashMap<NodeID, usize> = Default::default();
+    while let Some((node_id, mut info, mut received)) = receiver.recv().await {
+        if !active {
+            // The consults queue up behind the reply, it never waits for the stun servers
+            predictor.refresh_in_background(pipe_writer.pipe_context());
+            if let Err(e) = consult_reply(&pipe_writer, node_id, &mut info).await {
+                log::debug!("consult_reply {e:?} {node_id:?}");
+                continue;
+            }
+            received = Instant::now();
+        }
+        #[cfg(feature = "turn")]
+        let peer_nat_info = info.peer_nat_info.clone();
+        let local_nat_info = pipe_writer
+            .pipe_context()
+            .punch_info()
+            .read()
+            .nat_info(info.peer_nat_info.seq);
+        let mut punch_info = PunchInfo::new(
             active,
             info.peer_punch_model & pipe_writer.pipe_context().punch_model_box(),
             info.peer_nat_info,
-        );
+        )
+        .set_local_nat_info(local_nat_info);
+        if let Some(delay) = info.tcp_punch_delay {
+            let rtt = pipe_writer
+                .pipe_writer
+                .route_table()
+                .get_route_by_id(&node_id)
+                .ok()
+                .map(|route| route.rtt())
+                .filter(|&rtt| rtt != rust_p2p_core::route::DEFAULT_RTT)
+                .map(|rtt| Duration::from_millis(rtt as u64));
+            punch_info =
+                punch_info.set_tcp_punch_time(tcp_punch_time(active, received, delay, rtt));
+        }
         if let Ok(packet) = pipe_writer.allocate_send_packet_proto(ProtocolType::PunchRequest, 0) {
-            if let Err(e) = puncher.punch(node_id, packet.buf(), punch_info).await {
+            let buf = pipe_writer.pipe_context().obfuscate(packet.buf().into());
+            let rs = if info.immediate {
+                puncher.punch_now(node_id, &buf, punch_info).await
+            } else {
+                puncher.punch(node_id, &buf, punch_info).await
+            };
+            if let Err(e) = rs {
                 log::warn!("punch {e:?} {node_id:?}");
             }
+            #[cfg(feature = "turn")]
+            if let Some(client) = pipe_writer.pipe_context().turn_client.as_ref() {
+                let route_table = pipe_writer.pipe_writer.route_table();
+                punch_attempts.retain(|id, _| route_table.need_punch(id));
+                let attempts = punch_attempts.entry(node_id).or_default();
+                *attempts += 1;
+                // The TURN server is only for the peers no node can relay to
+                let relayed = route_table
+                    .route(&node_id)
+                    .is_some_and(|routes| routes.iter().any(|route| route.is_relay()));
+                if *attempts > super::turn::PUNCH_ATTEMPTS && !relayed {
+                    puncher
+                        .reports()
+                        .record(&node_id, rust_p2p_core::punch::PunchStrategy::Turn);
+                    if let Err(e) =
+                        super::turn::punch_turn(&pipe_writer, client, &peer_nat_info, packet.buf())
+                            .await
+                    {
+                        log::warn!("punch turn {e:?} {node_id:?}");
+                    }
+                }
+            }
         }
     }
 }
+
+/// When to connect `TCP` simultaneously. The replier counts the delay from sending the reply,
+/// which reaches the requester half a round trip later, so the requester starts that much earlier
+fn tcp_punch_time(
+    active: bool,
+    received: Instant,
+    delay: Duration,
+    rtt: Option<Duration>,
+) -> Instant {
+    match rtt {
+        Some(rtt) if active => received + delay.saturating_sub(rtt / 2),
+        _ => received + delay,
+    }
+}
+
+/// The replier decides when both sides connect `TCP` simultaneously
+async fn consult_reply(
+    pipe_writer: &PipeWriter,
+    node_id: NodeID,
+    info: &mut PunchConsultInfo,
+) -> crate::error::Result<()> {
+    let consult_info = pipe_writer
+        .pipe_context()
+        .gen_punch_info(info.peer_nat_info.seq)
+        .set_tcp_punch_delay(TCP_PUNCH_DELAY)
+        .set_immediate(info.immediate);
+    info.tcp_punch_delay = consult_info.tcp_punch_delay;
+    let data = encode_consult_info(&consult_info)?;
+    let mut send_packet =
+        pipe_writer.allocate_send_packet_proto(ProtocolType::PunchConsultReply, data.len())?;
+    send_packet.set_payload(&data);
+    pipe_writer.send_packet_to(send_packet, &node_id).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::tcp_punch_time;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn tcp_punch_time_meets() {
+        let delay = Duration::from_secs(1);
+        let rtt = Duration::from_millis(120);
+        let replied = Instant::now();
+        let passive = tcp_punch_time(false, replied, delay, Some(rtt));
+        // The reply arrives a one-way trip later
+        let active = tcp_punch_time(true, replied + rtt / 2, delay, Some(rtt));
+        assert_eq!(passive, active);
+        assert_eq!(passive, replied + delay);
+        // Without a measured rtt both wait for the full delay
+        let active = tcp_punch_time(true, replied + rtt / 2, delay, None);
+        assert_eq!(active, replied + rtt / 2 + delay);
+        // A round trip longer than twice the delay starts at once
+        let active = tcp_punch_time(true, replied, delay, Some(delay * 3));
+        assert_eq!(active, replied);
+    }
+}
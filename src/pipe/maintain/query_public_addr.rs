@@ -1,4 +1,5 @@
 use crate::pipe::PipeWriter;
+use rust_p2p_core::extend::addr::is_ip_global;
 use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::Duration;
@@ -117,6 +118,87 @@ pub(crate) async fn query_udp_public_addr_loop(
     }
 }
 
+/// Queries the mesh members that advertise `PipeConfig::stun_server` in the punch consult,
+/// on a schedule apart from the configured servers.
+/// The STUN requests over `TCP` bypass the transport, so they are not sent with `tcp_mesh` off, such as with TLS
+pub(crate) async fn query_mesh_public_addr_loop(pipe_writer: PipeWriter, tcp_mesh: bool) {
+    let stun_request = rust_p2p_core::stun::send_stun_request();
+    let mut count = 0;
+    loop {
+        let servers = mesh_stun_servers(&pipe_writer, tcp_mesh);
+        if servers.is_empty() {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+        let (tcp, addr) = servers[count % servers.len()];
+        count += 1;
+        if tcp {
+            match query_tcp_mesh(&pipe_writer, addr, &stun_request).await {
+                Ok(pub_addr) => {
+                    pipe_writer.pipe_context().update_tcp_public_addr(pub_addr);
+                }
+                Err(e) => {
+                    log::debug!("query_mesh_public_addr_loop {e:?},server={addr:?}");
+                }
+            }
+        } else if let Some(w) = pipe_writer.pipe_writer.udp_pipe_writer() {
+            if let Err(e) = w.detect_pub_addrs(&stun_request, addr).await {
+                log::debug!("detect_pub_addrs {e:?} {addr:?}");
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(12)).await;
+    }
+}
+
+/// The members that answer STUN, `true` for `TCP`.
+/// Only the direct nodes are used for `TCP`, the accepted connections do not tell the listening port
+fn mesh_stun_servers(pipe_writer: &PipeWriter, tcp_mesh: bool) -> Vec<(bool, SocketAddr)> {
+    let pipe_context = pipe_writer.pipe_context();
+    let mut addrs: Vec<(bool, SocketAddr)> = pipe_context
+        .get_direct_nodes()
+        .into_iter()
+        .filter(|(addr, id)| {
+            (tcp_mesh || !addr.is_tcp())
+                && id.is_some_and(|(_, node_id)| pipe_context.peer_stun_server(&node_id))
+        })
+        .map(|(addr, _)| (addr.is_tcp(), *addr.addr()))
+        .collect();
+    for (node_id, route) in pipe_writer.pipe_writer.route_table().route_table_p2p() {
+        let route_key = route.route_key();
+        let addr = (false, route_key.addr());
+        if route_key.protocol().is_udp()
+            && is_ip_global(&addr.1.ip())
+            && pipe_context.peer_stun_server(&node_id)
+            && !addrs.contains(&addr)
+        {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
+
+async fn query_tcp_mesh(
+    pipe_writer: &PipeWriter,
+    addr: SocketAddr,
+    stun_request: &[u8],
+) -> crate::error::Result<SocketAddr> {
+    let Some(w) = pipe_writer.pipe_writer.tcp_pipe_writer() else {
+        Err(crate::error::Error::InvalidArgument(
+            "tcp is disabled".into(),
+        ))?
+    };
+    let mut tcp_stream =
+        match tokio::time::timeout(Duration::from_secs(5), w.connect_reuse_port_raw(addr)).await {
+            Ok(rs) => rs?,
+            Err(_) => Err(crate::error::Error::Timeout)?,
+        };
+    match tokio::time::timeout(Duration::from_secs(5), tcp_stream.write_all(stun_request)).await {
+        Ok(rs) => rs?,
+        Err(_) => Err(crate::error::Error::Timeout)?,
+    };
+    stun_tcp_read(&mut tcp_stream).await
+}
+
 async fn stun_tcp_read(tcp_stream: &mut TcpStream) -> crate::error::Result<SocketAddr> {
     let mut head = [0; 20];
     match tokio::time::timeout(Duration::from_secs(5), tcp_stream.read_exact(&mut head)).await {
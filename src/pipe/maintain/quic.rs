@@ -0,0 +1,44 @@
+use crate::pipe::PipeWriter;
+use crate::protocol::node_id::NodeID;
+use crate::protocol::protocol_type::ProtocolType;
+use rust_p2p_core::punch::Puncher;
+use std::time::Duration;
+
+const UPGRADE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upgrades the direct `UDP` routes to `QUIC`,
+/// the node with the smaller id connects to the peer if it advertised `QUIC` in the punch consult
+pub(crate) async fn quic_upgrade_loop(pipe_writer: PipeWriter, puncher: Puncher<NodeID>) {
+    let route_table = pipe_writer.pipe_writer.route_table();
+    loop {
+        tokio::time::sleep(UPGRADE_INTERVAL).await;
+        let Some(self_id) = pipe_writer.pipe_context.load_id() else {
+            continue;
+        };
+        let buf = match pipe_writer.allocate_send_packet_proto(ProtocolType::PunchRequest, 0) {
+            Ok(packet) => pipe_writer.pipe_context().obfuscate(packet.buf().into()),
+            Err(e) => {
+                log::warn!("quic_upgrade_loop send_packet {e:?}");
+                continue;
+            }
+        };
+        for (node_id, routes) in route_table.route_table() {
+            if node_id <= self_id
+                || !pipe_writer.pipe_context.peer_quic(&node_id)
+                || routes.iter().any(|v| v.route_key().protocol().is_quic())
+            {
+                continue;
+            }
+            let Some(route) = routes
+                .iter()
+                .find(|v| v.is_direct() && v.route_key().protocol().is_udp())
+            else {
+                continue;
+            };
+            match puncher.punch_quic(&node_id, &buf, &route.route_key()).await {
+                Ok(route_key) => log::debug!("quic upgrade {node_id:?} {route_key:?}"),
+                Err(e) => log::debug!("quic upgrade {e:?} {node_id:?}"),
+            }
+        }
+    }
+}
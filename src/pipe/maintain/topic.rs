@@ -0,0 +1,19 @@
+use crate::pipe::PipeWriter;
+use std::time::Duration;
+
+/// Subscriptions expire if they are not announced again within this many intervals
+const EXPIRE_INTERVALS: u32 = 3;
+
+pub async fn topic_announce_loop(pipe_writer: PipeWriter, announce_interval: Duration) {
+    loop {
+        tokio::time::sleep(announce_interval).await;
+        let topics = &pipe_writer.pipe_context.topics;
+        topics.clear_expired(announce_interval * EXPIRE_INTERVALS);
+        if topics.local_topics().is_empty() {
+            continue;
+        }
+        if let Err(e) = pipe_writer.announce_topics().await {
+            log::warn!("announce_topics e={e:?}");
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::pipe::PipeWriter;
+use rust_p2p_core::nat::NatInfo;
+use rust_p2p_core::turn::TurnClient;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Punching is retried this many times before relaying through the TURN server,
+/// unless the peer is relayed by a node
+pub(crate) const PUNCH_ATTEMPTS: usize = 3;
+const MAX_PEER_ADDRS: usize = 4;
+
+/// Keeps the allocation on the TURN server alive,
+/// the relayed address is advertised as a mapping address
+pub(crate) async fn turn_loop(pipe_writer: PipeWriter, client: TurnClient) {
+    let mut relayed_addr = None;
+    loop {
+        let rs = tokio::select! {
+            rs = client.run() => rs.map_err(anyhow::Error::from),
+            rs = allocate(&pipe_writer, &client, &mut relayed_addr) => rs,
+        };
+        if let Err(e) = rs {
+            log::warn!("turn {e:?}");
+        }
+        if let Some(addr) = relayed_addr.take() {
+            let mut guard = pipe_writer.pipe_context.punch_info().write();
+            guard.mapping_udp_addr.retain(|v| v != &addr);
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+async fn allocate(
+    pipe_writer: &PipeWriter,
+    client: &TurnClient,
+    relayed_addr: &mut Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let addr = client.allocate().await?;
+    log::info!("turn relayed address {addr}");
+    {
+        let mut guard = pipe_writer.pipe_context.punch_info().write();
+        if !guard.mapping_udp_addr.contains(&addr) {
+            guard.mapping_udp_addr.push(addr);
+            relayed_addr.replace(addr);
+        }
+    }
+    loop {
+        tokio::time::sleep(client.refresh_interval()).await;
+        client.refresh().await?;
+    }
+}
+
+/// Send the punch packet to the public addresses of the peer through the TURN server
+pub(crate) async fn punch_turn(
+    pipe_writer: &PipeWriter,
+    client: &TurnClient,
+    nat_info: &NatInfo,
+    buf: &[u8],
+) -> anyhow::Result<()> {
+    if client.relayed_addr().is_none() {
+        return Ok(());
+    }
+    let mut addrs: Vec<SocketAddr> = nat_info
+        .public_ips
+        .iter()
+        .flat_map(|ip| {
+            nat_info
+                .public_ports
+                .iter()
+                .filter(|port| **port != 0)
+                .map(|port| SocketAddr::new(IpAddr::V4(*ip), *port))
+        })
+        .collect();
+    addrs.extend(&nat_info.mapping_udp_addr);
+    addrs.dedup();
+    addrs.truncate(MAX_PEER_ADDRS);
+    let mut ips: Vec<IpAddr> = addrs.iter().map(|v| v.ip()).collect();
+    ips.sort();
+    ips.dedup();
+    if ips.is_empty() {
+        return Ok(());
+    }
+    client.create_permission(&ips).await?;
+    for addr in addrs {
+        let route_key = client.connect(addr).await?;
+        pipe_writer.send_to_route(buf, &route_key).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use crate::pipe::maintain::extend_connect::extend_connect_loop;
+use crate::pipe::PipeWriter;
+use rust_p2p_core::websocket::rustls::ClientConfig;
+use rust_p2p_core::websocket::WebSocketListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) async fn websocket_accept_loop(listener: WebSocketListener) {
+    if let Err(e) = listener.run().await {
+        log::warn!("websocket_accept_loop {e:?}");
+    }
+}
+
+/// Keeps a connection to every `ws://`/`wss://` direct node
+pub(crate) async fn websocket_connect_loop(
+    pipe_writer: PipeWriter,
+    tls_config: Option<Arc<ClientConfig>>,
+    interval: Duration,
+) {
+    let connect = |url: String, extensible_pipe_writer| {
+        let tls_config = tls_config.clone();
+        async move { rust_p2p_core::websocket::connect(&url, tls_config, &extensible_pipe_writer).await }
+    };
+    extend_connect_loop(
+        "websocket_connect_loop",
+        pipe_writer,
+        interval,
+        |pipe_writer| pipe_writer.pipe_context.get_direct_websocket_nodes(),
+        connect,
+    )
+    .await
+}
@@ -1,32 +1,56 @@
+use crate::config::punch_info::decode_consult_info;
 use crate::config::PipeConfig;
 use crate::error::{Error, Result};
 use crate::extend::byte_pool::{Block, BufferPool};
+use crate::pipe::capture::{Capture, CaptureFilter, Direction};
+use crate::pipe::channel::{ChannelReceiver, ChannelSender};
 use crate::pipe::pipe_context::PipeContext;
+use crate::protocol::addr::{decode_addr, encode_addr};
 use crate::protocol::broadcast::RangeBroadcastPacket;
+use crate::protocol::congestion::decode_ack;
+use crate::protocol::echo::{probe_size, PROBE_HEAD_LEN};
 use crate::protocol::id_route::IDRouteReplyPacket;
 use crate::protocol::node_id::{GroupCode, NodeID};
 use crate::protocol::protocol_type::ProtocolType;
+use crate::protocol::topic::{decode_topics, encode_topics, Topic, TOPIC_LEN};
 use crate::protocol::{broadcast, NetPacket, HEAD_LEN};
 use async_shutdown::ShutdownManager;
 use bytes::BytesMut;
+pub use channel::DEFAULT_CHANNEL;
 use dashmap::DashMap;
 pub use pipe_context::NodeAddress;
 pub use pipe_context::PeerNodeAddress;
 use rust_p2p_core::nat::NatType;
+use rust_p2p_core::pipe::priority::Priority;
 use rust_p2p_core::pipe::recycle::RecycleBuf;
-use rust_p2p_core::punch::PunchConsultInfo;
+use rust_p2p_core::pipe::udp_pipe::UDPIndex;
+use rust_p2p_core::punch::{PunchConsultInfo, PunchReport, PunchReports};
 use rust_p2p_core::route::route_table::RouteTable;
-use rust_p2p_core::route::{ConnectProtocol, Route, RouteKey};
+use rust_p2p_core::route::{ConnectProtocol, Index, Route, RouteKey};
+use rust_p2p_core::stun::StunRequest;
 pub use send_packet::SendPacket;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, UNIX_EPOCH};
 use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
 
+/// The delay from the punch consult reply until both sides connect TCP simultaneously
+pub(crate) const TCP_PUNCH_DELAY: Duration = Duration::from_secs(1);
+/// The most packets a `UDP` pipeline receives per wakeup
+const RECV_BATCH: usize = 16;
+
+pub mod capture;
+pub mod channel;
 mod maintain;
+mod observed_addr;
 mod pipe_context;
+mod pmtu;
+#[cfg(all(test, feature = "sim"))]
+mod sim_tests;
+mod topic;
 
 mod send_packet;
 
@@ -36,27 +60,62 @@ pub struct Pipe {
     pipe_context: PipeContext,
     pipe: rust_p2p_core::pipe::Pipe<NodeID>,
     shutdown_manager: ShutdownManager<()>,
-    active_punch_sender: Sender<(NodeID, PunchConsultInfo)>,
-    passive_punch_sender: Sender<(NodeID, PunchConsultInfo)>,
+    active_punch_sender: Sender<(NodeID, PunchConsultInfo, Instant)>,
+    passive_punch_sender: Sender<(NodeID, PunchConsultInfo, Instant)>,
     buffer_pool: Option<BufferPool<BytesMut>>,
     recycle_buf: Option<RecycleBuf>,
+    stun_server: bool,
+    punch_reports: PunchReports<NodeID>,
+    punch_now_sender: Sender<NodeID>,
+    /// The node of every pinned certificate fingerprint
+    #[cfg(feature = "tls")]
+    tls_pins: Arc<HashMap<[u8; 32], NodeID>>,
 }
 
 impl Pipe {
     pub async fn new(mut config: PipeConfig) -> Result<Pipe> {
         let multi_pipeline = config.multi_pipeline;
         let send_buffer_size = config.send_buffer_size;
+        let obfuscator = config.obfuscation.clone();
+        let congestion = config
+            .congestion
+            .take()
+            .map(crate::congestion::CongestionControl::new);
+        // The obfuscated packets are received whole
+        if obfuscator.is_some() {
+            config.recv_buffer_size += crate::obfuscation::MAX_OVERHEAD;
+        }
         let recv_buffer_size = config.recv_buffer_size;
         let query_id_interval = config.query_id_interval;
         let query_id_max_num = config.query_id_max_num;
         let heartbeat_interval = config.heartbeat_interval;
+        let topic_announce_interval = config.topic_announce_interval;
         let route_idle_time = config.route_idle_time;
         let group_code = config.group_code.take();
         let self_id = config.self_id.take();
+        // The direct `WebSocket` addresses require the extensible pipe
+        config.enable_extend = config.requires_extend();
         let direct_addrs = config.direct_addrs.take();
         let mapping_addrs = config.mapping_addrs.take();
         let dns = config.dns.take();
         let default_interface = config.default_interface.clone();
+        let port_mapping = config.port_mapping;
+        let pmtu_discovery = config.pmtu_discovery;
+        let interface_watch = config.interface_watch;
+        let stun_server = config.stun_server;
+        #[cfg(feature = "turn")]
+        let turn_config = config.turn.clone();
+        #[cfg(feature = "websocket")]
+        let websocket_config = config.websocket.clone().unwrap_or_default();
+        #[cfg(feature = "tls")]
+        let tls_config = config.tls.take();
+        let udp_proxy = config.udp_pipe_config.as_mut().and_then(|v| v.proxy.take());
+        if matches!(&udp_proxy, Some(v) if v.protocol != rust_p2p_core::proxy::ProxyProtocol::Socks5)
+        {
+            return Err(Error::InvalidArgument(
+                "the UDP proxy must be SOCKS5".into(),
+            ));
+        }
         let buffer_pool = if config.recycle_buf_cap > 0 {
             Some(BufferPool::new(
                 config.recycle_buf_cap,
@@ -80,8 +139,37 @@ impl Pipe {
         }
         #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
         let cipher = config.encryption.clone().map(crate::cipher::Cipher::from);
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        let compression = config.compression;
 
-        let config: rust_p2p_core::pipe::config::PipeConfig = config.into();
+        #[allow(unused_mut)]
+        let mut config: rust_p2p_core::pipe::config::PipeConfig = config.into();
+        // The mesh members only answer STUN inside the transport
+        #[allow(unused_mut)]
+        let mut tcp_mesh_stun = true;
+        #[cfg(feature = "tls")]
+        let tls_pins = match (tls_config, config.tcp_pipe_config.as_mut()) {
+            (Some(tls_config), Some(tcp_pipe_config)) => {
+                tcp_mesh_stun = false;
+                let transport = if tls_config.pins.is_empty() && tls_config.allow_unpinned {
+                    rust_p2p_core::pipe::tls::TlsTransport::unpinned(
+                        tls_config.cert_chain,
+                        tls_config.private_key,
+                        &tls_config.server_name,
+                    )?
+                } else {
+                    rust_p2p_core::pipe::tls::TlsTransport::new(
+                        tls_config.cert_chain,
+                        tls_config.private_key,
+                        tls_config.pins.values().copied().collect(),
+                        &tls_config.server_name,
+                    )?
+                };
+                tcp_pipe_config.transport = Arc::new(transport);
+                tls_config.pins.into_iter().map(|(k, v)| (v, k)).collect()
+            }
+            _ => HashMap::new(),
+        };
         let mut recycle_buf: Option<RecycleBuf> = None;
         if let Some(v) = config.tcp_pipe_config.as_ref() {
             recycle_buf.clone_from(&v.recycle_buf);
@@ -89,6 +177,8 @@ impl Pipe {
             recycle_buf.clone_from(&v.recycle_buf);
         };
         let (pipe, puncher, idle_route_manager) = rust_p2p_core::pipe::pipe::<NodeID>(config)?;
+        let punch_reports = puncher.reports();
+        let (punch_now_sender, punch_now_receiver) = tokio::sync::mpsc::channel(16);
         let writer_ref = pipe.writer_ref();
         let local_tcp_port = if let Some(v) = writer_ref.tcp_pipe_writer_ref() {
             v.local_addr().port()
@@ -100,6 +190,28 @@ impl Pipe {
         } else {
             vec![]
         };
+        #[cfg(feature = "turn")]
+        let turn_client = match (turn_config, writer_ref.extensible_pipe_writer_ref()) {
+            (Some(turn_config), Some(w)) => {
+                Some(rust_p2p_core::turn::TurnClient::new(&turn_config, w.to_owned()).await?)
+            }
+            _ => None,
+        };
+        #[cfg(feature = "websocket")]
+        let websocket_listener = match (
+            websocket_config.listen_addr,
+            writer_ref.extensible_pipe_writer_ref(),
+        ) {
+            (Some(addr), Some(w)) => Some(
+                rust_p2p_core::websocket::WebSocketListener::bind(
+                    addr,
+                    websocket_config.server_tls_config.clone(),
+                    w.to_owned(),
+                )
+                .await?,
+            ),
+            _ => None,
+        };
         let pipe_context = PipeContext::new(
             multi_pipeline,
             local_udp_ports,
@@ -108,6 +220,12 @@ impl Pipe {
             dns,
             #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
             cipher,
+            #[cfg(any(feature = "lz4", feature = "zstd"))]
+            compression,
+            #[cfg(feature = "turn")]
+            turn_client,
+            obfuscator,
+            congestion,
         );
         if let Some(group_code) = group_code {
             pipe_context.store_group_code(group_code)?;
@@ -122,6 +240,11 @@ impl Pipe {
         if let Some(addrs) = mapping_addrs {
             pipe_context.set_mapping_addrs(addrs);
         }
+        #[cfg(feature = "quic")]
+        {
+            pipe_context.punch_info().write().quic = writer_ref.quic_pipe_writer_ref().is_some();
+        }
+        pipe_context.punch_info().write().stun_server = stun_server;
         let shutdown_manager = ShutdownManager::<()>::new();
         let pipe_writer = PipeWriter {
             send_buffer_size,
@@ -129,6 +252,8 @@ impl Pipe {
             pipe_writer: pipe.writer_ref().to_owned(),
             shutdown_manager: shutdown_manager.clone(),
             recycle_buf: recycle_buf.clone(),
+            punch_reports: punch_reports.clone(),
+            punch_now_sender: punch_now_sender.clone(),
         };
         let (active_punch_sender, active_punch_receiver) = tokio::sync::mpsc::channel(3);
         let (passive_punch_sender, passive_punch_receiver) = tokio::sync::mpsc::channel(3);
@@ -139,12 +264,23 @@ impl Pipe {
             query_id_interval,
             query_id_max_num,
             heartbeat_interval,
+            topic_announce_interval,
             route_idle_time,
             tcp_stun_servers,
+            tcp_mesh_stun,
             udp_stun_servers,
             default_interface,
+            port_mapping,
+            pmtu_discovery,
+            interface_watch,
             active_punch_receiver,
             passive_punch_receiver,
+            punch_now_receiver,
+            udp_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_listener,
+            #[cfg(feature = "websocket")]
+            websocket_config.client_tls_config,
         );
         let fut = shutdown_manager
             .wrap_cancel(async move { while join_set.join_next().await.is_some() {} });
@@ -163,6 +299,11 @@ impl Pipe {
             passive_punch_sender,
             buffer_pool,
             recycle_buf,
+            stun_server,
+            punch_reports,
+            punch_now_sender,
+            #[cfg(feature = "tls")]
+            tls_pins: Arc::new(tls_pins),
         })
     }
     pub fn writer(&self) -> PipeWriter {
@@ -172,6 +313,8 @@ impl Pipe {
             pipe_writer: self.pipe.writer_ref().to_owned(),
             shutdown_manager: self.shutdown_manager.clone(),
             recycle_buf: self.recycle_buf.clone(),
+            punch_reports: self.punch_reports.clone(),
+            punch_now_sender: self.punch_now_sender.clone(),
         }
     }
 }
@@ -187,6 +330,10 @@ impl Pipe {
             return Err(Error::ShutDown);
         };
         let pipe_line = pipe_line?;
+        #[cfg(feature = "tls")]
+        let pinned_id = pipe_line
+            .peer_fingerprint()
+            .and_then(|v| self.tls_pins.get(&v).copied());
         Ok(PipeLine {
             shutdown_manager: self.shutdown_manager.clone(),
             pipe_context: self.pipe_context.clone(),
@@ -197,6 +344,12 @@ impl Pipe {
             passive_punch_sender: self.passive_punch_sender.clone(),
             buffer_pool: self.buffer_pool.clone(),
             recv_buffer_size: self.recv_buffer_size,
+            stun_server: self.stun_server,
+            #[cfg(feature = "tls")]
+            pinned_id,
+            blocks: Vec::new(),
+            received: Vec::new(),
+            next_received: 0,
         })
     }
 }
@@ -208,12 +361,29 @@ pub struct PipeWriter {
     pipe_writer: rust_p2p_core::pipe::PipeWriter<NodeID>,
     shutdown_manager: ShutdownManager<()>,
     recycle_buf: Option<RecycleBuf>,
+    punch_reports: PunchReports<NodeID>,
+    punch_now_sender: Sender<NodeID>,
 }
 
 impl PipeWriter {
     pub fn pipe_context(&self) -> &PipeContext {
         &self.pipe_context
     }
+    /// How punching the peer went, it explains why the peer stays relayed
+    pub fn punch_report(&self, node_id: &NodeID) -> Option<PunchReport> {
+        self.punch_reports.get(node_id)
+    }
+    pub fn punch_reports(&self) -> Vec<(NodeID, PunchReport)> {
+        self.punch_reports.all()
+    }
+    /// Consult the peer right away and punch regardless of the backoff,
+    /// nothing happens if the peer is already reached directly
+    pub async fn punch_now(&self, node_id: &NodeID) -> Result<()> {
+        self.punch_now_sender
+            .send(*node_id)
+            .await
+            .map_err(|_| Error::ShutDown)
+    }
     pub fn switch_model(&self, nat_type: NatType) -> Result<()> {
         use rust_p2p_core::pipe::udp_pipe::Model;
         match nat_type {
@@ -259,6 +429,15 @@ impl PipeWriter {
             .get(group_code)
             .map(|v| v.route(node_id))?
     }
+    /// The largest IP packet the route to the node carries, `None` until probed.
+    /// Only the direct `UDP` routes are probed, see `PipeConfig::pmtu_discovery`
+    pub fn path_mtu(&self, node_id: &NodeID) -> Option<u16> {
+        self.pipe_writer
+            .route_table()
+            .get_route_by_id(node_id)
+            .ok()?
+            .mtu()
+    }
     pub fn current_group_code(&self) -> GroupCode {
         self.pipe_context.load_group_code()
     }
@@ -298,11 +477,43 @@ impl PipeWriter {
         buf: &NetPacket<B>,
         id: &NodeID,
     ) -> Result<()> {
-        self.pipe_writer.send_to_id(buf.buffer().into(), id).await?;
-        Ok(())
+        self.send_id(buf.buffer().into(), id, priority_of(buf.buffer()))
+            .await
     }
     pub(crate) async fn send_to_route(&self, buf: &[u8], route_key: &RouteKey) -> Result<()> {
-        self.pipe_writer.send_to(buf.into(), route_key).await?;
+        self.send_route(buf.into(), route_key, priority_of(buf))
+            .await
+    }
+    async fn send_id(&self, buf: BytesMut, id: &NodeID, priority: Priority) -> Result<()> {
+        let route = self.pipe_writer.route_table().get_route_by_id(id)?;
+        self.send_route(buf, &route.route_key(), priority).await
+    }
+    async fn send_route(
+        &self,
+        buf: BytesMut,
+        route_key: &RouteKey,
+        priority: Priority,
+    ) -> Result<()> {
+        self.pipe_context
+            .capture(Direction::Outbound, *route_key, &buf);
+        let buf = self.pipe_context.obfuscate(buf);
+        self.pipe_writer
+            .send_to_priority(buf, route_key, priority)
+            .await?;
+        Ok(())
+    }
+    /// Sends without the random obfuscation padding, so the size on the wire is exact
+    pub(crate) async fn send_probe(&self, buf: &[u8], route_key: &RouteKey) -> Result<()> {
+        self.pipe_context
+            .capture(Direction::Outbound, *route_key, buf);
+        let buf = match &self.pipe_context.obfuscator {
+            Some(obfuscator) => obfuscator.obfuscate_with_padding(buf, 0),
+            None => buf.into(),
+        };
+        let Some(udp_pipe_writer) = self.pipe_writer.udp_pipe_writer() else {
+            return Err(rust_p2p_core::error::Error::InvalidProtocol.into());
+        };
+        udp_pipe_writer.send_probe_to(buf, route_key).await?;
         Ok(())
     }
     async fn send_to0(
@@ -311,19 +522,21 @@ impl PipeWriter {
         group_code: &GroupCode,
         src_id: &NodeID,
         dest_id: &NodeID,
+        priority: Priority,
     ) -> Result<()> {
         if dest_id.is_broadcast() {
-            self.send_broadcast0(&buf, group_code, src_id).await;
+            self.send_broadcast0(&buf, group_code, src_id, None, priority)
+                .await;
             return Ok(());
         }
 
         if let Ok(route) = self.pipe_writer.route_table().get_route_by_id(dest_id) {
-            self.pipe_writer.send_to(buf, &route.route_key()).await?
+            self.send_route(buf, &route.route_key(), priority).await?
         } else if let Some((relay_group_code, relay_node_id)) =
             self.pipe_context().reachable_node(group_code, dest_id)
         {
             if &relay_group_code == group_code {
-                self.pipe_writer.send_to_id(buf, &relay_node_id).await?
+                self.send_id(buf, &relay_node_id, priority).await?
             } else {
                 let route;
                 if let Some(v) = self.pipe_context().other_route_table.get(&relay_group_code) {
@@ -331,7 +544,7 @@ impl PipeWriter {
                 } else {
                     return Err(Error::NodeIDNotAvailable);
                 }
-                self.pipe_writer.send_to(buf, &route.route_key()).await?
+                self.send_route(buf, &route.route_key(), priority).await?
             }
         } else {
             Err(Error::NodeIDNotAvailable)?
@@ -339,13 +552,32 @@ impl PipeWriter {
         Ok(())
     }
 
-    async fn send_broadcast0(&self, buf: &[u8], group_code: &GroupCode, src_id: &NodeID) {
+    /// Send to all nodes, or only to the `targets`.
+    /// The nodes behind the same relay are packed into one `RangeBroadcast` packet
+    async fn send_broadcast0(
+        &self,
+        buf: &[u8],
+        group_code: &GroupCode,
+        src_id: &NodeID,
+        targets: Option<&HashSet<NodeID>>,
+        priority: Priority,
+    ) {
+        let is_target = |id: &NodeID| match targets {
+            Some(targets) => targets.contains(id),
+            None => true,
+        };
         let route_table = self.pipe_writer.route_table();
         let table = route_table.route_table_one();
         let mut map: HashMap<NodeID, (Vec<NodeID>, Route)> = HashMap::new();
         for (id, route) in &table {
+            if !is_target(id) {
+                continue;
+            }
             if route.is_direct() {
-                let list = if let Some((list, _)) = map.remove(id) {
+                let list = if let Some((mut list, _)) = map.remove(id) {
+                    if !list.contains(id) {
+                        list.push(*id);
+                    }
                     list
                 } else {
                     vec![*id]
@@ -354,8 +586,10 @@ impl PipeWriter {
             } else if let Some(owner_id) = route_table.get_id_by_route_key(&route.route_key()) {
                 if let Some((list, _)) = map.get_mut(&owner_id) {
                     list.push(*id);
-                } else {
+                } else if is_target(&owner_id) {
                     map.insert(owner_id, (vec![owner_id, *id], *route));
+                } else {
+                    map.insert(owner_id, (vec![*id], *route));
                 }
             } else {
                 // 通过其他组的节点转发的，正常来说应该也要找到这个转发节点，这里为了简化操作，写为直接发送
@@ -367,8 +601,7 @@ impl PipeWriter {
         for (owner_id, (list, route)) in map {
             if list.len() <= 1 && route.is_direct() {
                 if let Err(e) = self
-                    .pipe_writer
-                    .send_to(buf.into(), &route.route_key())
+                    .send_route(buf.into(), &route.route_key(), priority)
                     .await
                 {
                     log::debug!("send_broadcast0 {e:?} {owner_id:?}");
@@ -380,8 +613,7 @@ impl PipeWriter {
                         packet.set_dest_id(&owner_id);
                         packet.set_group_code(group_code);
                         if let Err(e) = self
-                            .pipe_writer
-                            .send_to(packet.buffer().into(), &route.route_key())
+                            .send_route(packet.buffer().into(), &route.route_key(), priority)
                             .await
                         {
                             log::debug!("send_range_broadcast {e:?} {owner_id:?}");
@@ -399,27 +631,169 @@ impl PipeWriter {
         self.send_packet_to(packet, &NodeID::broadcast()).await
     }
     pub async fn send_packet_to(&self, mut packet: SendPacket, dest_id: &NodeID) -> Result<()> {
+        let (group_code, src_id) = self.pack(&mut packet, dest_id)?;
+        if let Some(congestion) = self.pipe_context.congestion.as_ref() {
+            if packet.is_user_data() && !dest_id.is_broadcast() {
+                match self.next_hop(&group_code, dest_id) {
+                    Some(route) if route.route_key().protocol().is_udp() => {
+                        packet.set_ack_request_flag(true);
+                        // The acks come back from the destination, past the relays,
+                        // so the round trip to the next hop would be too short
+                        let rtt = self
+                            .pipe_writer
+                            .route_table()
+                            .get_route_by_id(dest_id)
+                            .map_or(rust_p2p_core::route::DEFAULT_RTT, |route| route.rtt());
+                        congestion.wait(*dest_id, packet.buf().len(), rtt).await;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let priority = packet.priority();
+        self.send_to0(packet.into_buf(), &group_code, &src_id, dest_id, priority)
+            .await
+    }
+    /// The route the packets to `dest_id` leave on, the relay's when not direct
+    fn next_hop(&self, group_code: &GroupCode, dest_id: &NodeID) -> Option<Route> {
+        if let Ok(route) = self.pipe_writer.route_table().get_route_by_id(dest_id) {
+            return Some(route);
+        }
+        let (relay_group_code, relay_node_id) =
+            self.pipe_context.reachable_node(group_code, dest_id)?;
+        if &relay_group_code == group_code {
+            self.pipe_writer
+                .route_table()
+                .get_route_by_id(&relay_node_id)
+                .ok()
+        } else {
+            self.pipe_context
+                .other_route_table
+                .get(&relay_group_code)?
+                .get_route_by_id(&relay_node_id)
+                .ok()
+        }
+    }
+    /// Fill in the header, then compress and encrypt the user data
+    fn pack(&self, packet: &mut SendPacket, dest_id: &NodeID) -> Result<(GroupCode, NodeID)> {
         let group_code = self.pipe_context.load_group_code();
         if let Some(src_id) = self.pipe_context.load_id() {
             packet.set_group_code(&group_code);
             packet.set_src_id(&src_id);
             packet.set_dest_id(dest_id);
+            if packet.is_user_data() {
+                self.pipe_context.update_active_peer(*dest_id);
+            }
+            #[cfg(any(feature = "lz4", feature = "zstd"))]
+            {
+                packet.set_compression(crate::compression::Algorithm::supported());
+                if packet.is_user_data() {
+                    self.compress(packet, dest_id)?;
+                }
+            }
             #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
             if packet.is_user_data() {
                 if let Some(cipher) = self.pipe_context.cipher.as_ref() {
                     let data_len = packet.len();
                     packet.resize(data_len + cipher.reserved_len(), 0);
 
-                    cipher.encrypt(tag(&src_id, dest_id), &mut packet)?;
+                    cipher.encrypt(tag(&src_id, dest_id), packet)?;
                     packet.set_encrypt_flag(true);
                 }
             }
-            self.send_to0(packet.into_buf(), &group_code, &src_id, dest_id)
-                .await
+            Ok((group_code, src_id))
         } else {
             Err(Error::NoIDSpecified)
         }
     }
+    /// Subscribe to the topic, the subscription is announced to all nodes.
+    /// The published data is received by `PipeLine::next`, see `RecvUserData::topic`
+    pub async fn subscribe(&self, topic: Topic) -> Result<()> {
+        if self.pipe_context.topics.subscribe(topic) {
+            self.announce_topics().await?;
+        }
+        Ok(())
+    }
+    pub async fn unsubscribe(&self, topic: Topic) -> Result<()> {
+        if self.pipe_context.topics.unsubscribe(&topic) {
+            self.announce_topics().await?;
+        }
+        Ok(())
+    }
+    /// Send the payload to all subscribers of the topic.
+    /// The subscribers behind the same relay share one packet that the relay hands out,
+    /// and a relay packs the copies for the subscribers behind its own relays the same way
+    pub async fn publish(&self, topic: Topic, payload: &[u8]) -> Result<()> {
+        let subscribers = self.pipe_context.topics.subscribers(&topic);
+        if subscribers.is_empty() {
+            return Ok(());
+        }
+        let mut packet = self.allocate_send_packet();
+        packet.set_protocol(ProtocolType::TopicData);
+        packet.resize(TOPIC_LEN + payload.len(), 0);
+        packet[..TOPIC_LEN].copy_from_slice(&u32::from(topic).to_be_bytes());
+        packet[TOPIC_LEN..].copy_from_slice(payload);
+        let (group_code, src_id) = self.pack(&mut packet, &NodeID::broadcast())?;
+        let priority = packet.priority();
+        self.send_broadcast0(
+            &packet.into_buf(),
+            &group_code,
+            &src_id,
+            Some(&subscribers),
+            priority,
+        )
+        .await;
+        Ok(())
+    }
+    pub(crate) async fn announce_topics(&self) -> Result<()> {
+        let data = encode_topics(&self.pipe_context.topics.local_topics());
+        let mut packet =
+            self.allocate_send_packet_proto(ProtocolType::TopicAnnounce, data.len())?;
+        packet.set_payload(&data);
+        self.broadcast_packet(packet).await
+    }
+
+    /// Opens a dedicated channel for user data.
+    /// Data sent by the returned `ChannelSender` is delivered to the peer's `ChannelReceiver` of the same channel,
+    /// or to the peer's `PipeLine::next` if that channel is not open there.
+    pub fn channel(&self, channel: u16) -> Result<(ChannelSender, ChannelReceiver)> {
+        if channel == DEFAULT_CHANNEL {
+            return Err(Error::InvalidArgument(
+                "channel 0 is the default channel".into(),
+            ));
+        }
+        let channels = &self.pipe_context.channels;
+        let receiver = match channels.entry(channel) {
+            dashmap::Entry::Occupied(_) => {
+                return Err(Error::InvalidArgument(format!(
+                    "channel {channel} is already open"
+                )))
+            }
+            dashmap::Entry::Vacant(entry) => {
+                let (sender, receiver) = tokio::sync::mpsc::channel(channel::CHANNEL_CAPACITY);
+                entry.insert(sender);
+                receiver
+            }
+        };
+        Ok((
+            ChannelSender::new(channel, self.clone()),
+            ChannelReceiver::new(channel, receiver, channels.clone()),
+        ))
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn compress(&self, packet: &mut SendPacket, dest_id: &NodeID) -> Result<()> {
+        if dest_id.is_broadcast() {
+            return Ok(());
+        }
+        if let Some(algorithm) = self.pipe_context.peer_compression(dest_id) {
+            if let Some(buf) = algorithm.compress(packet)? {
+                packet.set_payload(&buf);
+                packet.set_compressed_flag(true);
+            }
+        }
+        Ok(())
+    }
 
     pub fn allocate_send_packet(&self) -> SendPacket {
         let buf = if let Some(recycle_buf) = self.recycle_buf.as_ref() {
@@ -441,6 +815,7 @@ impl PipeWriter {
             return Err(Error::NoIDSpecified);
         };
         let mut send_packet = SendPacket::with_capacity(payload_size);
+        send_packet.set_priority(Priority::Control);
         unsafe {
             send_packet.set_payload_len(payload_size);
         }
@@ -451,13 +826,38 @@ impl PipeWriter {
         packet.set_group_code(&group_code);
         packet.set_src_id(&src_id);
         packet.set_dest_id(&NodeID::unspecified());
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        packet.set_compression(crate::compression::Algorithm::supported());
         packet.reset_data_len();
         Ok(send_packet)
     }
+    /// Writes the raw `NetPacket`s sent and received by this pipe to a pcapng file,
+    /// replacing the capture in progress, whose file is flushed in the background.
+    /// The underlying address and the direction are recorded for every packet
+    pub fn start_capture<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        filter: CaptureFilter,
+    ) -> Result<()> {
+        let capture = Capture::create(path, filter)?;
+        drop(self.pipe_context.capture.write().replace(capture));
+        Ok(())
+    }
+    /// Stops capturing and returns once the queued packets are written and the file is flushed
+    pub async fn stop_capture(&self) {
+        let capture = self.pipe_context.capture.write().take();
+        if let Some(capture) = capture {
+            if let Err(e) = tokio::task::spawn_blocking(move || capture.finish()).await {
+                log::warn!("stop_capture {e:?}");
+            }
+        }
+    }
+    /// The capture in progress is flushed in the background, see [`stop_capture`](Self::stop_capture)
     pub fn shutdown(&self) -> Result<()> {
         self.shutdown_manager
             .trigger_shutdown(())
             .map_err(|_| Error::AlreadyShutdown)?;
+        drop(self.pipe_context.capture.write().take());
         Ok(())
     }
 }
@@ -468,10 +868,17 @@ pub struct PipeLine {
     pipe_line: rust_p2p_core::pipe::PipeLine,
     pipe_writer: PipeWriter,
     route_table: RouteTable<NodeID>,
-    active_punch_sender: Sender<(NodeID, PunchConsultInfo)>,
-    passive_punch_sender: Sender<(NodeID, PunchConsultInfo)>,
+    active_punch_sender: Sender<(NodeID, PunchConsultInfo, Instant)>,
+    passive_punch_sender: Sender<(NodeID, PunchConsultInfo, Instant)>,
     buffer_pool: Option<BufferPool<BytesMut>>,
     recv_buffer_size: usize,
+    stun_server: bool,
+    #[cfg(feature = "tls")]
+    pinned_id: Option<NodeID>,
+    /// A `UDP` pipeline receives a batch per wakeup, `next` hands it out packet by packet
+    blocks: Vec<Data>,
+    received: Vec<(usize, RouteKey)>,
+    next_received: usize,
 }
 
 impl PipeLine {
@@ -485,33 +892,8 @@ impl PipeLine {
         &mut self,
         interceptor: Option<&I>,
     ) -> core::result::Result<core::result::Result<RecvUserData, HandleError>, RecvError> {
-        let mut block = if let Some(buffer_pool) = self.buffer_pool.as_ref() {
-            Data::Recyclable(buffer_pool.alloc())
-        } else {
-            Data::Temporary(BytesMut::with_capacity(self.recv_buffer_size))
-        };
         loop {
-            unsafe {
-                let capacity = block.capacity();
-                block.set_len(capacity);
-            }
-            let (len, route_key) = if let Ok(v) = self
-                .shutdown_manager
-                .wrap_cancel(self.pipe_line.recv_from(&mut block))
-                .await
-            {
-                match v {
-                    None => return Err(RecvError::Done),
-                    Some(recv_rs) => match recv_rs {
-                        Ok(rs) => rs,
-                        Err(e) => return Err(RecvError::Io(e)),
-                    },
-                }
-            } else {
-                self.pipe_line.done();
-                return Err(RecvError::Done);
-            };
-
+            let (mut block, len, route_key) = self.recv_block().await?;
             if len == 0 {
                 return Err(RecvError::Io(std::io::Error::from(
                     std::io::ErrorKind::UnexpectedEof,
@@ -522,6 +904,9 @@ impl PipeLine {
             }
             if rust_p2p_core::stun::is_stun_response(&block) {
                 if let Some(pub_addr) = rust_p2p_core::stun::recv_stun_response(&block) {
+                    self.pipe_context
+                        .observed_addrs
+                        .stun_replied(route_key.index());
                     self.pipe_context
                         .update_public_addr(route_key.index(), pub_addr);
                 } else {
@@ -529,6 +914,25 @@ impl PipeLine {
                 }
                 continue;
             }
+            if let Some(request) = rust_p2p_core::stun::parse_stun_request(&block) {
+                if self.stun_server {
+                    if let Err(e) = self.stun_reply(&request, route_key).await {
+                        log::debug!("stun reply {route_key:?} {e:?}")
+                    }
+                }
+                continue;
+            }
+            if let Some(obfuscator) = self.pipe_context.obfuscator.as_ref() {
+                match obfuscator.deobfuscate(&mut block) {
+                    Ok(len) => block.truncate(len),
+                    Err(e) => {
+                        log::debug!("deobfuscate {route_key:?} {e:?}");
+                        continue;
+                    }
+                }
+            }
+            self.pipe_context
+                .capture(Direction::Inbound, route_key, &block);
             let mut recv_result = RecvResult::new(&mut block, route_key);
             if let Some(interceptor) = interceptor {
                 if interceptor.pre_handle(&mut recv_result).await {
@@ -538,9 +942,7 @@ impl PipeLine {
 
             return match self.handle(recv_result).await {
                 Ok(handle_result) => {
-                    if let Some(rs) = handle_result {
-                        #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
-                        let mut rs = rs;
+                    if let Some(mut rs) = handle_result {
                         #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
                         {
                             if rs.is_encrypt != self.pipe_context.cipher.is_some() {
@@ -565,8 +967,33 @@ impl PipeLine {
                                 }
                             }
                         }
+                        #[cfg(any(feature = "lz4", feature = "zstd"))]
+                        if rs.is_compressed {
+                            match crate::compression::Algorithm::decompress(
+                                &block[rs.start..rs.end],
+                            ) {
+                                Ok(buf) => {
+                                    rs.start = 0;
+                                    rs.end = buf.len();
+                                    block = Data::Temporary(BytesMut::from(&buf[..]));
+                                }
+                                Err(e) => return Ok(Err(HandleError::new(route_key, e.into()))),
+                            }
+                        }
 
-                        return Ok(Ok(RecvUserData {
+                        let mut topic = None;
+                        if rs.is_topic {
+                            match Topic::try_from(&block[rs.start..rs.end]) {
+                                // Sent on a subscription the publisher has not seen withdrawn
+                                Ok(v) if !self.pipe_context.topics.is_subscribed(&v) => continue,
+                                Ok(v) => {
+                                    topic.replace(v);
+                                    rs.start += TOPIC_LEN;
+                                }
+                                Err(e) => return Ok(Err(HandleError::new(route_key, e))),
+                            }
+                        }
+                        let data = RecvUserData {
                             _start: rs.start,
                             _end: rs.end,
                             _src_id: rs.src_id,
@@ -575,7 +1002,13 @@ impl PipeLine {
                             _data: block,
                             _ttl: rs.ttl,
                             _max_ttl: rs.max_ttl,
-                        }));
+                            _channel: rs.channel,
+                            _topic: topic,
+                        };
+                        match channel::dispatch(&self.pipe_context.channels, rs.channel, data) {
+                            Some(data) => return Ok(Ok(data)),
+                            None => continue,
+                        }
                     } else {
                         continue;
                     }
@@ -584,6 +1017,54 @@ impl PipeLine {
             };
         }
     }
+    /// Hands out the batch of the last wakeup before receiving again
+    async fn recv_block(&mut self) -> core::result::Result<(Data, usize, RouteKey), RecvError> {
+        while self.next_received >= self.received.len() {
+            let batch = if self.pipe_line.protocol() == ConnectProtocol::UDP {
+                RECV_BATCH
+            } else {
+                1
+            };
+            while self.blocks.len() < batch {
+                let block = self.alloc_block();
+                self.blocks.push(block);
+            }
+            for block in &mut self.blocks {
+                unsafe {
+                    let capacity = block.capacity();
+                    block.set_len(capacity);
+                }
+            }
+            let Ok(rs) = self
+                .shutdown_manager
+                .wrap_cancel(
+                    self.pipe_line
+                        .recv_multiple_from(&mut self.blocks, &mut self.received),
+                )
+                .await
+            else {
+                self.pipe_line.done();
+                return Err(RecvError::Done);
+            };
+            match rs {
+                None => return Err(RecvError::Done),
+                Some(Err(e)) => return Err(RecvError::Io(e)),
+                Some(Ok(_)) => self.next_received = 0,
+            }
+        }
+        let (len, route_key) = self.received[self.next_received];
+        let block = self.alloc_block();
+        let block = std::mem::replace(&mut self.blocks[self.next_received], block);
+        self.next_received += 1;
+        Ok((block, len, route_key))
+    }
+    fn alloc_block(&self) -> Data {
+        if let Some(buffer_pool) = self.buffer_pool.as_ref() {
+            Data::Recyclable(buffer_pool.alloc())
+        } else {
+            Data::Temporary(BytesMut::with_capacity(self.recv_buffer_size))
+        }
+    }
     pub fn protocol(&self) -> ConnectProtocol {
         self.pipe_line.protocol()
     }
@@ -600,6 +1081,67 @@ impl PipeLine {
     pub(crate) async fn send_to_route(&self, buf: &[u8], route_key: &RouteKey) -> Result<()> {
         self.pipe_writer.send_to_route(buf, route_key).await
     }
+    /// CHANGE-REQUEST is served from another main `UDP` socket, which only changes the port
+    /// and is advertised as OTHER-ADDRESS. The node has no alternate IP,
+    /// so a request to change the IP is answered with the error 420
+    async fn stun_reply(&self, request: &StunRequest, route_key: RouteKey) -> Result<()> {
+        let response = if request.change_ip {
+            rust_p2p_core::stun::stun_error_response(request, 420, "no alternate address")
+        } else {
+            let other = self.stun_other_pipeline(route_key);
+            let other_addr = other.map(|(_, addr)| addr);
+            let response =
+                rust_p2p_core::stun::stun_binding_response(request, route_key.addr(), other_addr);
+            if let (true, Some((index, _)), Some(w)) = (
+                request.change_port,
+                other,
+                self.pipe_writer.pipe_writer.udp_pipe_writer(),
+            ) {
+                w.get(route_key.addr(), index)?.send(&response).await?;
+                return Ok(());
+            }
+            response
+        };
+        // Not a packet of the mesh, so it is never obfuscated
+        self.pipe_writer
+            .pipe_writer
+            .send_to(response.as_slice().into(), &route_key)
+            .await?;
+        Ok(())
+    }
+    /// The index of the next main `UDP` pipeline and its address as seen by the requester,
+    /// the IP is the same and only the port differs
+    fn stun_other_pipeline(&self, route_key: RouteKey) -> Option<(usize, SocketAddr)> {
+        let Index::Udp(UDPIndex::MainV4(index)) = route_key.index() else {
+            return None;
+        };
+        let len = self
+            .pipe_writer
+            .pipe_writer
+            .udp_pipe_writer()?
+            .main_pipeline_len();
+        if len < 2 {
+            return None;
+        }
+        let other = (index + 1) % len;
+        let guard = self.pipe_context.punch_info().read();
+        let local_port = *guard.local_udp_ports.get(other)?;
+        let requester = route_key.addr().ip();
+        let (ip, port) = if requester.is_loopback() {
+            (requester, local_port)
+        } else if rust_p2p_core::extend::addr::is_ip_global(&requester) {
+            let port = match guard.public_udp_ports.get(other) {
+                Some(port) if *port != 0 => *port,
+                _ => local_port,
+            };
+            (IpAddr::V4(*guard.public_ips.first()?), port)
+        } else if !guard.local_ipv4.is_unspecified() {
+            (IpAddr::V4(guard.local_ipv4), local_port)
+        } else {
+            return None;
+        };
+        Some((other, SocketAddr::new(ip, port)))
+    }
     async fn other_group_handle(
         &mut self,
         mut packet: NetPacket<&mut [u8]>,
@@ -735,6 +1277,10 @@ impl PipeLine {
         if src_id.is_unspecified() || src_id.is_broadcast() {
             return Err(Error::InvalidArgument("src id is unspecified".into()));
         }
+        #[cfg(feature = "tls")]
+        if let Some(pinned_id) = self.pinned_id {
+            check_pinned(pinned_id, src_id, packet.max_ttl() == packet.ttl())?;
+        }
         let self_id = if let Some(self_id) = self.pipe_context.load_id() {
             self_id
         } else {
@@ -766,6 +1312,18 @@ impl PipeLine {
         }
         self.route_table
             .add_route_if_absent(src_id, Route::from_default_rt(route_key, metric));
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        if matches!(
+            packet.protocol()?,
+            // Replies may carry the header of our own request
+            ProtocolType::UserData
+                | ProtocolType::TopicData
+                | ProtocolType::EchoRequest
+                | ProtocolType::TimestampRequest
+        ) {
+            self.pipe_context
+                .update_peer_compression(src_id, packet.compression());
+        }
         match packet.protocol()? {
             ProtocolType::PunchRequest => {
                 packet.set_protocol(ProtocolType::PunchReply);
@@ -773,9 +1331,15 @@ impl PipeLine {
                 packet.set_dest_id(&src_id);
                 packet.set_src_id(&self_id);
                 self.send_to_route(packet.buffer(), &route_key).await?;
+                if metric == 0 {
+                    self.pipe_writer.punch_reports.answered(&src_id, route_key);
+                }
                 log::debug!("===========PunchRequest {route_key:?} {src_id:?}")
             }
             ProtocolType::PunchReply => {
+                if metric == 0 {
+                    self.pipe_writer.punch_reports.answered(&src_id, route_key);
+                }
                 log::debug!("===========PunchReply {route_key:?} {src_id:?}")
             }
             ProtocolType::EchoRequest => {
@@ -783,9 +1347,19 @@ impl PipeLine {
                 packet.set_ttl(packet.max_ttl());
                 packet.set_dest_id(&src_id);
                 packet.set_src_id(&self_id);
-                self.send_to_route(packet.buffer(), &route_key).await?;
+                // The padding of a path MTU probe is not sent back
+                let len = HEAD_LEN + packet.payload().len().min(PROBE_HEAD_LEN);
+                let mut reply = NetPacket::unchecked(&mut packet.buffer_mut()[..len]);
+                reply.reset_data_len();
+                self.send_to_route(reply.buffer(), &route_key).await?;
+            }
+            ProtocolType::EchoReply => {
+                if metric == 0 {
+                    if let Some(size) = probe_size(packet.payload()) {
+                        self.pipe_context.probes.reply(src_id, route_key, size);
+                    }
+                }
             }
-            ProtocolType::EchoReply => {}
             ProtocolType::TimestampRequest => {
                 packet.set_protocol(ProtocolType::TimestampReply);
                 packet.set_ttl(packet.max_ttl());
@@ -817,7 +1391,13 @@ impl PipeLine {
                 self.id_route_reply_handle(packet, group_code, self_id, group_code, src_id)
                     .await?
             }
-            ProtocolType::UserData => {
+            protocol @ (ProtocolType::UserData | ProtocolType::TopicData) => {
+                self.pipe_context.update_active_peer(src_id);
+                if packet.is_ack_requested() {
+                    self.pipe_context
+                        .acks
+                        .received(src_id, packet.buffer().len());
+                }
                 return Ok(Some(HandleResultInner {
                     start: HEAD_LEN,
                     end: packet.buffer().len(),
@@ -826,65 +1406,107 @@ impl PipeLine {
                     route_key,
                     ttl: packet.ttl(),
                     max_ttl: packet.max_ttl(),
+                    channel: packet.channel(),
+                    is_topic: protocol == ProtocolType::TopicData,
                     #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
                     is_encrypt: packet.is_encrypt(),
-                }))
+                    #[cfg(any(feature = "lz4", feature = "zstd"))]
+                    is_compressed: packet.is_compressed(),
+                }));
             }
             ProtocolType::RangeBroadcast => {
                 let end = packet.buffer().len();
 
-                let broadcast_packet = RangeBroadcastPacket::new(packet.payload_mut())?;
-                let in_packet = NetPacket::new(broadcast_packet.payload())?;
+                let mut broadcast_packet = RangeBroadcastPacket::new(packet.payload_mut())?;
                 let start = HEAD_LEN + broadcast_packet.head_len() + HEAD_LEN;
-                let mut broadcast_to_self = false;
-
                 let range_id: Vec<NodeID> = broadcast_packet.iter().collect();
-                for node_id in range_id {
-                    if node_id == self_id {
-                        broadcast_to_self = true
-                    } else if let Err(e) = self.send_to(&in_packet, &node_id).await {
-                        log::debug!("RangeBroadcast {e:?}")
+                let mut in_packet = NetPacket::new(broadcast_packet.payload_mut())?;
+                // The outer packet comes from the last relay, the inner one from the sender
+                let in_src_id = NodeID::try_from(in_packet.src_id())?;
+                let ttl = in_packet.ttl();
+                let max_ttl = in_packet.max_ttl();
+                let broadcast_to_self = range_id.contains(&self_id);
+                // The copies sent on are relayed, a pinned receiver checks the ttl against the src id.
+                // The nodes behind the same next relay are packed again, so the fan-out follows the relays
+                if in_packet.incr_ttl() {
+                    let targets: HashSet<NodeID> = range_id
+                        .into_iter()
+                        .filter(|node_id| node_id != &self_id && node_id != &in_src_id)
+                        .collect();
+                    if !targets.is_empty() {
+                        self.pipe_writer
+                            .send_broadcast0(
+                                in_packet.buffer(),
+                                &group_code,
+                                &self_id,
+                                Some(&targets),
+                                priority_of(in_packet.buffer()),
+                            )
+                            .await;
                     }
                 }
-                if broadcast_to_self {
-                    return Ok(Some(HandleResultInner {
-                        start,
-                        end,
-                        src_id,
-                        dest_id,
-                        route_key,
-                        ttl: packet.ttl(),
-                        max_ttl: packet.max_ttl(),
-                        #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
-                        is_encrypt: packet.is_encrypt(),
-                    }));
+                if !broadcast_to_self {
+                    return Ok(None);
+                }
+                match in_packet.protocol()? {
+                    protocol @ (ProtocolType::UserData | ProtocolType::TopicData) => {
+                        // The outer packet is addressed to us and never encrypted,
+                        // the user data was encrypted for the inner destination
+                        return Ok(Some(HandleResultInner {
+                            start,
+                            end,
+                            src_id: in_src_id,
+                            dest_id: NodeID::try_from(in_packet.dest_id())?,
+                            route_key,
+                            ttl,
+                            max_ttl,
+                            channel: in_packet.channel(),
+                            is_topic: protocol == ProtocolType::TopicData,
+                            #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
+                            is_encrypt: in_packet.is_encrypt(),
+                            #[cfg(any(feature = "lz4", feature = "zstd"))]
+                            is_compressed: in_packet.is_compressed(),
+                        }));
+                    }
+                    ProtocolType::TopicAnnounce => {
+                        self.topic_announce_handle(in_src_id, in_packet.payload())?;
+                    }
+                    protocol => {
+                        // The other control packets are sent to one node and never broadcast
+                        log::debug!("RangeBroadcast {protocol:?} from {in_src_id:?}");
+                    }
                 }
             }
+            ProtocolType::TopicAnnounce => {
+                self.topic_announce_handle(src_id, packet.payload())?;
+            }
             ProtocolType::PunchConsultRequest => {
-                let punch_info = rmp_serde::from_slice::<PunchConsultInfo>(packet.payload())?;
+                let punch_info = decode_consult_info(packet.payload())?;
                 log::debug!("PunchConsultRequest {:?}", punch_info);
-                let consult_info = self
-                    .pipe_context
-                    .gen_punch_info(punch_info.peer_nat_info.seq);
-                let data = rmp_serde::to_vec(&consult_info)?;
-                let mut send_packet = self
-                    .pipe_writer
-                    .allocate_send_packet_proto(ProtocolType::PunchConsultReply, data.len())?;
-                send_packet.set_payload(&data);
-                if let Ok(sender) = self.passive_punch_sender.try_reserve() {
-                    self.pipe_writer
-                        .send_packet_to(send_packet, &src_id)
-                        .await?;
-                    sender.send((src_id, punch_info))
+                #[cfg(feature = "quic")]
+                self.pipe_context.update_peer_quic(src_id, punch_info.quic);
+                self.pipe_context
+                    .update_peer_stun_server(src_id, punch_info.stun_server);
+                // Replied by the passive punch loop
+                if self
+                    .passive_punch_sender
+                    .try_send((src_id, punch_info, Instant::now()))
+                    .is_err()
+                {
+                    log::debug!("passive_punch_sender err src_id={src_id:?}");
                 }
             }
             ProtocolType::PunchConsultReply => {
-                let punch_info = rmp_serde::from_slice::<PunchConsultInfo>(packet.payload())?;
+                let punch_info = decode_consult_info(packet.payload())?;
                 log::debug!("PunchConsultReply {:?}", punch_info);
+                #[cfg(feature = "quic")]
+                self.pipe_context.update_peer_quic(src_id, punch_info.quic);
+                self.pipe_context
+                    .update_peer_stun_server(src_id, punch_info.stun_server);
 
                 if self
                     .active_punch_sender
-                    .try_send((src_id, punch_info))
+                    .try_send((src_id, punch_info, Instant::now()))
                     .is_err()
                 {
                     log::debug!("active_punch_sender err src_id={self_id:?}");
@@ -892,10 +1514,46 @@ impl PipeLine {
             }
             ProtocolType::IDQuery => {}
             ProtocolType::IDReply => {}
+            ProtocolType::AddrRequest => {
+                // Only a direct route observes the source address of our socket
+                if metric == 0 {
+                    let data = encode_addr(route_key.addr());
+                    let mut send_packet = self
+                        .pipe_writer
+                        .allocate_send_packet_proto(ProtocolType::AddrReply, data.len())?;
+                    send_packet.set_payload(&data);
+                    send_packet.set_dest_id(&src_id);
+                    self.send_to_route(send_packet.buf(), &route_key).await?;
+                }
+            }
+            ProtocolType::AddrReply => {
+                if metric == 0 && route_key.protocol().is_udp() {
+                    let addr = decode_addr(packet.payload())?;
+                    if let Some(addr) = self.pipe_context.observed_addrs.reply(
+                        src_id,
+                        route_key.addr(),
+                        route_key.index(),
+                        addr,
+                    ) {
+                        self.pipe_context
+                            .update_public_addr(route_key.index(), addr);
+                    }
+                }
+            }
+            ProtocolType::CongestionAck => {
+                if let Some(congestion) = self.pipe_context.congestion.as_ref() {
+                    congestion.on_ack(&src_id, decode_ack(packet.payload())?);
+                }
+            }
         }
 
         Ok(None)
     }
+    fn topic_announce_handle(&self, src_id: NodeID, payload: &[u8]) -> Result<()> {
+        let topics = decode_topics(payload)?;
+        self.pipe_context.topics.update_remote(src_id, topics);
+        Ok(())
+    }
     async fn id_route_query_handle(
         &mut self,
         packet: NetPacket<&mut [u8]>,
@@ -991,6 +1649,14 @@ impl PipeLine {
     }
 }
 
+/// Forwarded user data keeps the default class, everything else is control traffic
+fn priority_of(buf: &[u8]) -> Priority {
+    match NetPacket::unchecked(buf).protocol() {
+        Ok(ProtocolType::UserData | ProtocolType::TopicData) => Priority::default(),
+        _ => Priority::Control,
+    }
+}
+
 async fn id_route_reply(
     pipe_writer: PipeWriter,
     other_route_table: Arc<DashMap<GroupCode, RouteTable<NodeID>>>,
@@ -1070,8 +1736,12 @@ struct HandleResultInner {
     pub(crate) route_key: RouteKey,
     pub(crate) ttl: u8,
     pub(crate) max_ttl: u8,
+    pub(crate) channel: u16,
+    pub(crate) is_topic: bool,
     #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
     pub(crate) is_encrypt: bool,
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub(crate) is_compressed: bool,
 }
 
 pub struct RecvUserData {
@@ -1083,6 +1753,8 @@ pub struct RecvUserData {
     _src_id: NodeID,
     _dest_id: NodeID,
     _route_key: RouteKey,
+    _channel: u16,
+    _topic: Option<Topic>,
 }
 
 pub enum Data {
@@ -1110,6 +1782,12 @@ impl DerefMut for Data {
     }
 }
 
+impl AsMut<[u8]> for Data {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
 impl RecvUserData {
     pub fn ttl(&self) -> u8 {
         self._ttl
@@ -1135,6 +1813,13 @@ impl RecvUserData {
     pub fn is_relay(&self) -> bool {
         (self._max_ttl - self._ttl) != 0
     }
+    pub fn channel(&self) -> u16 {
+        self._channel
+    }
+    /// The topic of the data sent by `PipeWriter::publish`
+    pub fn topic(&self) -> Option<Topic> {
+        self._topic
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -1171,3 +1856,179 @@ fn tag(src_id: &NodeID, dest_id: &NodeID) -> [u8; 12] {
     tmp[4..8].copy_from_slice(dest_id.as_ref());
     tmp
 }
+
+/// The connection is authenticated as the pinned node and it relays the packets of the others.
+/// The ttl is set by the sender, so every packet is checked:
+/// the packets of the pinned node are direct and the packets of another node are relayed by it
+#[cfg(feature = "tls")]
+fn check_pinned(pinned_id: NodeID, src_id: NodeID, direct: bool) -> Result<()> {
+    if direct != (pinned_id == src_id) {
+        return Err(Error::InvalidArgument(
+            "src id does not match the pinned certificate".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{PipeConfig, TcpPipeConfig, UdpPipeConfig};
+    use crate::pipe::Pipe;
+    use std::net::SocketAddr;
+    use stun_format::{Attr, MsgType};
+    use tokio::net::UdpSocket;
+
+    async fn stun_request(
+        udp: &UdpSocket,
+        target: SocketAddr,
+        change_ip: bool,
+        change_port: bool,
+    ) -> (MsgType, SocketAddr, Option<SocketAddr>) {
+        let mut buf = [0u8; 128];
+        let mut msg = stun_format::MsgBuilder::from(buf.as_mut_slice());
+        msg.typ(MsgType::BindingRequest);
+        msg.tid(7);
+        msg.add_attr(Attr::ChangeRequest {
+            change_ip,
+            change_port,
+        });
+        udp.send_to(msg.as_bytes(), target).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let (len, source) =
+            tokio::time::timeout(std::time::Duration::from_secs(3), udp.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        let msg = stun_format::Msg::from(&buf[..len]);
+        let other = msg.attrs_iter().find_map(|attr| match attr {
+            Attr::OtherAddress(stun_format::SocketAddr::V4(ip, port)) => {
+                Some(SocketAddr::from((ip, port)))
+            }
+            _ => None,
+        });
+        (msg.typ().unwrap(), source, other)
+    }
+
+    #[tokio::test]
+    async fn stun_server_reply() {
+        let config = PipeConfig::default()
+            .set_udp_pipe_config(UdpPipeConfig::default().set_udp_ports(vec![0, 0]))
+            .set_tcp_pipe_config(TcpPipeConfig::default())
+            .set_udp_stun_servers(vec![])
+            .set_tcp_stun_servers(vec![])
+            .set_interface_watch(false)
+            .set_stun_server(true)
+            .set_group_code(1u128.into())
+            .set_node_id(1u32.into());
+        let mut pipe = Pipe::new(config).await.unwrap();
+        let ports = pipe
+            .pipe_context
+            .punch_info()
+            .read()
+            .local_udp_ports
+            .clone();
+        tokio::spawn(async move {
+            while let Ok(mut line) = pipe.accept().await {
+                tokio::spawn(async move { while line.next().await.is_ok() {} });
+            }
+        });
+        let main: SocketAddr = format!("127.0.0.1:{}", ports[0]).parse().unwrap();
+        let other: SocketAddr = format!("127.0.0.1:{}", ports[1]).parse().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let (typ, source, other_addr) = stun_request(&udp, main, false, false).await;
+        assert!(matches!(typ, MsgType::BindingResponse));
+        assert_eq!((source, other_addr), (main, Some(other)));
+        let (typ, source, _) = stun_request(&udp, main, false, true).await;
+        assert!(matches!(typ, MsgType::BindingResponse));
+        assert_eq!(source, other);
+        let (typ, source, _) = stun_request(&udp, main, true, true).await;
+        assert!(matches!(typ, MsgType::BindingErrorResponse));
+        assert_eq!(source, main);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn check_pinned() {
+        use crate::protocol::node_id::NodeID;
+        let pinned: NodeID = 1u32.into();
+        let other: NodeID = 2u32.into();
+        assert!(super::check_pinned(pinned, pinned, true).is_ok());
+        assert!(super::check_pinned(pinned, other, false).is_ok());
+        // Another node claiming a direct packet, or the ttl lowered to skip the check
+        assert!(super::check_pinned(pinned, other, true).is_err());
+        assert!(super::check_pinned(pinned, pinned, false).is_err());
+    }
+
+    /// A node only reachable by `TCP` behind a pinned relay receives the broadcast of a node
+    /// only reachable by `UDP`. The streams of the simulated network skip the TLS transport,
+    /// so this runs on the loopback
+    #[cfg(all(feature = "tls", feature = "sim"))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn range_broadcast_through_pinned_relay() {
+        use crate::config::{fingerprint, self_signed, TlsConfig};
+        use crate::pipe::sim_tests::{accept, test_config};
+        use crate::pipe::PeerNodeAddress;
+        use crate::protocol::node_id::NodeID;
+        use std::collections::HashMap;
+        use std::time::Duration;
+
+        let config = |id: u32| {
+            test_config(PipeConfig::default(), id)
+                .set_udp_pipe_config(UdpPipeConfig::default().set_udp_ports(vec![0]))
+                .set_tcp_pipe_config(TcpPipeConfig::default().set_tcp_port(0))
+        };
+        let (relay_cert, relay_key) = self_signed("localhost").unwrap();
+        let (cert, key) = self_signed("localhost").unwrap();
+        let relay_pins = HashMap::from([(NodeID::from(2u32), fingerprint(&cert))]);
+        let pins = HashMap::from([(NodeID::from(1u32), fingerprint(&relay_cert))]);
+
+        let relay = Pipe::new(
+            config(1).set_tls(TlsConfig::new(vec![relay_cert], relay_key).set_pins(relay_pins)),
+        )
+        .await
+        .unwrap();
+        let relay_writer = relay.writer();
+        let (udp_port, tcp_port) = {
+            let punch_info = relay_writer.pipe_context().punch_info().read();
+            (punch_info.local_udp_ports[0], punch_info.local_tcp_port)
+        };
+        let _relay_receiver = accept(relay);
+
+        let mut tcp_config = config(2)
+            .set_tls(TlsConfig::new(vec![cert], key).set_pins(pins))
+            .set_direct_addrs(vec![PeerNodeAddress::Tcp(
+                format!("127.0.0.1:{tcp_port}").parse().unwrap(),
+            )]);
+        tcp_config.udp_pipe_config = None;
+        let mut tcp_receiver = accept(Pipe::new(tcp_config).await.unwrap());
+
+        let mut udp_config = config(3).set_direct_addrs(vec![PeerNodeAddress::Udp(
+            format!("127.0.0.1:{udp_port}").parse().unwrap(),
+        )]);
+        udp_config.tcp_pipe_config = None;
+        let udp_pipe = Pipe::new(udp_config).await.unwrap();
+        let udp_writer = udp_pipe.writer();
+        let _udp_receiver = accept(udp_pipe);
+
+        let relayed = tokio::time::timeout(Duration::from_secs(10), async {
+            while !udp_writer
+                .lookup_route(&NodeID::from(2u32))
+                .is_some_and(|routes| routes.iter().all(|route| route.is_relay()))
+            {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+        assert!(relayed.is_ok(), "no relayed route");
+        let mut packet = udp_writer.allocate_send_packet();
+        packet.set_payload(b"hello");
+        udp_writer.broadcast_packet(packet).await.unwrap();
+        let data = tokio::time::timeout(Duration::from_secs(3), tcp_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.payload(), b"hello");
+        assert_eq!(data.src_id(), NodeID::from(3u32));
+    }
+}
@@ -0,0 +1,115 @@
+use crate::protocol::node_id::NodeID;
+use parking_lot::Mutex;
+use rust_p2p_core::route::Index;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// The number of peers that must report the same address before it replaces a STUN result.
+/// A socket without a STUN result, such as when STUN is blocked, takes the first report
+const MIN_AGREEMENT: usize = 2;
+
+/// The `AddrRequest`s of the current round and the addresses the peers reported back.
+/// A reply only counts for an outstanding request, and the public address of a socket
+/// changes once enough peers agree on it
+#[derive(Clone, Default)]
+pub(crate) struct ObservedAddrs {
+    round: Arc<Mutex<Round>>,
+    stun: Arc<Mutex<HashSet<Index>>>,
+}
+
+#[derive(Default)]
+struct Round {
+    requests: HashSet<(NodeID, SocketAddr)>,
+    reports: HashMap<Index, HashMap<SocketAddr, HashSet<NodeID>>>,
+}
+
+impl ObservedAddrs {
+    /// Forgets the requests and the reports of the previous round
+    pub(crate) fn new_round(&self) {
+        *self.round.lock() = Round::default();
+    }
+    pub(crate) fn requested(&self, node_id: NodeID, peer_addr: SocketAddr) {
+        self.round.lock().requests.insert((node_id, peer_addr));
+    }
+    /// The socket got its public address from a STUN server
+    pub(crate) fn stun_replied(&self, index: Index) {
+        self.stun.lock().insert(index);
+    }
+    /// Returns the observed address once enough peers agree on it
+    pub(crate) fn reply(
+        &self,
+        node_id: NodeID,
+        peer_addr: SocketAddr,
+        index: Index,
+        observed: SocketAddr,
+    ) -> Option<SocketAddr> {
+        let mut round = self.round.lock();
+        if !round.requests.contains(&(node_id, peer_addr)) {
+            return None;
+        }
+        let reporters = round
+            .reports
+            .entry(index)
+            .or_default()
+            .entry(observed)
+            .or_default();
+        reporters.insert(node_id);
+        let min_agreement = if self.stun.lock().contains(&index) {
+            MIN_AGREEMENT
+        } else {
+            1
+        };
+        (reporters.len() >= min_agreement).then_some(observed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pipe::observed_addr::ObservedAddrs;
+    use crate::protocol::node_id::NodeID;
+    use rust_p2p_core::pipe::udp_pipe::UDPIndex;
+    use rust_p2p_core::route::Index;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn observed_addr_agreement() {
+        let observed_addrs = ObservedAddrs::default();
+        let index = Index::Udp(UDPIndex::MainV4(0));
+        let (a, b, c) = (NodeID::from(1u32), NodeID::from(2u32), NodeID::from(3u32));
+        let addr = |port: u16| SocketAddr::from(([203, 0, 113, 1], port));
+        let public = SocketAddr::from(([198, 51, 100, 1], 40000));
+        observed_addrs.stun_replied(index);
+        observed_addrs.new_round();
+        observed_addrs.requested(a, addr(1));
+        observed_addrs.requested(b, addr(2));
+        // Unsolicited
+        assert_eq!(observed_addrs.reply(c, addr(3), index, public), None);
+        assert_eq!(observed_addrs.reply(a, addr(1), index, public), None);
+        // The same peer again
+        assert_eq!(observed_addrs.reply(a, addr(1), index, public), None);
+        assert_eq!(
+            observed_addrs.reply(b, addr(2), index, public),
+            Some(public)
+        );
+        observed_addrs.new_round();
+        assert_eq!(observed_addrs.reply(b, addr(2), index, public), None);
+    }
+
+    #[test]
+    fn observed_addr_without_stun() {
+        let observed_addrs = ObservedAddrs::default();
+        let index = Index::Udp(UDPIndex::MainV4(0));
+        let a = NodeID::from(1u32);
+        let addr = SocketAddr::from(([203, 0, 113, 1], 1));
+        let public = SocketAddr::from(([198, 51, 100, 1], 40000));
+        observed_addrs.new_round();
+        observed_addrs.requested(a, addr);
+        // The only direct peer is enough
+        assert_eq!(observed_addrs.reply(a, addr, index, public), Some(public));
+        // Another socket
+        let other = Index::Udp(UDPIndex::MainV4(1));
+        observed_addrs.stun_replied(other);
+        assert_eq!(observed_addrs.reply(a, addr, other, public), None);
+    }
+}
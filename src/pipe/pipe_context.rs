@@ -3,24 +3,36 @@
 #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
 use crate::cipher::Cipher;
 use crate::config::punch_info::NodePunchInfo;
+use crate::congestion::{AckTable, CongestionControl};
 use crate::error::Error;
 use crate::extend::dns_query::{dns_query_all, dns_query_txt};
+use crate::obfuscation::Obfuscator;
+use crate::pipe::capture::{Capture, Direction};
+use crate::pipe::channel::ChannelMap;
+use crate::pipe::observed_addr::ObservedAddrs;
+use crate::pipe::pmtu::PmtuProbes;
+use crate::pipe::topic::TopicTable;
 use crate::protocol::node_id::{GroupCode, NodeID};
 use anyhow::Context;
+use bytes::BytesMut;
 use crossbeam_utils::atomic::AtomicCell;
 use dashmap::DashMap;
 use parking_lot::RwLock;
 use rand::seq::SliceRandom;
 use rust_p2p_core::punch::{PunchConsultInfo, PunchModelBox};
 use rust_p2p_core::route::route_table::RouteTable;
-use rust_p2p_core::route::Index;
+use rust_p2p_core::route::{Index, RouteKey};
 use rust_p2p_core::socket::LocalInterface;
+use std::collections::HashSet;
 use std::fmt::Display;
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// The granularity of the user traffic tracking, the punch scheduling works in seconds
+const ACTIVE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub struct PipeContext {
     self_node_id: Arc<AtomicCell<Option<NodeID>>>,
@@ -36,9 +48,32 @@ pub struct PipeContext {
     #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
     pub(crate) cipher: Option<Cipher>,
     pub(crate) multi_pipeline: usize,
+    pub(crate) channels: ChannelMap,
+    pub(crate) capture: Arc<RwLock<Option<Capture>>>,
+    pub(crate) topics: TopicTable,
+    /// The last user traffic with the peers, they are punched first
+    active_peers: Arc<DashMap<NodeID, Instant>>,
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub(crate) compression: Option<crate::compression::Algorithm>,
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    peer_compression: Arc<DashMap<NodeID, u8>>,
+    /// The peers that advertised `QUIC` in the punch consult
+    #[cfg(feature = "quic")]
+    peer_quic: Arc<DashMap<NodeID, bool>>,
+    /// Whether the peers answer STUN, a peer is absent until its punch consult arrives
+    peer_stun_server: Arc<DashMap<NodeID, bool>>,
+    #[cfg(feature = "turn")]
+    pub(crate) turn_client: Option<rust_p2p_core::turn::TurnClient>,
+    pub(crate) obfuscator: Option<Obfuscator>,
+    pub(crate) congestion: Option<CongestionControl>,
+    /// Acknowledges the congestion controlled senders
+    pub(crate) acks: AckTable,
+    pub(crate) probes: PmtuProbes,
+    pub(crate) observed_addrs: ObservedAddrs,
 }
 pub type DirectNodes = Vec<(NodeAddress, u16, Option<(GroupCode, NodeID)>)>;
 impl PipeContext {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         multi_pipeline: usize,
         local_udp_ports: Vec<u16>,
@@ -46,6 +81,12 @@ impl PipeContext {
         default_interface: Option<LocalInterface>,
         dns: Option<Vec<String>>,
         #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))] cipher: Option<Cipher>,
+        #[cfg(any(feature = "lz4", feature = "zstd"))] compression: Option<
+            crate::compression::Algorithm,
+        >,
+        #[cfg(feature = "turn")] turn_client: Option<rust_p2p_core::turn::TurnClient>,
+        obfuscator: Option<Obfuscator>,
+        congestion: Option<CongestionControl>,
     ) -> Self {
         let punch_info = NodePunchInfo::new(local_udp_ports, local_tcp_port);
         Self {
@@ -61,6 +102,53 @@ impl PipeContext {
             other_route_table: Arc::new(Default::default()),
             #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
             cipher,
+            channels: Arc::new(Default::default()),
+            capture: Arc::new(RwLock::new(None)),
+            topics: Default::default(),
+            active_peers: Arc::new(Default::default()),
+            #[cfg(any(feature = "lz4", feature = "zstd"))]
+            compression,
+            #[cfg(any(feature = "lz4", feature = "zstd"))]
+            peer_compression: Arc::new(Default::default()),
+            #[cfg(feature = "quic")]
+            peer_quic: Arc::new(Default::default()),
+            peer_stun_server: Arc::new(Default::default()),
+            #[cfg(feature = "turn")]
+            turn_client,
+            obfuscator,
+            congestion,
+            acks: Default::default(),
+            probes: Default::default(),
+            observed_addrs: Default::default(),
+        }
+    }
+    /// The packet as it goes on the wire
+    pub(crate) fn obfuscate(&self, buf: BytesMut) -> BytesMut {
+        match &self.obfuscator {
+            Some(obfuscator) => obfuscator.obfuscate(&buf),
+            None => buf,
+        }
+    }
+    /// Called for every user packet, so a peer is only touched once per `ACTIVE_SAMPLE_INTERVAL`
+    pub(crate) fn update_active_peer(&self, node_id: NodeID) {
+        if node_id.is_unspecified() || node_id.is_broadcast() {
+            return;
+        }
+        if let Some(v) = self.active_peers.get(&node_id) {
+            if v.elapsed() < ACTIVE_SAMPLE_INTERVAL {
+                return;
+            }
+        }
+        self.active_peers.insert(node_id, Instant::now());
+    }
+    /// The peers with user traffic within `window`, the others are forgotten
+    pub(crate) fn active_peers(&self, window: Duration) -> HashSet<NodeID> {
+        self.active_peers.retain(|_, v| v.elapsed() < window);
+        self.active_peers.iter().map(|v| *v.key()).collect()
+    }
+    pub(crate) fn capture(&self, direction: Direction, route_key: RouteKey, buf: &[u8]) {
+        if let Some(capture) = self.capture.read().as_ref() {
+            capture.capture(direction, route_key, buf);
         }
     }
     pub fn store_self_id(&self, node_id: NodeID) -> crate::error::Result<()> {
@@ -123,6 +211,16 @@ impl PipeContext {
         }
         addrs
     }
+    /// The `ws://`/`wss://` urls of the direct nodes
+    #[cfg(feature = "websocket")]
+    pub(crate) fn get_direct_websocket_nodes(&self) -> Vec<(String, u16)> {
+        let guard = self.direct_node_address_list.read();
+        guard
+            .iter()
+            .filter(|(addr, _, _)| addr.is_websocket())
+            .map(|(addr, id, _)| (addr.to_string(), *id))
+            .collect()
+    }
     pub fn get_direct_node_id(&self, id: &u16) -> Option<(GroupCode, NodeID)> {
         self.direct_node_id_map
             .get(id)
@@ -232,6 +330,51 @@ impl PipeContext {
     pub fn update_tcp_public_addr(&self, addr: SocketAddr) {
         self.punch_info.write().update_tcp_public_port(addr);
     }
+    #[cfg(feature = "quic")]
+    pub(crate) fn update_peer_quic(&self, node_id: NodeID, quic: bool) {
+        self.peer_quic.insert(node_id, quic);
+    }
+    #[cfg(feature = "quic")]
+    pub(crate) fn peer_quic(&self, node_id: &NodeID) -> bool {
+        self.peer_quic.get(node_id).is_some_and(|v| *v)
+    }
+    pub(crate) fn update_peer_stun_server(&self, node_id: NodeID, stun_server: bool) {
+        self.peer_stun_server.insert(node_id, stun_server);
+    }
+    pub(crate) fn peer_stun_server(&self, node_id: &NodeID) -> bool {
+        self.peer_stun_server.get(node_id).is_some_and(|v| *v)
+    }
+    /// The punch consult with the peer has taken place
+    pub(crate) fn peer_consulted(&self, node_id: &NodeID) -> bool {
+        self.peer_stun_server.contains_key(node_id)
+    }
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub(crate) fn update_peer_compression(&self, node_id: NodeID, compression: u8) {
+        if self.peer_compression.get(&node_id).map(|v| *v) != Some(compression) {
+            self.peer_compression.insert(node_id, compression);
+        }
+    }
+    /// Forget what the peer negotiated once its last route is gone
+    pub(crate) fn clear_peer(&self, node_id: &NodeID) {
+        #[cfg(any(feature = "lz4", feature = "zstd"))]
+        self.peer_compression.remove(node_id);
+        #[cfg(feature = "quic")]
+        self.peer_quic.remove(node_id);
+        self.peer_stun_server.remove(node_id);
+    }
+    /// The compression algorithm to use for sending to the peer
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub(crate) fn peer_compression(
+        &self,
+        node_id: &NodeID,
+    ) -> Option<crate::compression::Algorithm> {
+        let algorithm = self.compression?;
+        let compression = *self.peer_compression.get(node_id)?;
+        if compression & algorithm.id() == 0 {
+            return None;
+        }
+        Some(algorithm)
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -262,8 +405,21 @@ pub enum PeerNodeAddress {
     TcpDomain(String),
     UdpDomain(String),
     TxtDomain(String),
+    /// `ws://host:port/path`, the url without the scheme
+    #[cfg(feature = "websocket")]
+    Ws(String),
+    /// `wss://host:port/path`, the url without the scheme
+    #[cfg(feature = "websocket")]
+    Wss(String),
 }
 impl PeerNodeAddress {
+    pub fn is_websocket(&self) -> bool {
+        #[cfg(feature = "websocket")]
+        if matches!(self, PeerNodeAddress::Ws(_) | PeerNodeAddress::Wss(_)) {
+            return true;
+        }
+        false
+    }
     pub async fn to_addr(
         &self,
         name_servers: &Vec<String>,
@@ -303,6 +459,9 @@ impl PeerNodeAddress {
                 }
                 addrs
             }
+            // Dialed by the websocket connect loop
+            #[cfg(feature = "websocket")]
+            PeerNodeAddress::Ws(_) | PeerNodeAddress::Wss(_) => vec![],
         };
         Ok(addrs)
     }
@@ -312,6 +471,13 @@ impl FromStr for PeerNodeAddress {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let domain = s.to_lowercase();
+        // The path of the url is case-sensitive
+        #[cfg(feature = "websocket")]
+        if domain.starts_with("ws://") {
+            return Ok(PeerNodeAddress::Ws(s["ws://".len()..].to_string()));
+        } else if domain.starts_with("wss://") {
+            return Ok(PeerNodeAddress::Wss(s["wss://".len()..].to_string()));
+        }
         let addr = if let Some(v) = domain.strip_prefix("tcp://") {
             match SocketAddr::from_str(v) {
                 Ok(addr) => PeerNodeAddress::Tcp(addr),
@@ -351,6 +517,14 @@ impl Display for PeerNodeAddress {
             PeerNodeAddress::TxtDomain(addr) => {
                 format!("txt://{addr}")
             }
+            #[cfg(feature = "websocket")]
+            PeerNodeAddress::Ws(addr) => {
+                format!("ws://{addr}")
+            }
+            #[cfg(feature = "websocket")]
+            PeerNodeAddress::Wss(addr) => {
+                format!("wss://{addr}")
+            }
         };
         write!(f, "{}", str)
     }
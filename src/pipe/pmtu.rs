@@ -0,0 +1,162 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use rust_p2p_core::route::{Route, RouteKey};
+use tokio::sync::oneshot;
+
+use crate::obfuscation::OBFUSCATION_HEAD_LEN;
+use crate::pipe::PipeWriter;
+use crate::protocol::echo::PROBE_HEAD_LEN;
+use crate::protocol::node_id::NodeID;
+use crate::protocol::protocol_type::ProtocolType;
+use crate::protocol::HEAD_LEN;
+
+/// Every IPv4 host accepts this
+const MIN_MTU_V4: u16 = 576;
+/// The minimum link MTU of IPv6
+const MIN_MTU_V6: u16 = 1280;
+/// Ethernet, jumbo frames are not probed
+const MAX_MTU: u16 = 1500;
+/// The search stops when the bounds are this close
+const PRECISION: u16 = 8;
+/// A lost probe is sent again before the size is taken as too large
+const PROBE_ATTEMPTS: usize = 2;
+
+/// The probes waiting for their `EchoReply`, the reply comes back on the route of the probe
+#[derive(Clone, Default)]
+pub(crate) struct PmtuProbes {
+    waiting: Arc<DashMap<(NodeID, RouteKey, u16), oneshot::Sender<()>>>,
+}
+
+impl PmtuProbes {
+    pub(crate) fn reply(&self, node_id: NodeID, route_key: RouteKey, size: u16) {
+        if let Some((_, sender)) = self.waiting.remove(&(node_id, route_key, size)) {
+            let _ = sender.send(());
+        }
+    }
+}
+
+/// The largest IP packet that passes the direct `UDP` route,
+/// `None` if the peer answers no probe
+pub(crate) async fn probe_route(
+    pipe_writer: &PipeWriter,
+    node_id: NodeID,
+    route: &Route,
+) -> Option<u16> {
+    let route_key = route.route_key();
+    let min = if route_key.addr().is_ipv4() {
+        MIN_MTU_V4
+    } else {
+        MIN_MTU_V6
+    };
+    let timeout = Duration::from_millis((route.rtt() as u64 * 3).clamp(200, 1000));
+    search(min, MAX_MTU, |size| {
+        probe(pipe_writer, node_id, route_key, size, timeout)
+    })
+    .await
+}
+
+/// Binary search between `min` and `max`, the common `max` is tried first
+async fn search<F, Fut>(min: u16, max: u16, mut probe: F) -> Option<u16>
+where
+    F: FnMut(u16) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    if probe(max).await {
+        return Some(max);
+    }
+    let (mut low, mut high) = (min, max);
+    let mut answered = false;
+    while high - low > PRECISION {
+        let mid = low + (high - low) / 2;
+        if probe(mid).await {
+            low = mid;
+            answered = true;
+        } else {
+            high = mid;
+        }
+    }
+    if answered || probe(low).await {
+        Some(low)
+    } else {
+        None
+    }
+}
+
+async fn probe(
+    pipe_writer: &PipeWriter,
+    node_id: NodeID,
+    route_key: RouteKey,
+    size: u16,
+    timeout: Duration,
+) -> bool {
+    let mut overhead = ip_udp_header_len(&route_key.addr()) + HEAD_LEN;
+    if pipe_writer.pipe_context.obfuscator.is_some() {
+        overhead += OBFUSCATION_HEAD_LEN;
+    }
+    let payload_len = (size as usize).saturating_sub(overhead).max(PROBE_HEAD_LEN);
+    let Ok(mut packet) =
+        pipe_writer.allocate_send_packet_proto(ProtocolType::EchoRequest, payload_len)
+    else {
+        return false;
+    };
+    packet.set_dest_id(&node_id);
+    packet.fill(0);
+    packet[..PROBE_HEAD_LEN].copy_from_slice(&size.to_be_bytes());
+    for _ in 0..PROBE_ATTEMPTS {
+        let (sender, receiver) = oneshot::channel();
+        pipe_writer
+            .pipe_context
+            .probes
+            .waiting
+            .insert((node_id, route_key, size), sender);
+        if let Err(e) = pipe_writer.send_probe(packet.buf(), &route_key).await {
+            log::debug!("pmtu probe {e:?},size={size},route_key={route_key:?}");
+        }
+        if let Ok(Ok(())) = tokio::time::timeout(timeout, receiver).await {
+            return true;
+        }
+    }
+    pipe_writer
+        .pipe_context
+        .probes
+        .waiting
+        .remove(&(node_id, route_key, size));
+    false
+}
+
+fn ip_udp_header_len(addr: &SocketAddr) -> usize {
+    if addr.is_ipv4() {
+        20 + 8
+    } else {
+        40 + 8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{search, MAX_MTU, MIN_MTU_V4, PRECISION};
+
+    #[test]
+    fn search_path_mtu() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        for path_mtu in [1500, 1492, 1420, 1280, 600] {
+            let mtu = rt
+                .block_on(search(MIN_MTU_V4, MAX_MTU, |size| async move {
+                    size <= path_mtu
+                }))
+                .unwrap();
+            assert!(
+                mtu <= path_mtu && path_mtu - mtu <= PRECISION,
+                "{mtu} {path_mtu}"
+            );
+        }
+        let rs = rt.block_on(search(MIN_MTU_V4, MAX_MTU, |_| async { false }));
+        assert!(rs.is_none());
+    }
+}
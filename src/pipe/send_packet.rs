@@ -1,6 +1,7 @@
 use std::ops::{Deref, DerefMut};
 
 use bytes::BytesMut;
+use rust_p2p_core::pipe::priority::Priority;
 
 use crate::protocol::node_id::{GroupCode, NodeID};
 use crate::protocol::protocol_type::ProtocolType;
@@ -9,6 +10,7 @@ use crate::protocol::{NetPacket, HEAD_LEN};
 #[derive(Clone)]
 pub struct SendPacket {
     buf: BytesMut,
+    priority: Priority,
 }
 impl From<&[u8]> for SendPacket {
     fn from(value: &[u8]) -> Self {
@@ -26,7 +28,10 @@ impl SendPacket {
     }
     pub fn with_bytes_mut(mut buf: BytesMut) -> Self {
         buf.resize(HEAD_LEN, 0);
-        let mut send_packet = Self { buf };
+        let mut send_packet = Self {
+            buf,
+            priority: Priority::default(),
+        };
         let mut packet = NetPacket::unchecked(send_packet.buf_mut());
         packet.set_protocol(ProtocolType::UserData);
         packet.set_ttl(15);
@@ -99,20 +104,51 @@ impl SendPacket {
         let mut packet = NetPacket::unchecked(self.buf_mut());
         packet.set_dest_id(id);
     }
+    pub(crate) fn set_protocol(&mut self, protocol_type: ProtocolType) {
+        let mut packet = NetPacket::unchecked(self.buf_mut());
+        packet.set_protocol(protocol_type);
+    }
+    pub fn set_channel(&mut self, channel: u16) {
+        let mut packet = NetPacket::unchecked(self.buf_mut());
+        packet.set_channel(channel);
+    }
+    pub fn channel(&self) -> u16 {
+        NetPacket::unchecked(self.buf()).channel()
+    }
+    /// Sets the class used to schedule this packet on the local send queues
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
     #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
     pub(crate) fn set_encrypt_flag(&mut self, flag: bool) {
         let mut packet = NetPacket::unchecked(self.buf_mut());
         packet.set_encrypt_flag(flag);
     }
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub(crate) fn set_compressed_flag(&mut self, flag: bool) {
+        let mut packet = NetPacket::unchecked(self.buf_mut());
+        packet.set_compressed_flag(flag);
+    }
+    pub(crate) fn set_ack_request_flag(&mut self, flag: bool) {
+        let mut packet = NetPacket::unchecked(self.buf_mut());
+        packet.set_ack_request_flag(flag);
+    }
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    pub(crate) fn set_compression(&mut self, compression: u8) {
+        let mut packet = NetPacket::unchecked(self.buf_mut());
+        packet.set_compression(compression);
+    }
 }
 impl SendPacket {
-    #[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
     pub(crate) fn is_user_data(&self) -> bool {
         let packet = NetPacket::unchecked(self.buf());
-        if let Ok(p) = packet.protocol() {
-            return p == ProtocolType::UserData;
-        }
-        false
+        matches!(
+            packet.protocol(),
+            Ok(ProtocolType::UserData | ProtocolType::TopicData)
+        )
     }
 }
 
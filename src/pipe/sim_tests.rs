@@ -0,0 +1,461 @@
+use crate::config::{PipeConfig, TcpPipeConfig, UdpPipeConfig};
+use crate::pipe::{PeerNodeAddress, Pipe, PipeWriter, RecvUserData};
+use crate::protocol::node_id::NodeID;
+use rust_p2p_core::sim::{LinkConfig, NatType, SimHost, SimNetwork};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// A network of one host per nat, with 5ms links
+fn sim_hosts(seed: u64, nats: &[NatType]) -> (SimNetwork, Vec<SimHost>) {
+    let network = SimNetwork::new(seed);
+    network.set_default_link(LinkConfig::new(Duration::from_millis(5)));
+    let hosts = nats.iter().map(|nat| network.add_host(*nat)).collect();
+    (network, hosts)
+}
+
+/// Node `i + 1` runs on `hosts[i]` with `config(i)`.
+/// The first node serves STUN and is the direct node of the others
+async fn sim_mesh(
+    hosts: &[SimHost],
+    config: impl Fn(usize) -> PipeConfig,
+) -> (Vec<PipeWriter>, Vec<UnboundedReceiver<RecvUserData>>) {
+    let mut writers = Vec::with_capacity(hosts.len());
+    let mut receivers = Vec::with_capacity(hosts.len());
+    let mut direct = None;
+    for (i, host) in hosts.iter().enumerate() {
+        let (writer, receiver) = sim_pipe(host, i as u32 + 1, direct, config(i)).await;
+        if direct.is_none() {
+            let port = writer.pipe_context().punch_info().read().local_udp_ports[0];
+            direct = Some(SocketAddr::new(host.public_ip(), port));
+        }
+        writers.push(writer);
+        receivers.push(receiver);
+    }
+    (writers, receivers)
+}
+
+/// A node of the simulated network, `direct` is the node everyone starts from.
+/// The user data received is sent to the receiver
+async fn sim_pipe(
+    host: &SimHost,
+    id: u32,
+    direct: Option<SocketAddr>,
+    config: PipeConfig,
+) -> (PipeWriter, UnboundedReceiver<RecvUserData>) {
+    let mut config = test_config(config, id)
+        .set_udp_pipe_config(UdpPipeConfig::default().set_udp_ports(vec![0, 0]))
+        .set_tcp_pipe_config(TcpPipeConfig::default())
+        .set_sim_host(host.clone());
+    config = match direct {
+        Some(addr) => config.set_direct_addrs(vec![PeerNodeAddress::Udp(addr)]),
+        None => config.set_stun_server(true),
+    };
+    let pipe = Pipe::new(config).await.unwrap();
+    let writer = pipe.writer();
+    (writer, accept(pipe))
+}
+
+/// Node `id` of group 1, which learns nothing from outside the test
+pub(super) fn test_config(config: PipeConfig, id: u32) -> PipeConfig {
+    config
+        .set_udp_stun_servers(vec![])
+        .set_tcp_stun_servers(vec![])
+        .set_interface_watch(false)
+        .set_use_v6(false)
+        .set_query_id_interval(Duration::from_millis(300))
+        .set_group_code(1u128.into())
+        .set_node_id(id.into())
+}
+
+/// The user data received by the pipe is sent to the receiver
+pub(super) fn accept(mut pipe: Pipe) -> UnboundedReceiver<RecvUserData> {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(mut line) = pipe.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                while let Ok(rs) = line.next().await {
+                    match rs {
+                        Ok(data) => _ = sender.send(data),
+                        Err(e) => log::debug!("test pipe {e:?}"),
+                    }
+                }
+            });
+        }
+    });
+    receiver
+}
+
+/// Punch until every node knows every other node and `done` holds
+async fn sim_converge(writers: &[PipeWriter], done: impl Fn() -> bool) {
+    let ids: Vec<NodeID> = (1..=writers.len() as u32).map(NodeID::from).collect();
+    let converged = || {
+        writers.iter().enumerate().all(|(i, writer)| {
+            let nodes = writer.nodes();
+            ids.iter()
+                .enumerate()
+                .all(|(j, id)| i == j || nodes.contains(id))
+        }) && done()
+    };
+    let rs = tokio::time::timeout(Duration::from_secs(15), async {
+        while !converged() {
+            for (i, writer) in writers.iter().enumerate() {
+                for (j, id) in ids.iter().enumerate() {
+                    if i != j {
+                        _ = writer.punch_now(id).await;
+                    }
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+    })
+    .await;
+    assert!(rs.is_ok(), "not converged");
+}
+
+async fn recv(receiver: &mut UnboundedReceiver<RecvUserData>) -> RecvUserData {
+    tokio::time::timeout(Duration::from_secs(3), receiver.recv())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+/// Every node learns every other node from the direct node, the cones punch each other
+/// and the port restricted and the symmetric nat are left to the relay
+#[tokio::test(flavor = "multi_thread")]
+async fn id_route_and_punch() {
+    use rust_p2p_core::nat::NatBehavior;
+    use rust_p2p_core::punch::PunchStrategy;
+
+    let nats = [
+        NatType::Public,
+        NatType::Cone,
+        NatType::Cone,
+        NatType::PortRestricted,
+        NatType::Symmetric,
+    ];
+    let (_network, hosts) = sim_hosts(7, &nats);
+    let (writers, _receivers) = sim_mesh(&hosts, |_| PipeConfig::default()).await;
+    // What the nat test would find behind the emulated nats, the sim has no stun server to test with
+    for (writer, mapping) in writers[3..].iter().zip([
+        NatBehavior::EndpointIndependent,
+        NatBehavior::AddressAndPortDependent,
+    ]) {
+        let mut guard = writer.pipe_context().punch_info().write();
+        guard.mapping_behavior = mapping;
+        guard.filtering_behavior = NatBehavior::AddressAndPortDependent;
+    }
+    let ids: Vec<NodeID> = (1..=hosts.len() as u32).map(NodeID::from).collect();
+    let direct_to = |from: usize, to: usize| {
+        writers[from].lookup_route(&ids[to]).is_some_and(|routes| {
+            routes.iter().any(|route| {
+                route.is_direct() && route.route_key().addr().ip() == hosts[to].public_ip()
+            })
+        })
+    };
+    sim_converge(&writers, || direct_to(1, 2) && direct_to(2, 1)).await;
+    for to in 1..hosts.len() {
+        assert!(direct_to(0, to) && direct_to(to, 0));
+    }
+    // Nothing gets through a port restricted nat to a symmetric one,
+    // so the port restricted side does not spray random ports at the symmetric one
+    assert!(!direct_to(3, 4) && !direct_to(4, 3));
+    assert!(writers[3].lookup_route(&ids[4]).is_some());
+    let strategies = || {
+        writers[3]
+            .punch_report(&ids[4])
+            .map(|report| report.strategies)
+            .unwrap_or_default()
+    };
+    let rs = tokio::time::timeout(Duration::from_secs(10), async {
+        while !strategies().contains(&PunchStrategy::UdpPredictedPorts) {
+            _ = writers[3].punch_now(&ids[4]).await;
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+    })
+    .await;
+    assert!(rs.is_ok(), "{:?}", strategies());
+    assert!(!strategies().contains(&PunchStrategy::UdpPortPrediction));
+}
+
+/// The broadcast to a node behind a relay is packed into a `RangeBroadcast` for the relay,
+/// which decrypts its own copy by the flag and the tag of the inner packet
+#[cfg(any(feature = "aes-gcm", feature = "chacha20-poly1305"))]
+#[tokio::test(flavor = "multi_thread")]
+async fn encrypted_range_broadcast() {
+    #[cfg(feature = "aes-gcm")]
+    let encryption = crate::cipher::Algorithm::AesGcm("password".into());
+    #[cfg(not(feature = "aes-gcm"))]
+    let encryption = crate::cipher::Algorithm::ChaCha20Poly1305("password".into());
+    let nats = [NatType::Public, NatType::PortRestricted, NatType::Symmetric];
+    let (_network, hosts) = sim_hosts(11, &nats);
+    let (writers, mut receivers) = sim_mesh(&hosts, |_| {
+        PipeConfig::default().set_encryption(encryption.clone())
+    })
+    .await;
+    sim_converge(&writers, || true).await;
+    let routes = writers[1].lookup_route(&NodeID::from(3u32)).unwrap();
+    assert!(routes.iter().all(|route| route.is_relay()));
+
+    let mut packet = writers[1].allocate_send_packet();
+    packet.set_payload(b"hello");
+    writers[1].broadcast_packet(packet).await.unwrap();
+    for receiver in receivers.iter_mut().step_by(2) {
+        let data = recv(receiver).await;
+        assert_eq!(data.payload(), b"hello");
+        assert_eq!(data.src_id(), NodeID::from(2u32));
+        assert_eq!(data.dest_id(), NodeID::broadcast());
+    }
+}
+
+/// The acks come back from the receiver, so they time the whole path
+/// and not the round trip to the relay
+#[tokio::test(flavor = "multi_thread")]
+async fn relayed_congestion_rtt() {
+    use crate::congestion::CongestionConfig;
+
+    let nats = [NatType::Public, NatType::PortRestricted, NatType::Symmetric];
+    let (network, hosts) = sim_hosts(13, &nats);
+    // The relay is close to the sender and far from the receiver
+    network.set_link(
+        hosts[0].public_ip(),
+        hosts[2].public_ip(),
+        LinkConfig::new(Duration::from_millis(50)),
+    );
+    let (writers, mut receivers) = sim_mesh(&hosts, |_| {
+        PipeConfig::default().set_congestion(CongestionConfig::default())
+    })
+    .await;
+    sim_converge(&writers, || true).await;
+    let dest = NodeID::from(3u32);
+    let routes = writers[1].lookup_route(&dest).unwrap();
+    assert!(routes.iter().all(|route| route.is_relay()));
+
+    for _ in 0..50 {
+        let mut packet = writers[1].allocate_send_packet();
+        packet.set_payload(b"hello");
+        writers[1].send_packet_to(packet, &dest).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(recv(&mut receivers[2]).await.payload(), b"hello");
+    let congestion = writers[1].pipe_context().congestion.as_ref().unwrap();
+    let srtt = congestion.srtt(&dest).expect("no ack timed");
+    assert!(srtt >= Duration::from_millis(80), "{srtt:?}");
+    assert!(congestion.srtt(&NodeID::from(1u32)).is_none());
+}
+
+/// The routes of both peers are probed together
+#[tokio::test(flavor = "multi_thread")]
+async fn path_mtu_discovery() {
+    let (network, hosts) = sim_hosts(17, &[NatType::Public; 3]);
+    let mtus = [1400, 1200];
+    for (host, mtu) in hosts[1..].iter().zip(mtus) {
+        network.set_link(
+            hosts[0].public_ip(),
+            host.public_ip(),
+            LinkConfig::new(Duration::from_millis(5)).set_mtu(mtu),
+        );
+    }
+    let (writers, _receivers) = sim_mesh(&hosts, |_| {
+        PipeConfig::default()
+            .set_pmtu_discovery(true)
+            .set_heartbeat_interval(Duration::from_millis(500))
+    })
+    .await;
+    sim_converge(&writers, || true).await;
+    let server = &writers[0];
+    let rs = tokio::time::timeout(Duration::from_secs(15), async {
+        loop {
+            let probed: Vec<_> = [2u32, 3]
+                .iter()
+                .map(|id| server.path_mtu(&NodeID::from(*id)))
+                .collect();
+            if probed.iter().all(Option::is_some) {
+                return probed;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("not probed");
+    for (probed, mtu) in rs.into_iter().zip(mtus) {
+        let probed = probed.unwrap();
+        assert!(probed <= mtu && mtu - probed <= 8, "{probed} {mtu}");
+    }
+}
+
+/// The data of a topic the receiver does not subscribe to is dropped,
+/// even if the publisher still holds a subscription
+#[tokio::test(flavor = "multi_thread")]
+async fn topic_data_of_unsubscribed_topic() {
+    use crate::protocol::topic::Topic;
+
+    let (_network, hosts) = sim_hosts(19, &[NatType::Public; 2]);
+    let (writers, mut receivers) = sim_mesh(&hosts, |_| PipeConfig::default()).await;
+    sim_converge(&writers, || true).await;
+    let (subscribed, stale) = (Topic::from(1u32), Topic::from(2u32));
+    writers[1].subscribe(subscribed).await.unwrap();
+    writers[0]
+        .pipe_context()
+        .topics
+        .update_remote(NodeID::from(2u32), vec![subscribed, stale]);
+
+    writers[0].publish(stale, b"stale").await.unwrap();
+    writers[0].publish(subscribed, b"hello").await.unwrap();
+    let data = recv(&mut receivers[1]).await;
+    assert_eq!(data.topic(), Some(subscribed));
+    assert_eq!(data.payload(), b"hello");
+}
+
+/// The announcement of a subscriber behind a relay is packed into a `RangeBroadcast`,
+/// the relay takes the subscription from it and does not hand it to the application
+#[tokio::test(flavor = "multi_thread")]
+async fn topic_subscriber_behind_relay() {
+    use crate::protocol::topic::Topic;
+
+    let nats = [NatType::Public, NatType::PortRestricted, NatType::Symmetric];
+    let (_network, hosts) = sim_hosts(23, &nats);
+    let (writers, mut receivers) = sim_mesh(&hosts, |_| PipeConfig::default()).await;
+    sim_converge(&writers, || true).await;
+    let subscriber = NodeID::from(3u32);
+    let routes = writers[1].lookup_route(&subscriber).unwrap();
+    assert!(routes.iter().all(|route| route.is_relay()));
+
+    let topic = Topic::from(1u32);
+    writers[2].subscribe(topic).await.unwrap();
+    let rs = tokio::time::timeout(Duration::from_secs(3), async {
+        while !writers[..2].iter().all(|writer| {
+            writer
+                .pipe_context()
+                .topics
+                .subscribers(&topic)
+                .contains(&subscriber)
+        }) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(rs.is_ok(), "subscription not announced");
+    assert!(receivers[0].try_recv().is_err());
+
+    for (i, writer) in writers[..2].iter().enumerate() {
+        writer.publish(topic, b"hello").await.unwrap();
+        let data = recv(&mut receivers[2]).await;
+        assert_eq!(data.topic(), Some(topic));
+        assert_eq!(data.src_id(), NodeID::from(i as u32 + 1));
+        assert_eq!(data.payload(), b"hello");
+    }
+}
+
+/// Between two symmetric nats the consulting side sprays `probes` ports and the other side opens
+/// `mappings_per_socket` mappings from every sub socket. The emulated symmetric nat only lets the
+/// destination of a mapping reply, so the probes never meet and the mappings they open are counted
+#[tokio::test(flavor = "multi_thread")]
+async fn birthday_punch() {
+    use rust_p2p_core::nat::{NatBehavior, NatType as PeerNatType};
+    use rust_p2p_core::punch::{BirthdayPunchConfig, PunchStrategy};
+
+    let (probes, mappings_per_socket) = (200, 2);
+    let sub_num = UdpPipeConfig::default().sub_pipeline_num;
+    let nats = [NatType::Public, NatType::Symmetric, NatType::Symmetric];
+    let (_network, hosts) = sim_hosts(29, &nats);
+    let (writers, _receivers) = sim_mesh(&hosts, |_| {
+        PipeConfig::default().set_birthday_punch(
+            BirthdayPunchConfig::default()
+                .set_probes(probes)
+                .set_mappings_per_socket(mappings_per_socket),
+        )
+    })
+    .await;
+    // What the nat test would find, the sim has no stun server to test with
+    for writer in &writers[1..] {
+        writer.switch_model(PeerNatType::Symmetric).unwrap();
+        let mut guard = writer.pipe_context().punch_info().write();
+        guard.nat_type = PeerNatType::Symmetric;
+        guard.mapping_behavior = NatBehavior::AddressAndPortDependent;
+        guard.filtering_behavior = NatBehavior::AddressAndPortDependent;
+    }
+    sim_converge(&writers, || true).await;
+    let before: Vec<usize> = hosts[1..].iter().map(|host| host.mapping_num()).collect();
+    let opened = |i: usize| hosts[i + 1].mapping_num() - before[i];
+    let dest = NodeID::from(3u32);
+    let rs = tokio::time::timeout(Duration::from_secs(10), async {
+        writers[1].punch_now(&dest).await.unwrap();
+        while opened(0) < probes - 1 || opened(1) < mappings_per_socket * sub_num {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+    assert!(rs.is_ok(), "{} {}", opened(0), opened(1));
+    let report = writers[1].punch_report(&dest).unwrap();
+    assert!(report.strategies.contains(&PunchStrategy::UdpBirthday));
+}
+
+/// The STUN server is advertised in the punch consult, the peers reached directly learn it too
+#[tokio::test(flavor = "multi_thread")]
+async fn stun_server_advertised() {
+    let (_network, hosts) = sim_hosts(31, &[NatType::Public; 2]);
+    let (writers, _receivers) = sim_mesh(&hosts, |_| PipeConfig::default()).await;
+    let (server, client) = (NodeID::from(1u32), NodeID::from(2u32));
+    sim_converge(&writers, || {
+        writers[0].pipe_context().peer_consulted(&client)
+            && writers[1].pipe_context().peer_consulted(&server)
+    })
+    .await;
+    assert!(writers[1].pipe_context().peer_stun_server(&server));
+    assert!(!writers[0].pipe_context().peer_stun_server(&client));
+}
+
+/// A node of the mesh serves the behaviour discovery with the port it changes,
+/// which tells the port dependent nats apart
+#[tokio::test(flavor = "multi_thread")]
+async fn stun_behavior_from_mesh_member() {
+    use rust_p2p_core::nat::{NatBehavior, NatType as PeerNatType};
+
+    let nats = [
+        NatType::Public,
+        NatType::Cone,
+        NatType::PortRestricted,
+        NatType::Symmetric,
+    ];
+    let (_network, hosts) = sim_hosts(37, &nats);
+    let (server, _receiver) = sim_pipe(&hosts[0], 1, None, PipeConfig::default()).await;
+    let port = {
+        // What the nat test would find, the sim has no stun server to test with
+        let mut guard = server.pipe_context().punch_info().write();
+        guard.set_public_ip(vec![match hosts[0].public_ip() {
+            std::net::IpAddr::V4(ip) => ip,
+            std::net::IpAddr::V6(_) => unreachable!(),
+        }]);
+        guard.local_udp_ports[0]
+    };
+    let stun_server = SocketAddr::new(hosts[0].public_ip(), port).to_string();
+    let mut results = Vec::new();
+    for host in &hosts[1..] {
+        let rs = rust_p2p_core::stun::sim_stun_test_nat_behavior(vec![stun_server.clone()], host)
+            .await
+            .unwrap();
+        results.push((rs.nat_type, rs.mapping_behavior, rs.filtering_behavior));
+    }
+    assert_eq!(
+        results,
+        vec![
+            (
+                PeerNatType::Cone,
+                NatBehavior::Unknown,
+                NatBehavior::AddressDependent
+            ),
+            (
+                PeerNatType::Cone,
+                NatBehavior::Unknown,
+                NatBehavior::AddressAndPortDependent
+            ),
+            (
+                PeerNatType::Symmetric,
+                NatBehavior::AddressAndPortDependent,
+                NatBehavior::AddressAndPortDependent
+            ),
+        ]
+    );
+}
@@ -0,0 +1,52 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use parking_lot::RwLock;
+
+use crate::protocol::node_id::NodeID;
+use crate::protocol::topic::Topic;
+
+/// Topics subscribed by this node and the subscriptions announced by other nodes
+#[derive(Clone, Default)]
+pub(crate) struct TopicTable {
+    local: Arc<RwLock<HashSet<Topic>>>,
+    remote: Arc<DashMap<NodeID, (HashSet<Topic>, Instant)>>,
+}
+
+impl TopicTable {
+    /// Returns false if the topic is already subscribed
+    pub(crate) fn subscribe(&self, topic: Topic) -> bool {
+        self.local.write().insert(topic)
+    }
+    /// Returns false if the topic is not subscribed
+    pub(crate) fn unsubscribe(&self, topic: &Topic) -> bool {
+        self.local.write().remove(topic)
+    }
+    pub(crate) fn is_subscribed(&self, topic: &Topic) -> bool {
+        self.local.read().contains(topic)
+    }
+    pub(crate) fn local_topics(&self) -> Vec<Topic> {
+        self.local.read().iter().copied().collect()
+    }
+    pub(crate) fn update_remote(&self, node_id: NodeID, topics: Vec<Topic>) {
+        if topics.is_empty() {
+            self.remote.remove(&node_id);
+        } else {
+            self.remote
+                .insert(node_id, (topics.into_iter().collect(), Instant::now()));
+        }
+    }
+    pub(crate) fn subscribers(&self, topic: &Topic) -> HashSet<NodeID> {
+        self.remote
+            .iter()
+            .filter(|v| v.value().0.contains(topic))
+            .map(|v| *v.key())
+            .collect()
+    }
+    /// Remove the subscriptions that have not been announced again within `expire`
+    pub(crate) fn clear_expired(&self, expire: Duration) {
+        self.remote.retain(|_, (_, time)| time.elapsed() < expire);
+    }
+}
@@ -0,0 +1,66 @@
+/*
+  Report the source address observed on a direct route
+
+   0                                            15                                              31
+   0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |1|   protocol(7)       |                 data len(16)               |max ttl(4) |curr ttl(4) |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |   compression(8)      |e|c|a|  reserve(5)    |                  channel(16)                  |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                        group code(128)                                      |
+  |                                                                                             |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                         src ID(32)                                          |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                         dest ID(32)                                         |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |              port(16)                       |                 ip(32 or 128)                 |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  protocol = ProtocolType::AddrRequest or ProtocolType::AddrReply
+
+  The payload of ProtocolType::AddrRequest is empty,
+  the reply is sent back on the route the request arrived, so the requester knows which socket it describes.
+  The requests go over the direct UDP routes, and a reply is taken once two peers report the same address
+*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::error::*;
+
+pub(crate) fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut buf = addr.port().to_be_bytes().to_vec();
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+    }
+    buf
+}
+
+pub(crate) fn decode_addr(payload: &[u8]) -> Result<SocketAddr> {
+    if payload.len() < 2 {
+        return Err(Error::InvalidArgument("addr len invalid".into()));
+    }
+    let port = u16::from_be_bytes([payload[0], payload[1]]);
+    let ip = match payload[2..].len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&payload[2..]).unwrap())),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&payload[2..]).unwrap())),
+        _ => return Err(Error::InvalidArgument("addr len invalid".into())),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_addr, encode_addr};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_addr() {
+        for addr in ["1.2.3.4:5678", "[2001:db8::1]:443"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(decode_addr(&encode_addr(addr)).unwrap(), addr);
+        }
+        assert!(decode_addr(&[0, 1, 2]).is_err());
+    }
+}
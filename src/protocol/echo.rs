@@ -6,7 +6,7 @@
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |1|   protocol(7)       |                 data len(16)               |max ttl(4) |curr ttl(4) |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-  |                                         reserve(32)                                         |
+  |   compression(8)      |e|c|a|  reserve(5)    |                  channel(16)                  |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |                                        group code(128)                                      |
   |                                                                                             |
@@ -15,5 +15,18 @@
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |                                         dest ID(32)                                         |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |              probe size(16)                 |                  padding(n)                   |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   protocol = ProtocolType::EchoRequest or ProtocolType::EchoReply
+
+  The heartbeats have no payload. A path MTU probe is padded to the IP packet size it carries,
+  and is answered with the probe size only
 */
+
+/// The payload of the reply to a path MTU probe
+pub(crate) const PROBE_HEAD_LEN: usize = 2;
+
+pub(crate) fn probe_size(payload: &[u8]) -> Option<u16> {
+    let size = payload.get(..PROBE_HEAD_LEN)?;
+    Some(u16::from_be_bytes([size[0], size[1]]))
+}
@@ -4,7 +4,7 @@
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |1|   protocol(7)       |                 data len(16)               |max ttl(4) |curr ttl(4) |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-  |e|                                        reserve(31)                                         |
+  |   compression(8)      |e|c|a|  reserve(5)    |                  channel(16)                  |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |                                        group code(128)                                      |
   |                                                                                             |
@@ -27,13 +27,16 @@ use crate::protocol::protocol_type::ProtocolType;
 
 pub const HEAD_LEN: usize = 32;
 
+pub mod addr;
 pub mod broadcast;
+pub mod congestion;
 pub mod echo;
 pub mod id_route;
 pub mod node_id;
 pub mod protocol_type;
 pub mod punch;
 pub mod timestamp;
+pub mod topic;
 
 pub struct NetPacket<B> {
     buffer: B,
@@ -73,6 +76,20 @@ impl<B: AsRef<[u8]>> NetPacket<B> {
     pub fn is_encrypt(&self) -> bool {
         self.buffer.as_ref()[5] & 0x80 == 0x80
     }
+    pub fn is_compressed(&self) -> bool {
+        self.buffer.as_ref()[5] & 0x40 == 0x40
+    }
+    /// The sender runs congestion control and waits for `CongestionAck`
+    pub fn is_ack_requested(&self) -> bool {
+        self.buffer.as_ref()[5] & 0x20 == 0x20
+    }
+    /// Bit mask of the compression algorithms supported by the sender
+    pub fn compression(&self) -> u8 {
+        self.buffer.as_ref()[4]
+    }
+    pub fn channel(&self) -> u16 {
+        ((self.buffer.as_ref()[6] as u16) << 8) | self.buffer.as_ref()[7] as u16
+    }
 
     pub fn group_code(&self) -> &[u8] {
         &self.buffer.as_ref()[8..24]
@@ -102,7 +119,7 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> NetPacket<B> {
         if ttl <= 1 {
             return false;
         }
-        self.buffer.as_mut()[3] &= 0xF0 | (ttl - 1);
+        self.buffer.as_mut()[3] = (self.max_ttl() << 4) | (ttl - 1);
         true
     }
     pub fn set_ttl(&mut self, ttl: u8) {
@@ -125,6 +142,26 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> NetPacket<B> {
             self.buffer.as_mut()[5] = self.buffer.as_ref()[5] & 0x7F
         };
     }
+    pub fn set_compressed_flag(&mut self, is_compressed: bool) {
+        if is_compressed {
+            self.buffer.as_mut()[5] = self.buffer.as_ref()[5] | 0x40
+        } else {
+            self.buffer.as_mut()[5] = self.buffer.as_ref()[5] & 0xBF
+        };
+    }
+    pub fn set_ack_request_flag(&mut self, ack_request: bool) {
+        if ack_request {
+            self.buffer.as_mut()[5] = self.buffer.as_ref()[5] | 0x20
+        } else {
+            self.buffer.as_mut()[5] = self.buffer.as_ref()[5] & 0xDF
+        };
+    }
+    pub fn set_compression(&mut self, compression: u8) {
+        self.buffer.as_mut()[4] = compression;
+    }
+    pub fn set_channel(&mut self, channel: u16) {
+        self.buffer.as_mut()[6..8].copy_from_slice(&channel.to_be_bytes());
+    }
     pub fn set_group_code(&mut self, group_code: &GroupCode) {
         self.buffer.as_mut()[8..24].copy_from_slice(group_code.as_ref());
     }
@@ -166,7 +203,7 @@ impl<B: AsRef<[u8]>> Debug for NetPacket<B> {
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |{high}|   {0:^14}  |       {1:^30}      |   {2:^9} | {3:^9}   |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-  |                                       reserve(32)                                           |
+  |{9:^23}|{10}|{11}|{12}|  reserve(5)   |{8:^47}|
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   | {4:^91} |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
@@ -184,7 +221,12 @@ impl<B: AsRef<[u8]>> Debug for NetPacket<B> {
             group_code,
             src_id,
             dest_id,
-            format!("{}bytes", payload_size)
+            format!("{}bytes", payload_size),
+            format!("channel {}", self.channel()),
+            format!("compression {:08b}", self.compression()),
+            self.is_encrypt() as u8,
+            self.is_compressed() as u8,
+            self.is_ack_requested() as u8,
         );
         f.write_str(&s)
     }
@@ -205,11 +247,24 @@ mod test {
         packet.set_src_id(&3.into());
         packet.set_dest_id(&2.into());
         packet.set_protocol(ProtocolType::IDRouteQuery);
+        packet.set_encrypt_flag(true);
+        packet.set_channel(0x1234);
+        packet.set_compressed_flag(true);
+        packet.set_ack_request_flag(true);
+        packet.set_compression(0b11);
         println!("{:?}", packet);
         assert_eq!(packet.max_ttl(), packet.ttl());
         assert_eq!(packet.max_ttl(), 2);
         assert_eq!(packet.dest_id(), &2_u32.to_be_bytes());
         assert_eq!(packet.src_id(), &3_u32.to_be_bytes());
         assert_eq!(packet.protocol().unwrap(), ProtocolType::IDRouteQuery);
+        assert!(packet.is_encrypt());
+        assert!(packet.is_compressed());
+        assert!(packet.is_ack_requested());
+        assert_eq!(packet.compression(), 0b11);
+        assert_eq!(packet.channel(), 0x1234);
+        assert!(packet.incr_ttl());
+        assert_eq!((packet.max_ttl(), packet.ttl()), (2, 1));
+        assert!(!packet.incr_ttl());
     }
 }
@@ -19,13 +19,21 @@ pub enum ProtocolType {
     RangeBroadcast = 11,
     IDQuery = 12,
     IDReply = 13,
+    /// Subscribed topics of the node
+    TopicAnnounce = 14,
+    TopicData = 15,
+    /// Query the source address the peer observes
+    AddrRequest = 16,
+    AddrReply = 17,
+    /// The bytes received from the congestion controlled sender
+    CongestionAck = 18,
 }
 
 impl TryFrom<u8> for ProtocolType {
     type Error = crate::error::Error;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        const MAX: u8 = ProtocolType::IDReply as u8;
+        const MAX: u8 = ProtocolType::CongestionAck as u8;
         match value {
             0..=MAX => unsafe { Ok(std::mem::transmute::<u8, ProtocolType>(value)) },
             val => Err(crate::error::Error::InvalidArgument(format!(
@@ -0,0 +1,94 @@
+/*
+  Announce the subscribed topics
+
+   0                                            15                                              31
+   0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |1|   protocol(7)       |                 data len(16)               |max ttl(4) |curr ttl(4) |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |   compression(8)      |e|c|a|  reserve(5)    |                  channel(16)                  |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                        group code(128)                                      |
+  |                                                                                             |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                         src ID(32)                                          |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                         dest ID(32)                                         |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                         topic(32)                                           |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  |                                         topic...                                            |
+  +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+  protocol = ProtocolType::TopicAnnounce
+
+  The payload of ProtocolType::TopicData is topic(32) followed by the user data,
+  it is encrypted and compressed like ProtocolType::UserData
+*/
+
+use sha2::Digest;
+
+use crate::error::*;
+
+pub(crate) const TOPIC_LEN: usize = 4;
+
+/// Identifier of a named topic
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Topic(u32);
+
+impl Topic {
+    /// The topic is identified by the hash of the name
+    pub fn new(name: &str) -> Topic {
+        let hash = sha2::Sha256::digest(name.as_bytes());
+        Topic(u32::from_be_bytes(hash[..TOPIC_LEN].try_into().unwrap()))
+    }
+}
+
+impl From<u32> for Topic {
+    fn from(value: u32) -> Self {
+        Topic(value)
+    }
+}
+
+impl From<Topic> for u32 {
+    fn from(value: Topic) -> Self {
+        value.0
+    }
+}
+
+impl TryFrom<&[u8]> for Topic {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let buf: [u8; TOPIC_LEN] = value
+            .get(..TOPIC_LEN)
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| Error::InvalidArgument("topic len invalid".into()))?;
+        Ok(Topic(u32::from_be_bytes(buf)))
+    }
+}
+
+pub(crate) fn encode_topics(topics: &[Topic]) -> Vec<u8> {
+    topics.iter().flat_map(|v| v.0.to_be_bytes()).collect()
+}
+
+pub(crate) fn decode_topics(payload: &[u8]) -> Result<Vec<Topic>> {
+    let chunks = payload.chunks_exact(TOPIC_LEN);
+    if !chunks.remainder().is_empty() {
+        return Err(Error::InvalidArgument("topic list len invalid".into()));
+    }
+    chunks.map(Topic::try_from).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocol::topic::{decode_topics, encode_topics, Topic};
+
+    #[test]
+    fn test_topics() {
+        assert_eq!(Topic::new("metrics"), Topic::new("metrics"));
+        assert_ne!(Topic::new("metrics"), Topic::new("events"));
+        let topics = vec![Topic::new("metrics"), Topic::from(7)];
+        assert_eq!(decode_topics(&encode_topics(&topics)).unwrap(), topics);
+        assert!(decode_topics(&[1, 2, 3]).is_err());
+    }
+}